@@ -1,4 +1,4 @@
-use mars_raw_utils::print;
+use mars_raw_utils::{calibfile, memcache, print};
 mod subs;
 use subs::runnable::RunnableSubcommand;
 use subs::*;
@@ -15,6 +15,20 @@ struct Cli {
 
     #[clap(long, short, help = "Verbose output")]
     verbose: bool,
+
+    #[clap(
+        long,
+        global = true,
+        help = "Calibration data directory, searched before all others"
+    )]
+    data_path: Option<String>,
+
+    #[clap(
+        long,
+        global = true,
+        help = "Memory budget of each calibration file cache, in megabytes"
+    )]
+    cache_mb: Option<usize>,
 }
 
 #[derive(Subcommand)]
@@ -36,6 +50,7 @@ enum Mru {
 
     Calibrate(calibrate::Calibrate),
     Anaglyph(anaglyph::Anaglyph),
+    ColorCorrect(colorcorrect::ColorCorrect),
     Composite(composite::Composite),
     Crop(crop::Crop),
     Debayer(debayer::Debayer),
@@ -43,15 +58,32 @@ enum Mru {
     #[clap(name = "diffgif")]
     DiffGif(diffgif::DiffGif),
     FocusMerge(focusmerge::FocusMerge),
+    FocusStacks(focusstacks::FocusStacks),
     MeanStack(meanstack::MeanStack),
+    MotionDetect(motiondetect::MotionDetect),
     HpcFilter(hpcfilter::HpcFilter),
     Inpaint(inpaint::Inpaint),
     Levels(levels::Levels),
+    Assemble(assemble::Assemble),
+    Histogram(histogram::Histogram),
+    Lut(lut::Lut),
+    MakeFlat(makeflat::MakeFlat),
+    MakeMask(makemask::MakeMask),
     Info(info::Info),
     Xeye(xeye::CrossEye),
     Profile(profile::Profile),
     Decorr(decorr::DecorrelationStretch),
+    PointCloud(pointcloud::PointCloudExport),
+    StereoPairs(stereopairs::StereoPairs),
+    Spectra(spectra::Spectra),
+    SpectralParams(spectralparams::SpectralParams),
+
+    #[clap(name = "superres")]
+    SuperRes(superres::SuperRes),
     UpdateCalData(caldata::UpdateCalData),
+
+    #[clap(name = "caldata")]
+    CalData(caldata::CalData),
 }
 
 #[tokio::main]
@@ -63,6 +95,12 @@ async fn main() {
         print::set_verbose(true);
     }
 
+    calibfile::set_cli_data_path(args.data_path);
+    if let Some(mb) = args.cache_mb {
+        memcache::set_memory_budget(mb * 1024 * 1024);
+    }
+    let verbose = args.verbose;
+
     match args.command {
         Mru::MslFetch(args) => {
             args.run().await;
@@ -103,6 +141,9 @@ async fn main() {
         Mru::Anaglyph(args) => {
             args.run().await;
         }
+        Mru::ColorCorrect(args) => {
+            args.run().await;
+        }
         Mru::Composite(args) => {
             args.run().await;
         }
@@ -118,15 +159,45 @@ async fn main() {
         Mru::FocusMerge(args) => {
             args.run().await;
         }
+        Mru::FocusStacks(args) => {
+            args.run().await;
+        }
         Mru::MeanStack(args) => {
             args.run().await;
         }
+        Mru::MotionDetect(args) => {
+            args.run().await;
+        }
+        Mru::SuperRes(args) => {
+            args.run().await;
+        }
+        Mru::Spectra(args) => {
+            args.run().await;
+        }
+        Mru::SpectralParams(args) => {
+            args.run().await;
+        }
         Mru::HpcFilter(args) => {
             args.run().await;
         }
         Mru::Inpaint(args) => {
             args.run().await;
         }
+        Mru::Assemble(args) => {
+            args.run().await;
+        }
+        Mru::Histogram(args) => {
+            args.run().await;
+        }
+        Mru::Lut(args) => {
+            args.run().await;
+        }
+        Mru::MakeFlat(args) => {
+            args.run().await;
+        }
+        Mru::MakeMask(args) => {
+            args.run().await;
+        }
         Mru::Levels(args) => {
             args.run().await;
         }
@@ -142,9 +213,35 @@ async fn main() {
         Mru::Decorr(args) => {
             args.run().await;
         }
+        Mru::CalData(args) => {
+            args.run().await;
+        }
         Mru::UpdateCalData(args) => {
             args.run().await;
         }
+        Mru::PointCloud(args) => {
+            args.run().await;
+        }
+        Mru::StereoPairs(args) => {
+            args.run().await;
+        }
     };
+
+    if verbose {
+        for (name, stats) in memcache::cache_stats() {
+            if stats.hits + stats.misses > 0 {
+                println!(
+                    "Calibration {} cache: {} hits, {} misses, {} load errors, {} evictions, {} entries ({} bytes)",
+                    name,
+                    stats.hits,
+                    stats.misses,
+                    stats.load_errors,
+                    stats.evictions,
+                    stats.entries,
+                    stats.bytes
+                );
+            }
+        }
+    }
     println!("Runtime: {}s", t1.elapsed().as_secs_f64());
 }
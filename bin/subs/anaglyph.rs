@@ -1,12 +1,32 @@
 use crate::subs::runnable::RunnableSubcommand;
 use async_trait::async_trait;
 use clap::Parser;
+use mars_raw_utils::hyperstereo;
 use mars_raw_utils::prelude::*;
-use sciimg::{drawable::*, prelude::*, vector::Vector};
+use mars_raw_utils::stereopair::StereoImagePair;
+use sciimg::prelude::*;
 use std::process;
 
 pb_create_spinner!();
 
+pub fn print_report(report: &hyperstereo::HyperstereoReport) {
+    let b = &report.baseline;
+    println!(
+        "Baseline: {:.3}m between site {} drive {} and site {} drive {}",
+        b.length, b.left.site, b.left.drive, b.right.site, b.right.drive
+    );
+    println!(
+        "Nearest comfortable subject distance: {:.1}m",
+        report.nearest_comfortable_distance
+    );
+    if let (Some(r), Some(d)) = (report.nearest_ground_distance, report.max_disparity) {
+        println!("Expected disparity at {:.1}m: {:.1} pixels", r, d);
+    }
+    for warning in report.warnings() {
+        eprintln!("Warning: {}", warning);
+    }
+}
+
 #[derive(Parser)]
 #[command(author, version, about = "Generate anaglyph from stereo pair", long_about = None)]
 pub struct Anaglyph {
@@ -21,6 +41,59 @@ pub struct Anaglyph {
 
     #[arg(long, short, help = "Monochrome color (before converting to red/blue)")]
     mono: bool,
+
+    #[arg(
+        long,
+        short = 'M',
+        help = "Anaglyph mode (true, gray, color, half-color, optimized, dubois-red-cyan, dubois-green-magenta, dubois-amber-blue)"
+    )]
+    mode: Option<anaglyph::AnaglyphMode>,
+
+    #[arg(
+        long,
+        short = 'H',
+        help = "Horizontal convergence offset of the right eye (pixels)",
+        allow_hyphen_values = true
+    )]
+    horizontal_offset: Option<i32>,
+
+    #[arg(
+        long,
+        help = "Vertical disparity correction of the right eye (pixels)",
+        allow_hyphen_values = true
+    )]
+    vertical_offset: Option<i32>,
+
+    #[arg(long, short = 'a', help = "Automatically correct vertical disparity")]
+    auto_vertical: bool,
+
+    #[arg(
+        long,
+        short = 'y',
+        help = "Hyperstereo: pair images taken from different rover positions"
+    )]
+    hyperstereo: bool,
+
+    #[arg(
+        long,
+        short = 'S',
+        help = "Hyperstereo: JSON file of site frame origins for images from different sites"
+    )]
+    site_frames: Option<std::path::PathBuf>,
+}
+
+/// Loads the site frame origins given on the command line, if any
+pub fn load_site_frames(site_frames: &Option<std::path::PathBuf>) -> hyperstereo::SiteFrames {
+    match site_frames {
+        Some(f) => match hyperstereo::SiteFrames::load(f.as_os_str().to_str().unwrap()) {
+            Ok(sites) => sites,
+            Err(why) => {
+                eprintln!("Error: Failed to load site frames {:?}: {}", f, why);
+                process::exit(1);
+            }
+        },
+        None => hyperstereo::SiteFrames::default(),
+    }
 }
 #[async_trait]
 impl RunnableSubcommand for Anaglyph {
@@ -53,68 +126,58 @@ impl RunnableSubcommand for Anaglyph {
             process::exit(1);
         }
 
-        let mut left_img = MarsImage::open(left_image_path, Instrument::M20MastcamZLeft);
-        let mut right_img = MarsImage::open(right_image_path, Instrument::M20MastcamZRight);
+        let mut pair = match StereoImagePair::open(&left_image_path, &right_image_path) {
+            Ok(pair) => pair,
+            Err(why) => {
+                eprintln!("Error: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        };
 
         if self.mono {
             vprintln!("Converting input images to monochrome...");
-            left_img.to_mono();
-            right_img.to_mono();
+            pair.to_mono();
         }
 
-        let left_cahv = if let Some(left_md) = &left_img.metadata {
-            if left_md.camera_model_component_list.is_valid() {
-                left_md.camera_model_component_list.clone()
-            } else {
-                pb_done_with_error!();
-                process::exit(2);
-            }
-        } else {
-            pb_done_with_error!();
-            process::exit(1);
+        let options = anaglyph::AnaglyphOptions {
+            mode: self.mode.unwrap_or_default(),
+            horizontal_offset: self.horizontal_offset.unwrap_or(0),
+            vertical_offset: self.vertical_offset.unwrap_or(0),
+            auto_vertical: self.auto_vertical,
         };
 
-        let right_cahv = if let Some(right_md) = &right_img.metadata {
-            if right_md.camera_model_component_list.is_valid() {
-                right_md.camera_model_component_list.clone()
-            } else {
+        if self.hyperstereo {
+            let sites = load_site_frames(&self.site_frames);
+            match hyperstereo::create_hyperstereo_anaglyph(
+                &pair.left,
+                &pair.right,
+                &options,
+                &sites,
+            ) {
+                Ok((map, report)) => {
+                    print_report(&report);
+                    map.save(out_file_path);
+                    pb_done!();
+                }
+                Err(why) => {
+                    eprintln!("Error: {}", why);
+                    pb_done_with_error!();
+                    process::exit(2);
+                }
+            }
+            return;
+        }
+
+        let map = match pair.to_anaglyph(&options) {
+            Ok(map) => map,
+            Err(why) => {
+                eprintln!("Error: {}", why);
                 pb_done_with_error!();
                 process::exit(2);
             }
-        } else {
-            pb_done_with_error!();
-            process::exit(1);
         };
 
-        let ground = Vector::new(0.0, 0.0, 1.84566);
-
-        let mut map = Image::create(left_img.image.width, left_img.image.height);
-        let output_model = left_cahv
-            .linearize(
-                left_img.image.width,
-                left_img.image.height,
-                left_img.image.width,
-                left_img.image.height,
-            )
-            .unwrap();
-
-        anaglyph::process_image(
-            &right_img,
-            &mut map,
-            &right_cahv,
-            &output_model,
-            &ground,
-            Eye::Right,
-        );
-        anaglyph::process_image(
-            &left_img,
-            &mut map,
-            &left_cahv,
-            &output_model,
-            &ground,
-            Eye::Left,
-        );
-
         map.save(out_file_path);
         pb_done!();
     }
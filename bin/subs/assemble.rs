@@ -0,0 +1,184 @@
+use mars_raw_utils::prelude::*;
+
+use crate::subs::runnable::RunnableSubcommand;
+use colored::{self, Colorize};
+use mars_raw_utils::tileassembly::{AssemblyOptions, Tile};
+use mars_raw_utils::tilelevels::{self, LevelOptions, LevelReport};
+use std::collections::HashMap;
+use std::process;
+
+use clap::Parser;
+
+pb_create_spinner!();
+
+#[derive(Parser)]
+#[command(author, version, about = "Reassemble subframed images by their subframe rectangles", long_about = None)]
+pub struct Assemble {
+    #[arg(long, short, help = "Input images", num_args = 1..)]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[arg(long, short, help = "Output image")]
+    output: std::path::PathBuf,
+
+    #[arg(long, short, help = "Pixels to exclude along each tile edge")]
+    border: Option<usize>,
+
+    #[arg(long, short, help = "Crop to the area covered by tiles")]
+    crop: bool,
+
+    #[arg(long, short, help = "Skip tile level matching")]
+    no_match: bool,
+
+    #[arg(long, short, help = "Remove remaining seams in the gradient domain")]
+    seams: bool,
+
+    #[arg(long, short, help = "Report level mismatch per seam")]
+    residuals: bool,
+
+    #[arg(
+        long,
+        help = "Match tile levels pair by pair outward from the first tile instead of solving them together"
+    )]
+    no_solve: bool,
+}
+
+/// Opens and places the input tiles. Tiles at a scale factor other than the most common
+/// one (typically a full frame thumbnail sent with scale factor 1 subframes) are discarded.
+pub fn load_tiles(in_files: &[String]) -> Vec<Tile> {
+    let mut tiles: Vec<Tile> = vec![];
+    for in_file in in_files.iter() {
+        match Tile::open(in_file) {
+            Ok(t) => tiles.push(t),
+            Err(why) => {
+                eprintln!("{}: {}: {}", "ERROR".red(), in_file, why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        }
+    }
+
+    let mut counts: HashMap<u32, usize> = HashMap::new();
+    for t in tiles.iter() {
+        *counts.entry(t.scale).or_insert(0) += 1;
+    }
+    let scale = match counts
+        .iter()
+        .max_by_key(|(s, c)| (**c, std::cmp::Reverse(**s)))
+    {
+        Some((s, _)) => *s,
+        None => return tiles,
+    };
+    tiles
+        .into_iter()
+        .filter(|t| {
+            if t.scale != scale {
+                println!(
+                    "{}: Discarding image at scale factor {}: {}",
+                    "WARNING".yellow(),
+                    t.scale,
+                    t.image.file_path.clone().unwrap_or_default()
+                );
+            }
+            t.scale == scale
+        })
+        .collect()
+}
+
+/// Prints the level mismatch of each seam before and after matching
+pub fn print_residuals(report: &LevelReport) {
+    for (before, after) in report.before.iter().zip(report.after.iter()) {
+        let rms = match (before.rms, after.rms) {
+            (Some(b), Some(a)) => format!(", rms {:.2} -> {:.2}", b, a),
+            _ => String::from(""),
+        };
+        println!(
+            "Seam {}-{} band {}: mean difference {:.2} -> {:.2}, spread difference {:.2} -> {:.2}{}",
+            before.a,
+            before.b,
+            before.band,
+            before.mean_difference,
+            after.mean_difference,
+            before.spread_difference,
+            after.spread_difference,
+            rms
+        );
+    }
+}
+
+/// Assembles `in_files` into `output`, writing its metadata alongside. Returns the level
+/// matching report.
+pub fn assemble_files(
+    in_files: &[String],
+    output: &str,
+    options: &AssemblyOptions,
+    levels: &LevelOptions,
+) -> LevelReport {
+    let mut tiles = load_tiles(in_files);
+    if tiles.is_empty() {
+        eprintln!("{}: No images to assemble, exiting...", "ERROR".red());
+        pb_done_with_error!();
+        process::exit(1);
+    }
+
+    let (mut assembly, report) = match tilelevels::assemble_levelled(&mut tiles, options, levels) {
+        Ok(r) => r,
+        Err(why) => {
+            eprintln!("{}: {}", "ERROR".red(), why);
+            pb_done_with_error!();
+            process::exit(1);
+        }
+    };
+    for p in report.pairs.iter() {
+        vprintln!(
+            "Tiles {} and {} {}",
+            p.a,
+            p.b,
+            if p.overlapping { "overlap" } else { "abut" }
+        );
+    }
+
+    vprintln!("Saving composite to {}", output);
+    assembly.image.normalize_to_8bit();
+    assembly.image.save(output);
+
+    if let Some(md) = assembly.merged_metadata(&tiles) {
+        util::save_image_json(output, &md, false, None).unwrap();
+    }
+    report
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for Assemble {
+    async fn run(&self) {
+        pb_set_print!();
+
+        let in_files: Vec<String> = self
+            .input_files
+            .iter()
+            .map(|s| String::from(s.as_os_str().to_str().unwrap()))
+            .collect();
+
+        let options = AssemblyOptions {
+            border: self.border,
+            crop_to_tiles: self.crop,
+            ..Default::default()
+        };
+        let levels = LevelOptions {
+            match_levels: !self.no_match,
+            remove_seams: self.seams,
+            solve: !self.no_solve,
+            ..Default::default()
+        };
+        let report = assemble_files(
+            &in_files,
+            self.output.as_os_str().to_str().unwrap(),
+            &options,
+            &levels,
+        );
+        if self.residuals {
+            print_residuals(&report);
+        }
+
+        pb_done!();
+    }
+}
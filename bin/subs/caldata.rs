@@ -1,6 +1,11 @@
 use crate::subs::runnable::RunnableSubcommand;
-use clap::Parser;
-use mars_raw_utils::caldata;
+use clap::{Parser, Subcommand};
+use mars_raw_utils::caldata::{self, VerifyStatus};
+use mars_raw_utils::calibfile::{CalDataResolver, ObservationTime};
+use mars_raw_utils::enums::{CalFileType, Instrument};
+use mars_raw_utils::print;
+use std::process;
+use std::str::FromStr;
 
 pb_create!();
 
@@ -12,11 +17,84 @@ pub struct UpdateCalData {
 
     #[arg(long, short, help = "Override default storage path")]
     local_store: Option<String>,
+
+    #[arg(
+        long,
+        short,
+        help = "Check local files against the remote manifest without downloading"
+    )]
+    verify: bool,
+}
+
+impl UpdateCalData {
+    async fn run_verify(&self) {
+        let (manifest, results) = match caldata::verify_calibration_data(&self.local_store).await {
+            Ok(r) => r,
+            Err(why) => {
+                eprintln!("Error: {}", why);
+                process::exit(1);
+            }
+        };
+
+        let mut num_bad = 0;
+        for (entry, status) in results.iter() {
+            match status {
+                VerifyStatus::Ok => print::print_done(&format!("Ok: {}", entry.path)),
+                VerifyStatus::Unverifiable => {
+                    print::print_warn(&format!("No checksum: {}", entry.path))
+                }
+                VerifyStatus::Missing => print::print_fail(&format!("Missing: {}", entry.path)),
+                VerifyStatus::SizeMismatch { expected, actual } => print::print_fail(&format!(
+                    "Size mismatch: {} (expected {} bytes, found {})",
+                    entry.path, expected, actual
+                )),
+                VerifyStatus::ChecksumMismatch { .. } => {
+                    print::print_fail(&format!("Checksum mismatch: {}", entry.path))
+                }
+            }
+            if status.is_bad() {
+                num_bad += 1;
+            }
+        }
+
+        let installed = caldata::installed_version(&self.local_store);
+        let installed_version = installed.as_ref().and_then(|i| i.version.clone());
+        println!(
+            "Remote version: {}",
+            manifest.version.clone().unwrap_or("Not set".to_string())
+        );
+        match &installed {
+            Some(i) => println!(
+                "Installed version: {} ({})",
+                installed_version.clone().unwrap_or("Not set".to_string()),
+                i.installed
+            ),
+            None => println!("Installed version: Not recorded"),
+        }
+        if manifest.version.is_some() && installed_version != manifest.version {
+            println!("Local calibration data is out of date");
+        }
+
+        if num_bad > 0 {
+            println!(
+                "{} of {} files are missing or corrupt. Run 'mru update-cal-data' to repair",
+                num_bad,
+                results.len()
+            );
+            process::exit(1);
+        }
+        println!("All files verified.");
+    }
 }
 
 #[async_trait::async_trait]
 impl RunnableSubcommand for UpdateCalData {
     async fn run(&self) {
+        if self.verify {
+            self.run_verify().await;
+            return;
+        }
+
         pb_set_print!();
         match caldata::update_calibration_data(
             !self.noreplace,
@@ -33,3 +111,142 @@ impl RunnableSubcommand for UpdateCalData {
         };
     }
 }
+
+#[derive(Parser)]
+#[command(author, version, about = "Calibration data search path information", long_about = None)]
+pub struct CalData {
+    #[command(subcommand)]
+    command: CalDataCommand,
+}
+
+#[derive(Subcommand)]
+enum CalDataCommand {
+    /// Show which calibration file is used for an instrument, and why
+    Which {
+        #[arg(help = "Instrument, as named in image metadata (e.g. MCZ_LEFT, NAV_RIGHT_B)")]
+        instrument: String,
+
+        #[arg(help = "Calibration file type (flat, inpaint, mask, lut)")]
+        file_type: String,
+
+        #[arg(long, short, help = "Sol the image was taken on")]
+        sol: Option<u32>,
+
+        #[arg(
+            long,
+            short,
+            help = "UTC time the image was taken (e.g. 2023-01-08T03:53:04)"
+        )]
+        utc: Option<String>,
+    },
+
+    /// List the calibration data search path in priority order
+    Paths,
+}
+
+impl CalData {
+    fn which(instrument_name: &str, file_type: &str, when: &ObservationTime) {
+        let instrument = Instrument::from_str(instrument_name).unwrap();
+        if instrument == Instrument::None {
+            eprintln!("Error: Unknown instrument: {}", instrument_name);
+            process::exit(1);
+        }
+        let file_type = match CalFileType::from_str(file_type) {
+            Ok(t) => t,
+            Err(why) => {
+                eprintln!("Error: {}", why);
+                process::exit(1);
+            }
+        };
+
+        let explanation = CalDataResolver::new().explain_at(instrument, file_type, when);
+        println!("Instrument: {:?}", explanation.instrument);
+        println!("File type: {:?}", explanation.file_type);
+        match &explanation.override_used {
+            Some(o) => println!(
+                "Override: {} (from {})",
+                explanation.base_file,
+                o.defined_in.clone().unwrap_or("command line".to_string())
+            ),
+            None => println!(
+                "Mapping: {} (from {})",
+                explanation.base_file,
+                explanation
+                    .mapping_file
+                    .clone()
+                    .unwrap_or("Not found".to_string())
+            ),
+        }
+
+        match &explanation.resolution {
+            Ok(r) => {
+                match &r.location {
+                    Some(loc) => println!("Found in {} ({:?})", loc.path, loc.source),
+                    None => println!("Found as given"),
+                }
+                println!("File: {}", r.path);
+                for s in r.shadowed.iter() {
+                    println!("Shadowed: {}", s);
+                }
+            }
+            Err(why) => {
+                println!("Not resolved: {}", why);
+                process::exit(1);
+            }
+        }
+    }
+
+    fn paths() {
+        let resolver = CalDataResolver::new();
+        for (i, loc) in resolver.locations.iter().enumerate() {
+            println!(
+                "{:2} {:12} {}{}",
+                i + 1,
+                format!("{:?}", loc.source),
+                loc.path,
+                if std::path::Path::new(&loc.path).is_dir() {
+                    ""
+                } else {
+                    " (missing)"
+                }
+            );
+        }
+        for o in resolver.overrides.iter() {
+            println!(
+                "Override: {:?} {:?} -> {} ({})",
+                o.instrument,
+                o.file_type,
+                o.file,
+                o.defined_in.clone().unwrap_or_default()
+            );
+            for v in o.versions.iter() {
+                println!(
+                    "    {} sol {:?}-{:?} utc {:?}-{:?}",
+                    v.file, v.sol_start, v.sol_end, v.utc_start, v.utc_end
+                );
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for CalData {
+    async fn run(&self) {
+        match &self.command {
+            CalDataCommand::Which {
+                instrument,
+                file_type,
+                sol,
+                utc,
+            } => CalData::which(
+                instrument,
+                file_type,
+                &ObservationTime {
+                    sol: *sol,
+                    utc: utc.clone(),
+                },
+            ),
+            CalDataCommand::Paths => CalData::paths(),
+        }
+    }
+}
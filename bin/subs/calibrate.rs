@@ -53,6 +53,13 @@ pub struct Calibrate {
 
     #[arg(long, short = 'D', help = "Debayer method (malvar, amaze)")]
     debayer: Option<String>,
+
+    #[arg(
+        long,
+        short = 'L',
+        help = "Decompanding LUT: built-in name (ILT, NSYT_ILT, LUT2) or LUT file"
+    )]
+    lut: Option<String>,
 }
 
 impl Calibrate {
@@ -130,6 +137,10 @@ impl RunnableSubcommand for Calibrate {
                                 profile_mut.decorrelate_color = true;
                             }
 
+                            if let Some(lut) = &self.lut {
+                                profile_mut.lut = Some(lut.clone());
+                            }
+
                             if let Some(debayer) = &self.debayer {
                                 profile_mut.debayer_method = match DebayerMethod::from_str(debayer)
                                 {
@@ -169,6 +180,8 @@ impl RunnableSubcommand for Calibrate {
                 } else {
                     DebayerMethod::Malvar
                 },
+                cal_target: None,
+                lut: self.lut.clone(),
             }],
         };
 
@@ -0,0 +1,129 @@
+use crate::subs::runnable::RunnableSubcommand;
+use clap::Parser;
+use mars_raw_utils::calprofile::load_calibration_profile;
+use mars_raw_utils::colorcorrect::ColorCorrection;
+use mars_raw_utils::prelude::*;
+use sciimg::{image::Image, path};
+use std::process;
+
+pb_create!();
+
+#[derive(Parser)]
+#[command(author, version, about = "Color correct a sequence against the calibration target", long_about = None)]
+pub struct ColorCorrect {
+    #[arg(long, short, help = "Input images", num_args = 1..)]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[arg(long, short, help = "Calibrated image of the calibration target")]
+    target: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        short = 'P',
+        help = "Calibration profile containing the calibration target patches"
+    )]
+    profile: Option<String>,
+
+    #[arg(long, short, help = "Previously saved correction (JSON)")]
+    correction: Option<std::path::PathBuf>,
+
+    #[arg(long, short, help = "Save the derived correction (JSON)")]
+    save: Option<std::path::PathBuf>,
+}
+
+impl ColorCorrect {
+    fn derive_correction(&self) -> anyhow::Result<ColorCorrection> {
+        if let Some(c) = &self.correction {
+            return ColorCorrection::load(c.as_os_str().to_str().unwrap());
+        }
+
+        let (target_file, profile_name) = match (&self.target, &self.profile) {
+            (Some(t), Some(p)) => (String::from(t.as_os_str().to_str().unwrap()), p),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "A target image and profile, or a saved correction, are required"
+                ))
+            }
+        };
+
+        let profile = load_calibration_profile(profile_name)?;
+        let cal_target = match &profile.cal_target {
+            Some(t) => t,
+            None => {
+                return Err(anyhow::anyhow!(
+                    "Profile {} does not define a calibration target",
+                    profile_name
+                ))
+            }
+        };
+
+        if !path::file_exists(&target_file) {
+            return Err(anyhow::anyhow!("File not found: {}", target_file));
+        }
+        let image = Image::open_str(&target_file)?;
+        ColorCorrection::from_target_image(&image, cal_target)
+    }
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for ColorCorrect {
+    async fn run(&self) {
+        pb_set_print_and_length!(self.input_files.len());
+        print::print_experimental();
+
+        let correction = match self.derive_correction() {
+            Ok(c) => c,
+            Err(why) => {
+                eprintln!("Error: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        };
+
+        println!(
+            "Red: {}, Green: {}, Blue: {}",
+            correction.red_scalar, correction.green_scalar, correction.blue_scalar
+        );
+        if let Some(m) = &correction.matrix {
+            println!("Color Matrix: {:?}", m);
+        }
+
+        if let Some(s) = &self.save {
+            if let Err(why) = correction.save(s.as_os_str().to_str().unwrap()) {
+                eprintln!("Error saving correction: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        }
+
+        for in_file in self.input_files.iter() {
+            let in_file = in_file.as_os_str().to_str().unwrap();
+            if !path::file_exists(in_file) {
+                eprintln!("File not found: {}", in_file);
+                pb_inc!();
+                continue;
+            }
+            vprintln!("Processing File: {}", in_file);
+
+            let mut image = match Image::open_str(in_file) {
+                Ok(i) => i,
+                Err(why) => {
+                    eprintln!("Error opening {}: {}", in_file, why);
+                    pb_inc!();
+                    continue;
+                }
+            };
+            match correction.apply(&mut image) {
+                Ok(_) => {
+                    let out_file = util::append_file_name(in_file, "cc");
+                    image.save(&out_file);
+                    print::print_done(&path::basename(&out_file));
+                }
+                Err(why) => {
+                    eprintln!("Error correcting {}: {}", in_file, why);
+                }
+            }
+            pb_inc!();
+        }
+    }
+}
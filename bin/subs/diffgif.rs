@@ -1,6 +1,9 @@
 use crate::subs::runnable::RunnableSubcommand;
 use clap::Parser;
 use mars_raw_utils::diffgif;
+use mars_raw_utils::framesink::OutputFormat;
+use mars_raw_utils::registration::RegistrationMethod;
+use mars_raw_utils::stacking::StackMethod;
 use std::process;
 
 pb_create_spinner!();
@@ -34,6 +37,27 @@ pub struct DiffGif {
 
     #[arg(long, short, help = "Convert RGB to mono")]
     mono: bool,
+
+    #[arg(
+        long,
+        short,
+        help = "Output format (gif, apng, apng16, webp, frames, frames16). Defaults to the output file extension"
+    )]
+    format: Option<OutputFormat>,
+
+    #[arg(
+        long,
+        short,
+        help = "Align frames before differencing (translation, affine)"
+    )]
+    register: Option<RegistrationMethod>,
+
+    #[arg(
+        long,
+        short,
+        help = "Background stacking method (mean, median, sigma, winsor, min, max)"
+    )]
+    stack: Option<StackMethod>,
 }
 
 #[async_trait::async_trait]
@@ -86,9 +110,22 @@ impl RunnableSubcommand for DiffGif {
             .map(|s| String::from(s.as_os_str().to_str().unwrap()))
             .collect();
 
-        diffgif::process(&diffgif::DiffGif {
+        let output_format = match self
+            .format
+            .or_else(|| OutputFormat::from_output_path(output))
+        {
+            Some(f) => f,
+            None => {
+                eprintln!("Unable to determine output format, use --format");
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        };
+
+        if let Err(why) = diffgif::process(&diffgif::DiffGif {
             input_files: in_files,
             output: String::from(output),
+            output_format,
             product_type,
             black_level: black_level / 100.0,
             white_level: white_level / 100.0,
@@ -96,7 +133,13 @@ impl RunnableSubcommand for DiffGif {
             delay,
             lowpass_window_size,
             convert_to_mono: self.mono,
-        });
+            registration: self.register,
+            stack_method: self.stack.unwrap_or_default(),
+        }) {
+            eprintln!("Error: {}", why);
+            pb_done_with_error!();
+            process::exit(1);
+        }
         pb_done!();
     }
 }
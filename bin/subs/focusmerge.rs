@@ -1,6 +1,8 @@
 use crate::subs::runnable::RunnableSubcommand;
 use clap::Parser;
-use mars_raw_utils::focusmerge;
+use mars_raw_utils::focusmerge::{self, DepthOutput, FocusCalibration, FocusMergeParams};
+use mars_raw_utils::registration::RegistrationMethod;
+use std::process;
 
 pb_create_spinner!();
 
@@ -18,6 +20,38 @@ pub struct FocusMerge {
 
     #[arg(long, short = 'd', help = "Produce a depth map")]
     depth_map: bool,
+
+    #[arg(
+        long,
+        short,
+        help = "Align images to the first image before merging (translation, affine)"
+    )]
+    register: Option<RegistrationMethod>,
+
+    #[arg(long, short, help = "Laplacian pyramid levels")]
+    levels: Option<usize>,
+
+    #[arg(
+        long,
+        short = 'e',
+        help = "Quality weight exponent, higher values favor the sharpest frame (default 2.0)"
+    )]
+    exponent: Option<f32>,
+
+    #[arg(
+        long,
+        short = 'c',
+        help = "Focus motor count to distance calibration (TOML). Produces a distance map"
+    )]
+    focus_calibration: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        short = 'f',
+        help = "Focus motor count of each input. Read from metadata if omitted",
+        num_args = 1..
+    )]
+    focus_positions: Option<Vec<f64>>,
 }
 
 #[async_trait::async_trait]
@@ -25,15 +59,47 @@ impl RunnableSubcommand for FocusMerge {
     async fn run(&self) {
         pb_set_print!();
 
-        let quality_window_size = self.window.unwrap_or(15);
-
         let output = self.output.as_os_str().to_str().unwrap();
         let in_files: Vec<String> = self
             .input_files
             .iter()
             .map(|s| String::from(s.as_os_str().to_str().unwrap()))
             .collect();
-        focusmerge::focusmerge(&in_files, quality_window_size, self.depth_map, output);
+
+        let calibration = match &self.focus_calibration {
+            Some(f) => match FocusCalibration::load(f.as_os_str().to_str().unwrap()) {
+                Ok(c) => Some(c),
+                Err(why) => {
+                    eprintln!("Error loading focus calibration: {}", why);
+                    pb_done_with_error!();
+                    process::exit(1);
+                }
+            },
+            None => None,
+        };
+
+        let defaults = FocusMergeParams::default();
+        let params = FocusMergeParams {
+            quality_window_size: self.window.unwrap_or(defaults.quality_window_size),
+            levels: self.levels,
+            weight_exponent: self.exponent.unwrap_or(defaults.weight_exponent),
+            registration: self.register,
+        };
+
+        if let Err(why) = focusmerge::focusmerge(
+            &in_files,
+            &params,
+            &DepthOutput {
+                depth_map: self.depth_map,
+                calibration,
+                focus_positions: self.focus_positions.clone(),
+            },
+            output,
+        ) {
+            eprintln!("Error: {}", why);
+            pb_done_with_error!();
+            process::exit(1);
+        }
 
         pb_done!();
     }
@@ -0,0 +1,158 @@
+use crate::subs::runnable::RunnableSubcommand;
+use clap::Parser;
+use mars_raw_utils::focusmerge::{DepthOutput, FocusCalibration, FocusMergeParams};
+use mars_raw_utils::focusstack::{self, StackCriteria};
+use mars_raw_utils::prelude::*;
+use mars_raw_utils::registration::RegistrationMethod;
+use mars_raw_utils::stereopair;
+use sciimg::path;
+use std::process;
+
+pb_create!();
+
+#[derive(Parser)]
+#[command(author, version, about = "Discover MAHLI/WATSON focus stacks and optionally merge them", long_about = None)]
+pub struct FocusStacks {
+    #[arg(long, short, help = "Input images or directories", num_args = 1..)]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        short = 't',
+        help = "Maximum SCLK gap between frames in seconds (default 60)"
+    )]
+    sclk_window: Option<f64>,
+
+    #[arg(
+        long,
+        short,
+        help = "Maximum pointing difference in degrees (default 0.5)"
+    )]
+    pointing_tolerance: Option<f64>,
+
+    #[arg(long, help = "Don't require matching subframe rectangles")]
+    ignore_subframe: bool,
+
+    #[arg(long, short = 'n', help = "Minimum frames per stack (default 3)")]
+    min_frames: Option<usize>,
+
+    #[arg(long, short, help = "Focus merge each stack")]
+    merge: bool,
+
+    #[arg(long, short, help = "Output directory for merged products")]
+    output_dir: Option<std::path::PathBuf>,
+
+    #[arg(long, short = 'w', help = "Quality determination window size (pixels)")]
+    window: Option<usize>,
+
+    #[arg(long, short = 'd', help = "Produce depth maps")]
+    depth_map: bool,
+
+    #[arg(
+        long,
+        short,
+        help = "Align frames before merging (translation, affine)"
+    )]
+    register: Option<RegistrationMethod>,
+
+    #[arg(
+        long,
+        short = 'c',
+        help = "Focus motor count to distance calibration (TOML). Produces distance maps"
+    )]
+    focus_calibration: Option<std::path::PathBuf>,
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for FocusStacks {
+    async fn run(&self) {
+        pb_set_print!();
+
+        if let Some(dir) = &self.output_dir {
+            if !dir.is_dir() {
+                eprintln!("Error: Output directory not found: {:?}", dir);
+                process::exit(1);
+            }
+        }
+
+        let mut in_files: Vec<String> = vec![];
+        for input in self.input_files.iter() {
+            let input_str = input.as_os_str().to_str().unwrap();
+            if input.is_dir() {
+                in_files.extend(stereopair::list_images_in_directory(input_str));
+            } else if input.exists() {
+                in_files.push(String::from(input_str));
+            } else {
+                eprintln!("File not found: {:?}", input);
+            }
+        }
+
+        let defaults = StackCriteria::default();
+        let criteria = StackCriteria {
+            sclk_window: self.sclk_window.unwrap_or(defaults.sclk_window),
+            pointing_tolerance: self
+                .pointing_tolerance
+                .unwrap_or(defaults.pointing_tolerance),
+            match_subframe: !self.ignore_subframe,
+            min_frames: self.min_frames.unwrap_or(defaults.min_frames),
+        };
+
+        let candidates = focusstack::candidates_from_files(&in_files);
+        let stacks = focusstack::find_stacks(&candidates, &criteria);
+
+        println!("Found {} focus stacks", stacks.len());
+        for stack in stacks.iter() {
+            println!(
+                "{:?} SCLK {:.3}-{:.3}: {}",
+                stack.instrument,
+                stack.sclk_start,
+                stack.sclk_end,
+                stack.files.join(" ")
+            );
+        }
+
+        if !self.merge {
+            return;
+        }
+
+        let calibration = match &self.focus_calibration {
+            Some(f) => match FocusCalibration::load(f.as_os_str().to_str().unwrap()) {
+                Ok(c) => Some(c),
+                Err(why) => {
+                    eprintln!("Error loading focus calibration: {}", why);
+                    process::exit(1);
+                }
+            },
+            None => None,
+        };
+
+        let params = FocusMergeParams {
+            quality_window_size: self
+                .window
+                .unwrap_or(FocusMergeParams::default().quality_window_size),
+            registration: self.register,
+            ..Default::default()
+        };
+        let depth = DepthOutput {
+            depth_map: self.depth_map,
+            calibration,
+            focus_positions: None,
+        };
+        let output_dir = self
+            .output_dir
+            .as_ref()
+            .map(|d| d.as_os_str().to_str().unwrap());
+
+        pb_set_length!(stacks.len());
+        for stack in stacks.iter() {
+            match focusstack::merge_stack(stack, &params, &depth, output_dir) {
+                Ok(out_file) => print::print_done(&path::basename(&out_file)),
+                Err(why) => {
+                    vprintln!("Focus merge failed for {}: {}", stack.files[0], why);
+                    print::print_fail(&path::basename(&stack.output_file(output_dir)));
+                }
+            }
+            pb_inc!();
+        }
+    }
+}
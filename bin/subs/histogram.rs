@@ -0,0 +1,142 @@
+use crate::subs::runnable::RunnableSubcommand;
+use clap::Parser;
+use mars_raw_utils::histogram;
+use mars_raw_utils::prelude::*;
+use sciimg::{enums::ImageMode, image::Image, path};
+use std::fs;
+
+/// Plot dimensions
+const PLOT_WIDTH: usize = 512;
+const PLOT_HEIGHT: usize = 200;
+
+#[derive(Parser)]
+#[command(author, version, about = "Image histogram statistics, gaps and stretch detection", long_about = None)]
+pub struct Histogram {
+    #[arg(long, short, help = "Input images", required(true), num_args = 1..)]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[arg(long, short, help = "Write per-band histograms as CSV")]
+    csv: bool,
+
+    #[arg(long, short, help = "Write a histogram plot image")]
+    plot: bool,
+
+    #[arg(long, short, help = "Logarithmic plot scale")]
+    log: bool,
+}
+
+fn write_csv(out_file: &str, hists: &[histogram::Histogram]) -> std::io::Result<()> {
+    let mut text = String::from("value");
+    for b in 0..hists.len() {
+        text.push_str(&format!(",band{}", b));
+    }
+    text.push('\n');
+    for v in 0..hists[0].bins.len() {
+        text.push_str(&format!("{}", v));
+        for h in hists.iter() {
+            text.push_str(&format!(",{}", h.bins[v]));
+        }
+        text.push('\n');
+    }
+    fs::write(out_file, text)
+}
+
+/// Draws each band's histogram in its own color channel (all channels for a single band),
+/// over the range up to the highest occupied value
+fn plot(hists: &[histogram::Histogram], log: bool) -> Image {
+    let mut img = Image::new_with_bands(PLOT_WIDTH, PLOT_HEIGHT, 3, ImageMode::U8BIT).unwrap();
+    let max_value = hists.iter().filter_map(|h| h.max()).max().unwrap_or(0) + 1;
+    let width = PLOT_WIDTH.min(max_value).max(1);
+    let scale = |c: u64| if log { (c as f64 + 1.0).ln() } else { c as f64 };
+
+    let columns: Vec<Vec<f64>> = hists
+        .iter()
+        .map(|h| {
+            (0..width)
+                .map(|x| {
+                    let from = x * max_value / width;
+                    let to = ((x + 1) * max_value / width).max(from + 1);
+                    scale(h.bins[from..to].iter().sum())
+                })
+                .collect()
+        })
+        .collect();
+    let peak = columns.iter().flatten().fold(0.0_f64, |a, v| a.max(*v));
+    if peak <= 0.0 {
+        return img;
+    }
+
+    for (b, col) in columns.iter().enumerate() {
+        let channels: Vec<usize> = if hists.len() == 1 {
+            vec![0, 1, 2]
+        } else {
+            vec![b.min(2)]
+        };
+        for (x, v) in col.iter().enumerate() {
+            let height = (v / peak * PLOT_HEIGHT as f64).round() as usize;
+            for y in (PLOT_HEIGHT - height)..PLOT_HEIGHT {
+                for px in (x * PLOT_WIDTH / width)..((x + 1) * PLOT_WIDTH / width) {
+                    for c in channels.iter() {
+                        img.put(px, y, 255.0, *c);
+                    }
+                }
+            }
+        }
+    }
+    img
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for Histogram {
+    async fn run(&self) {
+        for in_file in self.input_files.iter() {
+            let in_file = in_file.as_os_str().to_str().unwrap();
+            if !path::file_exists(in_file) {
+                eprintln!("File not found: {}", in_file);
+                continue;
+            }
+            let image = match Image::open_str(in_file) {
+                Ok(i) => i,
+                Err(why) => {
+                    eprintln!("Error opening {}: {}", in_file, why);
+                    continue;
+                }
+            };
+
+            println!("Image: {}", in_file);
+            let hists: Vec<histogram::Histogram> = (0..image.num_bands())
+                .map(|b| histogram::Histogram::of_band(&image, b))
+                .collect();
+            for (b, h) in hists.iter().enumerate() {
+                if let Some(s) = h.stats() {
+                    println!(
+                        "Band {}: min {}, max {}, mean {:.2}, median {}, std dev {:.2}, {} levels, {} gaps ({} bins)",
+                        b, s.min, s.max, s.mean, s.median, s.std_dev, s.occupied, s.num_gaps, s.gap_bins
+                    );
+                }
+            }
+            match histogram::detect_stretch(&histogram::Histogram::of_image(&image)) {
+                Some(p) => println!(
+                    "Stretch detected: gain {:.3} from value {} ({:.0}% regular)",
+                    p.gain,
+                    p.min,
+                    p.regularity * 100.0
+                ),
+                None => println!("No stretch detected"),
+            }
+
+            if self.csv {
+                let out_file = util::replace_image_extension(in_file, "-histogram.csv");
+                match write_csv(&out_file, &hists) {
+                    Ok(_) => print::print_done(&path::basename(&out_file)),
+                    Err(why) => eprintln!("Error writing {}: {}", out_file, why),
+                }
+            }
+            if self.plot {
+                let out_file = util::append_file_name(in_file, "histogram");
+                plot(&hists, self.log).save(&out_file);
+                print::print_done(&path::basename(&out_file));
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use crate::subs::runnable::RunnableSubcommand;
+use clap::Parser;
+use mars_raw_utils::decompanding::{self, LookUpTable};
+use mars_raw_utils::prelude::*;
+use sciimg::{image::Image, path};
+use std::fs;
+use std::process;
+
+#[derive(Parser)]
+#[command(author, version, about = "Validate, invert and detect decompanding lookup tables", long_about = None)]
+pub struct Lut {
+    #[arg(
+        long,
+        short,
+        help = "LUT to check: built-in name (ILT, NSYT_ILT, LUT2) or LUT file"
+    )]
+    lut: Option<String>,
+
+    #[arg(
+        long,
+        short,
+        help = "Write the inverse (companding) table to this file"
+    )]
+    output: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        short,
+        help = "Companded (raw 8 bit) images to identify the LUT of",
+        num_args = 1..
+    )]
+    input_files: Vec<std::path::PathBuf>,
+}
+
+impl Lut {
+    fn check(&self, spec: &str) -> LookUpTable {
+        let lut = match decompanding::load_lut(spec) {
+            Ok(l) => l,
+            Err(why) => {
+                eprintln!("Error: {}", why);
+                process::exit(1);
+            }
+        };
+        match lut.validate() {
+            Ok(_) => print::print_done(&format!("{} is valid, maximum {}", lut.name, lut.max())),
+            Err(why) => {
+                print::print_fail(&format!("{}", why));
+                process::exit(1);
+            }
+        }
+
+        if let Some(output) = &self.output {
+            let inverse = lut.inverse();
+            let text: String = inverse
+                .table
+                .iter()
+                .enumerate()
+                .map(|(v, c)| format!("{} {}\n", v, c))
+                .collect();
+            if let Err(why) = fs::write(output, text) {
+                eprintln!("Error writing {:?}: {}", output, why);
+                process::exit(1);
+            }
+        }
+        lut
+    }
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for Lut {
+    async fn run(&self) {
+        let mut candidates = decompanding::builtin_luts();
+        if let Some(spec) = &self.lut {
+            let lut = self.check(spec);
+            if LookUpTable::builtin(spec).is_none() {
+                candidates.push(lut);
+            }
+        }
+
+        for in_file in self.input_files.iter() {
+            let in_file = in_file.as_os_str().to_str().unwrap();
+            if !path::file_exists(in_file) {
+                eprintln!("File not found: {}", in_file);
+                continue;
+            }
+            let image = match Image::open_str(in_file) {
+                Ok(i) => i,
+                Err(why) => {
+                    eprintln!("Error opening {}: {}", in_file, why);
+                    continue;
+                }
+            };
+            // As when calibrating, the default table is kept unless the image rules it out
+            let histogram = decompanding::value_histogram(&image);
+            let chosen = decompanding::select_lut(&histogram, &candidates[0], &candidates);
+            match decompanding::detect_lut(&histogram, &[chosen]) {
+                Some(m) => println!(
+                    "{}: {} ({:.1}% of pixels on its codes)",
+                    path::basename(in_file),
+                    m.lut.name,
+                    m.score * 100.0
+                ),
+                None => println!("{}: No matching LUT", path::basename(in_file)),
+            }
+        }
+    }
+}
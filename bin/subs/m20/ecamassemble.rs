@@ -1,16 +1,16 @@
 use mars_raw_utils::prelude::*;
 
+use crate::subs::assemble;
 use crate::subs::runnable::RunnableSubcommand;
-use colored::{self, Colorize};
-use mars_raw_utils::m20::assemble::{Composite, NavcamTile};
-use mars_raw_utils::m20::ncamlevels;
-use mars_raw_utils::util;
+use mars_raw_utils::tileassembly::AssemblyOptions;
+use mars_raw_utils::tilegroup::{self, GroupCriteria};
+use mars_raw_utils::tilelevels::LevelOptions;
 use sciimg::path;
 use std::process;
 
 use clap::Parser;
 
-pb_create_spinner!();
+pb_create!();
 
 #[derive(Parser)]
 #[command(author, version, about = "Reassemble M20 ECAM subframes", long_about = None)]
@@ -18,8 +18,127 @@ pub struct M20EcamAssemble {
     #[arg(long, short, help = "Input raw images", num_args = 1..)]
     input_files: Vec<std::path::PathBuf>,
 
-    #[arg(long, short, help = "Output image")]
-    output: std::path::PathBuf,
+    #[arg(
+        long,
+        short,
+        help = "Output image, or output directory when grouping a directory"
+    )]
+    output: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        short,
+        help = "Group the tiles of a directory into frames and assemble each"
+    )]
+    directory: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        short = 't',
+        help = "Maximum SCLK gap between tiles of a frame in seconds (default 5)"
+    )]
+    sclk_window: Option<f64>,
+
+    #[arg(long, short, help = "List frames and missing tiles without assembling")]
+    list: bool,
+
+    #[arg(long, short, help = "Only assemble frames with no missing tiles")]
+    complete_only: bool,
+
+    #[arg(long, short, help = "Remove remaining seams in the gradient domain")]
+    seams: bool,
+
+    #[arg(long, short, help = "Report level mismatch per seam")]
+    residuals: bool,
+
+    #[arg(
+        long,
+        help = "Match tile levels pair by pair outward from the first tile instead of solving them together"
+    )]
+    no_solve: bool,
+}
+
+impl M20EcamAssemble {
+    fn level_options(&self) -> LevelOptions {
+        LevelOptions {
+            remove_seams: self.seams,
+            solve: !self.no_solve,
+            ..Default::default()
+        }
+    }
+
+    fn run_directory(&self, directory: &std::path::Path) {
+        let output_dir = self
+            .output
+            .as_ref()
+            .map(|d| d.as_os_str().to_str().unwrap());
+        if let Some(dir) = output_dir {
+            if !path::file_exists(dir) {
+                eprintln!("Error: Output directory not found: {}", dir);
+                process::exit(1);
+            }
+        }
+
+        let criteria = GroupCriteria {
+            sclk_window: self
+                .sclk_window
+                .unwrap_or(GroupCriteria::default().sclk_window),
+            ..Default::default()
+        };
+        let groups =
+            tilegroup::find_groups_in_directory(directory.as_os_str().to_str().unwrap(), &criteria);
+
+        println!("Found {} frames", groups.len());
+        for group in groups.iter() {
+            println!(
+                "{:?} {} SCLK {:.3}-{:.3} scale {}: {} tiles",
+                group.instrument,
+                group.sequence_id.clone().unwrap_or_default(),
+                group.sclk_start,
+                group.sclk_end,
+                group.scale,
+                group.files.len()
+            );
+            for m in group.missing.iter() {
+                print::print_warn(&format!(
+                    "Missing tile at {:?}",
+                    m.subframe_rect(group.scale)
+                ));
+            }
+        }
+
+        if self.list {
+            return;
+        }
+
+        pb_set_length!(groups.len());
+        for group in groups.iter() {
+            let out_file = group.output_file(output_dir);
+            if self.complete_only && !group.is_complete() {
+                vprintln!("Skipping incomplete frame {}", out_file);
+                pb_inc!();
+                continue;
+            }
+            match tilegroup::assemble_group(
+                group,
+                &AssemblyOptions::default(),
+                &self.level_options(),
+                output_dir,
+            ) {
+                Ok((f, report)) => {
+                    print::print_done(&path::basename(&f));
+                    if self.residuals {
+                        assemble::print_residuals(&report);
+                    }
+                }
+                Err(why) => {
+                    vprintln!("Assembly failed for {}: {}", out_file, why);
+                    print::print_fail(&path::basename(&out_file));
+                }
+            }
+            pb_inc!();
+        }
+    }
 }
 
 #[async_trait::async_trait]
@@ -27,68 +146,42 @@ impl RunnableSubcommand for M20EcamAssemble {
     async fn run(&self) {
         pb_set_print!();
 
-        let in_files: Vec<String> = self
-            .input_files
-            .iter()
-            .map(|s| String::from(s.as_os_str().to_str().unwrap()))
-            .collect();
-        let output = self.output.as_os_str().to_str().unwrap();
+        if let Some(directory) = &self.directory {
+            self.run_directory(directory);
+            pb_done!();
+            return;
+        }
 
-        let mut tiles: Vec<NavcamTile> = vec![];
-        for in_file in in_files.iter() {
-            if !path::file_exists(in_file) {
-                eprintln!("File not found: {}", in_file);
+        let output = match &self.output {
+            Some(o) => o.as_os_str().to_str().unwrap(),
+            None => {
+                eprintln!("Error: An output image is required");
                 pb_done_with_error!();
                 process::exit(1);
             }
-            let image =
-                NavcamTile::new_from_file(&String::from(in_file), Instrument::M20NavcamRight);
-
-            // Disabling destretch for now as the tool will just restretch it anyway
-            // when the image is reencoded for 16 bit.
-            //image.image.destretch_image();
-
-            // Oftentimes an image will be subframed at scale factor 1 with a full frame at scale factor 4. We cannot
-            // (or rather, choose not to) mix image sizes, so we will discard that full frame image.
-            if !image.is_supported_scale_factor() {
-                println!(
-                    "{}: Discarding image at unsupported scale factor of {}: {}",
-                    "WARNING".yellow(),
-                    image.get_scale_factor(),
-                    in_file
-                );
-            } else {
-                tiles.push(image);
-            }
-        }
-
-        // We can 'assemble' with just one. At a minimum, it just embeds it into
-        // the full frame, even if most of that full frame is black.
-        if tiles.is_empty() {
-            eprintln!("{}: No images to assemble, exiting...", "ERROR".red());
+        };
+        if self.input_files.is_empty() {
+            eprintln!("Error: No input images");
             pb_done_with_error!();
             process::exit(1);
         }
 
-        // Runs each tile through the level matching algorithms
-        ncamlevels::match_levels(&mut tiles);
-
-        // Build a composite canvas. This will be the output image
-        vprintln!("Creating composite structure");
-        let mut composite = Composite::new(&tiles);
-
-        // Pastes the tiles into the canvas
-        vprintln!("Adding {} tiles to composite", tiles.len());
-        composite.paste_tiles(&tiles);
-
-        // Stretches image to 16 bit and saves to disk
-        vprintln!("Saving composite to {}", output);
-        composite.finalize_and_save(output);
+        let in_files: Vec<String> = self
+            .input_files
+            .iter()
+            .map(|s| String::from(s.as_os_str().to_str().unwrap()))
+            .collect();
 
-        // Update 'scale_factor' in the metadata and save to disk
-        if let Some(mut md) = tiles[0].image.metadata.clone() {
-            md.subframe_rect = Some(vec![1.0, 1.0, 5120.0, 3840.0]);
-            util::save_image_json(output, &md, false, None).unwrap();
+        // Tiles are placed by their subframe rectangles for any scale factor, with the
+        // instrument (Navcam or Hazcam) taken from their metadata
+        let report = assemble::assemble_files(
+            &in_files,
+            output,
+            &AssemblyOptions::default(),
+            &self.level_options(),
+        );
+        if self.residuals {
+            assemble::print_residuals(&report);
         }
 
         pb_done!();
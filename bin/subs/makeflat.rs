@@ -0,0 +1,131 @@
+use crate::subs::runnable::RunnableSubcommand;
+use clap::Parser;
+use mars_raw_utils::calgen::{self, FlatParams};
+use mars_raw_utils::calibfile::CalDataResolver;
+use mars_raw_utils::enums::CalFileType;
+use mars_raw_utils::prelude::*;
+use mars_raw_utils::stacking::{FileFrames, StackMethod};
+use sciimg::path;
+use std::process;
+use std::str::FromStr;
+
+pb_create_spinner!();
+
+#[derive(Parser)]
+#[command(author, version, about = "Create a flat field from sky or uniformly lit images", long_about = None)]
+pub struct MakeFlat {
+    #[arg(long, short, help = "Input images", num_args = 1..)]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[arg(long, short, help = "Output flat field image")]
+    output: std::path::PathBuf,
+
+    #[arg(
+        long,
+        short,
+        help = "Stacking method (sigma, median, mean, winsorized). Default sigma"
+    )]
+    method: Option<String>,
+
+    #[arg(long, short, help = "Rejection threshold in standard deviations")]
+    sigma: Option<f32>,
+
+    #[arg(
+        long,
+        short = 'I',
+        help = "Instrument to register the flat for in the local calibration data"
+    )]
+    instrument: Option<String>,
+
+    #[arg(
+        long,
+        short,
+        help = "Register in this data directory instead of the default"
+    )]
+    register_in: Option<String>,
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for MakeFlat {
+    async fn run(&self) {
+        pb_set_print!();
+        print::print_experimental();
+
+        let in_files: Vec<String> = self
+            .input_files
+            .iter()
+            .map(|s| String::from(s.as_os_str().to_str().unwrap()))
+            .collect();
+        for f in in_files.iter() {
+            if !path::file_exists(f) {
+                eprintln!("Error: File not found: {}", f);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        }
+
+        let defaults = FlatParams::default();
+        let params = FlatParams {
+            method: match &self.method {
+                Some(m) => match StackMethod::from_str(m) {
+                    Ok(m) => m,
+                    Err(why) => {
+                        eprintln!("Error: {}", why);
+                        pb_done_with_error!();
+                        process::exit(1);
+                    }
+                },
+                None => defaults.method,
+            },
+            sigma: self.sigma.unwrap_or(defaults.sigma),
+            ..defaults
+        };
+
+        let output = self.output.as_os_str().to_str().unwrap();
+        match calgen::make_flat(&FileFrames::new(&in_files), &params) {
+            Ok(flat) => flat.save(output),
+            Err(why) => {
+                eprintln!("Error: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        }
+
+        if let Some(instrument) = &self.instrument {
+            register(
+                instrument,
+                CalFileType::FlatField,
+                output,
+                &self.register_in,
+            );
+        }
+        pb_done!();
+    }
+}
+
+/// Registers a generated calibration file in the local calibration data overrides
+pub fn register(
+    instrument: &str,
+    file_type: CalFileType,
+    file: &str,
+    register_in: &Option<String>,
+) {
+    let store = match register_in {
+        Some(d) => Some(d.clone()),
+        None => CalDataResolver::new().local_store(),
+    };
+    let store = match store {
+        Some(s) => s,
+        None => {
+            eprintln!("Error: Unable to determine the local calibration data directory");
+            process::exit(1);
+        }
+    };
+    match calgen::register_calibration_file(&store, instrument, file_type, file) {
+        Ok(f) => println!("Registered {} for {} in {}", file, instrument, f),
+        Err(why) => {
+            eprintln!("Error registering calibration file: {}", why);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use crate::subs::makeflat::register;
+use crate::subs::runnable::RunnableSubcommand;
+use clap::Parser;
+use mars_raw_utils::calgen::{self, MaskParams};
+use mars_raw_utils::enums::CalFileType;
+use mars_raw_utils::prelude::*;
+use mars_raw_utils::stacking::FileFrames;
+use sciimg::path;
+use std::process;
+
+pb_create_spinner!();
+
+#[derive(Parser)]
+#[command(author, version, about = "Create a hot/dead pixel inpaint mask from dark or uniformly lit images", long_about = None)]
+pub struct MakeMask {
+    #[arg(long, short, help = "Dark frames, for hot pixels", num_args = 1..)]
+    darks: Vec<std::path::PathBuf>,
+
+    #[arg(long, short, help = "Uniformly lit frames, for dead pixels", num_args = 1..)]
+    uniform: Vec<std::path::PathBuf>,
+
+    #[arg(long, short, help = "Output mask image")]
+    output: std::path::PathBuf,
+
+    #[arg(
+        long,
+        short = 't',
+        help = "Hot pixel threshold in robust standard deviations (default 6)"
+    )]
+    hot_sigma: Option<f32>,
+
+    #[arg(
+        long,
+        short = 'f',
+        help = "Dead pixel threshold as a fraction of the neighbourhood median (default 0.5)"
+    )]
+    dead_fraction: Option<f32>,
+
+    #[arg(long, short, help = "Dead pixel neighbourhood size (default 7)")]
+    window: Option<usize>,
+
+    #[arg(
+        long,
+        short = 'I',
+        help = "Instrument to register the mask for in the local calibration data"
+    )]
+    instrument: Option<String>,
+
+    #[arg(
+        long,
+        short,
+        help = "Register in this data directory instead of the default"
+    )]
+    register_in: Option<String>,
+}
+
+fn to_strings(files: &[std::path::PathBuf]) -> Vec<String> {
+    files
+        .iter()
+        .map(|s| String::from(s.as_os_str().to_str().unwrap()))
+        .collect()
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for MakeMask {
+    async fn run(&self) {
+        pb_set_print!();
+        print::print_experimental();
+
+        let darks = to_strings(&self.darks);
+        let uniform = to_strings(&self.uniform);
+        for f in darks.iter().chain(uniform.iter()) {
+            if !path::file_exists(f) {
+                eprintln!("Error: File not found: {}", f);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        }
+
+        let defaults = MaskParams::default();
+        let params = MaskParams {
+            hot_sigma: self.hot_sigma.unwrap_or(defaults.hot_sigma),
+            dead_fraction: self.dead_fraction.unwrap_or(defaults.dead_fraction),
+            window_size: self.window.unwrap_or(defaults.window_size),
+        };
+
+        let output = self.output.as_os_str().to_str().unwrap();
+        match calgen::make_mask(
+            &FileFrames::new(&darks),
+            &FileFrames::new(&uniform),
+            &params,
+        ) {
+            Ok(result) => {
+                result.mask.save_16bit(output);
+                println!("{} hot and {} dead pixels", result.num_hot, result.num_dead);
+            }
+            Err(why) => {
+                eprintln!("Error: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        }
+
+        if let Some(instrument) = &self.instrument {
+            register(
+                instrument,
+                CalFileType::InpaintMask,
+                output,
+                &self.register_in,
+            );
+        }
+        pb_done!();
+    }
+}
@@ -1,71 +1,154 @@
 use crate::subs::runnable::RunnableSubcommand;
+use anyhow::Result;
 use clap::Parser;
 use mars_raw_utils::prelude::*;
+use mars_raw_utils::registration::{self, RegistrationMethod};
+use mars_raw_utils::stacking::{self, FileFrames, FrameSource, StackMethod, StackOptions};
 use sciimg::prelude::*;
+use std::cell::RefCell;
 use std::process;
 
 pb_create!();
 
+/// Advances the progress bar the first time each input frame is read. Some stacking methods
+/// read the frames more than once.
+struct ProgressFrames<'a, S: FrameSource + ?Sized> {
+    source: &'a S,
+    seen: RefCell<Vec<bool>>,
+}
+
+impl<'a, S: FrameSource + ?Sized> ProgressFrames<'a, S> {
+    fn new(source: &'a S) -> Self {
+        ProgressFrames {
+            source,
+            seen: RefCell::new(vec![false; source.num_frames()]),
+        }
+    }
+}
+
+impl<S: FrameSource + ?Sized> FrameSource for ProgressFrames<'_, S> {
+    fn num_frames(&self) -> usize {
+        self.source.num_frames()
+    }
+
+    fn frame(&self, index: usize) -> Result<Image> {
+        let frame = self.source.frame(index)?;
+        let mut seen = self.seen.borrow_mut();
+        if index < seen.len() && !seen[index] {
+            seen[index] = true;
+            pb_inc!();
+        }
+        Ok(frame)
+    }
+}
+
 #[derive(Parser)]
-#[command(author, version, about = "Compute the mean of a series of images", long_about = None)]
+#[command(
+    author,
+    version,
+    about = "Stack a series of images (mean, median, sigma clipping, winsorized, min, max)",
+    long_about = None
+)]
 pub struct MeanStack {
     #[arg(long, short, help = "Input images", num_args = 1..)]
     input_files: Vec<std::path::PathBuf>,
 
     #[arg(long, short, help = "Output image")]
     output: std::path::PathBuf,
+
+    #[arg(
+        long,
+        short,
+        help = "Align images to the first image before stacking (translation, affine)"
+    )]
+    register: Option<RegistrationMethod>,
+
+    #[arg(
+        long,
+        short,
+        help = "Stacking method (mean, median, sigma, winsor, min, max)"
+    )]
+    method: Option<StackMethod>,
+
+    #[arg(
+        long,
+        short,
+        help = "Per-frame weights, one per input image",
+        num_args = 1..
+    )]
+    weights: Option<Vec<f32>>,
+
+    #[arg(long, short = 'l', help = "Sigma clipping/winsorizing low threshold")]
+    sigma_low: Option<f32>,
+
+    #[arg(long, short = 'u', help = "Sigma clipping/winsorizing high threshold")]
+    sigma_high: Option<f32>,
+
+    #[arg(long, short = 'n', help = "Sigma clipping iterations")]
+    iterations: Option<usize>,
 }
 
 #[async_trait::async_trait]
 impl RunnableSubcommand for MeanStack {
     async fn run(&self) {
-        pb_set_print_and_length!(self.input_files.len() + 1); // The +1 accounts for the final division by # of images
+        pb_set_print!();
 
         let output = self.output.as_os_str().to_str().unwrap();
 
-        let mut mean: Image = Image::new_empty().unwrap();
-        let mut count: ImageBuffer = ImageBuffer::new_empty().unwrap();
-        let mut ones: ImageBuffer = ImageBuffer::new_empty().unwrap();
-
-        for in_file in self.input_files.iter() {
-            if in_file.exists() {
-                vprintln!("Processing File: {:?}", in_file);
-
-                let raw =
-                    Image::open(&String::from(in_file.as_os_str().to_str().unwrap())).unwrap();
-
-                if mean.is_empty() {
-                    mean = raw;
-                    count = ImageBuffer::new(mean.width, mean.height).unwrap();
-                    ones = ImageBuffer::new_with_fill(mean.width, mean.height, 1.0).unwrap();
-                } else {
-                    if raw.width != mean.width || raw.height != mean.height {
-                        eprintln!("Input image has differing dimensions, cannot continue");
-                        process::exit(1);
-                    }
+        if !path::parent_exists_and_writable(output) {
+            eprintln!("Unable to write output image, parent doesn't exist or is not writable");
+            pb_done_with_error!();
+            process::exit(1);
+        }
 
-                    mean.add(&raw);
+        let in_files: Vec<String> = self
+            .input_files
+            .iter()
+            .filter(|f| {
+                if !f.exists() {
+                    eprintln!("File not found: {:?}", f);
                 }
+                f.exists()
+            })
+            .map(|s| String::from(s.as_os_str().to_str().unwrap()))
+            .collect();
+        pb_set_length!(in_files.len() + 1); // The +1 accounts for writing the stacked image
 
-                count = count.add(&ones).unwrap();
-            } else {
-                eprintln!("File not found: {:?}", in_file);
-            }
-            pb_inc!();
+        if in_files.is_empty() {
+            println!("No images processed, cannot create output");
+            pb_done_with_error!();
+            process::exit(1);
         }
 
-        if !mean.is_empty() {
-            mean.divide_from_each(&count);
+        let defaults = StackOptions::default();
+        let options = StackOptions {
+            method: self.method.unwrap_or_default(),
+            sigma_low: self.sigma_low.unwrap_or(defaults.sigma_low),
+            sigma_high: self.sigma_high.unwrap_or(defaults.sigma_high),
+            iterations: self.iterations.unwrap_or(defaults.iterations),
+            weights: self.weights.clone(),
+            ..defaults
+        };
+
+        let result = if let Some(method) = self.register {
+            registration::open_and_register(&in_files, Some(method))
+                .and_then(|images| stacking::stack(&ProgressFrames::new(&images), &options))
+        } else {
+            stacking::stack(&ProgressFrames::new(&FileFrames::new(&in_files)), &options)
+        };
 
-            if path::parent_exists_and_writable(output) {
+        match result {
+            Ok(stacked) => {
                 vprintln!("Writing image to {}", output);
-                mean.save(output);
-            } else {
-                eprintln!("Unable to write output image, parent doesn't exist or is not writable");
+                stacked.save(output);
+                pb_inc!();
+                pb_done!();
+            }
+            Err(why) => {
+                eprintln!("Error: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
             }
-        } else {
-            println!("No images processed, cannot create output");
         }
-        pb_inc!();
     }
 }
@@ -126,18 +126,31 @@ pub mod nsyt;
 
 // Multimission subcommands:
 pub mod anaglyph;
+pub mod assemble;
 pub mod caldata;
 pub mod calibrate;
+pub mod colorcorrect;
 pub mod composite;
 pub mod crop;
 pub mod debayer;
 pub mod decorr;
 pub mod diffgif;
 pub mod focusmerge;
+pub mod focusstacks;
+pub mod histogram;
 pub mod hpcfilter;
 pub mod info;
 pub mod inpaint;
 pub mod levels;
+pub mod lut;
+pub mod makeflat;
+pub mod makemask;
 pub mod meanstack;
+pub mod motiondetect;
+pub mod pointcloud;
 pub mod profile;
+pub mod spectra;
+pub mod spectralparams;
+pub mod stereopairs;
+pub mod superres;
 pub mod xeye;
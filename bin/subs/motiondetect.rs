@@ -0,0 +1,141 @@
+use crate::subs::runnable::RunnableSubcommand;
+use clap::Parser;
+use mars_raw_utils::motiondetect::{self, DetectionParams};
+use mars_raw_utils::prelude::*;
+use sciimg::path;
+use std::process;
+
+pb_create_spinner!();
+
+#[derive(Parser)]
+#[command(author, version, about = "Detect and track dust devils and clouds in an image sequence", long_about = None)]
+pub struct MotionDetect {
+    #[arg(long, short, help = "Input images (co-pointed sequence)", num_args = 1..)]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[arg(long, short, help = "Output JSON report")]
+    output: std::path::PathBuf,
+
+    #[arg(long, short, help = "Write annotated frames to this directory")]
+    annotate: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        short,
+        help = "Detection threshold in standard deviations (default 4.0)"
+    )]
+    threshold: Option<f32>,
+
+    #[arg(
+        long,
+        short = 'A',
+        help = "Minimum detection area in pixels (default 20)"
+    )]
+    min_area: Option<usize>,
+
+    #[arg(long, short, help = "Blur radius in pixels (default 2)")]
+    blur: Option<usize>,
+
+    #[arg(
+        long,
+        short = 'D',
+        help = "Maximum movement between frames in pixels (default 50)"
+    )]
+    max_distance: Option<f64>,
+
+    #[arg(
+        long,
+        short,
+        help = "Frames a track may go undetected before closing (default 1)"
+    )]
+    gap: Option<usize>,
+
+    #[arg(long, short = 'L', help = "Minimum track length in frames (default 2)")]
+    min_length: Option<usize>,
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for MotionDetect {
+    async fn run(&self) {
+        pb_set_print!();
+        print::print_experimental();
+
+        let in_files: Vec<String> = self
+            .input_files
+            .iter()
+            .map(|s| String::from(s.as_os_str().to_str().unwrap()))
+            .collect();
+
+        for f in in_files.iter() {
+            if !path::file_exists(f) {
+                eprintln!("Error: File not found: {}", f);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        }
+
+        let output = self.output.as_os_str().to_str().unwrap();
+        if !path::parent_exists_and_writable(output) {
+            eprintln!(
+                "Error: Output file directory not found or is not writable: {}",
+                output
+            );
+            pb_done_with_error!();
+            process::exit(1);
+        }
+
+        let defaults = DetectionParams::default();
+        let params = DetectionParams {
+            threshold_sigma: self.threshold.unwrap_or(defaults.threshold_sigma),
+            min_area: self.min_area.unwrap_or(defaults.min_area),
+            blur_radius: self.blur.unwrap_or(defaults.blur_radius),
+            max_track_distance: self.max_distance.unwrap_or(defaults.max_track_distance),
+            max_gap: self.gap.unwrap_or(defaults.max_gap),
+            min_track_length: self.min_length.unwrap_or(defaults.min_track_length),
+        };
+
+        let report = match motiondetect::detect(&in_files, &params) {
+            Ok(report) => report,
+            Err(why) => {
+                eprintln!("Error: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        };
+
+        for track in report.tracks.iter() {
+            let first = track.first();
+            println!(
+                "Track {}: {:?}, {} frames, start ({:.1}, {:.1}), {:.2} px/frame, mean area {:.0} px{}",
+                track.id,
+                track.classification,
+                track.detections.len(),
+                first.blob.x,
+                first.blob.y,
+                track.velocity_px_per_frame,
+                track.mean_area,
+                match (first.azimuth, first.elevation) {
+                    (Some(az), Some(el)) => format!(", az {:.2} el {:.2}", az, el),
+                    _ => String::from(""),
+                }
+            );
+        }
+
+        if let Err(why) = report.save(output) {
+            eprintln!("Error writing report: {}", why);
+            pb_done_with_error!();
+            process::exit(1);
+        }
+
+        if let Some(annotate) = &self.annotate {
+            let annotate = annotate.as_os_str().to_str().unwrap();
+            if let Err(why) = motiondetect::save_annotated_frames(&report, annotate) {
+                eprintln!("Error writing annotated frames: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        }
+
+        pb_done!();
+    }
+}
@@ -0,0 +1,103 @@
+use crate::subs::runnable::RunnableSubcommand;
+use clap::Parser;
+use mars_raw_utils::pointcloud::{Mesh, PointCloud, XyzImage};
+use mars_raw_utils::prelude::*;
+use sciimg::path;
+use std::process;
+use std::str::FromStr;
+
+pb_create_spinner!();
+
+#[derive(Parser)]
+#[command(author, version, about = "Export a colored point cloud (PLY) or textured mesh (OBJ) from per-pixel XYZ", long_about = None)]
+pub struct PointCloudExport {
+    #[arg(long, short, help = "Color (calibrated) image")]
+    input: std::path::PathBuf,
+
+    #[arg(long, short, help = "Three band XYZ image matching the color image")]
+    xyz: std::path::PathBuf,
+
+    #[arg(long, short, help = "Output file (.ply or .obj)")]
+    output: std::path::PathBuf,
+
+    #[arg(long, short, help = "Pixel sampling step (default 1)")]
+    step: Option<usize>,
+
+    #[arg(
+        long,
+        short = 'e',
+        help = "Maximum mesh triangle edge length in XYZ units (default 1.0)"
+    )]
+    max_edge: Option<f64>,
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for PointCloudExport {
+    async fn run(&self) {
+        pb_set_print!();
+        print::print_experimental();
+
+        let input_path = String::from(self.input.as_os_str().to_str().unwrap());
+        let xyz_path = self.xyz.as_os_str().to_str().unwrap();
+        let out_file_path = self.output.as_os_str().to_str().unwrap();
+
+        if !path::file_exists(&input_path) {
+            eprintln!("Error: File not found: {}", input_path);
+            pb_done_with_error!();
+            process::exit(1);
+        }
+
+        if !path::file_exists(xyz_path) {
+            eprintln!("Error: File not found: {}", xyz_path);
+            pb_done_with_error!();
+            process::exit(1);
+        }
+
+        if !path::parent_exists_and_writable(out_file_path) {
+            eprintln!(
+                "Error: Output file directory not found or is not writable: {}",
+                out_file_path
+            );
+            pb_done_with_error!();
+            process::exit(1);
+        }
+
+        let mut img = MarsImage::open(input_path, Instrument::None);
+        if let Some(md) = &img.metadata {
+            img.instrument = Instrument::from_str(&md.instrument).unwrap();
+        }
+        let xyz = match XyzImage::open(xyz_path) {
+            Ok(xyz) => xyz,
+            Err(why) => {
+                eprintln!("Error loading XYZ image: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        };
+
+        let step = self.step.unwrap_or(1);
+
+        let result = if out_file_path.to_lowercase().ends_with(".obj") {
+            Mesh::from_xyz_and_image(&xyz, &img, step, self.max_edge.unwrap_or(1.0))
+                .and_then(|mesh| mesh.save_obj(out_file_path, &img))
+        } else if out_file_path.to_lowercase().ends_with(".ply") {
+            PointCloud::from_xyz_and_image(&xyz, &img, step)
+                .and_then(|cloud| cloud.save_ply(out_file_path))
+        } else {
+            eprintln!("Error: Unsupported output format. Use .ply or .obj");
+            pb_done_with_error!();
+            process::exit(1);
+        };
+
+        match result {
+            Ok(_) => {
+                pb_done!();
+            }
+            Err(why) => {
+                eprintln!("Error: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        }
+    }
+}
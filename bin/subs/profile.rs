@@ -1,8 +1,8 @@
+use mars_raw_utils::calibfile::CalDataResolver;
 use mars_raw_utils::calprofile::*;
 
 use crate::subs::runnable::RunnableSubcommand;
 use glob::glob;
-use std::env;
 use std::path::Path;
 
 use clap::Parser;
@@ -54,20 +54,9 @@ impl RunnableSubcommand for Profile {
         } else if self.list {
             print_list_header();
 
-            //list_profiles_in_directory("mars-raw-utils-data/caldata");
-            list_profiles_in_directory("/usr/share/mars_raw_utils/data");
-
-            if let Some(v) = option_env!("MARSDATAROOT") {
-                list_profiles_in_directory(v);
-            }
-
-            if let Some(dir) = dirs::home_dir() {
-                let homedatadir = format!("{}/.marsdata", dir.to_str().unwrap());
-                list_profiles_in_directory(homedatadir.as_str());
-            }
-
-            if let Ok(dir) = env::var("MARS_RAW_DATA") {
-                list_profiles_in_directory(dir.as_str());
+            // Lowest priority first so the profile that would be used is listed last
+            for loc in CalDataResolver::new().locations.iter().rev() {
+                list_profiles_in_directory(&loc.path);
             }
         } else if let Some(profile) = self.profile.clone() {
             match load_calibration_profile(&profile) {
@@ -109,6 +98,13 @@ impl RunnableSubcommand for Profile {
                         println!("HPC Window Size: {}", profile.hot_pixel_window_size);
                     }
                     println!("Output Filename Suffix: {}", profile.filename_suffix);
+                    if let Some(lut) = &profile.lut {
+                        println!("Decompanding LUT: {}", lut);
+                    }
+                    if let Some(target) = &profile.cal_target {
+                        println!("Calibration Target Patches: {}", target.patches.len());
+                        println!("Calibration Target Color Matrix: {}", target.color_matrix);
+                    }
                 }
                 Err(why) => {
                     eprintln!("Error: {}", why);
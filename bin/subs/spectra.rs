@@ -0,0 +1,138 @@
+use crate::subs::runnable::RunnableSubcommand;
+use clap::Parser;
+use mars_raw_utils::prelude::*;
+use mars_raw_utils::registration::RegistrationMethod;
+use mars_raw_utils::spectral::{self, Roi, SpectralCube};
+use sciimg::path;
+use std::process;
+
+pb_create_spinner!();
+
+#[derive(Parser)]
+#[command(author, version, about = "Build a Mastcam-Z/Mastcam filter cube and extract ROI spectra", long_about = None)]
+pub struct Spectra {
+    #[arg(long, short, help = "Calibrated filter images of a multispectral sequence", num_args = 1..)]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        short = 'R',
+        help = "Region of interest as name:x,y,width,height",
+        num_args = 1..
+    )]
+    roi: Vec<Roi>,
+
+    #[arg(long, short, help = "Output spectra file (.csv or .json)")]
+    output: Option<std::path::PathBuf>,
+
+    #[arg(long, short, help = "Write the co-registered cube to this directory")]
+    cube_dir: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        short,
+        help = "Registration method for aligning filters and eyes (translation, affine). Default translation"
+    )]
+    register: Option<RegistrationMethod>,
+
+    #[arg(
+        long,
+        short,
+        help = "Sequence id to use when the inputs contain several"
+    )]
+    sequence: Option<String>,
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for Spectra {
+    async fn run(&self) {
+        pb_set_print!();
+        print::print_experimental();
+
+        if self.output.is_none() && self.cube_dir.is_none() {
+            eprintln!("Error: Nothing to do. Specify an output spectra file and/or cube directory");
+            process::exit(1);
+        }
+
+        if let Some(dir) = &self.cube_dir {
+            if !dir.is_dir() {
+                eprintln!("Error: Cube directory not found: {:?}", dir);
+                process::exit(1);
+            }
+        }
+
+        let in_files: Vec<String> = self
+            .input_files
+            .iter()
+            .map(|s| String::from(s.as_os_str().to_str().unwrap()))
+            .collect();
+
+        let frames = spectral::frames_from_files(&in_files);
+        let groups = spectral::group_by_sequence(&frames);
+        let frames = match (&self.sequence, groups.len()) {
+            (Some(seq), _) => match groups.get(seq) {
+                Some(f) => f.clone(),
+                None => {
+                    eprintln!("Error: Sequence {} not found in inputs", seq);
+                    pb_done_with_error!();
+                    process::exit(1);
+                }
+            },
+            (None, 1) => groups.into_values().next().unwrap(),
+            (None, 0) => {
+                eprintln!("Error: No multispectral images found in inputs");
+                pb_done_with_error!();
+                process::exit(1);
+            }
+            (None, _) => {
+                eprintln!(
+                    "Error: Inputs contain several sequences ({}). Select one with --sequence",
+                    groups.keys().cloned().collect::<Vec<String>>().join(", ")
+                );
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        };
+
+        let cube = match SpectralCube::build(
+            &frames,
+            Some(self.register.unwrap_or(RegistrationMethod::Translation)),
+        ) {
+            Ok(c) => c,
+            Err(why) => {
+                eprintln!("Error building cube: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        };
+        for band in cube.bands.iter() {
+            vprintln!("{} {} nm: {}", band.label, band.wavelength_nm, band.source);
+        }
+
+        if let Some(dir) = &self.cube_dir {
+            if let Err(why) = cube.save(dir.as_os_str().to_str().unwrap()) {
+                eprintln!("Error writing cube: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        }
+
+        if let Some(output) = &self.output {
+            let output = output.as_os_str().to_str().unwrap();
+            if !path::parent_exists_and_writable(output) {
+                eprintln!("Unable to write output, parent doesn't exist or is not writable");
+                pb_done_with_error!();
+                process::exit(1);
+            }
+            if let Err(why) = spectral::extract_spectra(&cube, &self.roi)
+                .and_then(|spectra| spectral::save_spectra(&spectra, output))
+            {
+                eprintln!("Error: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        }
+
+        pb_done!();
+    }
+}
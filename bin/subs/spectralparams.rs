@@ -0,0 +1,111 @@
+use crate::subs::runnable::RunnableSubcommand;
+use clap::Parser;
+use mars_raw_utils::prelude::*;
+use mars_raw_utils::registration::RegistrationMethod;
+use mars_raw_utils::spectral::{self, SpectralCube};
+use mars_raw_utils::spectralparams::{self, SpectralDefinitions};
+use sciimg::path;
+use std::process;
+
+pb_create_spinner!();
+
+#[derive(Parser)]
+#[command(author, version, about = "Compute spectral parameter maps and false color composites from a filter cube", long_about = None)]
+pub struct SpectralParams {
+    #[arg(long, short, help = "Calibrated filter images of a multispectral sequence", num_args = 1..)]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[arg(long, short, help = "Cube directory written by 'mru spectra'")]
+    cube_dir: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        short,
+        help = "Parameter and composite definitions (TOML). Built-in defaults if omitted"
+    )]
+    definitions: Option<String>,
+
+    #[arg(long, short, help = "Output directory")]
+    output_dir: std::path::PathBuf,
+
+    #[arg(
+        long,
+        short,
+        help = "Registration method when building from images (translation, affine). Default translation"
+    )]
+    register: Option<RegistrationMethod>,
+
+    #[arg(long, short, help = "Print the built-in definitions and exit")]
+    print_defaults: bool,
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for SpectralParams {
+    async fn run(&self) {
+        if self.print_defaults {
+            println!("{}", spectralparams::DEFAULT_DEFINITIONS);
+            return;
+        }
+
+        pb_set_print!();
+        print::print_experimental();
+
+        if !self.output_dir.is_dir() {
+            eprintln!("Error: Output directory not found: {:?}", self.output_dir);
+            process::exit(1);
+        }
+
+        let definitions = match &self.definitions {
+            Some(f) => match SpectralDefinitions::load(f) {
+                Ok(d) => d,
+                Err(why) => {
+                    eprintln!("Error: {}", why);
+                    pb_done_with_error!();
+                    process::exit(1);
+                }
+            },
+            None => SpectralDefinitions::default_definitions(),
+        };
+
+        let cube = match &self.cube_dir {
+            Some(dir) => SpectralCube::load(dir.as_os_str().to_str().unwrap()),
+            None => {
+                let in_files: Vec<String> = self
+                    .input_files
+                    .iter()
+                    .map(|s| String::from(s.as_os_str().to_str().unwrap()))
+                    .collect();
+                SpectralCube::build(
+                    &spectral::frames_from_files(&in_files),
+                    Some(self.register.unwrap_or(RegistrationMethod::Translation)),
+                )
+            }
+        };
+        let cube = match cube {
+            Ok(c) => c,
+            Err(why) => {
+                eprintln!("Error loading cube: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        };
+
+        match spectralparams::save_products(
+            &cube,
+            &definitions,
+            self.output_dir.as_os_str().to_str().unwrap(),
+        ) {
+            Ok(written) => {
+                for f in written.iter() {
+                    print::print_done(&path::basename(f));
+                }
+                pb_done!();
+            }
+            Err(why) => {
+                eprintln!("Error: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        }
+    }
+}
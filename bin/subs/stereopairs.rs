@@ -0,0 +1,275 @@
+use crate::subs::anaglyph::{load_site_frames, print_report};
+use crate::subs::runnable::RunnableSubcommand;
+use crate::subs::xeye;
+use clap::Parser;
+use mars_raw_utils::hyperstereo::{self, HyperstereoCriteria, HyperstereoPair};
+use mars_raw_utils::prelude::*;
+use mars_raw_utils::stereoformat::{self, StereoFormat};
+use mars_raw_utils::stereopair::{self, PairingCriteria, StereoImagePair, StereoPair};
+use sciimg::path;
+use std::process;
+
+pb_create!();
+
+#[derive(Parser)]
+#[command(author, version, about = "Discover stereo pairs and optionally generate anaglyph/cross-eye products", long_about = None)]
+pub struct StereoPairs {
+    #[arg(long, short, help = "Input images or directories", num_args = 1..)]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[arg(long, short = 't', help = "SCLK tolerance in seconds (default 2.0)")]
+    sclk_tolerance: Option<f64>,
+
+    #[arg(long, help = "Don't require matching sequence ids")]
+    ignore_sequence: bool,
+
+    #[arg(long, help = "Don't require matching site/drive")]
+    ignore_site_drive: bool,
+
+    #[arg(long, help = "Don't require matching subframe rectangles")]
+    ignore_subframe: bool,
+
+    #[arg(long, short, help = "Generate an anaglyph for each pair")]
+    anaglyph: bool,
+
+    #[arg(long, short = 'M', help = "Anaglyph mode")]
+    mode: Option<anaglyph::AnaglyphMode>,
+
+    #[arg(long, short = 'x', help = "Generate a cross-eye image for each pair")]
+    xeye: bool,
+
+    #[arg(
+        long,
+        short,
+        help = "Generate a stereo image for each pair (parallel, cross, over-under, interleaved, mpo)"
+    )]
+    format: Option<StereoFormat>,
+
+    #[arg(long, short, help = "Output directory for generated products")]
+    output_dir: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        short = 'y',
+        help = "Discover hyperstereo pairs taken by one camera from different rover positions"
+    )]
+    hyperstereo: bool,
+
+    #[arg(long, help = "Hyperstereo: minimum baseline in meters (default 0.5)")]
+    min_baseline: Option<f64>,
+
+    #[arg(long, help = "Hyperstereo: maximum baseline in meters (default 100)")]
+    max_baseline: Option<f64>,
+
+    #[arg(
+        long,
+        short = 'S',
+        help = "Hyperstereo: JSON file of site frame origins for pairs across sites"
+    )]
+    site_frames: Option<std::path::PathBuf>,
+}
+
+impl StereoPairs {
+    fn output_file_for_image(&self, file_path: &str, append: &str) -> String {
+        let out_file = util::append_file_name(file_path, append);
+        match &self.output_dir {
+            Some(dir) => format!(
+                "{}/{}",
+                dir.as_os_str().to_str().unwrap(),
+                path::basename(&out_file)
+            ),
+            None => out_file,
+        }
+    }
+
+    fn output_file_for_pair(&self, pair: &StereoPair, append: &str) -> String {
+        self.output_file_for_image(&pair.left, append)
+    }
+
+    fn generate_hyperstereo_anaglyph(
+        &self,
+        pair: &HyperstereoPair,
+        sites: &hyperstereo::SiteFrames,
+    ) {
+        let out_file = self.output_file_for_image(&pair.left, "hyperstereo");
+        let images = match StereoImagePair::open(&pair.left, &pair.right) {
+            Ok(images) => images,
+            Err(why) => {
+                vprintln!("Unable to load pair {}: {}", pair.left, why);
+                print::print_fail(&path::basename(&pair.left));
+                return;
+            }
+        };
+        let options = anaglyph::AnaglyphOptions {
+            mode: self.mode.unwrap_or_default(),
+            auto_vertical: true,
+            ..Default::default()
+        };
+        match hyperstereo::create_hyperstereo_anaglyph(&images.left, &images.right, &options, sites)
+        {
+            Ok((map, report)) => {
+                print_report(&report);
+                vprintln!("Writing hyperstereo anaglyph to {}", out_file);
+                map.save(&out_file);
+                print::print_done(&path::basename(&out_file));
+            }
+            Err(why) => {
+                vprintln!("Hyperstereo anaglyph failed for {}: {}", pair.left, why);
+                print::print_fail(&path::basename(&out_file));
+            }
+        }
+    }
+
+    fn run_hyperstereo(&self, in_files: &[String]) {
+        let sites = load_site_frames(&self.site_frames);
+        let defaults = HyperstereoCriteria::default();
+        let criteria = HyperstereoCriteria {
+            min_baseline: self.min_baseline.unwrap_or(defaults.min_baseline),
+            max_baseline: self.max_baseline.unwrap_or(defaults.max_baseline),
+            ..defaults
+        };
+
+        let candidates = hyperstereo::candidates_from_files(in_files);
+        let pairs = hyperstereo::find_pairs(&candidates, &criteria, &sites);
+
+        println!("Found {} hyperstereo pairs", pairs.len());
+        for pair in pairs.iter() {
+            println!("{} {} {:.3}m", pair.left, pair.right, pair.baseline);
+        }
+
+        if self.xeye || self.format.is_some() {
+            eprintln!("Warning: Hyperstereo pairs only generate anaglyphs");
+        }
+
+        if self.anaglyph {
+            pb_set_length!(pairs.len());
+            for pair in pairs.iter() {
+                self.generate_hyperstereo_anaglyph(pair, &sites);
+                pb_inc!();
+            }
+        }
+    }
+
+    fn generate_products(&self, pair: &StereoPair) {
+        let images = match StereoImagePair::open(&pair.left, &pair.right) {
+            Ok(images) => images,
+            Err(why) => {
+                vprintln!("Unable to load pair {}: {}", pair.left, why);
+                print::print_fail(&path::basename(&pair.left));
+                return;
+            }
+        };
+
+        if self.anaglyph {
+            let out_file = self.output_file_for_pair(pair, "anaglyph");
+            let options = anaglyph::AnaglyphOptions {
+                mode: self.mode.unwrap_or_default(),
+                auto_vertical: true,
+                ..Default::default()
+            };
+            match images.to_anaglyph(&options) {
+                Ok(map) => {
+                    vprintln!("Writing anaglyph to {}", out_file);
+                    map.save(&out_file);
+                    print::print_done(&path::basename(&out_file));
+                }
+                Err(why) => {
+                    vprintln!("Anaglyph failed for {}: {}", pair.left, why);
+                    print::print_fail(&path::basename(&out_file));
+                }
+            }
+        }
+
+        if self.xeye {
+            let out_file = self.output_file_for_pair(pair, "xeye");
+            match xeye::create_cross_eye(&images, true) {
+                Ok(map) => {
+                    vprintln!("Writing cross-eye to {}", out_file);
+                    map.save(&out_file);
+                    print::print_done(&path::basename(&out_file));
+                }
+                Err(why) => {
+                    vprintln!("Cross-eye failed for {}: {}", pair.left, why);
+                    print::print_fail(&path::basename(&out_file));
+                }
+            }
+        }
+
+        if let Some(format) = self.format {
+            let mut out_file = self.output_file_for_pair(pair, "stereo");
+            if format == StereoFormat::Mpo {
+                out_file = String::from(
+                    std::path::Path::new(&out_file)
+                        .with_extension("mpo")
+                        .to_str()
+                        .unwrap(),
+                );
+            }
+            match images.eye_images(true).and_then(|(left, right)| {
+                stereoformat::save(&left, &right, format, false, &out_file)
+            }) {
+                Ok(_) => print::print_done(&path::basename(&out_file)),
+                Err(why) => {
+                    vprintln!("Stereo output failed for {}: {}", pair.left, why);
+                    print::print_fail(&path::basename(&out_file));
+                }
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for StereoPairs {
+    async fn run(&self) {
+        pb_set_print!();
+
+        if let Some(dir) = &self.output_dir {
+            if !dir.is_dir() {
+                eprintln!("Error: Output directory not found: {:?}", dir);
+                process::exit(1);
+            }
+        }
+
+        let mut in_files: Vec<String> = vec![];
+        for input in self.input_files.iter() {
+            let input_str = input.as_os_str().to_str().unwrap();
+            if input.is_dir() {
+                in_files.extend(stereopair::list_images_in_directory(input_str));
+            } else if input.exists() {
+                in_files.push(String::from(input_str));
+            } else {
+                eprintln!("File not found: {:?}", input);
+            }
+        }
+
+        if self.hyperstereo {
+            self.run_hyperstereo(&in_files);
+            return;
+        }
+
+        let criteria = PairingCriteria {
+            sclk_tolerance: self
+                .sclk_tolerance
+                .unwrap_or(PairingCriteria::default().sclk_tolerance),
+            match_sequence: !self.ignore_sequence,
+            match_site_drive: !self.ignore_site_drive,
+            match_subframe: !self.ignore_subframe,
+        };
+
+        let candidates = stereopair::candidates_from_files(&in_files);
+        let pairs = stereopair::find_pairs(&candidates, &criteria);
+
+        println!("Found {} stereo pairs", pairs.len());
+        for pair in pairs.iter() {
+            println!("{} {}", pair.left, pair.right);
+        }
+
+        if self.anaglyph || self.xeye || self.format.is_some() {
+            pb_set_length!(pairs.len());
+            for pair in pairs.iter() {
+                self.generate_products(pair);
+                pb_inc!();
+            }
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use crate::subs::runnable::RunnableSubcommand;
+use clap::Parser;
+use mars_raw_utils::prelude::*;
+use mars_raw_utils::registration::RegistrationMethod;
+use mars_raw_utils::superres::{self, DrizzleParams};
+use sciimg::path;
+use std::process;
+
+pb_create_spinner!();
+
+#[derive(Parser)]
+#[command(author, version, about = "Multi-frame drizzle super-resolution", long_about = None)]
+pub struct SuperRes {
+    #[arg(long, short, help = "Input images", num_args = 1..)]
+    input_files: Vec<std::path::PathBuf>,
+
+    #[arg(long, short, help = "Output image")]
+    output: std::path::PathBuf,
+
+    #[arg(long, short, help = "Output scale factor (default 2.0)")]
+    scale: Option<f64>,
+
+    #[arg(
+        long,
+        short,
+        help = "Drop size as a fraction of input pixel size (default 0.7)"
+    )]
+    pixfrac: Option<f64>,
+
+    #[arg(long, short, help = "Registration method (translation, affine)")]
+    register: Option<RegistrationMethod>,
+
+    #[arg(long, short = 'R', help = "Index of the reference frame (default 0)")]
+    reference: Option<usize>,
+
+    #[arg(
+        long,
+        short,
+        help = "Per-frame weights, one per input image",
+        num_args = 1..
+    )]
+    weights: Option<Vec<f32>>,
+}
+
+#[async_trait::async_trait]
+impl RunnableSubcommand for SuperRes {
+    async fn run(&self) {
+        pb_set_print!();
+        print::print_experimental();
+
+        let output = self.output.as_os_str().to_str().unwrap();
+        if !path::parent_exists_and_writable(output) {
+            eprintln!("Unable to write output image, parent doesn't exist or is not writable");
+            pb_done_with_error!();
+            process::exit(1);
+        }
+
+        let in_files: Vec<String> = self
+            .input_files
+            .iter()
+            .map(|s| String::from(s.as_os_str().to_str().unwrap()))
+            .collect();
+
+        let defaults = DrizzleParams::default();
+        let params = DrizzleParams {
+            scale: self.scale.unwrap_or(defaults.scale),
+            pixfrac: self.pixfrac.unwrap_or(defaults.pixfrac),
+            registration: self.register.unwrap_or(defaults.registration),
+            reference_index: self.reference.unwrap_or(defaults.reference_index),
+            weights: self.weights.clone(),
+        };
+
+        match superres::superres_files(&in_files, &params) {
+            Ok(result) => {
+                vprintln!("Writing image to {}", output);
+                result.image.save(output);
+                pb_done!();
+            }
+            Err(why) => {
+                eprintln!("Error: {}", why);
+                pb_done_with_error!();
+                process::exit(1);
+            }
+        }
+    }
+}
@@ -78,6 +78,8 @@ fi
 
 if [ `ls *${seqid}*-rjcal.png 2> /dev/null | wc -l` -gt 0 ]; then
     mru -v diffgif -i *${seqid}*-rjcal.png -o DustDevil_${sol}_${seqid}_rjcal.gif -b 0 -w 2.0 -g 2.5 -l 5 -d 20 -p stacked
+    mkdir -p Motion_${seqid}
+    mru -v motion-detect -i *${seqid}*-rjcal.png -o DustDevil_${sol}_${seqid}_tracks.json -a Motion_${seqid}
 fi
 
 if [ `ls *FHAZ00595*-rjcal.png 2> /dev/null | wc -l` -gt 0 ]; then
@@ -1,6 +1,347 @@
 use crate::prelude::*;
 use sciimg::{drawable::*, prelude::*, vector::Vector};
 
+use anyhow::anyhow;
+use anyhow::Result;
+use std::str::FromStr;
+
+/// Ground plane (rover frame) onto which both eyes are projected
+pub fn ground_plane() -> Vector {
+    Vector::new(0.0, 0.0, 1.84566)
+}
+
+/// Returns the camera model from the image metadata, if present and valid
+pub fn get_camera_model(img: &MarsImage) -> Result<CameraModel> {
+    match &img.metadata {
+        Some(md) => {
+            if md.camera_model_component_list.is_valid() {
+                Ok(md.camera_model_component_list.clone())
+            } else {
+                Err(anyhow!("Image camera model is invalid"))
+            }
+        }
+        None => Err(anyhow!("Image lacks metadata")),
+    }
+}
+
+type ColorMatrix = [[f32; 3]; 3];
+
+const LUMA: [f32; 3] = [0.299, 0.587, 0.114];
+const ZERO: [f32; 3] = [0.0, 0.0, 0.0];
+
+/// Algorithms for combining the left and right eyes into a single anaglyph image. Each is
+/// expressed as a pair of 3x3 matrices applied to the left and right eye RGB values which
+/// are then summed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum AnaglyphMode {
+    /// Red/blue with both eyes reduced to luminance
+    True,
+
+    /// Red/cyan with both eyes reduced to luminance
+    Gray,
+
+    /// Red/cyan with the left eye red channel and right eye green and blue channels
+    #[default]
+    Color,
+
+    /// Red/cyan with a luminance left eye and full color right eye. Reduces retinal rivalry.
+    HalfColor,
+
+    /// Red/cyan with the left eye red reconstructed from green and blue.
+    Optimized,
+
+    /// Dubois least-squares projection for red/cyan glasses
+    DuboisRedCyan,
+
+    /// Dubois least-squares projection for green/magenta glasses
+    DuboisGreenMagenta,
+
+    /// Dubois least-squares projection for amber/blue glasses
+    DuboisAmberBlue,
+}
+
+impl FromStr for AnaglyphMode {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "true" => Ok(AnaglyphMode::True),
+            "gray" | "grey" => Ok(AnaglyphMode::Gray),
+            "color" | "colour" => Ok(AnaglyphMode::Color),
+            "half-color" | "halfcolor" => Ok(AnaglyphMode::HalfColor),
+            "optimized" => Ok(AnaglyphMode::Optimized),
+            "dubois" | "dubois-red-cyan" => Ok(AnaglyphMode::DuboisRedCyan),
+            "dubois-green-magenta" => Ok(AnaglyphMode::DuboisGreenMagenta),
+            "dubois-amber-blue" => Ok(AnaglyphMode::DuboisAmberBlue),
+            _ => Err("Invalid anaglyph mode"),
+        }
+    }
+}
+
+impl AnaglyphMode {
+    /// Returns the (left, right) eye color matrices for the mode
+    pub fn matrices(&self) -> (ColorMatrix, ColorMatrix) {
+        match self {
+            AnaglyphMode::True => ([LUMA, ZERO, ZERO], [ZERO, ZERO, LUMA]),
+            AnaglyphMode::Gray => ([LUMA, ZERO, ZERO], [ZERO, LUMA, LUMA]),
+            AnaglyphMode::Color => (
+                [[1.0, 0.0, 0.0], ZERO, ZERO],
+                [ZERO, [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            ),
+            AnaglyphMode::HalfColor => {
+                ([LUMA, ZERO, ZERO], [ZERO, [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]])
+            }
+            AnaglyphMode::Optimized => (
+                [[0.0, 0.7, 0.3], ZERO, ZERO],
+                [ZERO, [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            ),
+            // Matrices from Eric Dubois, "A Projection Method to Generate Anaglyph Stereo Images" (2001)
+            // and the follow-up green/magenta and amber/blue derivations.
+            AnaglyphMode::DuboisRedCyan => (
+                [
+                    [0.456, 0.500, 0.176],
+                    [-0.040, -0.038, -0.016],
+                    [-0.015, -0.021, -0.005],
+                ],
+                [
+                    [-0.043, -0.088, -0.002],
+                    [0.378, 0.734, -0.018],
+                    [-0.072, -0.113, 1.226],
+                ],
+            ),
+            AnaglyphMode::DuboisGreenMagenta => (
+                [
+                    [-0.062, -0.158, -0.039],
+                    [0.284, 0.668, 0.143],
+                    [-0.015, -0.027, 0.021],
+                ],
+                [
+                    [0.529, 0.705, 0.024],
+                    [-0.016, -0.015, -0.065],
+                    [0.009, 0.075, 0.937],
+                ],
+            ),
+            AnaglyphMode::DuboisAmberBlue => (
+                [
+                    [1.062, -0.205, 0.299],
+                    [-0.026, 0.908, 0.068],
+                    [-0.038, -0.173, 0.022],
+                ],
+                [
+                    [-0.016, -0.123, -0.017],
+                    [0.006, 0.062, -0.017],
+                    [0.094, 0.185, 0.911],
+                ],
+            ),
+        }
+    }
+}
+
+/// Anaglyph generation options
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct AnaglyphOptions {
+    pub mode: AnaglyphMode,
+
+    /// Horizontal shift (pixels) applied to the right eye to adjust convergence. Positive
+    /// values move the right eye to the left, pushing the scene further behind the screen.
+    pub horizontal_offset: i32,
+
+    /// Vertical shift (pixels) applied to the right eye. Ignored when `auto_vertical` is set.
+    pub vertical_offset: i32,
+
+    /// Estimate and correct vertical disparity between the eyes automatically
+    pub auto_vertical: bool,
+}
+
+/// Maximum vertical disparity (pixels) searched when estimating it automatically
+pub const MAX_VERTICAL_DISPARITY: i32 = 50;
+
+/// Returns a copy of `img` shifted by `dx`, `dy` pixels. Exposed areas are left black.
+pub fn shift_image(img: &Image, dx: i32, dy: i32) -> Image {
+    if dx == 0 && dy == 0 {
+        return img.clone();
+    }
+
+    let mut shifted = img.clone();
+    for b in 0..img.num_bands() {
+        let band = img.get_band(b);
+        for y in 0..img.height as i32 {
+            for x in 0..img.width as i32 {
+                let src_x = x - dx;
+                let src_y = y - dy;
+                let v = if src_x >= 0
+                    && src_y >= 0
+                    && src_x < img.width as i32
+                    && src_y < img.height as i32
+                {
+                    band.get(src_x as usize, src_y as usize)
+                } else {
+                    0.0
+                };
+                shifted.put(x as usize, y as usize, v, b);
+            }
+        }
+    }
+    shifted
+}
+
+fn row_profile(img: &Image) -> Vec<f32> {
+    let mut profile: Vec<f32> = (0..img.height)
+        .map(|y| {
+            let mut sum = 0.0;
+            for b in 0..img.num_bands() {
+                for x in 0..img.width {
+                    sum += img.get_band(b).get(x, y);
+                }
+            }
+            sum / (img.width * img.num_bands()) as f32
+        })
+        .collect();
+
+    // Remove the mean so brightness differences between the eyes don't bias the fit
+    let mean = profile.iter().sum::<f32>() / profile.len().max(1) as f32;
+    profile.iter_mut().for_each(|v| *v -= mean);
+    profile
+}
+
+/// Estimates the vertical shift (pixels) that best aligns `right` to `left` by comparing
+/// the row intensity profiles of the two images.
+pub fn estimate_vertical_disparity(left: &Image, right: &Image, max_offset: i32) -> i32 {
+    let left_profile = row_profile(left);
+    let right_profile = row_profile(right);
+    let height = left_profile.len().min(right_profile.len()) as i32;
+
+    let mut best_offset = 0;
+    let mut best_error = f32::MAX;
+    for dy in -max_offset..=max_offset {
+        let mut error = 0.0;
+        let mut count = 0;
+        for y in 0..height {
+            let ry = y - dy;
+            if ry >= 0 && ry < height {
+                error += (left_profile[y as usize] - right_profile[ry as usize]).abs();
+                count += 1;
+            }
+        }
+        // Require at least half of the rows to overlap
+        if count > height / 2 {
+            let error = error / count as f32;
+            if error < best_error {
+                best_error = error;
+                best_offset = dy;
+            }
+        }
+    }
+    best_offset
+}
+
+/// Combines projected left and right eye images into an anaglyph using the mode's matrices.
+/// Output values are clipped to the range `[0, max_value]`.
+pub fn combine_eyes(left: &Image, right: &Image, mode: AnaglyphMode, max_value: f32) -> Image {
+    let (lm, rm) = mode.matrices();
+    let mut map = Image::create(left.width, left.height);
+
+    let channel = |img: &Image, c: usize, x: usize, y: usize| {
+        if img.num_bands() > c {
+            img.get_band(c).get(x, y)
+        } else {
+            img.get_band(0).get(x, y)
+        }
+    };
+
+    for y in 0..left.height {
+        for x in 0..left.width {
+            let l = [
+                channel(left, 0, x, y),
+                channel(left, 1, x, y),
+                channel(left, 2, x, y),
+            ];
+            let r = [
+                channel(right, 0, x, y),
+                channel(right, 1, x, y),
+                channel(right, 2, x, y),
+            ];
+            for (c, (lrow, rrow)) in lm.iter().zip(rm.iter()).enumerate() {
+                let v = lrow[0] * l[0]
+                    + lrow[1] * l[1]
+                    + lrow[2] * l[2]
+                    + rrow[0] * r[0]
+                    + rrow[1] * r[1]
+                    + rrow[2] * r[2];
+                map.put(x, y, v.clamp(0.0, max_value), c);
+            }
+        }
+    }
+    map
+}
+
+/// Creates a red/cyan anaglyph from a stereo pair, projecting the right eye into the
+/// linearized left eye camera model.
+pub fn create_anaglyph(left_img: &MarsImage, right_img: &MarsImage) -> Result<Image> {
+    create_anaglyph_with_options(left_img, right_img, &AnaglyphOptions::default())
+}
+
+/// Creates an anaglyph from a stereo pair using the specified color mode and offsets. Both
+/// eyes are projected into the linearized left eye camera model before being combined.
+pub fn create_anaglyph_with_options(
+    left_img: &MarsImage,
+    right_img: &MarsImage,
+    options: &AnaglyphOptions,
+) -> Result<Image> {
+    let left_cahv = get_camera_model(left_img)?;
+    let right_cahv = get_camera_model(right_img)?;
+
+    let ground = ground_plane();
+
+    let output_model = left_cahv.linearize(
+        left_img.image.width,
+        left_img.image.height,
+        left_img.image.width,
+        left_img.image.height,
+    )?;
+
+    let mut left_map = Image::create(left_img.image.width, left_img.image.height);
+    process_image(
+        left_img,
+        &mut left_map,
+        &left_cahv,
+        &output_model,
+        &ground,
+        Eye::DontCare,
+    );
+
+    let mut right_map = Image::create(left_img.image.width, left_img.image.height);
+    process_image(
+        right_img,
+        &mut right_map,
+        &right_cahv,
+        &output_model,
+        &ground,
+        Eye::DontCare,
+    );
+
+    let vertical_offset = if options.auto_vertical {
+        let v = estimate_vertical_disparity(&left_map, &right_map, MAX_VERTICAL_DISPARITY);
+        vprintln!("Estimated vertical disparity: {} pixels", v);
+        v
+    } else {
+        options.vertical_offset
+    };
+
+    if options.horizontal_offset != 0 || vertical_offset != 0 {
+        vprintln!(
+            "Shifting right eye by {}, {} pixels",
+            -options.horizontal_offset,
+            vertical_offset
+        );
+        right_map = shift_image(&right_map, -options.horizontal_offset, vertical_offset);
+    }
+
+    vprintln!("Combining eyes with anaglyph mode {:?}", options.mode);
+    let max_value = util::max_value_for_mode(left_img.image.get_mode());
+    Ok(combine_eyes(&left_map, &right_map, options.mode, max_value))
+}
+
 pub fn process_image(
     img: &MarsImage,
     map: &mut Image,
@@ -1,14 +1,19 @@
+use crate::calibfile::{self, CalDataResolver};
 use crate::httpfetch;
 use crate::print;
 use crate::vprintln;
+use anyhow::anyhow;
 use anyhow::Result;
-use dirs;
+use chrono::prelude::*;
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::env;
 use std::fs;
 use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use url::Url;
 
 // TODO: I would prefer this not being hardcoded. Find how to define it in Cargo.toml
@@ -18,19 +23,18 @@ use url::Url;
 const CALIBRATION_FILE_REMOTE_ROOT: &str =
     "https://raw.githubusercontent.com/kmgill/mars-raw-utils-data/main/caldata/";
 
-/// Determine where to put the calibration data files. A path
-/// indicated by the environment variable `MARS_RAW_DATA` would be used first
-/// followed by `$HOME/.marsdata`. It is assumed that `/usr/share/mars_raw_utils/data/`
+/// Determine where to put the calibration data files. An explicitly given path is used
+/// first, followed by the first writable location of the calibration data search path:
+/// the `--data-path` command line option, the environment variable `MARS_RAW_DATA`, then
+/// `$HOME/.marsdata`. It is assumed that `/usr/share/mars_raw_utils/data/`
 /// or `/Program Files/mars_raw_utils/data` would be unwritable thus not considered
 /// by this function. This function also does not attempt to determine if the
 /// returned path exists or is writable.
 pub fn get_calibration_local_store(use_local_store: &Option<String>) -> PathBuf {
     if let Some(local_store) = use_local_store {
         PathBuf::from(&local_store)
-    } else if let Ok(dir) = env::var("MARS_RAW_DATA") {
+    } else if let Some(dir) = CalDataResolver::shared().local_store() {
         PathBuf::from(&dir)
-    } else if let Some(dir) = dirs::home_dir() {
-        PathBuf::from(format!("{}/.marsdata", dir.to_str().unwrap()))
     } else {
         panic!("Unable to determine where to put calibration data!");
     }
@@ -67,9 +71,143 @@ pub fn get_calibration_file_remote_url(remote_file_uri: &str) -> String {
     joined.to_string()
 }
 
+/// Name of the file recording the installed calibration data version in the local store
+pub const INSTALLED_VERSION_FILE: &str = "caldata.version";
+
+/// A file listed in the calibration data manifest. Checksum and size are absent in legacy
+/// manifests, which are a bare list of paths.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ManifestEntry {
+    pub path: String,
+
+    /// Lowercase hex SHA-256 of the file contents
+    pub sha256: Option<String>,
+
+    /// File size in bytes
+    pub size: Option<u64>,
+}
+
+impl ManifestEntry {
+    pub fn new(path: &str) -> Self {
+        ManifestEntry {
+            path: path.to_string(),
+            sha256: None,
+            size: None,
+        }
+    }
+
+    /// Checks `bytes` against the recorded size and checksum
+    pub fn verify_bytes(&self, bytes: &[u8]) -> VerifyStatus {
+        if let Some(size) = self.size {
+            if bytes.len() as u64 != size {
+                return VerifyStatus::SizeMismatch {
+                    expected: size,
+                    actual: bytes.len() as u64,
+                };
+            }
+        }
+        match &self.sha256 {
+            Some(expected) => {
+                let actual = sha256_hex(bytes);
+                if actual.eq_ignore_ascii_case(expected) {
+                    VerifyStatus::Ok
+                } else {
+                    VerifyStatus::ChecksumMismatch {
+                        expected: expected.to_lowercase(),
+                        actual,
+                    }
+                }
+            }
+            None => VerifyStatus::Unverifiable,
+        }
+    }
+}
+
+/// Versioned list of calibration data files
+///
+/// ```toml
+/// version = "2023.11.02"
+///
+/// [[file]]
+/// path = "m20/ilut/M20_LUT2_v2a.txt"
+/// size = 9010
+/// sha256 = "9f2c..."
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct CalDataManifest {
+    pub version: Option<String>,
+
+    #[serde(rename = "file", default)]
+    pub files: Vec<ManifestEntry>,
+}
+
+/// Whether a manifest is in the versioned TOML format: its first line that is neither blank
+/// nor a comment opens a table or assigns a key. Legacy manifests start with a file path.
+pub fn is_toml_manifest(data: &str) -> bool {
+    match data
+        .lines()
+        .map(|l| l.trim())
+        .find(|l| !l.is_empty() && !l.starts_with('#'))
+    {
+        Some(line) if line.starts_with('[') => true,
+        Some(line) => line.split_once('=').is_some_and(|(key, _)| {
+            let key = key.trim();
+            !key.is_empty()
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '"'))
+        }),
+        None => false,
+    }
+}
+
+/// Parses a calibration data manifest, accepting both the versioned TOML format and the
+/// legacy one-path-per-line list. A manifest that looks like TOML but does not parse is
+/// an error rather than being read as a file list.
+pub fn parse_manifest(data: &str) -> Result<CalDataManifest> {
+    if is_toml_manifest(data) {
+        return toml::from_str::<CalDataManifest>(data)
+            .map_err(|why| anyhow!("Failed to parse calibration manifest: {}", why));
+    }
+    Ok(CalDataManifest {
+        version: None,
+        files: data
+            .lines()
+            .map(|l| l.trim())
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(ManifestEntry::new)
+            .collect(),
+    })
+}
+
 /// Splits the file list into a vector of Strings
-pub fn parse_manifest_file_list(data: &str) -> Vec<String> {
-    data.lines().map(|f| f.to_string()).collect()
+pub fn parse_manifest_file_list(data: &str) -> Result<Vec<String>> {
+    Ok(parse_manifest(data)?
+        .files
+        .into_iter()
+        .map(|f| f.path)
+        .collect())
+}
+
+/// Lowercase hex SHA-256 digest of `bytes`
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Fetches the remote calibration manifest from a specified url
+pub async fn fetch_remote_manifest_from(uri: &str) -> Result<CalDataManifest> {
+    match httpfetch::simple_fetch_text(uri).await {
+        Ok(data) => parse_manifest(&data),
+        Err(why) => Err(anyhow!("Failed to retrieve remote manifest: {}", why)),
+    }
+}
+
+/// Fetches the system defined remote calibration manifest
+pub async fn fetch_remote_manifest() -> Result<CalDataManifest> {
+    fetch_remote_manifest_from(&get_calibration_file_remote_url("caldata.manifest")).await
 }
 
 /// Fetches the remote calibration manifest from a specified url and returns the parsed file list
@@ -77,7 +215,7 @@ pub async fn fetch_remote_calibration_manifest_from(
     uri: &str,
 ) -> Result<Vec<String>, &'static str> {
     if let Ok(data) = httpfetch::simple_fetch_text(uri).await {
-        Ok(parse_manifest_file_list(&data))
+        parse_manifest_file_list(&data).map_err(|_| "Failed to parse remote manifest")
     } else {
         Err("Failed to retrieve remote resource")
     }
@@ -90,6 +228,130 @@ pub async fn fetch_remote_calibration_manifest() -> Result<Vec<String>, &'static
         .await
 }
 
+/// Integrity of a local calibration file relative to its manifest entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyStatus {
+    Ok,
+    Missing,
+    SizeMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    ChecksumMismatch {
+        expected: String,
+        actual: String,
+    },
+
+    /// Present, but the manifest carries no checksum to compare against
+    Unverifiable,
+}
+
+impl VerifyStatus {
+    /// Whether the file needs to be downloaded again
+    pub fn is_bad(&self) -> bool {
+        !matches!(self, VerifyStatus::Ok | VerifyStatus::Unverifiable)
+    }
+}
+
+/// Checks a file in the local store against its manifest entry
+pub fn verify_local_file(entry: &ManifestEntry, use_local_store: &Option<String>) -> VerifyStatus {
+    let local = get_calibration_file_local_path(&entry.path, use_local_store);
+    if !local.exists() {
+        return VerifyStatus::Missing;
+    }
+    if let (Some(size), Ok(md)) = (entry.size, fs::metadata(&local)) {
+        if md.len() != size {
+            return VerifyStatus::SizeMismatch {
+                expected: size,
+                actual: md.len(),
+            };
+        }
+    }
+    if entry.sha256.is_none() {
+        return VerifyStatus::Unverifiable;
+    }
+    let mut bytes = vec![];
+    match File::open(&local).and_then(|mut f| f.read_to_end(&mut bytes)) {
+        Ok(_) => entry.verify_bytes(&bytes),
+        Err(_) => VerifyStatus::Missing,
+    }
+}
+
+/// Checks every file in `manifest` against the local store
+pub fn verify_against_manifest(
+    manifest: &CalDataManifest,
+    use_local_store: &Option<String>,
+) -> Vec<(ManifestEntry, VerifyStatus)> {
+    manifest
+        .files
+        .par_iter()
+        .map(|e| (e.clone(), verify_local_file(e, use_local_store)))
+        .collect()
+}
+
+/// Calibration data version recorded in the local store after an update
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct InstalledVersion {
+    pub version: Option<String>,
+
+    /// UTC time of the update
+    pub installed: String,
+
+    /// Manifest the data was installed from
+    pub source: String,
+}
+
+/// Reads the installed calibration data version, if recorded
+pub fn installed_version(use_local_store: &Option<String>) -> Option<InstalledVersion> {
+    read_installed_version(&get_calibration_local_store(use_local_store))
+}
+
+fn read_installed_version(store: &Path) -> Option<InstalledVersion> {
+    let text = fs::read_to_string(store.join(INSTALLED_VERSION_FILE)).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+/// Installed version cached for the `--data-path` it was read with
+static CURRENT_INSTALLED_VERSION: Mutex<Option<(Option<String>, Option<InstalledVersion>)>> =
+    Mutex::new(None);
+
+/// Installed calibration data version in the local store of the current search path. The
+/// version file is read once per data path, so this is cheap to call for every image. None
+/// when there is no local store, where `installed_version` would panic.
+pub fn current_installed_version() -> Option<InstalledVersion> {
+    let data_path = calibfile::cli_data_path();
+    let mut cache = CURRENT_INSTALLED_VERSION.lock().unwrap();
+    if let Some((path, installed)) = cache.as_ref() {
+        if *path == data_path {
+            return installed.clone();
+        }
+    }
+    let installed = CalDataResolver::shared()
+        .local_store()
+        .and_then(|store| read_installed_version(Path::new(&store)));
+    *cache = Some((data_path, installed.clone()));
+    installed
+}
+
+/// Records the installed calibration data version in the local store
+pub fn save_installed_version(
+    manifest: &CalDataManifest,
+    use_local_store: &Option<String>,
+) -> Result<InstalledVersion> {
+    let installed = InstalledVersion {
+        version: manifest.version.clone(),
+        installed: Utc::now().to_rfc3339(),
+        source: get_calibration_file_remote_url("caldata.manifest"),
+    };
+    let store = get_calibration_local_store(use_local_store);
+    fs::create_dir_all(&store)?;
+    fs::write(
+        store.join(INSTALLED_VERSION_FILE),
+        serde_json::to_string_pretty(&installed)?,
+    )?;
+    Ok(installed)
+}
+
 /// Success states for `fetch_and_save_file`
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum SaveResult {
@@ -98,6 +360,23 @@ pub enum SaveResult {
     IsNew,
 }
 
+/// Writes `bytes` next to `save_to` and moves it into place, so an interrupted write never
+/// leaves a truncated calibration file behind
+fn write_file_atomic(save_to: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    if let Some(parent) = save_to.parent() {
+        if !parent.exists() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    let mut partial = save_to.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+    let mut file = File::create(&partial)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    fs::rename(&partial, save_to)
+}
+
 /// Fetch the remote file and save it to disk in a location indicated by `get_calibration_file_local_path`.
 /// If `replace` is false and the file already exists, this function will quit and not overwrite.
 pub async fn fetch_and_save_file(
@@ -105,9 +384,25 @@ pub async fn fetch_and_save_file(
     replace: bool,
     use_local_store: &Option<String>,
 ) -> Result<SaveResult, String> {
+    fetch_and_save_entry(
+        &ManifestEntry::new(remote_file_uri),
+        replace,
+        use_local_store,
+    )
+    .await
+}
+
+/// Fetch the file described by a manifest entry, verifying its size and checksum before it
+/// is written. An existing file that fails verification is replaced even if `replace` is false.
+pub async fn fetch_and_save_entry(
+    entry: &ManifestEntry,
+    replace: bool,
+    use_local_store: &Option<String>,
+) -> Result<SaveResult, String> {
+    let remote_file_uri = entry.path.as_str();
     let save_to = get_calibration_file_local_path(remote_file_uri, use_local_store);
     let save_to_exists = save_to.exists();
-    if save_to_exists && !replace {
+    if save_to_exists && !replace && !verify_local_file(entry, use_local_store).is_bad() {
         print::print_warn(&format!("Skipped: {}", remote_file_uri));
         vprintln!(
             "Calibraton file {} already exists and replace is set to false",
@@ -125,16 +420,21 @@ pub async fn fetch_and_save_file(
 
         match httpfetch::simple_fetch_bin(&resource_url).await {
             Ok(bytes_array) => {
-                vprintln!("Saving to {:?}", save_to);
+                let status = entry.verify_bytes(&bytes_array[..]);
+                if status.is_bad() {
+                    print::print_fail(&format!("Failed: {}", remote_file_uri));
+                    return Err(format!(
+                        "Downloaded {} failed verification: {:?}",
+                        remote_file_uri, status
+                    ));
+                }
 
-                if let Some(parent) = save_to.parent() {
-                    if !parent.exists() {
-                        fs::create_dir_all(parent).unwrap();
-                    }
+                vprintln!("Saving to {:?}", save_to);
+                if let Err(why) = write_file_atomic(&save_to, &bytes_array[..]) {
+                    print::print_fail(&format!("Failed: {}", remote_file_uri));
+                    return Err(format!("Error writing {:?}: {}", save_to, why));
                 }
 
-                let mut file = File::create(save_to).unwrap();
-                file.write_all(&bytes_array[..]).unwrap();
                 if save_to_exists {
                     print::print_done(&format!("Replaced: {}", remote_file_uri));
                     Ok(SaveResult::Replaced)
@@ -153,29 +453,42 @@ pub async fn fetch_and_save_file(
 }
 
 /// Retrieves the remote calibration file manifest `caldata.manifest` and downloads each
-/// referenced file. If `replace` is false, existing files will not be overwritten.
+/// referenced file. If `replace` is false, existing files will not be overwritten unless
+/// they fail verification. The manifest version is recorded in the local store once every
+/// file has been fetched.
 pub async fn update_calibration_data<A: FnOnce(usize), B: Fn()>(
     replace: bool,
     use_local_store: &Option<String>,
     on_total_known: A,
     on_file_downloaded: B,
 ) -> Result<(), String> {
-    let manifest_config_res = fetch_remote_calibration_manifest().await;
-
-    if let Ok(file_list) = manifest_config_res {
-        on_total_known(file_list.len());
-
-        let tasks: Vec<_> = file_list
-            .par_iter()
-            .map(|f| fetch_and_save_file(f, replace, use_local_store))
-            .collect();
-        for task in tasks {
-            task.await?;
-            on_file_downloaded();
-        }
+    let manifest = match fetch_remote_manifest().await {
+        Ok(m) => m,
+        Err(_) => return Err("Failed to retrieve remote data manifest".to_string()),
+    };
+    on_total_known(manifest.files.len());
 
-        Ok(())
-    } else {
-        Err("Failed to retrieve remote data manifest".to_string())
+    let tasks: Vec<_> = manifest
+        .files
+        .par_iter()
+        .map(|f| fetch_and_save_entry(f, replace, use_local_store))
+        .collect();
+    for task in tasks {
+        task.await?;
+        on_file_downloaded();
     }
+
+    match save_installed_version(&manifest, use_local_store) {
+        Ok(_) => Ok(()),
+        Err(why) => Err(format!("Failed to record installed version: {}", why)),
+    }
+}
+
+/// Checks the local store against the remote manifest without downloading anything
+pub async fn verify_calibration_data(
+    use_local_store: &Option<String>,
+) -> Result<(CalDataManifest, Vec<(ManifestEntry, VerifyStatus)>)> {
+    let manifest = fetch_remote_manifest().await?;
+    let results = verify_against_manifest(&manifest, use_local_store);
+    Ok((manifest, results))
 }
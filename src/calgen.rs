@@ -0,0 +1,354 @@
+use crate::calibfile::{self, OVERRIDES_FILE};
+use crate::enums::{CalFileType, Instrument};
+use crate::stacking::{self, FrameSource, StackMethod, StackOptions};
+use crate::vprintln;
+
+use sciimg::{enums::ImageMode, image::Image, imagebuffer::ImageBuffer};
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+use rayon::prelude::*;
+
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Maximum number of pixels sampled when estimating a band median
+const MEDIAN_SAMPLES: usize = 1_000_000;
+
+/// Median of `values`, reordering them
+fn median_of(values: &mut [f32]) -> f32 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    let mid = values.len() / 2;
+    values.select_nth_unstable_by(mid, |a, b| {
+        a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal)
+    });
+    values[mid]
+}
+
+/// Approximate median of each band, sampling at most `MEDIAN_SAMPLES` pixels
+pub fn band_medians(image: &Image) -> Vec<f32> {
+    let n = image.width * image.height;
+    let stride = n.div_ceil(MEDIAN_SAMPLES).max(1);
+    (0..image.num_bands())
+        .map(|b| {
+            let band = image.get_band(b);
+            let mut values: Vec<f32> = (0..n)
+                .step_by(stride)
+                .map(|i| band.get(i % image.width, i / image.width))
+                .collect();
+            median_of(&mut values)
+        })
+        .collect()
+}
+
+/// Frames divided band by band by their own median, so frames of differing exposure or
+/// scene brightness can be combined
+pub struct MedianNormalized<'a, S: FrameSource + ?Sized> {
+    pub source: &'a S,
+}
+
+impl<S: FrameSource + ?Sized> FrameSource for MedianNormalized<'_, S> {
+    fn num_frames(&self) -> usize {
+        self.source.num_frames()
+    }
+
+    fn frame(&self, index: usize) -> Result<Image> {
+        let mut frame = self.source.frame(index)?;
+        let medians = band_medians(&frame);
+        for (b, m) in medians.iter().enumerate() {
+            if *m <= 0.0 {
+                return Err(anyhow!("Frame {} band {} has no signal", index, b));
+            }
+        }
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                for (b, m) in medians.iter().enumerate() {
+                    let v = frame.get_band(b).get(x, y);
+                    frame.put(x, y, v / m, b);
+                }
+            }
+        }
+        Ok(frame)
+    }
+}
+
+/// Flat field generation parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlatParams {
+    /// How normalized frames are combined. Sigma clipping or the median reject stars,
+    /// cosmic rays and transient scene content.
+    pub method: StackMethod,
+    pub sigma: f32,
+    pub min_frames: usize,
+}
+
+impl Default for FlatParams {
+    fn default() -> Self {
+        FlatParams {
+            method: StackMethod::SigmaClip,
+            sigma: 3.0,
+            min_frames: 3,
+        }
+    }
+}
+
+/// Builds a flat field from sky or uniformly lit frames. Each frame is normalized by its
+/// band medians and the stack scaled so its brightest value is full scale 16 bit. Flat
+/// fielding divides by the flat relative to its mean, so the absolute scale is arbitrary.
+pub fn make_flat<S: FrameSource + ?Sized>(source: &S, params: &FlatParams) -> Result<Image> {
+    if source.num_frames() < params.min_frames {
+        return Err(anyhow!(
+            "At least {} frames are needed for a flat field, got {}",
+            params.min_frames,
+            source.num_frames()
+        ));
+    }
+
+    let options = StackOptions {
+        method: params.method,
+        sigma_low: params.sigma,
+        sigma_high: params.sigma,
+        ..Default::default()
+    };
+    let mut flat = stacking::stack(&MedianNormalized { source }, &options)?;
+
+    let mut max = 0.0_f32;
+    for b in 0..flat.num_bands() {
+        let band = flat.get_band(b);
+        for y in 0..flat.height {
+            for x in 0..flat.width {
+                max = max.max(band.get(x, y));
+            }
+        }
+    }
+    if max <= 0.0 {
+        return Err(anyhow!("Flat field has no signal"));
+    }
+
+    let scale = 65535.0 / max;
+    vprintln!("Scaling flat field by {}", scale);
+    for y in 0..flat.height {
+        for x in 0..flat.width {
+            for b in 0..flat.num_bands() {
+                let v = flat.get_band(b).get(x, y);
+                flat.put(x, y, (v * scale).clamp(0.0, 65535.0), b);
+            }
+        }
+    }
+    flat.set_mode(ImageMode::U16BIT);
+    Ok(flat)
+}
+
+/// Bad pixel detection parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaskParams {
+    /// Pixels of the mean dark frame more than this many robust standard deviations above
+    /// the dark median are hot
+    pub hot_sigma: f32,
+
+    /// Pixels of the mean uniform frame below this fraction of their neighbourhood median
+    /// are dead
+    pub dead_fraction: f32,
+
+    /// Neighbourhood size (pixels) for dead pixel detection
+    pub window_size: usize,
+}
+
+impl Default for MaskParams {
+    fn default() -> Self {
+        MaskParams {
+            hot_sigma: 6.0,
+            dead_fraction: 0.5,
+            window_size: 7,
+        }
+    }
+}
+
+/// Detected bad pixels. The mask is nonzero on bad pixels, as used by inpainting.
+pub struct BadPixelMask {
+    pub mask: ImageBuffer,
+    pub num_hot: usize,
+    pub num_dead: usize,
+}
+
+/// Per-band mean of all frames in `source`
+fn mean_frame<S: FrameSource + ?Sized>(source: &S) -> Result<Image> {
+    let options = StackOptions {
+        method: StackMethod::Mean,
+        ..Default::default()
+    };
+    stacking::stack(source, &options)
+}
+
+/// Builds a hot/dead pixel mask from dark frames and/or uniformly lit frames. Either may be
+/// empty, but not both. Hot pixels are found in the mean dark frame against its global
+/// median and median absolute deviation, dead pixels in the mean uniform frame against the
+/// median of their neighbourhood. A pixel is bad if it is bad in any band.
+pub fn make_mask<D: FrameSource + ?Sized, U: FrameSource + ?Sized>(
+    darks: &D,
+    uniform: &U,
+    params: &MaskParams,
+) -> Result<BadPixelMask> {
+    if darks.num_frames() == 0 && uniform.num_frames() == 0 {
+        return Err(anyhow!("No dark or uniform frames"));
+    }
+
+    let dark = match darks.num_frames() {
+        0 => None,
+        _ => Some(mean_frame(darks)?),
+    };
+    let flat = match uniform.num_frames() {
+        0 => None,
+        _ => Some(mean_frame(uniform)?),
+    };
+
+    let (width, height) = match (&dark, &flat) {
+        (Some(d), Some(f)) if d.width != f.width || d.height != f.height => {
+            return Err(anyhow!(
+                "Dark and uniform frames differ in size: {}x{} and {}x{}",
+                d.width,
+                d.height,
+                f.width,
+                f.height
+            ))
+        }
+        (Some(d), _) => (d.width, d.height),
+        (None, Some(f)) => (f.width, f.height),
+        (None, None) => unreachable!(),
+    };
+
+    let mut hot = vec![false; width * height];
+    if let Some(dark) = &dark {
+        for b in 0..dark.num_bands() {
+            let band = dark.get_band(b);
+            let mut values: Vec<f32> = (0..width * height)
+                .map(|i| band.get(i % width, i / width))
+                .collect();
+            let median = median_of(&mut values);
+            let mut deviations: Vec<f32> = values.iter().map(|v| (v - median).abs()).collect();
+            // Scaled to the standard deviation of a normal distribution. Floor at one DN so
+            // perfectly uniform darks don't flag every pixel above the median.
+            let sigma = (median_of(&mut deviations) * 1.4826).max(1.0);
+            let threshold = median + params.hot_sigma * sigma;
+            vprintln!(
+                "Dark band {}: median {}, sigma {}, hot threshold {}",
+                b,
+                median,
+                sigma,
+                threshold
+            );
+            for (i, h) in hot.iter_mut().enumerate() {
+                if band.get(i % width, i / width) > threshold {
+                    *h = true;
+                }
+            }
+        }
+    }
+
+    let mut dead = vec![false; width * height];
+    if let Some(flat) = &flat {
+        let half = (params.window_size / 2).max(1);
+        for b in 0..flat.num_bands() {
+            let band = flat.get_band(b);
+            dead.par_chunks_mut(width)
+                .enumerate()
+                .for_each(|(y, dead_row)| {
+                    // One neighbourhood buffer per row, reused for every pixel
+                    let mut neighbours = Vec::with_capacity((2 * half + 1) * (2 * half + 1));
+                    for (x, d) in dead_row.iter_mut().enumerate() {
+                        neighbours.clear();
+                        for ny in y.saturating_sub(half)..(y + half + 1).min(height) {
+                            for nx in x.saturating_sub(half)..(x + half + 1).min(width) {
+                                if nx != x || ny != y {
+                                    neighbours.push(band.get(nx, ny));
+                                }
+                            }
+                        }
+                        let local = median_of(&mut neighbours);
+                        if local > 0.0 && band.get(x, y) < local * params.dead_fraction {
+                            *d = true;
+                        }
+                    }
+                });
+        }
+    }
+
+    let mut mask =
+        ImageBuffer::new_with_fill_as_mode(width, height, 0.0, ImageMode::U16BIT).unwrap();
+    let (mut num_hot, mut num_dead) = (0, 0);
+    for (i, (h, d)) in hot.iter().zip(dead.iter()).enumerate() {
+        if *h {
+            num_hot += 1;
+        }
+        if *d {
+            num_dead += 1;
+        }
+        if *h || *d {
+            mask.put(i % width, i / width, 65535.0);
+        }
+    }
+    vprintln!("Found {} hot and {} dead pixels", num_hot, num_dead);
+
+    Ok(BadPixelMask {
+        mask,
+        num_hot,
+        num_dead,
+    })
+}
+
+/// Records `file` as the calibration file of `file_type` for `instrument` in the
+/// `caldata-overrides.toml` of `store_dir`, creating it if needed. Returns the override
+/// file path.
+pub fn register_calibration_file(
+    store_dir: &str,
+    instrument: &str,
+    file_type: CalFileType,
+    file: &str,
+) -> Result<String> {
+    if Instrument::from_str(instrument).unwrap() == Instrument::None {
+        return Err(anyhow!("Unknown instrument: {}", instrument));
+    }
+
+    let file = match fs::canonicalize(file) {
+        Ok(p) => String::from(p.to_str().unwrap()),
+        Err(_) => file.to_string(),
+    };
+
+    let overrides_file = format!("{}/{}", store_dir, OVERRIDES_FILE);
+    let mut table: toml::Table = if Path::new(&overrides_file).exists() {
+        match toml::from_str(&fs::read_to_string(&overrides_file)?) {
+            Ok(t) => t,
+            Err(why) => return Err(anyhow!("Failed to parse {}: {}", overrides_file, why)),
+        }
+    } else {
+        toml::Table::new()
+    };
+
+    let entry = table
+        .entry(instrument.to_uppercase())
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+    match entry {
+        toml::Value::Table(t) => {
+            t.insert(
+                calibfile::caldata_key(file_type).to_string(),
+                toml::Value::String(file),
+            );
+        }
+        _ => {
+            return Err(anyhow!(
+                "{} in {} is not a table",
+                instrument,
+                overrides_file
+            ))
+        }
+    }
+
+    fs::create_dir_all(store_dir)?;
+    fs::write(&overrides_file, toml::to_string(&table)?)?;
+    vprintln!("Registered calibration file in {}", overrides_file);
+    Ok(overrides_file)
+}
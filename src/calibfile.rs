@@ -1,14 +1,20 @@
 use std::env;
 
 use crate::enums::CalFileType;
-use crate::{constants, enums, vprintln};
+use crate::metadata::Metadata;
+use crate::{constants, enums, veprintln, vprintln};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
 
 use sciimg::path;
 
 extern crate dirs;
 
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
 
 use anyhow::anyhow;
 use anyhow::Result;
@@ -26,6 +32,7 @@ fn default_instrument_properties() -> InstrumentProperties {
         inpaint_mask: default_blank(),
         mask: default_blank(),
         lut: default_blank(),
+        versions: vec![],
     }
 }
 
@@ -52,6 +59,215 @@ pub struct InstrumentProperties {
 
     #[serde(default = "default_blank")]
     pub lut: String,
+
+    /// Time-dependent replacements for the files above
+    #[serde(default)]
+    pub versions: Vec<CalFileVersion>,
+}
+
+/// When an image was taken, used to pick between versions of a calibration file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ObservationTime {
+    pub sol: Option<u32>,
+
+    /// UTC timestamp as found in image metadata (e.g. `2023-01-08T03:53:04.187`)
+    pub utc: Option<String>,
+}
+
+impl ObservationTime {
+    pub fn from_metadata(md: &Option<Metadata>) -> Self {
+        match md {
+            Some(md) => ObservationTime {
+                sol: Some(md.sol),
+                utc: if md.date_taken_utc.is_empty() {
+                    None
+                } else {
+                    Some(md.date_taken_utc.clone())
+                },
+            },
+            None => ObservationTime::default(),
+        }
+    }
+}
+
+/// Parses the UTC formats found in metadata and calibration mappings: full timestamps with
+/// or without fractional seconds and time zone, or a bare date
+pub fn parse_utc(s: &str) -> Option<NaiveDateTime> {
+    let s = s.trim();
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Some(dt.naive_utc());
+    }
+    for fmt in ["%Y-%m-%dT%H:%M:%S%.f", "%Y-%m-%d %H:%M:%S%.f"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(s.trim_end_matches('Z'), fmt) {
+            return Some(dt);
+        }
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| d.and_hms_opt(0, 0, 0))
+}
+
+/// A calibration file valid for a range of sols and/or UTC dates. Ranges are inclusive of
+/// the start and exclusive of the end; omitted bounds are open. Malformed or empty ranges
+/// are rejected when the mapping or override file is read.
+///
+/// ```toml
+/// [[msl.mastcam_left.versions]]
+/// type = "flat"
+/// file = "msl/MSL_MCAM_FLAT_L_Sol3000.png"
+/// sol_start = 3000
+/// ```
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "CalFileVersionFields")]
+pub struct CalFileVersion {
+    pub file_type: CalFileType,
+    pub file: String,
+    pub sol_start: Option<u32>,
+    pub sol_end: Option<u32>,
+    pub utc_start: Option<String>,
+    pub utc_end: Option<String>,
+}
+
+/// A `CalFileVersion` as written, before its bounds are checked
+#[derive(Deserialize)]
+struct CalFileVersionFields {
+    #[serde(rename = "type")]
+    file_type: CalFileType,
+    file: String,
+    sol_start: Option<u32>,
+    sol_end: Option<u32>,
+    utc_start: Option<String>,
+    utc_end: Option<String>,
+}
+
+impl TryFrom<CalFileVersionFields> for CalFileVersion {
+    type Error = anyhow::Error;
+
+    fn try_from(f: CalFileVersionFields) -> Result<Self> {
+        let version = CalFileVersion {
+            file_type: f.file_type,
+            file: f.file,
+            sol_start: f.sol_start,
+            sol_end: f.sol_end,
+            utc_start: f.utc_start,
+            utc_end: f.utc_end,
+        };
+        version.validate()?;
+        Ok(version)
+    }
+}
+
+impl CalFileVersion {
+    /// Checks that UTC bounds parse and that each range ends after it starts
+    pub fn validate(&self) -> Result<()> {
+        if let (Some(start), Some(end)) = (self.sol_start, self.sol_end) {
+            if start >= end {
+                return Err(anyhow!(
+                    "Version {}: sol_start {} is not before sol_end {}",
+                    self.file,
+                    start,
+                    end
+                ));
+            }
+        }
+
+        let parse_bound = |name: &str, bound: &Option<String>| match bound {
+            Some(b) => match parse_utc(b) {
+                Some(t) => Ok(Some(t)),
+                None => Err(anyhow!("Version {}: malformed {} '{}'", self.file, name, b)),
+            },
+            None => Ok(None),
+        };
+        let utc_start = parse_bound("utc_start", &self.utc_start)?;
+        let utc_end = parse_bound("utc_end", &self.utc_end)?;
+        if let (Some(start), Some(end)) = (utc_start, utc_end) {
+            if start >= end {
+                return Err(anyhow!(
+                    "Version {}: utc_start {} is not before utc_end {}",
+                    self.file,
+                    start,
+                    end
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the version applies at `when`. A bound can only be satisfied if the matching
+    /// observation time is known, and a bound that does not parse is never satisfied.
+    pub fn is_valid_for(&self, when: &ObservationTime) -> bool {
+        let sol_ok = match (self.sol_start, self.sol_end) {
+            (None, None) => true,
+            (start, end) => match when.sol {
+                Some(sol) => start.is_none_or(|s| sol >= s) && end.is_none_or(|e| sol < e),
+                None => false,
+            },
+        };
+
+        let utc_ok = match (&self.utc_start, &self.utc_end) {
+            (None, None) => true,
+            (start, end) => match when.utc.as_ref().and_then(|u| parse_utc(u)) {
+                Some(t) => {
+                    start
+                        .as_ref()
+                        .is_none_or(|s| parse_utc(s).is_some_and(|s| t >= s))
+                        && end
+                            .as_ref()
+                            .is_none_or(|e| parse_utc(e).is_some_and(|e| t < e))
+                }
+                None => false,
+            },
+        };
+
+        sol_ok && utc_ok
+    }
+
+    /// Sort key placing versions with later start bounds first
+    fn start_key(&self) -> (Option<u32>, Option<NaiveDateTime>) {
+        (
+            self.sol_start,
+            self.utc_start.as_ref().and_then(|s| parse_utc(s)),
+        )
+    }
+}
+
+/// Version of the given type valid at `when`. Of several valid versions the one starting
+/// latest wins (the last listed on ties).
+pub fn latest_valid_version<'a>(
+    versions: &'a [CalFileVersion],
+    cal_file_type: CalFileType,
+    when: &ObservationTime,
+) -> Option<&'a CalFileVersion> {
+    let mut best: Option<&CalFileVersion> = None;
+    for v in versions
+        .iter()
+        .filter(|v| v.file_type == cal_file_type && v.is_valid_for(when))
+    {
+        if best.is_none_or(|b| v.start_key() >= b.start_key()) {
+            best = Some(v);
+        }
+    }
+    best
+}
+
+impl InstrumentProperties {
+    /// File of the given type valid at `when`, see `latest_valid_version`. Falls back to
+    /// the unversioned file.
+    pub fn file_for(&self, cal_file_type: CalFileType, when: &ObservationTime) -> String {
+        match latest_valid_version(&self.versions, cal_file_type, when) {
+            Some(v) => {
+                vprintln!(
+                    "Using {:?} version {} for sol {:?}, utc {:?}",
+                    cal_file_type,
+                    v.file,
+                    when.sol,
+                    when.utc
+                );
+                v.file.clone()
+            }
+            None => get_calibration_file_for_type(self, cal_file_type),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -195,7 +411,7 @@ pub struct NsytCalData {
 pub fn parse_caldata_from_string(caldata_toml_str: &str) -> Result<Config> {
     match toml::from_str(caldata_toml_str) {
         Ok(c) => Ok(c),
-        Err(_) => Err(anyhow!("Failed to parse calibration manifest")),
+        Err(why) => Err(anyhow!("Failed to parse calibration manifest: {}", why)),
     }
 }
 
@@ -234,66 +450,460 @@ pub fn locate_calibration_file_no_extention(
 }
 
 pub fn locate_calibration_file(file_path: &str) -> Result<String> {
-    // If the file exists as-is, return it
-    if path::file_exists(file_path) {
-        return Ok(file_path.into());
-    }
-
-    // Some default locations
-    let mut locations = vec![
-        String::from("mars-raw-utils-data/caldata"), // Running within the repo directory (dev: cargo run --bin ...)
-        String::from("/usr/share/mars_raw_utils/data/"), // Linux, installed via apt or rpm
-    ];
-
-    if let Ok(exe_path) = std::env::current_exe() {
-        if cfg!(windows) {
-            // I'm not even a little comfortable with this...
-            // So, to figure out the installation path, we get the path to the running executable, then get the path, and then
-            // append 'data' to it to get to the calibration files. We also have to get rid of those quotation marks.
-            if let Some(filename) = exe_path.parent() {
-                locations.insert(
-                    0,
-                    format!("{:?}", filename.with_file_name("data").as_os_str()).replace('\"', ""),
+    CalDataResolver::shared().locate(file_path)
+}
+
+/// Name of the optional per-instrument override file looked for in each search location
+pub const OVERRIDES_FILE: &str = "caldata-overrides.toml";
+
+static CLI_DATA_PATH: RwLock<Option<String>> = RwLock::new(None);
+
+/// Resolver shared by calibration lookups, with the `--data-path` it was built for
+static SHARED_RESOLVER: Mutex<Option<(Option<String>, Arc<CalDataResolver>)>> = Mutex::new(None);
+
+/// Sets a calibration data directory given on the command line, searched before any other
+pub fn set_cli_data_path(path: Option<String>) {
+    *CLI_DATA_PATH.write().unwrap() = path;
+}
+
+/// Calibration data directory given on the command line, if any
+pub fn cli_data_path() -> Option<String> {
+    CLI_DATA_PATH.read().unwrap().clone()
+}
+
+/// Where a calibration data search location came from
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SearchSource {
+    /// `--data-path` command line option
+    CommandLine,
+
+    /// `MARS_RAW_DATA` environment variable
+    Environment,
+
+    /// `.marsdata` in the working directory or one of its parents
+    Project,
+
+    /// `~/.marsdata`
+    User,
+
+    /// `MARSDATAROOT` at build time
+    BuildTime,
+
+    /// Installation data directory (`/usr/share/mars_raw_utils/data`, or next to the
+    /// executable on Windows)
+    System,
+
+    /// `mars-raw-utils-data/caldata` when running from the source tree
+    Development,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchLocation {
+    pub source: SearchSource,
+    pub path: String,
+}
+
+/// A located calibration file, the location it was found in, and any lower priority copies
+/// it shadows
+#[derive(Debug, Clone)]
+pub struct Resolution {
+    pub path: String,
+    pub location: Option<SearchLocation>,
+    pub shadowed: Vec<String>,
+}
+
+/// Calibration file override for an instrument, read from a `caldata-overrides.toml`
+#[derive(Debug, Clone)]
+pub struct InstrumentOverride {
+    pub instrument: enums::Instrument,
+    pub file_type: CalFileType,
+
+    /// Unversioned file, empty if the override only has time-dependent versions
+    pub file: String,
+
+    /// Time-dependent replacements for `file`
+    pub versions: Vec<CalFileVersion>,
+
+    /// Override file the entry was read from, if any
+    pub defined_in: Option<String>,
+}
+
+impl InstrumentOverride {
+    /// File the override gives for an image taken at `when`: the version valid then, or the
+    /// unversioned file. Empty if neither applies.
+    pub fn file_at(&self, when: &ObservationTime) -> String {
+        match latest_valid_version(&self.versions, self.file_type, when) {
+            Some(v) => v.file.clone(),
+            None => self.file.clone(),
+        }
+    }
+}
+
+/// How the calibration file for an instrument was chosen
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    pub instrument: enums::Instrument,
+    pub file_type: CalFileType,
+
+    /// File name before location, from an override or the calibration mapping
+    pub base_file: String,
+    pub override_used: Option<InstrumentOverride>,
+
+    /// `caldata.toml` consulted when no override applied
+    pub mapping_file: Option<String>,
+    pub resolution: Result<Resolution, String>,
+}
+
+/// Resolves calibration files against an ordered search path. The first location holding
+/// a file wins. Per-instrument overrides replace the `caldata.toml` mapping and are read
+/// from a `caldata-overrides.toml` in any search location, higher priority locations
+/// taking precedence. Overrides may carry versions like the mapping:
+///
+/// ```toml
+/// [MCZ_LEFT]
+/// flat = "/home/me/flats/ZCAM_FLAT_L0.png"
+///
+/// [[MCZ_LEFT.versions]]
+/// type = "flat"
+/// file = "/home/me/flats/ZCAM_FLAT_L0_Sol1000.png"
+/// sol_start = 1000
+/// ```
+#[derive(Debug, Clone)]
+pub struct CalDataResolver {
+    pub locations: Vec<SearchLocation>,
+    pub overrides: Vec<InstrumentOverride>,
+}
+
+impl Default for CalDataResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CalDataResolver {
+    /// Resolver using the default search path:
+    /// command line, `MARS_RAW_DATA`, project `.marsdata`, `~/.marsdata`, `MARSDATAROOT`,
+    /// system installation, source tree.
+    pub fn new() -> Self {
+        let mut locations = vec![];
+        let mut add = |source: SearchSource, path: String| {
+            locations.push(SearchLocation { source, path });
+        };
+
+        if let Some(dir) = cli_data_path() {
+            add(SearchSource::CommandLine, dir);
+        }
+
+        if let Ok(dir) = env::var("MARS_RAW_DATA") {
+            add(SearchSource::Environment, dir);
+        }
+
+        if let Ok(cwd) = env::current_dir() {
+            if let Some(dir) = cwd
+                .ancestors()
+                .map(|d| d.join(".marsdata"))
+                .find(|d| d.is_dir())
+            {
+                add(SearchSource::Project, dir.to_str().unwrap().to_string());
+            }
+        }
+
+        if let Some(dir) = dirs::home_dir() {
+            add(
+                SearchSource::User,
+                format!("{}/.marsdata", dir.to_str().unwrap()),
+            );
+        }
+
+        // Add a path based on the location of the running executable
+        // Intended for Windows installations
+        if let Ok(exe_path) = std::env::current_exe() {
+            if cfg!(windows) {
+                add(
+                    SearchSource::System,
+                    format!("{:?}/../data/", exe_path.file_name()),
                 );
             }
         }
+
+        // Allow for a custom data path to be defined during build.
+        if let Some(v) = option_env!("MARSDATAROOT") {
+            add(SearchSource::BuildTime, String::from(v));
+        }
+
+        if let Ok(exe_path) = std::env::current_exe() {
+            if cfg!(windows) {
+                // To figure out the installation path, we get the path to the running executable, then get the path, and then
+                // append 'data' to it to get to the calibration files. We also have to get rid of those quotation marks.
+                if let Some(filename) = exe_path.parent() {
+                    add(
+                        SearchSource::System,
+                        format!("{:?}", filename.with_file_name("data").as_os_str())
+                            .replace('\"', ""),
+                    );
+                }
+            }
+        }
+
+        // Running within the repo directory (dev: cargo run --bin ...)
+        add(
+            SearchSource::Development,
+            String::from("mars-raw-utils-data/caldata"),
+        );
+
+        // Linux, installed via apt or rpm
+        add(
+            SearchSource::System,
+            String::from("/usr/share/mars_raw_utils/data/"),
+        );
+
+        CalDataResolver::with_locations(locations)
     }
 
-    // Allow for a custom data path to be defined during build.
-    if let Some(v) = option_env!("MARSDATAROOT") {
-        locations.insert(0, String::from(v));
+    /// Resolver using the default search path, built once and reused until the
+    /// `--data-path` changes
+    pub fn shared() -> Arc<CalDataResolver> {
+        let data_path = cli_data_path();
+        let mut shared = SHARED_RESOLVER.lock().unwrap();
+        if let Some((path, resolver)) = shared.as_ref() {
+            if *path == data_path {
+                return resolver.clone();
+            }
+        }
+        let resolver = Arc::new(CalDataResolver::new());
+        *shared = Some((data_path, resolver.clone()));
+        resolver
     }
 
-    // Add a path based on the location of the running executable
-    // Intended for Windows installations
-    if let Ok(exe_path) = std::env::current_exe() {
-        if cfg!(windows) {
-            let bn = format!("{:?}/../data/", exe_path.file_name());
-            locations.insert(0, bn);
+    /// Resolver searching only `locations`, in order, loading any override files in them
+    pub fn with_locations(locations: Vec<SearchLocation>) -> Self {
+        let mut resolver = CalDataResolver {
+            locations,
+            overrides: vec![],
+        };
+        let override_files: Vec<String> = resolver
+            .locations
+            .iter()
+            .map(|l| format!("{}/{}", l.path, OVERRIDES_FILE))
+            .filter(|f| path::file_exists(f))
+            .collect();
+        for f in override_files.iter() {
+            match load_overrides(f) {
+                Ok(overrides) => resolver.overrides.extend(overrides),
+                Err(why) => veprintln!("Ignoring overrides in {}: {}", f, why),
+            }
         }
+        resolver
+    }
+
+    /// Adds an override taking precedence over all others
+    pub fn add_override(
+        &mut self,
+        instrument: enums::Instrument,
+        file_type: CalFileType,
+        file: &str,
+    ) {
+        self.overrides.insert(
+            0,
+            InstrumentOverride {
+                instrument,
+                file_type,
+                file: file.to_string(),
+                versions: vec![],
+                defined_in: None,
+            },
+        );
+    }
+
+    /// Finds `file_path` as given, or relative to each search location
+    pub fn resolve(&self, file_path: &str) -> Result<Resolution> {
+        // If the file exists as-is, return it
+        if path::file_exists(file_path) {
+            return Ok(Resolution {
+                path: file_path.into(),
+                location: None,
+                shadowed: vec![],
+            });
+        }
+
+        let mut found: Vec<(String, &SearchLocation)> = self
+            .locations
+            .iter()
+            .map(|loc| (format!("{}/{}", loc.path, file_path), loc))
+            .filter(|(f, _)| path::file_exists(f))
+            .collect();
+
+        if found.is_empty() {
+            return Err(anyhow!(constants::status::FILE_NOT_FOUND));
+        }
+        let (path, location) = found.remove(0);
+        Ok(Resolution {
+            path,
+            location: Some(location.clone()),
+            shadowed: found.into_iter().map(|(f, _)| f).collect(),
+        })
+    }
+
+    /// Path of the first match for `file_path`
+    pub fn locate(&self, file_path: &str) -> Result<String> {
+        Ok(self.resolve(file_path)?.path)
+    }
+
+    /// Highest priority override for the instrument and file type
+    pub fn override_for(
+        &self,
+        instrument: enums::Instrument,
+        file_type: CalFileType,
+    ) -> Option<&InstrumentOverride> {
+        self.override_for_at(instrument, file_type, &ObservationTime::default())
     }
 
-    // Prepend a home directory if known
-    if let Some(dir) = dirs::home_dir() {
-        let homedatadir = format!("{}/.marsdata", dir.to_str().unwrap());
-        locations.insert(0, homedatadir);
+    /// Highest priority override for the instrument and file type giving a file at `when`
+    pub fn override_for_at(
+        &self,
+        instrument: enums::Instrument,
+        file_type: CalFileType,
+        when: &ObservationTime,
+    ) -> Option<&InstrumentOverride> {
+        self.overrides.iter().find(|o| {
+            o.instrument == instrument && o.file_type == file_type && !o.file_at(when).is_empty()
+        })
+    }
+
+    /// Describes how the calibration file for an instrument is chosen
+    pub fn explain(&self, instrument: enums::Instrument, file_type: CalFileType) -> Explanation {
+        self.explain_at(instrument, file_type, &ObservationTime::default())
+    }
+
+    /// Describes how the calibration file for an instrument is chosen for an image taken at
+    /// `when`
+    pub fn explain_at(
+        &self,
+        instrument: enums::Instrument,
+        file_type: CalFileType,
+        when: &ObservationTime,
+    ) -> Explanation {
+        let override_used = self.override_for_at(instrument, file_type, when).cloned();
+        let mapping_file = self.locate("caldata.toml").ok();
+
+        let base_file = match &override_used {
+            Some(o) => Ok(o.file_at(when)),
+            None => match &mapping_file {
+                Some(_) => get_calibration_base_file_for_instrument_at(instrument, file_type, when)
+                    .map_err(|e| e.to_string()),
+                None => Err(String::from(
+                    "Unable to locate calibration configuration file",
+                )),
+            },
+        };
+
+        let (base_file, resolution) = match base_file {
+            Ok(f) if f.is_empty() => (
+                f,
+                Err(String::from(constants::status::UNSUPPORTED_INSTRUMENT)),
+            ),
+            Ok(f) => {
+                let r = self.resolve(&f).map_err(|e| e.to_string());
+                (f, r)
+            }
+            Err(why) => (String::new(), Err(why)),
+        };
+
+        Explanation {
+            instrument,
+            file_type,
+            base_file,
+            override_used,
+            mapping_file,
+            resolution,
+        }
     }
 
-    // Prepend a location specified by environment variable
-    if let Ok(dir) = env::var("MARS_RAW_DATA") {
-        locations.insert(0, dir);
+    /// Path of the calibration file for an instrument
+    pub fn locate_for_instrument(
+        &self,
+        instrument: enums::Instrument,
+        file_type: CalFileType,
+    ) -> Result<String> {
+        self.locate_for_instrument_at(instrument, file_type, &ObservationTime::default())
     }
 
-    // First match wins
-    for loc in locations.iter() {
-        let full_file_path = format!("{}/{}", loc, file_path);
-        if path::file_exists(&full_file_path) {
-            return Ok(full_file_path);
+    /// Path of the calibration file for an instrument valid at `when`
+    pub fn locate_for_instrument_at(
+        &self,
+        instrument: enums::Instrument,
+        file_type: CalFileType,
+        when: &ObservationTime,
+    ) -> Result<String> {
+        let base_file = match self.override_for_at(instrument, file_type, when) {
+            Some(o) => {
+                let file = o.file_at(when);
+                vprintln!(
+                    "Using override {} for {:?} {:?}",
+                    file,
+                    instrument,
+                    file_type
+                );
+                file
+            }
+            None => get_calibration_base_file_for_instrument_at(instrument, file_type, when)?,
+        };
+        match base_file.len() {
+            0 => Err(anyhow!(constants::status::UNSUPPORTED_INSTRUMENT)),
+            _ => self.locate(&base_file),
         }
     }
 
-    // Oh nos!
-    Err(anyhow!(constants::status::FILE_NOT_FOUND))
+    /// Writable location for downloaded calibration data: the command line path,
+    /// `MARS_RAW_DATA`, or `~/.marsdata`
+    pub fn local_store(&self) -> Option<String> {
+        self.locations
+            .iter()
+            .find(|l| {
+                matches!(
+                    l.source,
+                    SearchSource::CommandLine | SearchSource::Environment | SearchSource::User
+                )
+            })
+            .map(|l| l.path.clone())
+    }
+}
+
+/// Reads per-instrument overrides, keyed by instrument name as used in image metadata. An
+/// instrument may list versions as in `caldata.toml`.
+pub fn load_overrides(file_path: &str) -> Result<Vec<InstrumentOverride>> {
+    let text = std::fs::read_to_string(file_path)?;
+    let table: HashMap<String, InstrumentProperties> = match toml::from_str(&text) {
+        Ok(t) => t,
+        Err(why) => return Err(anyhow!("Failed to parse overrides: {}", why)),
+    };
+
+    let mut overrides = vec![];
+    for (name, props) in table.into_iter() {
+        let instrument = enums::Instrument::from_str(&name).unwrap();
+        if instrument == enums::Instrument::None {
+            return Err(anyhow!("Unknown instrument: {}", name));
+        }
+        let versions = props.versions.clone();
+        for entry in props.into_iter() {
+            let entry_versions: Vec<CalFileVersion> = versions
+                .iter()
+                .filter(|v| v.file_type == entry.file_type)
+                .cloned()
+                .collect();
+            if entry.file.is_empty() && entry_versions.is_empty() {
+                continue;
+            }
+            overrides.push(InstrumentOverride {
+                instrument,
+                file_type: entry.file_type,
+                file: entry.file,
+                versions: entry_versions,
+                defined_in: Some(file_path.to_string()),
+            });
+        }
+    }
+    Ok(overrides)
 }
 
 pub fn get_calibration_file_for_type(
@@ -308,133 +918,73 @@ pub fn get_calibration_file_for_type(
     }
 }
 
+/// Name of the `caldata.toml` instrument field holding files of `cal_file_type`
+pub fn caldata_key(cal_file_type: enums::CalFileType) -> &'static str {
+    match cal_file_type {
+        enums::CalFileType::FlatField => "flat",
+        enums::CalFileType::InpaintMask => "inpaint_mask",
+        enums::CalFileType::Mask => "mask",
+        enums::CalFileType::Lut => "lut",
+    }
+}
+
 pub fn get_calibration_base_file_for_instrument(
     instrument: enums::Instrument,
     cal_file_type: enums::CalFileType,
 ) -> Result<String> {
-    let config = load_caldata_mapping_file().unwrap();
+    get_calibration_base_file_for_instrument_at(
+        instrument,
+        cal_file_type,
+        &ObservationTime::default(),
+    )
+}
 
+/// Calibration file name for an instrument, choosing the version valid at `when`
+pub fn get_calibration_base_file_for_instrument_at(
+    instrument: enums::Instrument,
+    cal_file_type: enums::CalFileType,
+    when: &ObservationTime,
+) -> Result<String> {
+    let config = load_caldata_mapping_file()?;
+    Ok(get_instrument_properties(&config, instrument)?.file_for(cal_file_type, when))
+}
+
+/// Calibration mapping entry for an instrument
+pub fn get_instrument_properties(
+    config: &Config,
+    instrument: enums::Instrument,
+) -> Result<&InstrumentProperties> {
     match instrument {
-        enums::Instrument::MslMAHLI => Ok(get_calibration_file_for_type(
-            &config.msl.mahli,
-            cal_file_type,
-        )),
-        enums::Instrument::MslMastcamLeft => Ok(get_calibration_file_for_type(
-            &config.msl.mastcam_left,
-            cal_file_type,
-        )),
-        enums::Instrument::MslMastcamRight => Ok(get_calibration_file_for_type(
-            &config.msl.mastcam_right,
-            cal_file_type,
-        )),
-        enums::Instrument::MslNavCamRight => Ok(get_calibration_file_for_type(
-            &config.msl.nav_right,
-            cal_file_type,
-        )), // Limiting to RCE-B camera for ECAM. For now.
-        enums::Instrument::MslNavCamLeft => Ok(get_calibration_file_for_type(
-            &config.msl.nav_left,
-            cal_file_type,
-        )),
-        enums::Instrument::MslFrontHazLeft => Ok(get_calibration_file_for_type(
-            &config.msl.fhaz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::MslFrontHazRight => Ok(get_calibration_file_for_type(
-            &config.msl.fhaz_right,
-            cal_file_type,
-        )),
-        enums::Instrument::MslRearHazLeft => Ok(get_calibration_file_for_type(
-            &config.msl.rhaz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::MslRearHazRight => Ok(get_calibration_file_for_type(
-            &config.msl.rhaz_right,
-            cal_file_type,
-        )),
-        enums::Instrument::MslMARDI => Ok(get_calibration_file_for_type(
-            &config.msl.mardi,
-            cal_file_type,
-        )),
-        enums::Instrument::MslChemCam => Ok(get_calibration_file_for_type(
-            &config.msl.chemcam,
-            cal_file_type,
-        )),
-        enums::Instrument::M20MastcamZLeft => Ok(get_calibration_file_for_type(
-            &config.m20.mastcamz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::M20MastcamZRight => Ok(get_calibration_file_for_type(
-            &config.m20.mastcamz_right,
-            cal_file_type,
-        )),
-        enums::Instrument::M20NavcamLeft => Ok(get_calibration_file_for_type(
-            &config.m20.nav_left,
-            cal_file_type,
-        )),
-        enums::Instrument::M20NavcamRight => Ok(get_calibration_file_for_type(
-            &config.m20.nav_right,
-            cal_file_type,
-        )),
-        enums::Instrument::M20FrontHazLeft => Ok(get_calibration_file_for_type(
-            &config.m20.fhaz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::M20FrontHazRight => Ok(get_calibration_file_for_type(
-            &config.m20.fhaz_right,
-            cal_file_type,
-        )),
-        enums::Instrument::M20RearHazLeft => Ok(get_calibration_file_for_type(
-            &config.m20.rhaz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::M20RearHazRight => Ok(get_calibration_file_for_type(
-            &config.m20.rhaz_left,
-            cal_file_type,
-        )),
-        enums::Instrument::M20Watson => Ok(get_calibration_file_for_type(
-            &config.m20.watson,
-            cal_file_type,
-        )),
-        enums::Instrument::M20SuperCam => Ok(get_calibration_file_for_type(
-            &config.m20.supercam_rmi,
-            cal_file_type,
-        )),
-        enums::Instrument::M20HeliNav => Ok(get_calibration_file_for_type(
-            &config.m20.heli_nav,
-            cal_file_type,
-        )),
-        enums::Instrument::M20HeliRte => Ok(get_calibration_file_for_type(
-            &config.m20.heli_rte,
-            cal_file_type,
-        )),
-        enums::Instrument::M20Pixl => Ok(get_calibration_file_for_type(
-            &config.m20.pixl_mcc,
-            cal_file_type,
-        )),
-        enums::Instrument::M20SkyCam => Ok(get_calibration_file_for_type(
-            &config.m20.skycam,
-            cal_file_type,
-        )),
-        enums::Instrument::M20SherlocAci => Ok(get_calibration_file_for_type(
-            &config.m20.sherloc_aci,
-            cal_file_type,
-        )),
-        enums::Instrument::M20CacheCam => Ok(get_calibration_file_for_type(
-            &config.m20.cachecam,
-            cal_file_type,
-        )),
-        enums::Instrument::M20EdlRdcam => Ok(get_calibration_file_for_type(
-            &config.m20.edl_rdcam,
-            cal_file_type,
-        )),
-        enums::Instrument::NsytICC => Ok(get_calibration_file_for_type(
-            &config.nsyt.icc,
-            cal_file_type,
-        )),
-        enums::Instrument::NsytIDC => Ok(get_calibration_file_for_type(
-            &config.nsyt.idc,
-            cal_file_type,
-        )),
+        enums::Instrument::MslMAHLI => Ok(&config.msl.mahli),
+        enums::Instrument::MslMastcamLeft => Ok(&config.msl.mastcam_left),
+        enums::Instrument::MslMastcamRight => Ok(&config.msl.mastcam_right),
+        enums::Instrument::MslNavCamRight => Ok(&config.msl.nav_right), // Limiting to RCE-B camera for ECAM. For now.
+        enums::Instrument::MslNavCamLeft => Ok(&config.msl.nav_left),
+        enums::Instrument::MslFrontHazLeft => Ok(&config.msl.fhaz_left),
+        enums::Instrument::MslFrontHazRight => Ok(&config.msl.fhaz_right),
+        enums::Instrument::MslRearHazLeft => Ok(&config.msl.rhaz_left),
+        enums::Instrument::MslRearHazRight => Ok(&config.msl.rhaz_right),
+        enums::Instrument::MslMARDI => Ok(&config.msl.mardi),
+        enums::Instrument::MslChemCam => Ok(&config.msl.chemcam),
+        enums::Instrument::M20MastcamZLeft => Ok(&config.m20.mastcamz_left),
+        enums::Instrument::M20MastcamZRight => Ok(&config.m20.mastcamz_right),
+        enums::Instrument::M20NavcamLeft => Ok(&config.m20.nav_left),
+        enums::Instrument::M20NavcamRight => Ok(&config.m20.nav_right),
+        enums::Instrument::M20FrontHazLeft => Ok(&config.m20.fhaz_left),
+        enums::Instrument::M20FrontHazRight => Ok(&config.m20.fhaz_right),
+        enums::Instrument::M20RearHazLeft => Ok(&config.m20.rhaz_left),
+        enums::Instrument::M20RearHazRight => Ok(&config.m20.rhaz_left),
+        enums::Instrument::M20Watson => Ok(&config.m20.watson),
+        enums::Instrument::M20SuperCam => Ok(&config.m20.supercam_rmi),
+        enums::Instrument::M20HeliNav => Ok(&config.m20.heli_nav),
+        enums::Instrument::M20HeliRte => Ok(&config.m20.heli_rte),
+        enums::Instrument::M20Pixl => Ok(&config.m20.pixl_mcc),
+        enums::Instrument::M20SkyCam => Ok(&config.m20.skycam),
+        enums::Instrument::M20SherlocAci => Ok(&config.m20.sherloc_aci),
+        enums::Instrument::M20CacheCam => Ok(&config.m20.cachecam),
+        enums::Instrument::M20EdlRdcam => Ok(&config.m20.edl_rdcam),
+        enums::Instrument::NsytICC => Ok(&config.nsyt.icc),
+        enums::Instrument::NsytIDC => Ok(&config.nsyt.idc),
         enums::Instrument::None => Err(anyhow!(constants::status::UNSUPPORTED_INSTRUMENT)),
     }
 }
@@ -443,11 +993,14 @@ pub fn get_calibration_file_for_instrument(
     instrument: enums::Instrument,
     cal_file_type: enums::CalFileType,
 ) -> Result<String> {
-    match get_calibration_base_file_for_instrument(instrument, cal_file_type) {
-        Ok(file_name) => match file_name.len() {
-            0 => Err(anyhow!(constants::status::UNSUPPORTED_INSTRUMENT)),
-            _ => locate_calibration_file(&file_name),
-        },
-        Err(e) => Err(e),
-    }
+    CalDataResolver::shared().locate_for_instrument(instrument, cal_file_type)
+}
+
+/// Locates the calibration file for an instrument valid at the time an image was taken
+pub fn get_calibration_file_for_instrument_at(
+    instrument: enums::Instrument,
+    cal_file_type: enums::CalFileType,
+    when: &ObservationTime,
+) -> Result<String> {
+    CalDataResolver::shared().locate_for_instrument_at(instrument, cal_file_type, when)
 }
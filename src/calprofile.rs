@@ -1,3 +1,4 @@
+use crate::colorcorrect::CalTarget;
 use crate::{calibfile, constants, veprintln, vprintln};
 
 use sciimg::prelude::*;
@@ -52,6 +53,15 @@ pub struct CalProfile {
 
     #[serde(default = "default_debayer_method")]
     pub debayer_method: DebayerMethod,
+
+    /// Calibration target patches used by `mru color-correct`
+    #[serde(default)]
+    pub cal_target: Option<CalTarget>,
+
+    /// Decompanding LUT replacing the instrument default: a built-in table name (ILT,
+    /// NSYT_ILT, LUT2) or a LUT file path
+    #[serde(default)]
+    pub lut: Option<String>,
 }
 
 impl Default for CalProfile {
@@ -72,6 +82,8 @@ impl Default for CalProfile {
             instrument: None,
             description: None,
             debayer_method: default_debayer_method(),
+            cal_target: None,
+            lut: None,
         }
     }
 }
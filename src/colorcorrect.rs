@@ -0,0 +1,253 @@
+use crate::{util::max_value_for_mode, vprintln};
+
+use sciimg::{image::Image, path};
+
+use anyhow::anyhow;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+
+/// A calibration target patch in the designated target image, with its known reflectance
+/// in the red, green and blue bands
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CalTargetPatch {
+    pub name: String,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub reflectance: [f32; 3],
+}
+
+/// Calibration target layout, declared as `[cal_target]` in a calibration profile
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct CalTarget {
+    #[serde(default)]
+    pub patches: Vec<CalTargetPatch>,
+
+    /// Derive a full 3x3 color matrix in addition to the per-band scale factors. Needs at
+    /// least three patches of differing color.
+    #[serde(default)]
+    pub color_matrix: bool,
+}
+
+/// Mean red, green and blue values measured over a patch
+pub type PatchMeasurement = [f32; 3];
+
+/// Correction derived from a calibration target. Scale factors are normalized to the green
+/// band so corrected images stay in the DN range of their inputs.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ColorCorrection {
+    pub red_scalar: f32,
+    pub green_scalar: f32,
+    pub blue_scalar: f32,
+
+    /// Row major color matrix, applied instead of the scale factors when present
+    pub matrix: Option<[[f32; 3]; 3]>,
+}
+
+impl Default for ColorCorrection {
+    fn default() -> Self {
+        ColorCorrection {
+            red_scalar: 1.0,
+            green_scalar: 1.0,
+            blue_scalar: 1.0,
+            matrix: None,
+        }
+    }
+}
+
+/// Mean value of each band within every patch of `target`
+pub fn measure_patches(image: &Image, target: &CalTarget) -> Result<Vec<PatchMeasurement>> {
+    if image.num_bands() < 3 {
+        return Err(anyhow!(
+            "Calibration target image must have three color bands"
+        ));
+    }
+    target
+        .patches
+        .iter()
+        .map(|p| {
+            if p.width == 0
+                || p.height == 0
+                || p.x + p.width > image.width
+                || p.y + p.height > image.height
+            {
+                return Err(anyhow!(
+                    "Patch {} lies outside of the {}x{} target image",
+                    p.name,
+                    image.width,
+                    image.height
+                ));
+            }
+            let mut m = [0.0_f32; 3];
+            for (b, v) in m.iter_mut().enumerate() {
+                let band = image.get_band(b);
+                let mut sum = 0.0_f64;
+                for y in p.y..(p.y + p.height) {
+                    for x in p.x..(p.x + p.width) {
+                        sum += band.get(x, y) as f64;
+                    }
+                }
+                *v = (sum / (p.width * p.height) as f64) as f32;
+            }
+            vprintln!("Patch {}: measured {:?}", p.name, m);
+            Ok(m)
+        })
+        .collect()
+}
+
+/// Determinant of a 3x3 matrix
+fn det3(m: &[[f64; 3]; 3]) -> f64 {
+    m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+}
+
+/// Solves `a x = b` by Cramer's rule
+fn solve3(a: &[[f64; 3]; 3], b: &[f64; 3]) -> Option<[f64; 3]> {
+    let d = det3(a);
+    let scale = a.iter().flatten().fold(0.0_f64, |acc, v| acc.max(v.abs()));
+    if scale == 0.0 || d.abs() <= 1e-12 * scale.powi(3) {
+        return None;
+    }
+    let mut x = [0.0; 3];
+    for (c, xc) in x.iter_mut().enumerate() {
+        let mut m = *a;
+        for (row, v) in m.iter_mut().zip(b.iter()) {
+            row[c] = *v;
+        }
+        *xc = det3(&m) / d;
+    }
+    Some(x)
+}
+
+impl ColorCorrection {
+    /// Derives the correction mapping `measured` patch values onto the known reflectances
+    /// of `target`. Each band's scale factor is the least squares fit through the origin
+    /// over all patches. The optional matrix is the least squares fit of each output band
+    /// against all three measured bands.
+    pub fn derive(target: &CalTarget, measured: &[PatchMeasurement]) -> Result<Self> {
+        if target.patches.is_empty() {
+            return Err(anyhow!("Calibration target has no patches"));
+        }
+        if target.patches.len() != measured.len() {
+            return Err(anyhow!(
+                "Number of measurements does not match the number of patches"
+            ));
+        }
+
+        let mut scalars = [0.0_f64; 3];
+        for (b, s) in scalars.iter_mut().enumerate() {
+            let (mut num, mut den) = (0.0_f64, 0.0_f64);
+            for (p, m) in target.patches.iter().zip(measured.iter()) {
+                num += p.reflectance[b] as f64 * m[b] as f64;
+                den += m[b] as f64 * m[b] as f64;
+            }
+            if den <= 0.0 || num <= 0.0 {
+                return Err(anyhow!("Calibration target band {} has no signal", b));
+            }
+            *s = num / den;
+        }
+        let norm = scalars[1];
+
+        let matrix = if target.color_matrix {
+            if target.patches.len() < 3 {
+                return Err(anyhow!(
+                    "A color matrix needs at least three calibration target patches"
+                ));
+            }
+            let mut ata = [[0.0_f64; 3]; 3];
+            for m in measured.iter() {
+                for (row, mr) in ata.iter_mut().zip(m.iter()) {
+                    for (v, mc) in row.iter_mut().zip(m.iter()) {
+                        *v += *mr as f64 * *mc as f64;
+                    }
+                }
+            }
+            let mut matrix = [[0.0_f32; 3]; 3];
+            for (out_band, row) in matrix.iter_mut().enumerate() {
+                let mut atb = [0.0_f64; 3];
+                for (p, m) in target.patches.iter().zip(measured.iter()) {
+                    for (c, v) in atb.iter_mut().enumerate() {
+                        *v += m[c] as f64 * p.reflectance[out_band] as f64;
+                    }
+                }
+                let x = solve3(&ata, &atb).ok_or_else(|| {
+                    anyhow!("Calibration target patches are too similar in color to fit a matrix")
+                })?;
+                for (v, xc) in row.iter_mut().zip(x.iter()) {
+                    *v = (xc / norm) as f32;
+                }
+            }
+            Some(matrix)
+        } else {
+            None
+        };
+
+        Ok(ColorCorrection {
+            red_scalar: (scalars[0] / norm) as f32,
+            green_scalar: 1.0,
+            blue_scalar: (scalars[2] / norm) as f32,
+            matrix,
+        })
+    }
+
+    /// Measures `target` in `image` and derives the correction from it
+    pub fn from_target_image(image: &Image, target: &CalTarget) -> Result<Self> {
+        let measured = measure_patches(image, target)?;
+        ColorCorrection::derive(target, &measured)
+    }
+
+    /// Corrected red, green and blue values of a pixel
+    pub fn correct(&self, rgb: [f32; 3]) -> [f32; 3] {
+        match &self.matrix {
+            Some(m) => {
+                let mut out = [0.0; 3];
+                for (r, v) in out.iter_mut().enumerate() {
+                    *v = m[r][0] * rgb[0] + m[r][1] * rgb[1] + m[r][2] * rgb[2];
+                }
+                out
+            }
+            None => [
+                rgb[0] * self.red_scalar,
+                rgb[1] * self.green_scalar,
+                rgb[2] * self.blue_scalar,
+            ],
+        }
+    }
+
+    /// Applies the correction in place, clipping to the range of the image mode
+    pub fn apply(&self, image: &mut Image) -> Result<()> {
+        if image.num_bands() < 3 {
+            return Err(anyhow!("Color correction needs a three band image"));
+        }
+        let max = max_value_for_mode(image.get_mode());
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let rgb = [
+                    image.get_band(0).get(x, y),
+                    image.get_band(1).get(x, y),
+                    image.get_band(2).get(x, y),
+                ];
+                for (b, v) in self.correct(rgb).iter().enumerate() {
+                    image.put(x, y, v.clamp(0.0, max), b);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, file_path: &str) -> Result<()> {
+        fs::write(file_path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    pub fn load(file_path: &str) -> Result<Self> {
+        if !path::file_exists(file_path) {
+            return Err(anyhow!("File not found: {}", file_path));
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(file_path)?)?)
+    }
+}
@@ -1,10 +1,12 @@
 use crate::calibfile;
+use crate::calibfile::ObservationTime;
+use crate::calprofile::CalProfile;
 use crate::enums;
 use crate::memcache;
 use crate::veprintln;
 use crate::vprintln;
 use regex::Regex;
-use sciimg::path;
+use sciimg::{image::Image, path};
 use std::convert::TryInto;
 
 use anyhow::anyhow;
@@ -68,22 +70,55 @@ lazy_static! {
     static ref LUT_SPEC_PAIR: Regex = Regex::new(r"([0-9]+) ([0-9]+)").unwrap();
 }
 
+/// Largest linear value a decompanding LUT may map to (12 bit)
+pub const MAX_LUT_VALUE: u32 = 4095;
+
+/// Fraction of (nonzero) pixels that must fall on a LUT's values for histogram detection
+/// to accept it
+pub const MIN_DETECTION_SCORE: f32 = 0.98;
+
 #[derive(Debug, Clone)]
 pub struct LookUpTable {
     pub lut: Vec<u32>,
+
+    /// Built-in table name or the file the table was loaded from
+    pub name: String,
 }
 
 impl LookUpTable {
     pub fn new(lut: &[u32; 256]) -> LookUpTable {
-        LookUpTable { lut: lut.to_vec() }
+        LookUpTable {
+            lut: lut.to_vec(),
+            name: "custom".to_string(),
+        }
     }
+
     pub fn new_from_vec(lut: &Vec<u32>) -> Result<LookUpTable> {
         if lut.len() != 256 {
             Err(anyhow!("Invalid LUT specification length"))
         } else {
-            Ok(LookUpTable { lut: lut.clone() })
+            Ok(LookUpTable {
+                lut: lut.clone(),
+                name: "custom".to_string(),
+            })
+        }
+    }
+
+    pub fn with_name(mut self, name: &str) -> LookUpTable {
+        self.name = name.to_string();
+        self
+    }
+
+    /// Built-in table by name: `ILT` (default), `NSYT_ILT` or `LUT2`
+    pub fn builtin(name: &str) -> Option<LookUpTable> {
+        match name.to_uppercase().as_str() {
+            "ILT" => Some(LookUpTable::new(&ILT).with_name("ILT")),
+            "NSYT_ILT" => Some(LookUpTable::new(&NSYT_ILT).with_name("NSYT_ILT")),
+            "LUT2" => Some(LookUpTable::new(&LUT2).with_name("LUT2")),
+            _ => None,
         }
     }
+
     pub fn max(&self) -> u32 {
         self.lut[255]
     }
@@ -94,20 +129,249 @@ impl LookUpTable {
             .try_into()
             .unwrap_or_else(|_: Vec<u32>| panic!("LUT array is of invalid length"))
     }
+
+    /// Checks the table has 256 entries, never decreases and stays within 12 bits
+    pub fn validate(&self) -> Result<()> {
+        if self.lut.len() != 256 {
+            return Err(anyhow!(
+                "LUT {} has {} entries, expected 256",
+                self.name,
+                self.lut.len()
+            ));
+        }
+        if let Some(i) = (1..self.lut.len()).find(|i| self.lut[*i] < self.lut[i - 1]) {
+            return Err(anyhow!(
+                "LUT {} is not monotonic: entry {} ({}) is less than entry {} ({})",
+                self.name,
+                i,
+                self.lut[i],
+                i - 1,
+                self.lut[i - 1]
+            ));
+        }
+        if self.max() > MAX_LUT_VALUE {
+            return Err(anyhow!(
+                "LUT {} maximum {} exceeds {}",
+                self.name,
+                self.max(),
+                MAX_LUT_VALUE
+            ));
+        }
+        Ok(())
+    }
+
+    /// Generates the companding table, mapping each linear value up to the LUT maximum to
+    /// the code whose entry is nearest. Ties and repeated entries go to the lowest code.
+    pub fn inverse(&self) -> InverseLookUpTable {
+        let mut table = Vec::with_capacity(self.max() as usize + 1);
+        let mut code = 0;
+        for v in 0..=self.max() as i64 {
+            loop {
+                // First code of the next distinct entry
+                let mut next = code + 1;
+                while next < self.lut.len() && self.lut[next] == self.lut[code] {
+                    next += 1;
+                }
+                if next < self.lut.len()
+                    && (self.lut[next] as i64 - v).abs() < (self.lut[code] as i64 - v).abs()
+                {
+                    code = next;
+                } else {
+                    break;
+                }
+            }
+            table.push(code as u8);
+        }
+        InverseLookUpTable { table }
+    }
+
+    /// Codes the onboard compander can emit with this table, indexed by code. Codes whose
+    /// entry repeats an earlier one are never produced.
+    pub fn output_codes(&self) -> Vec<bool> {
+        let mut codes = vec![false; self.lut.len()];
+        for c in self.inverse().table.iter() {
+            codes[*c as usize] = true;
+        }
+        codes
+    }
+}
+
+/// Linear value to 8 bit code table, generated from a `LookUpTable`
+#[derive(Debug, Clone)]
+pub struct InverseLookUpTable {
+    pub table: Vec<u8>,
+}
+
+impl InverseLookUpTable {
+    /// Code for a linear value, clamped to the table range
+    pub fn compand(&self, value: f32) -> u8 {
+        let i = (value.round().max(0.0) as usize).min(self.table.len() - 1);
+        self.table[i]
+    }
+}
+
+/// Result of identifying a LUT from an image histogram
+#[derive(Debug, Clone)]
+pub struct LutMatch {
+    pub lut: LookUpTable,
+
+    /// Fraction of nonzero pixels whose value is a code the LUT's compander emits
+    pub score: f32,
+}
+
+/// Histogram of all bands of `image`, with one bin per integer value
+pub fn value_histogram(image: &Image) -> Vec<u32> {
+    let mut hist = vec![0_u32; 65536];
+    for b in 0..image.num_bands() {
+        let band = image.get_band(b);
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let v = band.get(x, y).round().clamp(0.0, 65535.0) as usize;
+                hist[v] += 1;
+            }
+        }
+    }
+    hist
+}
+
+/// Identifies which of `candidates` a companded (raw 8 bit) image was companded with
+/// onboard. Such an image only holds codes its compander emits, so each candidate is scored
+/// by the fraction of nonzero pixels on its output codes; values above 255 are never on
+/// them. Returns the best scoring candidate at or above `MIN_DETECTION_SCORE`, preferring
+/// earlier candidates on equal scores, as tables sharing the codes present can't be told
+/// apart.
+pub fn detect_lut(histogram: &[u32], candidates: &[LookUpTable]) -> Option<LutMatch> {
+    let total: u64 = histogram.iter().skip(1).map(|c| *c as u64).sum();
+    if total == 0 {
+        return None;
+    }
+
+    let mut best: Option<LutMatch> = None;
+    for lut in candidates.iter() {
+        let on_lut: u64 = lut
+            .output_codes()
+            .iter()
+            .enumerate()
+            .skip(1)
+            .filter(|(c, emitted)| **emitted && *c < histogram.len())
+            .map(|(c, _)| histogram[c] as u64)
+            .sum();
+        let score = on_lut as f32 / total as f32;
+        vprintln!("LUT {} histogram score: {}", lut.name, score);
+        if score >= MIN_DETECTION_SCORE && best.as_ref().is_none_or(|b| score > b.score) {
+            best = Some(LutMatch {
+                lut: lut.clone(),
+                score,
+            });
+        }
+    }
+    best
+}
+
+/// Chooses between the `configured` LUT and `candidates` for a companded image with
+/// `histogram`. `configured` is kept whenever it scores at least `MIN_DETECTION_SCORE`:
+/// tables emitting more codes (LUT2 emits all of them) fit any image it fits, and noise
+/// puts a few pixels on codes it never emits. Another candidate is only chosen when
+/// too many pixels sit on such codes and that candidate fits.
+pub fn select_lut(
+    histogram: &[u32],
+    configured: &LookUpTable,
+    candidates: &[LookUpTable],
+) -> LookUpTable {
+    if detect_lut(histogram, std::slice::from_ref(configured)).is_some() {
+        return configured.clone();
+    }
+    let others: Vec<LookUpTable> = candidates
+        .iter()
+        .filter(|l| l.lut != configured.lut)
+        .cloned()
+        .collect();
+    match detect_lut(histogram, &others) {
+        Some(m) => {
+            vprintln!(
+                "Detected LUT {} in place of {} ({:.1}% of pixels on its codes)",
+                m.lut.name,
+                configured.name,
+                m.score * 100.0
+            );
+            m.lut
+        }
+        None => configured.clone(),
+    }
+}
+
+/// The LUT to decompand a companded image with: the profile's LUT if it names one,
+/// otherwise the instrument's LUT valid at `when` unless the image's codes rule it out and
+/// another built-in table fits (see `select_lut`).
+pub fn get_ilt_for_image(
+    profile: &CalProfile,
+    instrument: enums::Instrument,
+    image: &Image,
+    when: &ObservationTime,
+) -> Result<LookUpTable> {
+    if profile.lut.is_some() {
+        return get_ilt_for_profile(profile, instrument, when);
+    }
+    let configured = get_ilt_for_instrument_at(instrument, when)?;
+    Ok(select_lut(
+        &value_histogram(image),
+        &configured,
+        &builtin_luts(),
+    ))
+}
+
+/// The built-in tables, default first
+pub fn builtin_luts() -> Vec<LookUpTable> {
+    ["ILT", "NSYT_ILT", "LUT2"]
+        .iter()
+        .filter_map(|n| LookUpTable::builtin(n))
+        .collect()
 }
 
 pub fn get_ilt_for_instrument(instrument: enums::Instrument) -> Result<LookUpTable> {
-    let lut_file_path =
-        calibfile::get_calibration_file_for_instrument(instrument, enums::CalFileType::Lut)
-            .unwrap_or("".to_string());
+    get_ilt_for_instrument_at(instrument, &ObservationTime::default())
+}
+
+/// Loads the LUT valid for an image taken at `when`
+pub fn get_ilt_for_instrument_at(
+    instrument: enums::Instrument,
+    when: &ObservationTime,
+) -> Result<LookUpTable> {
+    let lut_file_path = calibfile::get_calibration_file_for_instrument_at(
+        instrument,
+        enums::CalFileType::Lut,
+        when,
+    )
+    .unwrap_or("".to_string());
 
     if lut_file_path.is_empty() {
-        Ok(LookUpTable::new(&ILT))
+        Ok(LookUpTable::builtin("ILT").unwrap())
     } else {
         load_ilut_spec_file(&lut_file_path)
     }
 }
 
+/// Loads the LUT named by a profile or the command line: a built-in table name or a LUT
+/// file, looked for in the calibration data search path if not found as given
+pub fn load_lut(spec: &str) -> Result<LookUpTable> {
+    if let Some(lut) = LookUpTable::builtin(spec) {
+        return Ok(lut);
+    }
+    load_ilut_spec_file(&calibfile::locate_calibration_file(spec)?)
+}
+
+/// The profile's LUT if it names one, otherwise the instrument's LUT valid at `when`
+pub fn get_ilt_for_profile(
+    profile: &CalProfile,
+    instrument: enums::Instrument,
+    when: &ObservationTime,
+) -> Result<LookUpTable> {
+    match &profile.lut {
+        Some(spec) => load_lut(spec),
+        None => get_ilt_for_instrument_at(instrument, when),
+    }
+}
+
 pub fn load_ilut_spec_file(file_path: &String) -> Result<LookUpTable> {
     vprintln!("Loading LUT file: {}", file_path);
 
@@ -117,8 +381,7 @@ pub fn load_ilut_spec_file(file_path: &String) -> Result<LookUpTable> {
     }
 
     let mut lut_vec: Vec<u32> = vec![];
-    memcache::load_text_file(file_path)
-        .unwrap()
+    memcache::load_text_file(file_path)?
         .split('\n')
         .for_each(|line| {
             // This regex capture will validate if the line is in the format "<number><space><number>"
@@ -128,5 +391,7 @@ pub fn load_ilut_spec_file(file_path: &String) -> Result<LookUpTable> {
                 lut_vec.push(s_lut_value);
             }
         });
-    LookUpTable::new_from_vec(&lut_vec)
+    let lut = LookUpTable::new_from_vec(&lut_vec)?.with_name(file_path);
+    lut.validate()?;
+    Ok(lut)
 }
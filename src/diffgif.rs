@@ -2,11 +2,13 @@
 
 use crate::vprintln;
 
-use sciimg::{enums::ImageMode, image, imagebuffer, lowpass, path};
+use crate::framesink::{self, FrameSink, OutputFormat};
+use crate::registration::{self, RegistrationMethod};
+use crate::stacking::{self, StackMethod, StackOptions};
 
-use gif;
+use sciimg::{enums::ImageMode, image, imagebuffer, lowpass};
 
-use std::fs::File;
+use anyhow::Result;
 use std::str::FromStr;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -38,69 +40,15 @@ impl ProductType {
     }
 }
 
-fn imagebuffer_to_vec_v8(
-    buff_0: &imagebuffer::ImageBuffer,
-    buff_1: &imagebuffer::ImageBuffer,
-    buff_2: &imagebuffer::ImageBuffer,
-) -> Vec<u8> {
-    let mut f: Vec<u8> = vec![0; buff_0.width * buff_0.height * 3];
-    for y in 0..buff_0.height {
-        for x in 0..buff_0.width {
-            let idx = (y * buff_0.width + x) * 3;
-            f[idx] = buff_0.get(x, y).round() as u8;
-            f[idx + 1] = buff_1.get(x, y).round() as u8;
-            f[idx + 2] = buff_2.get(x, y).round() as u8;
-        }
-    }
-
-    f
-}
-
-fn rgbimage_to_vec_v8(img3band: &image::Image) -> Vec<u8> {
-    let b0 = img3band.get_band(0);
-    let b1 = img3band.get_band(1);
-    let b2 = img3band.get_band(2);
-    imagebuffer_to_vec_v8(b0, b1, b2)
-}
-
-fn generate_mean_stack(input_files: &[String]) -> image::Image {
-    let mut mean: image::Image = image::Image::new_empty().unwrap();
-    let mut count: imagebuffer::ImageBuffer = imagebuffer::ImageBuffer::new_empty().unwrap();
-    let mut ones: imagebuffer::ImageBuffer = imagebuffer::ImageBuffer::new_empty().unwrap();
-
-    vprintln!("Creating mean stack of all input frames...");
-
-    for in_file in input_files.iter() {
-        if path::file_exists(in_file) {
-            vprintln!("Adding file to stack: {}", in_file);
-
-            let raw = image::Image::open(in_file).unwrap();
-
-            if mean.is_empty() {
-                mean = raw;
-                count = imagebuffer::ImageBuffer::new(mean.width, mean.height).unwrap();
-                ones =
-                    imagebuffer::ImageBuffer::new_with_fill(mean.width, mean.height, 1.0).unwrap();
-            } else {
-                if raw.width != mean.width || raw.height != mean.height {
-                    eprintln!("Input image has differing dimensions, cannot continue");
-                    panic!("Input image has differing dimensions, cannot continue");
-                }
-
-                mean.add(&raw);
-            }
-
-            count = count.add(&ones).unwrap();
-        } else {
-            eprintln!("File not found: {}", in_file);
-        }
-    }
-
-    if !mean.is_empty() {
-        mean.divide_from_each(&count);
-    }
-
-    mean
+fn generate_mean_stack(frames: &[image::Image], method: StackMethod) -> Result<image::Image> {
+    vprintln!("Creating {:?} stack of all input frames...", method);
+    stacking::stack(
+        frames,
+        &StackOptions {
+            method,
+            ..Default::default()
+        },
+    )
 }
 
 fn process_band(
@@ -233,10 +181,6 @@ fn process_frame_3channel(
         product_type == ProductType::STANDARD,
     );
 
-    processed_band_0.normalize_force_minmax_mut(0.0, 255.0, 0.0, 65535.0);
-    processed_band_1.normalize_force_minmax_mut(0.0, 255.0, 0.0, 65535.0);
-    processed_band_2.normalize_force_minmax_mut(0.0, 255.0, 0.0, 65535.0);
-
     if convert_to_mono {
         processed_band_0.scale_mut(0.2125);
         processed_band_1.scale_mut(0.7154);
@@ -259,117 +203,98 @@ fn process_frame_3channel(
     .unwrap()
 }
 
-fn process_file(
-    encoder: &mut gif::Encoder<&mut std::fs::File>,
-    in_file: &String,
+/// Renders the output frame for a single input file. Values are within `[0, 65535]`.
+fn render_frame(
+    raw: &image::Image,
     mean_stack: &image::Image,
-    black_level: f32,
-    white_level: f32,
-    gamma: f32,
-    lowpass_window_size: u8,
-    delay: u16,
-    product_type: ProductType,
-    convert_to_mono: bool,
-) {
-    vprintln!("Processing frame differential on file: {}", in_file);
-
-    let raw = image::Image::open(in_file).unwrap();
+    params: &DiffGif,
+) -> Result<image::Image> {
+    let render = |product_type: ProductType| {
+        process_frame_3channel(
+            raw,
+            mean_stack,
+            params.black_level,
+            params.white_level,
+            params.gamma,
+            params.lowpass_window_size,
+            product_type,
+            params.convert_to_mono,
+        )
+    };
 
-    let (pixels, height) = match product_type {
+    match params.product_type {
         ProductType::STACKED => {
-            let img_std = process_frame_3channel(
-                &raw,
-                mean_stack,
-                black_level,
-                white_level,
-                gamma,
-                lowpass_window_size,
-                ProductType::STANDARD,
-                convert_to_mono,
-            );
-            let img_diff = process_frame_3channel(
-                &raw,
-                mean_stack,
-                black_level,
-                white_level,
-                gamma,
-                lowpass_window_size,
-                ProductType::DIFFERENTIAL,
-                convert_to_mono,
-            );
+            let img_std = render(ProductType::STANDARD);
+            let img_diff = render(ProductType::DIFFERENTIAL);
             let mut stacked = image::Image::new_with_bands(
                 img_std.width,
                 img_std.height * 2,
                 3,
                 ImageMode::U16BIT,
-            )
-            .unwrap();
+            )?;
             stacked.paste(&img_diff, 0, 0);
             stacked.paste(&img_std, 0, img_std.height);
-            (rgbimage_to_vec_v8(&stacked), img_std.height * 2)
+            Ok(stacked)
         }
-        _ => {
-            let img = process_frame_3channel(
-                &raw,
-                mean_stack,
-                black_level,
-                white_level,
-                gamma,
-                lowpass_window_size,
-                product_type,
-                convert_to_mono,
-            );
-            (rgbimage_to_vec_v8(&img), img.height)
-        }
-    };
-
-    let mut frame = gif::Frame::from_rgb(raw.width as u16, height as u16, &pixels);
-
-    frame.delay = delay;
-    encoder.write_frame(&frame).unwrap();
+        _ => Ok(render(params.product_type)),
+    }
 }
 
 pub struct DiffGif {
     pub input_files: Vec<String>,
     pub product_type: ProductType,
     pub output: String,
+    pub output_format: OutputFormat,
     pub black_level: f32,
     pub white_level: f32,
     pub gamma: f32,
     pub delay: u16,
     pub lowpass_window_size: u8,
     pub convert_to_mono: bool,
+
+    /// Align frames to the first frame before differencing
+    pub registration: Option<RegistrationMethod>,
+
+    /// How frames are combined into the background the differences are taken against
+    pub stack_method: StackMethod,
 }
 
-pub fn process(params: &DiffGif) {
-    let mean_stack = generate_mean_stack(&params.input_files);
+/// Renders each input frame and writes it to a sink for the requested output format
+pub fn process(params: &DiffGif) -> Result<()> {
+    let frames = registration::open_and_register(&params.input_files, params.registration)?;
+    let mean_stack = generate_mean_stack(&frames, params.stack_method)?;
+
     let height = match params.product_type {
         ProductType::STACKED => mean_stack.height * 2,
         _ => mean_stack.height,
     };
+    let mut sink = framesink::create_sink(
+        params.output_format,
+        &params.output,
+        mean_stack.width,
+        height,
+        frames.len(),
+    )?;
+    render_frames(params, &frames, &mean_stack, sink.as_mut())
+}
 
-    let mut image = File::create(&params.output).unwrap();
-    let mut encoder =
-        gif::Encoder::new(&mut image, mean_stack.width as u16, height as u16, &[]).unwrap();
-    encoder.set_repeat(gif::Repeat::Infinite).unwrap();
-
-    for in_file in params.input_files.iter() {
-        if path::file_exists(in_file) {
-            process_file(
-                &mut encoder,
-                in_file,
-                &mean_stack,
-                params.black_level,
-                params.white_level,
-                params.gamma,
-                params.lowpass_window_size,
-                params.delay,
-                params.product_type,
-                params.convert_to_mono,
-            );
-        } else {
-            eprintln!("File not found: {}", in_file);
-            panic!("File not found");
-        }
+/// Renders each input frame into the supplied sink
+pub fn process_with_sink(params: &DiffGif, sink: &mut dyn FrameSink) -> Result<()> {
+    let frames = registration::open_and_register(&params.input_files, params.registration)?;
+    let mean_stack = generate_mean_stack(&frames, params.stack_method)?;
+    render_frames(params, &frames, &mean_stack, sink)
+}
+
+fn render_frames(
+    params: &DiffGif,
+    frames: &[image::Image],
+    mean_stack: &image::Image,
+    sink: &mut dyn FrameSink,
+) -> Result<()> {
+    for (i, raw) in frames.iter().enumerate() {
+        vprintln!("Processing frame differential on frame {}", i);
+        let frame = render_frame(raw, mean_stack, params)?;
+        sink.add_frame(&frame, params.delay)?;
     }
+    sink.finish()
 }
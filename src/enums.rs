@@ -1,3 +1,4 @@
+use serde::Deserialize;
 use std::num::ParseIntError;
 use std::str::FromStr;
 
@@ -95,10 +96,82 @@ impl FromStr for Instrument {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+impl Instrument {
+    /// Returns which side of a stereo pair the instrument represents, or `Eye::DontCare`
+    /// for monoscopic instruments.
+    pub fn eye(&self) -> Eye {
+        match self {
+            Instrument::MslMastcamLeft
+            | Instrument::MslNavCamLeft
+            | Instrument::MslFrontHazLeft
+            | Instrument::MslRearHazLeft
+            | Instrument::M20MastcamZLeft
+            | Instrument::M20NavcamLeft
+            | Instrument::M20FrontHazLeft
+            | Instrument::M20RearHazLeft => Eye::Left,
+            Instrument::MslMastcamRight
+            | Instrument::MslNavCamRight
+            | Instrument::MslFrontHazRight
+            | Instrument::MslRearHazRight
+            | Instrument::M20MastcamZRight
+            | Instrument::M20NavcamRight
+            | Instrument::M20FrontHazRight
+            | Instrument::M20RearHazRight => Eye::Right,
+            _ => Eye::DontCare,
+        }
+    }
+
+    /// Returns the opposite eye of a stereo camera pair, if the instrument is part of one.
+    pub fn stereo_counterpart(&self) -> Option<Instrument> {
+        match self {
+            Instrument::MslMastcamLeft => Some(Instrument::MslMastcamRight),
+            Instrument::MslMastcamRight => Some(Instrument::MslMastcamLeft),
+            Instrument::MslNavCamLeft => Some(Instrument::MslNavCamRight),
+            Instrument::MslNavCamRight => Some(Instrument::MslNavCamLeft),
+            Instrument::MslFrontHazLeft => Some(Instrument::MslFrontHazRight),
+            Instrument::MslFrontHazRight => Some(Instrument::MslFrontHazLeft),
+            Instrument::MslRearHazLeft => Some(Instrument::MslRearHazRight),
+            Instrument::MslRearHazRight => Some(Instrument::MslRearHazLeft),
+            Instrument::M20MastcamZLeft => Some(Instrument::M20MastcamZRight),
+            Instrument::M20MastcamZRight => Some(Instrument::M20MastcamZLeft),
+            Instrument::M20NavcamLeft => Some(Instrument::M20NavcamRight),
+            Instrument::M20NavcamRight => Some(Instrument::M20NavcamLeft),
+            Instrument::M20FrontHazLeft => Some(Instrument::M20FrontHazRight),
+            Instrument::M20FrontHazRight => Some(Instrument::M20FrontHazLeft),
+            Instrument::M20RearHazLeft => Some(Instrument::M20RearHazRight),
+            Instrument::M20RearHazRight => Some(Instrument::M20RearHazLeft),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
 pub enum CalFileType {
     FlatField,
     InpaintMask,
     Mask,
     Lut,
 }
+
+impl FromStr for CalFileType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<CalFileType, String> {
+        match s.to_lowercase().as_str() {
+            "flat" | "flatfield" => Ok(CalFileType::FlatField),
+            "inpaint" | "inpaint_mask" | "inpaintmask" => Ok(CalFileType::InpaintMask),
+            "mask" => Ok(CalFileType::Mask),
+            "lut" => Ok(CalFileType::Lut),
+            _ => Err(format!("Unknown calibration file type: {}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for CalFileType {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<CalFileType, String> {
+        CalFileType::from_str(&s)
+    }
+}
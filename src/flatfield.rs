@@ -1,16 +1,37 @@
+use crate::calibfile::ObservationTime;
 use crate::{calibfile, enums, marsimage::MarsImage, memcache::load_image, vprintln};
 
 use anyhow::Result;
+use sciimg::image::Image;
+use std::sync::Arc;
 
 pub fn load_flat(instrument: enums::Instrument) -> Result<MarsImage> {
-    match calibfile::get_calibration_file_for_instrument(instrument, enums::CalFileType::FlatField)
-    {
+    load_flat_at(instrument, &ObservationTime::default())
+}
+
+/// Loads a copy of the flat field valid for an image taken at `when`, for callers that
+/// crop or otherwise modify it
+pub fn load_flat_at(instrument: enums::Instrument, when: &ObservationTime) -> Result<MarsImage> {
+    Ok(MarsImage::from_image(
+        &load_flat_image_at(instrument, when)?,
+        instrument,
+    ))
+}
+
+/// Loads the flat field valid for an image taken at `when`, shared with the calibration
+/// file cache. Use `Arc::make_mut` to modify it.
+pub fn load_flat_image_at(
+    instrument: enums::Instrument,
+    when: &ObservationTime,
+) -> Result<Arc<Image>> {
+    match calibfile::get_calibration_file_for_instrument_at(
+        instrument,
+        enums::CalFileType::FlatField,
+        when,
+    ) {
         Ok(cal_file) => {
             vprintln!("Loading calibration file from {}", cal_file);
-            Ok(MarsImage::from_image(
-                &load_image(&cal_file).unwrap(),
-                instrument,
-            ))
+            load_image(&cal_file)
         }
         Err(e) => Err(e),
     }
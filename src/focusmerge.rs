@@ -1,165 +1,570 @@
-use std::process;
-
+use crate::metadata::load_image_metadata;
+use crate::registration::{self, RegistrationMethod};
 use crate::{util, vprintln};
 
-use sciimg::{image, imagebuffer, lowpass, path, quality, stats};
+use sciimg::{enums::ImageMode, image::Image, imagebuffer::ImageBuffer, path};
 
-use colored::*;
+use anyhow::anyhow;
+use anyhow::Result;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-struct Diff {
-    band_0: imagebuffer::ImageBuffer,
-    band_1: imagebuffer::ImageBuffer,
-    band_2: imagebuffer::ImageBuffer,
-    image: image::Image,
-}
+use std::fs;
+
+/// Focus merge parameters
+#[derive(Debug, Clone)]
+pub struct FocusMergeParams {
+    /// Window (pixels) over which local sharpness is averaged into a quality weight
+    pub quality_window_size: usize,
+
+    /// Number of Laplacian pyramid levels. Determined from the image size when `None`.
+    pub levels: Option<usize>,
 
-fn make_diff_for_band(
-    buffer: &imagebuffer::ImageBuffer,
-    amount: usize,
-) -> imagebuffer::ImageBuffer {
-    let blurred = lowpass::lowpass_imagebuffer(buffer, amount);
-    blurred.subtract(buffer).unwrap()
+    /// Exponent applied to the quality weights. Higher values approach a hard per-pixel
+    /// selection of the sharpest frame.
+    pub weight_exponent: f32,
+
+    /// Align frames to the first frame before merging
+    pub registration: Option<RegistrationMethod>,
 }
 
-fn make_diff_container(image: &image::Image, blur_amount: usize) -> Diff {
-    Diff {
-        band_0: make_diff_for_band(image.get_band(0), blur_amount),
-        band_1: make_diff_for_band(image.get_band(1), blur_amount),
-        band_2: make_diff_for_band(image.get_band(2), blur_amount),
-        image: image.clone(),
+impl Default for FocusMergeParams {
+    fn default() -> Self {
+        FocusMergeParams {
+            quality_window_size: 15,
+            levels: None,
+            weight_exponent: 2.0,
+            registration: None,
+        }
     }
 }
 
-pub fn focusmerge(
-    input_files: &[String],
-    quality_window_size: usize,
-    depth_map: bool,
-    output_file: &str,
-) {
-    let mut images: Vec<Diff> = vec![];
+/// Single channel floating point raster used for the pyramids and weight maps
+#[derive(Debug, Clone)]
+struct Plane {
+    width: usize,
+    height: usize,
+    data: Vec<f32>,
+}
 
-    let mut out_width = 0;
-    let mut out_height = 0;
+impl Plane {
+    fn new(width: usize, height: usize) -> Self {
+        Plane {
+            width,
+            height,
+            data: vec![0.0; width * height],
+        }
+    }
 
-    for in_file in input_files.iter() {
-        if path::file_exists(in_file) {
-            vprintln!("Processing File: {}", in_file);
+    fn from_band(image: &Image, band: usize) -> Self {
+        let buffer = image.get_band(band);
+        let mut plane = Plane::new(image.width, image.height);
+        plane
+            .data
+            .par_chunks_mut(image.width)
+            .enumerate()
+            .for_each(|(y, row)| {
+                row.iter_mut()
+                    .enumerate()
+                    .for_each(|(x, v)| *v = buffer.get(x, y))
+            });
+        plane
+    }
 
-            let image = image::Image::open(in_file).unwrap();
+    /// Mean of all bands
+    fn luminance(image: &Image) -> Self {
+        let bands: Vec<Plane> = (0..image.num_bands())
+            .map(|b| Plane::from_band(image, b))
+            .collect();
+        let mut plane = Plane::new(image.width, image.height);
+        plane.data.par_iter_mut().enumerate().for_each(|(i, v)| {
+            *v = bands.iter().map(|b| b.data[i]).sum::<f32>() / bands.len() as f32
+        });
+        plane
+    }
 
-            if out_width == 0 {
-                out_width = image.width;
-                out_height = image.height;
-            } else if out_width != image.width || out_height != image.height {
-                eprintln!(
-                    "{} Input images have differing dimensions. Cannot continue",
-                    "Error".red()
-                );
-                process::exit(1);
+    fn get_clamped(&self, x: isize, y: isize) -> f32 {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        self.data[y * self.width + x]
+    }
+
+    /// Builds a plane of the given size by evaluating `f` at each pixel, a row per task
+    fn generate<F: Fn(usize, usize) -> f32 + Sync>(width: usize, height: usize, f: F) -> Self {
+        let mut plane = Plane::new(width, height);
+        plane
+            .data
+            .par_chunks_mut(width)
+            .enumerate()
+            .for_each(|(y, row)| row.iter_mut().enumerate().for_each(|(x, v)| *v = f(x, y)));
+        plane
+    }
+
+    /// Squared response of a 3x3 Laplacian, a per-pixel measure of local sharpness
+    fn laplacian_energy(&self) -> Self {
+        Plane::generate(self.width, self.height, |x, y| {
+            let (x, y) = (x as isize, y as isize);
+            let l = 4.0 * self.get_clamped(x, y)
+                - self.get_clamped(x - 1, y)
+                - self.get_clamped(x + 1, y)
+                - self.get_clamped(x, y - 1)
+                - self.get_clamped(x, y + 1);
+            l * l
+        })
+    }
+
+    /// Mean over a `window` x `window` neighbourhood using a summed area table
+    fn box_filter(&self, window: usize) -> Self {
+        let (w, h) = (self.width, self.height);
+        let mut sat = vec![0.0_f64; (w + 1) * (h + 1)];
+        for y in 0..h {
+            let mut row_sum = 0.0;
+            for x in 0..w {
+                row_sum += self.data[y * w + x] as f64;
+                sat[(y + 1) * (w + 1) + x + 1] = sat[y * (w + 1) + x + 1] + row_sum;
             }
+        }
+        let r = window / 2;
+        Plane::generate(w, h, |x, y| {
+            let x0 = x.saturating_sub(r);
+            let y0 = y.saturating_sub(r);
+            let x1 = (x + r + 1).min(w);
+            let y1 = (y + r + 1).min(h);
+            let sum = sat[y1 * (w + 1) + x1] - sat[y0 * (w + 1) + x1] - sat[y1 * (w + 1) + x0]
+                + sat[y0 * (w + 1) + x0];
+            (sum / ((x1 - x0) * (y1 - y0)) as f64) as f32
+        })
+    }
+
+    /// 5-tap binomial blur followed by 2x decimation
+    fn reduce(&self) -> Self {
+        const K: [f32; 5] = [1.0 / 16.0, 4.0 / 16.0, 6.0 / 16.0, 4.0 / 16.0, 1.0 / 16.0];
+        Plane::generate((self.width + 1) / 2, (self.height + 1) / 2, |x, y| {
+            let mut sum = 0.0;
+            for (j, ky) in K.iter().enumerate() {
+                for (i, kx) in K.iter().enumerate() {
+                    sum += ky
+                        * kx
+                        * self.get_clamped((2 * x + i) as isize - 2, (2 * y + j) as isize - 2);
+                }
+            }
+            sum
+        })
+    }
 
-            images.push(make_diff_container(&image, 5));
-        } else {
-            eprintln!("File not found: {}", in_file);
-            panic!("File not found");
+    /// Bilinear upsampling to `width` x `height`
+    fn expand(&self, width: usize, height: usize) -> Self {
+        Plane::generate(width, height, |x, y| {
+            let fx = x as f32 / 2.0;
+            let fy = y as f32 / 2.0;
+            let (x0, y0) = (fx.floor() as isize, fy.floor() as isize);
+            let (ax, ay) = (fx - x0 as f32, fy - y0 as f32);
+            let top = self.get_clamped(x0, y0) * (1.0 - ax) + self.get_clamped(x0 + 1, y0) * ax;
+            let bottom =
+                self.get_clamped(x0, y0 + 1) * (1.0 - ax) + self.get_clamped(x0 + 1, y0 + 1) * ax;
+            top * (1.0 - ay) + bottom * ay
+        })
+    }
+
+    fn gaussian_pyramid(&self, levels: usize) -> Vec<Plane> {
+        let mut pyramid = vec![self.clone()];
+        for _ in 1..levels {
+            let next = pyramid.last().unwrap().reduce();
+            pyramid.push(next);
         }
+        pyramid
     }
 
-    let mut depth_map_buffer = imagebuffer::ImageBuffer::new_with_fill_as_mode(
-        images[0].band_0.width,
-        images[0].band_0.height,
-        0.0,
-        images[0].band_0.mode,
-    )
-    .unwrap();
-
-    let mut b0_merge_buffer = imagebuffer::ImageBuffer::new_with_fill_as_mode(
-        images[0].band_0.width,
-        images[0].band_0.height,
-        0.0,
-        images[0].band_0.mode,
-    )
-    .unwrap();
-    let mut b1_merge_buffer = imagebuffer::ImageBuffer::new_with_fill_as_mode(
-        images[0].band_0.width,
-        images[0].band_0.height,
-        0.0,
-        images[0].band_0.mode,
-    )
-    .unwrap();
-    let mut b2_merge_buffer = imagebuffer::ImageBuffer::new_with_fill_as_mode(
-        images[0].band_0.width,
-        images[0].band_0.height,
-        0.0,
-        images[0].band_0.mode,
-    )
-    .unwrap();
-
-    // Super mega inefficient. This'll take a few minutes to run.
-    for y in 0..b0_merge_buffer.height {
-        vprintln!(
-            "Row {} of {}. {}%",
-            y,
-            b0_merge_buffer.height,
-            (y as f32 / b0_merge_buffer.height as f32 * 100.0)
-        );
-        for x in 0..b0_merge_buffer.width {
-            let mut b0_value = 0.0_f32;
-            let mut b1_value = 0.0_f32;
-            let mut b2_value = 0.0_f32;
-            let mut max_quality = 0.0_f32;
-            let mut depth_value = 0;
-
-            for (image_num, image) in images.iter().enumerate() {
-                let q0 = quality::get_point_quality_estimation_on_diff_buffer(
-                    &image.band_0,
-                    quality_window_size,
-                    x,
-                    y,
-                );
-                let q1 = quality::get_point_quality_estimation_on_diff_buffer(
-                    &image.band_1,
-                    quality_window_size,
-                    x,
-                    y,
-                );
-                let q2 = quality::get_point_quality_estimation_on_diff_buffer(
-                    &image.band_2,
-                    quality_window_size,
-                    x,
-                    y,
-                );
-                let q = stats::mean(&[q0, q1, q2]).unwrap_or(0.0);
-
-                if q > max_quality {
-                    depth_value = image_num;
-                    max_quality = q;
-                    b0_value = image.image.get_band(0).get(x, y);
-                    b1_value = image.image.get_band(1).get(x, y);
-                    b2_value = image.image.get_band(2).get(x, y);
+    /// Band-pass levels with the low-pass residual as the last level
+    fn laplacian_pyramid(&self, levels: usize) -> Vec<Plane> {
+        let gaussian = self.gaussian_pyramid(levels);
+        let mut pyramid: Vec<Plane> = gaussian
+            .windows(2)
+            .map(|pair| {
+                let up = pair[1].expand(pair[0].width, pair[0].height);
+                Plane {
+                    width: pair[0].width,
+                    height: pair[0].height,
+                    data: pair[0]
+                        .data
+                        .iter()
+                        .zip(up.data.iter())
+                        .map(|(a, b)| a - b)
+                        .collect(),
                 }
+            })
+            .collect();
+        pyramid.push(gaussian.last().unwrap().clone());
+        pyramid
+    }
+
+    fn collapse(pyramid: &[Plane]) -> Plane {
+        let mut result = pyramid.last().unwrap().clone();
+        for level in pyramid.iter().rev().skip(1) {
+            let mut up = result.expand(level.width, level.height);
+            up.data
+                .iter_mut()
+                .zip(level.data.iter())
+                .for_each(|(u, l)| *u += l);
+            result = up;
+        }
+        result
+    }
+}
+
+/// Per-pixel estimate of which frame of the stack is in focus, as a continuous (weighted)
+/// frame index
+#[derive(Debug, Clone)]
+pub struct DepthMap {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<f32>,
+}
+
+impl DepthMap {
+    pub fn get(&self, x: usize, y: usize) -> f32 {
+        self.data[y * self.width + x]
+    }
+
+    /// Depth map normalized to the full 16-bit range
+    pub fn to_imagebuffer(&self) -> ImageBuffer {
+        let mut buffer =
+            ImageBuffer::new_with_fill_as_mode(self.width, self.height, 0.0, ImageMode::U16BIT)
+                .unwrap();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                buffer.put(x, y, self.get(x, y));
             }
+        }
+        buffer.normalize(0.0, 65535.0).unwrap()
+    }
 
-            depth_map_buffer.put(x, y, depth_value as f32);
-            b0_merge_buffer.put(x, y, b0_value);
-            b1_merge_buffer.put(x, y, b1_value);
-            b2_merge_buffer.put(x, y, b2_value);
+    /// Converts frame indices to distance (millimeters) using the focus motor position of
+    /// each frame and a motor count to distance calibration
+    pub fn to_distance(
+        &self,
+        focus_positions: &[f64],
+        calibration: &FocusCalibration,
+    ) -> Result<DepthMap> {
+        if focus_positions.is_empty() {
+            return Err(anyhow!("No focus positions"));
         }
+        let last = focus_positions.len() - 1;
+        let data = self
+            .data
+            .par_iter()
+            .map(|d| {
+                let d = (*d as f64).clamp(0.0, last as f64);
+                let i0 = d.floor() as usize;
+                let i1 = (i0 + 1).min(last);
+                let f = d - i0 as f64;
+                let motor = focus_positions[i0] * (1.0 - f) + focus_positions[i1] * f;
+                calibration.distance(motor) as f32
+            })
+            .collect();
+        Ok(DepthMap {
+            width: self.width,
+            height: self.height,
+            data,
+        })
     }
 
-    let merge_buffer = image::Image::new_from_buffers_rgb(
-        &b0_merge_buffer,
-        &b1_merge_buffer,
-        &b2_merge_buffer,
-        b0_merge_buffer.mode,
-    )
-    .unwrap();
+    /// Distance map in millimeters as a 16-bit buffer (not normalized)
+    pub fn to_distance_imagebuffer(&self) -> ImageBuffer {
+        let mut buffer =
+            ImageBuffer::new_with_fill_as_mode(self.width, self.height, 0.0, ImageMode::U16BIT)
+                .unwrap();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                buffer.put(x, y, self.get(x, y).clamp(0.0, 65535.0));
+            }
+        }
+        buffer
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct FocusCalibrationPoint {
+    pub motor_count: f64,
+    pub distance_mm: f64,
+}
 
-    merge_buffer.save(output_file);
+/// Focus motor count to working distance relationship for a variable focus camera such as
+/// MAHLI or WATSON, interpolated linearly between calibration points:
+///
+/// ```toml
+/// [[points]]
+/// motor_count = 12000
+/// distance_mm = 21
+///
+/// [[points]]
+/// motor_count = 9000
+/// distance_mm = 250
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FocusCalibration {
+    pub points: Vec<FocusCalibrationPoint>,
+}
 
-    if depth_map {
-        depth_map_buffer = depth_map_buffer.normalize(0.0, 65535.0).unwrap();
+impl FocusCalibration {
+    pub fn new(mut points: Vec<FocusCalibrationPoint>) -> Result<Self> {
+        if points.len() < 2 {
+            return Err(anyhow!("Focus calibration needs at least two points"));
+        }
+        points.sort_by(|a, b| a.motor_count.partial_cmp(&b.motor_count).unwrap());
+        if points
+            .windows(2)
+            .any(|p| p[0].motor_count == p[1].motor_count)
+        {
+            return Err(anyhow!("Focus calibration has duplicate motor counts"));
+        }
+        Ok(FocusCalibration { points })
+    }
+
+    pub fn load(file_path: &str) -> Result<Self> {
+        let text = fs::read_to_string(file_path)?;
+        let cal: FocusCalibration = toml::from_str(&text)?;
+        FocusCalibration::new(cal.points)
+    }
+
+    /// Working distance at `motor_count`. Clamped to the calibrated range.
+    pub fn distance(&self, motor_count: f64) -> f64 {
+        let first = self.points.first().unwrap();
+        let last = self.points.last().unwrap();
+        if motor_count <= first.motor_count {
+            return first.distance_mm;
+        }
+        if motor_count >= last.motor_count {
+            return last.distance_mm;
+        }
+        let p = self
+            .points
+            .windows(2)
+            .find(|p| motor_count <= p[1].motor_count)
+            .unwrap();
+        let f = (motor_count - p[0].motor_count) / (p[1].motor_count - p[0].motor_count);
+        p[0].distance_mm + f * (p[1].distance_mm - p[0].distance_mm)
+    }
+}
+
+/// Focus motor positions recorded in each input's metadata sidecar
+pub fn focus_positions_from_metadata(input_files: &[String]) -> Result<Vec<f64>> {
+    input_files
+        .iter()
+        .map(|f| {
+            let metadata_file = util::replace_image_extension(f, "-metadata.json");
+            if !path::file_exists(&metadata_file) {
+                return Err(anyhow!("Metadata file not found for {}", f));
+            }
+            load_image_metadata(&metadata_file)?
+                .focus_motor_count
+                .ok_or_else(|| anyhow!("No focus motor position in metadata for {}", f))
+        })
+        .collect()
+}
+
+pub struct FocusMergeResult {
+    pub image: Image,
+    pub depth_map: DepthMap,
+}
+
+fn pyramid_levels(width: usize, height: usize, levels: Option<usize>) -> usize {
+    let max_levels = ((width.min(height) as f32 / 8.0).log2().floor() as usize + 1).max(1);
+    levels.unwrap_or(6).clamp(1, max_levels)
+}
+
+/// Merges a stack of aligned, equally sized frames by Laplacian pyramid fusion. Each frame
+/// is weighted per pixel by its smoothed local sharpness so that detail is taken from the
+/// frame in focus while the low frequencies blend without seams.
+pub fn merge_images(images: &[Image], params: &FocusMergeParams) -> Result<FocusMergeResult> {
+    if images.is_empty() {
+        return Err(anyhow!("No input images"));
+    }
+    let (width, height) = (images[0].width, images[0].height);
+    if images
+        .iter()
+        .any(|i| i.width != width || i.height != height)
+    {
+        return Err(anyhow!("Input images have differing dimensions"));
+    }
+    let bands = images.iter().map(|i| i.num_bands()).min().unwrap();
+    let levels = pyramid_levels(width, height, params.levels);
+
+    vprintln!("Computing focus quality for {} frames", images.len());
+    let mut quality: Vec<Plane> = images
+        .par_iter()
+        .map(|img| {
+            Plane::luminance(img)
+                .laplacian_energy()
+                .box_filter(params.quality_window_size.max(1))
+        })
+        .collect();
+
+    // Scale by the largest quality over all frames before raising to the exponent so that
+    // the power cannot overflow. A common scale leaves the ratios between frames unchanged.
+    let max_quality = quality
+        .iter()
+        .flat_map(|q| q.data.iter())
+        .fold(0.0_f32, |m, v| m.max(*v));
+    if max_quality > 0.0 {
+        quality.par_iter_mut().for_each(|q| {
+            q.data
+                .iter_mut()
+                .for_each(|v| *v = (*v / max_quality).powf(params.weight_exponent))
+        });
+    }
+
+    // Normalize the weights to sum to one at each pixel. Flat regions where no frame has
+    // any detail get equal weights.
+    let totals: Vec<f32> = (0..width * height)
+        .into_par_iter()
+        .map(|i| quality.iter().map(|q| q.data[i]).sum())
+        .collect();
+    let equal_weight = 1.0 / images.len() as f32;
+    let mut weights: Vec<Plane> = quality;
+    weights.par_iter_mut().for_each(|w| {
+        w.data
+            .par_iter_mut()
+            .zip(totals.par_iter())
+            .for_each(|(v, total)| {
+                *v = if *total > 0.0 {
+                    *v / total
+                } else {
+                    equal_weight
+                }
+            })
+    });
+
+    let depth_map = DepthMap {
+        width,
+        height,
+        data: (0..width * height)
+            .into_par_iter()
+            .map(|i| {
+                weights
+                    .iter()
+                    .enumerate()
+                    .map(|(n, w)| n as f32 * w.data[i])
+                    .sum()
+            })
+            .collect(),
+    };
+
+    vprintln!("Fusing {} pyramid levels", levels);
+    let weight_pyramids: Vec<Vec<Plane>> = weights
+        .par_iter()
+        .map(|w| w.gaussian_pyramid(levels))
+        .collect();
+
+    let mut merged = Image::new_with_bands(width, height, bands, images[0].get_mode()).unwrap();
+    for b in 0..bands {
+        vprintln!("Merging band {}", b);
+        let band_pyramids: Vec<Vec<Plane>> = images
+            .par_iter()
+            .map(|img| Plane::from_band(img, b).laplacian_pyramid(levels))
+            .collect();
+
+        let fused: Vec<Plane> = (0..levels)
+            .map(|level| {
+                let mut plane = Plane::new(
+                    band_pyramids[0][level].width,
+                    band_pyramids[0][level].height,
+                );
+                plane.data.par_iter_mut().enumerate().for_each(|(i, v)| {
+                    *v = band_pyramids
+                        .iter()
+                        .zip(weight_pyramids.iter())
+                        .map(|(l, w)| l[level].data[i] * w[level].data[i])
+                        .sum()
+                });
+                plane
+            })
+            .collect();
+
+        // Pyramid blending can overshoot near strong edges, keep to the input range
+        let (min, max) = images
+            .iter()
+            .map(|img| img.get_band(b).get_min_max())
+            .fold((f32::MAX, f32::MIN), |(lo, hi), mm| {
+                (lo.min(mm.min), hi.max(mm.max))
+            });
+
+        let result = Plane::collapse(&fused);
+        for y in 0..height {
+            for x in 0..width {
+                merged.put(x, y, result.data[y * width + x].clamp(min, max), b);
+            }
+        }
+    }
+
+    Ok(FocusMergeResult {
+        image: merged,
+        depth_map,
+    })
+}
+
+/// Opens, optionally registers and focus merges `input_files`
+pub fn focusmerge_files(
+    input_files: &[String],
+    params: &FocusMergeParams,
+) -> Result<FocusMergeResult> {
+    let loaded = registration::open_and_register(input_files, params.registration)?;
+    merge_images(&loaded, params)
+}
+
+/// Depth map output options for `focusmerge`
+#[derive(Debug, Clone, Default)]
+pub struct DepthOutput {
+    /// Write the normalized frame index depth map as `<output>-depth`
+    pub depth_map: bool,
+
+    /// Write a distance map (millimeters) as `<output>-distance` using this calibration
+    pub calibration: Option<FocusCalibration>,
+
+    /// Focus motor position of each input. Read from the metadata sidecars when `None`.
+    pub focus_positions: Option<Vec<f64>>,
+}
+
+pub fn focusmerge(
+    input_files: &[String],
+    params: &FocusMergeParams,
+    depth: &DepthOutput,
+    output_file: &str,
+) -> Result<()> {
+    let result = focusmerge_files(input_files, params)?;
+
+    vprintln!("Writing merged image to {}", output_file);
+    result.image.save(output_file);
+
+    if depth.depth_map {
         let depth_map_out_file = util::append_file_name(output_file, "depth");
-        depth_map_buffer.save_16bit(&depth_map_out_file);
+        vprintln!("Writing depth map to {}", depth_map_out_file);
+        result
+            .depth_map
+            .to_imagebuffer()
+            .save_16bit(&depth_map_out_file);
+    }
+
+    if let Some(calibration) = &depth.calibration {
+        let positions = match &depth.focus_positions {
+            Some(p) => p.clone(),
+            None => focus_positions_from_metadata(input_files)?,
+        };
+        if positions.len() != input_files.len() {
+            return Err(anyhow!(
+                "Number of focus positions ({}) does not match the number of inputs ({})",
+                positions.len(),
+                input_files.len()
+            ));
+        }
+        let distance_out_file = util::append_file_name(output_file, "distance");
+        vprintln!("Writing distance map to {}", distance_out_file);
+        result
+            .depth_map
+            .to_distance(&positions, calibration)?
+            .to_distance_imagebuffer()
+            .save_16bit(&distance_out_file);
     }
+
+    Ok(())
 }
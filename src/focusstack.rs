@@ -0,0 +1,277 @@
+use crate::{
+    enums::Instrument,
+    focusmerge::{self, DepthOutput, FocusMergeParams},
+    metadata::{load_image_metadata, Metadata},
+    stereopair::{self, product_suffix},
+    util, vprintln,
+};
+
+use sciimg::path;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use regex::Regex;
+
+use std::str::FromStr;
+
+lazy_static! {
+    /// Matches the four digit camera specific field that follows the sequence id in Mars 2020
+    /// product ids (e.g. `SRLC00702_0412LMJ`), which for WATSON holds the focus position
+    static ref M20_CAMERA_SPECIFIC: Regex = Regex::new(r"[A-Z]{4}[0-9]{5}_([0-9]{4})").unwrap();
+}
+
+/// Options controlling how frames are grouped into focus stacks
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct StackCriteria {
+    /// Maximum spacecraft clock difference (seconds) between consecutive frames of a stack
+    pub sclk_window: f64,
+
+    /// Maximum angle (degrees) between the camera pointing vectors of frames in a stack
+    pub pointing_tolerance: f64,
+
+    /// Require identical subframe rectangles when frames have them
+    pub match_subframe: bool,
+
+    /// Minimum number of frames for a group to be considered a stack
+    pub min_frames: usize,
+}
+
+impl Default for StackCriteria {
+    fn default() -> Self {
+        StackCriteria {
+            sclk_window: 60.0,
+            pointing_tolerance: 0.5,
+            match_subframe: true,
+            min_frames: 3,
+        }
+    }
+}
+
+/// A MAHLI or WATSON image considered for focus stacking
+#[derive(Clone)]
+pub struct FocusCandidate {
+    pub file_path: String,
+    pub instrument: Instrument,
+    pub sclk: f64,
+
+    /// Focus motor position, from the metadata or the product id
+    pub focus_position: Option<f64>,
+    pub metadata: Metadata,
+}
+
+/// A group of frames of one scene taken at different focus positions
+#[derive(Debug, Clone, PartialEq)]
+pub struct FocusStack {
+    pub instrument: Instrument,
+    pub files: Vec<String>,
+    pub focus_positions: Vec<Option<f64>>,
+    pub sclk_start: f64,
+    pub sclk_end: f64,
+}
+
+/// Angle in degrees between two direction vectors
+fn angle_between(a: &[f64], b: &[f64]) -> Option<f64> {
+    if a.len() < 3 || b.len() < 3 {
+        return None;
+    }
+    let dot = a[0] * b[0] + a[1] * b[1] + a[2] * b[2];
+    let la = (a[0] * a[0] + a[1] * a[1] + a[2] * a[2]).sqrt();
+    let lb = (b[0] * b[0] + b[1] * b[1] + b[2] * b[2]).sqrt();
+    if la == 0.0 || lb == 0.0 {
+        return None;
+    }
+    Some((dot / (la * lb)).clamp(-1.0, 1.0).acos().to_degrees())
+}
+
+/// Focus position encoded in the product id, for cameras whose ids carry one (WATSON)
+pub fn focus_position_from_product_id(instrument: Instrument, imageid: &str) -> Option<f64> {
+    if instrument != Instrument::M20Watson {
+        return None;
+    }
+    M20_CAMERA_SPECIFIC
+        .captures(imageid)
+        .and_then(|c| c.get(1))
+        .and_then(|m| m.as_str().parse::<f64>().ok())
+}
+
+impl FocusCandidate {
+    /// Constructs a candidate from an image path and its metadata. Returns an error if the
+    /// image is not from a focus stacking camera or lacks a spacecraft clock.
+    pub fn new_from_metadata(file_path: &str, metadata: &Metadata) -> Result<Self> {
+        let instrument = Instrument::from_str(&metadata.instrument).unwrap();
+        if !matches!(instrument, Instrument::M20Watson | Instrument::MslMAHLI) {
+            return Err(anyhow!(
+                "Instrument {} does not acquire focus stacks",
+                metadata.instrument
+            ));
+        }
+
+        let sclk = match metadata.sclk {
+            Some(s) => s,
+            None => return Err(anyhow!("Image lacks a spacecraft clock value")),
+        };
+
+        Ok(FocusCandidate {
+            file_path: file_path.to_string(),
+            instrument,
+            sclk,
+            focus_position: metadata
+                .focus_motor_count
+                .or_else(|| focus_position_from_product_id(instrument, &metadata.imageid)),
+            metadata: metadata.clone(),
+        })
+    }
+
+    /// Constructs a candidate from an image path, loading the accompanying metadata file
+    pub fn new_from_file(file_path: &str) -> Result<Self> {
+        let metadata_file = util::replace_image_extension(file_path, "-metadata.json");
+        if !path::file_exists(&metadata_file) {
+            return Err(anyhow!("Metadata file not found for {}", file_path));
+        }
+        let md = load_image_metadata(&metadata_file)?;
+        FocusCandidate::new_from_metadata(file_path, &md)
+    }
+
+    /// Determines if `other` images the same scene with the same camera configuration
+    pub fn same_scene(&self, other: &FocusCandidate, criteria: &StackCriteria) -> bool {
+        if self.instrument != other.instrument {
+            return false;
+        }
+
+        if let (Some(a), Some(b)) = (&self.metadata.camera_vector, &other.metadata.camera_vector) {
+            if let Some(angle) = angle_between(a, b) {
+                if angle > criteria.pointing_tolerance {
+                    return false;
+                }
+            }
+        }
+
+        if criteria.match_subframe {
+            if let (Some(a), Some(b)) =
+                (&self.metadata.subframe_rect, &other.metadata.subframe_rect)
+            {
+                if a != b {
+                    return false;
+                }
+            }
+        }
+
+        if self.metadata.scale_factor != other.metadata.scale_factor {
+            return false;
+        }
+
+        product_suffix(&self.file_path, &self.metadata.imageid)
+            == product_suffix(&other.file_path, &other.metadata.imageid)
+    }
+}
+
+impl FocusStack {
+    /// Focus positions of every frame, if all are known
+    pub fn known_focus_positions(&self) -> Option<Vec<f64>> {
+        self.focus_positions.iter().copied().collect()
+    }
+
+    /// Whether the known focus positions show the frames were taken at different focus.
+    /// False when fewer than two positions are known.
+    pub fn focus_varies(&self) -> bool {
+        let known: Vec<f64> = self.focus_positions.iter().flatten().copied().collect();
+        known.len() >= 2 && known.iter().any(|p| *p != known[0])
+    }
+
+    /// Output file name for the merged stack, derived from the first frame
+    pub fn output_file(&self, output_dir: Option<&str>) -> String {
+        let out_file = util::append_file_name(&self.files[0], "focusmerge");
+        match output_dir {
+            Some(dir) => format!("{}/{}", dir, path::basename(&out_file)),
+            None => out_file,
+        }
+    }
+}
+
+/// Groups candidates into focus stacks. Frames are ordered by spacecraft clock and a stack
+/// continues while each frame follows the previous one within the SCLK window and images the
+/// same scene as the first frame. Groups with fewer than `min_frames` frames, or whose focus
+/// positions are unknown or all identical, are dropped.
+pub fn find_stacks(candidates: &[FocusCandidate], criteria: &StackCriteria) -> Vec<FocusStack> {
+    let mut sorted: Vec<&FocusCandidate> = candidates.iter().collect();
+    sorted.sort_by(|a, b| a.sclk.total_cmp(&b.sclk));
+
+    let mut groups: Vec<Vec<&FocusCandidate>> = vec![];
+    for candidate in sorted {
+        // Instruments may interleave, so look for an open group of any camera
+        let open = groups.iter_mut().rev().find(|g| {
+            let last = g.last().unwrap();
+            last.instrument == candidate.instrument
+                && candidate.sclk - last.sclk <= criteria.sclk_window
+                && g[0].same_scene(candidate, criteria)
+        });
+        match open {
+            Some(group) => group.push(candidate),
+            None => groups.push(vec![candidate]),
+        }
+    }
+
+    groups
+        .into_iter()
+        .filter(|g| g.len() >= criteria.min_frames.max(2))
+        .map(|g| FocusStack {
+            instrument: g[0].instrument,
+            files: g.iter().map(|c| c.file_path.clone()).collect(),
+            focus_positions: g.iter().map(|c| c.focus_position).collect(),
+            sclk_start: g[0].sclk,
+            sclk_end: g[g.len() - 1].sclk,
+        })
+        .filter(|s| {
+            let varies = s.focus_varies();
+            if !varies {
+                vprintln!(
+                    "Skipping group starting at {}: focus position unknown or constant",
+                    s.files[0]
+                );
+            }
+            varies
+        })
+        .collect()
+}
+
+/// Builds candidates from a list of image files, skipping those that are not usable
+pub fn candidates_from_files(input_files: &[String]) -> Vec<FocusCandidate> {
+    input_files
+        .iter()
+        .filter_map(|f| match FocusCandidate::new_from_file(f) {
+            Ok(c) => Some(c),
+            Err(why) => {
+                vprintln!("Skipping {}: {}", f, why);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Scans a directory and returns all focus stacks found
+pub fn find_stacks_in_directory(directory: &str, criteria: &StackCriteria) -> Vec<FocusStack> {
+    let files = stereopair::list_images_in_directory(directory);
+    vprintln!("Found {} images in {}", files.len(), directory);
+    find_stacks(&candidates_from_files(&files), criteria)
+}
+
+/// Focus merges a discovered stack, returning the output file. The stack's focus positions
+/// are used for the distance map when all are known.
+pub fn merge_stack(
+    stack: &FocusStack,
+    params: &FocusMergeParams,
+    depth: &DepthOutput,
+    output_dir: Option<&str>,
+) -> Result<String> {
+    let out_file = stack.output_file(output_dir);
+    let depth = DepthOutput {
+        focus_positions: depth
+            .focus_positions
+            .clone()
+            .or_else(|| stack.known_focus_positions()),
+        ..depth.clone()
+    };
+    vprintln!("Merging {} frames into {}", stack.files.len(), out_file);
+    focusmerge::focusmerge(&stack.files, params, &depth, &out_file)?;
+    Ok(out_file)
+}
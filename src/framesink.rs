@@ -0,0 +1,387 @@
+use crate::vprintln;
+
+use sciimg::image::Image;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use image::codecs::webp::WebPEncoder;
+use image::ColorType;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Animation and frame sequence output formats
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Animated GIF. Frames are quantized to a 256 color palette.
+    Gif,
+
+    /// Animated PNG, 8 bits per channel
+    Apng,
+
+    /// Animated PNG, 16 bits per channel
+    Apng16,
+
+    /// Lossless animated WebP
+    WebP,
+
+    /// Numbered 8-bit PNG frames (e.g. for ffmpeg)
+    PngFrames,
+
+    /// Numbered 16-bit PNG frames
+    PngFrames16,
+}
+
+impl FromStr for OutputFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "gif" => Ok(OutputFormat::Gif),
+            "apng" | "apng8" => Ok(OutputFormat::Apng),
+            "apng16" => Ok(OutputFormat::Apng16),
+            "webp" => Ok(OutputFormat::WebP),
+            "frames" | "png" | "frames8" => Ok(OutputFormat::PngFrames),
+            "frames16" | "png16" => Ok(OutputFormat::PngFrames16),
+            _ => Err("Invalid output format"),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Determines the output format from the output file extension. PNG output defaults
+    /// to an 8-bit animated PNG.
+    pub fn from_output_path(output: &str) -> Option<OutputFormat> {
+        match Path::new(output)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase())
+            .as_deref()
+        {
+            Some("gif") => Some(OutputFormat::Gif),
+            Some("png") | Some("apng") => Some(OutputFormat::Apng),
+            Some("webp") => Some(OutputFormat::WebP),
+            _ => None,
+        }
+    }
+}
+
+/// Receives rendered animation frames. Frame values are expected within `[0, 65535]` and
+/// are scaled to the sink's bit depth.
+pub trait FrameSink {
+    /// Adds a frame displayed for `delay` hundredths of a second
+    fn add_frame(&mut self, frame: &Image, delay: u16) -> Result<()>;
+
+    /// Completes the output. No frames may be added afterwards.
+    fn finish(&mut self) -> Result<()>;
+}
+
+fn channel(frame: &Image, band: usize, x: usize, y: usize) -> f32 {
+    frame
+        .get_band(band.min(frame.num_bands() - 1))
+        .get(x, y)
+        .clamp(0.0, 65535.0)
+}
+
+/// Interleaved 8-bit RGB pixels from a 16-bit range frame
+pub fn frame_to_rgb8(frame: &Image) -> Vec<u8> {
+    let mut pixels: Vec<u8> = vec![0; frame.width * frame.height * 3];
+    for y in 0..frame.height {
+        for x in 0..frame.width {
+            let idx = (y * frame.width + x) * 3;
+            for b in 0..3 {
+                pixels[idx + b] = (channel(frame, b, x, y) / 257.0).round() as u8;
+            }
+        }
+    }
+    pixels
+}
+
+/// Interleaved big-endian 16-bit RGB pixels, as stored in PNG
+pub fn frame_to_rgb16_be(frame: &Image) -> Vec<u8> {
+    let mut pixels: Vec<u8> = vec![0; frame.width * frame.height * 6];
+    for y in 0..frame.height {
+        for x in 0..frame.width {
+            let idx = (y * frame.width + x) * 6;
+            for b in 0..3 {
+                let v = channel(frame, b, x, y).round() as u16;
+                pixels[idx + b * 2..idx + b * 2 + 2].copy_from_slice(&v.to_be_bytes());
+            }
+        }
+    }
+    pixels
+}
+
+fn frame_pixels(frame: &Image, sixteen_bit: bool) -> Vec<u8> {
+    if sixteen_bit {
+        frame_to_rgb16_be(frame)
+    } else {
+        frame_to_rgb8(frame)
+    }
+}
+
+/// Animated GIF output
+pub struct GifSink {
+    encoder: Option<gif::Encoder<BufWriter<File>>>,
+}
+
+impl GifSink {
+    pub fn new(output: &str, width: usize, height: usize) -> Result<Self> {
+        let file = BufWriter::new(File::create(output)?);
+        let mut encoder = gif::Encoder::new(file, width as u16, height as u16, &[])?;
+        encoder.set_repeat(gif::Repeat::Infinite)?;
+        Ok(GifSink {
+            encoder: Some(encoder),
+        })
+    }
+}
+
+impl FrameSink for GifSink {
+    fn add_frame(&mut self, frame: &Image, delay: u16) -> Result<()> {
+        let encoder = self
+            .encoder
+            .as_mut()
+            .ok_or_else(|| anyhow!("GIF output already finished"))?;
+        let pixels = frame_to_rgb8(frame);
+        let mut gif_frame = gif::Frame::from_rgb(frame.width as u16, frame.height as u16, &pixels);
+        gif_frame.delay = delay;
+        encoder.write_frame(&gif_frame)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        // The GIF trailer is written when the encoder is dropped
+        self.encoder.take();
+        Ok(())
+    }
+}
+
+/// Animated PNG output at 8 or 16 bits per channel
+pub struct ApngSink {
+    writer: Option<png::Writer<BufWriter<File>>>,
+    sixteen_bit: bool,
+}
+
+impl ApngSink {
+    /// Creates an APNG of `num_frames` frames. APNG requires the frame count up front.
+    pub fn new(
+        output: &str,
+        width: usize,
+        height: usize,
+        num_frames: usize,
+        sixteen_bit: bool,
+    ) -> Result<Self> {
+        let file = BufWriter::new(File::create(output)?);
+        let mut encoder = png::Encoder::new(file, width as u32, height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(if sixteen_bit {
+            png::BitDepth::Sixteen
+        } else {
+            png::BitDepth::Eight
+        });
+        encoder.set_animated(num_frames as u32, 0)?;
+        Ok(ApngSink {
+            writer: Some(encoder.write_header()?),
+            sixteen_bit,
+        })
+    }
+}
+
+impl FrameSink for ApngSink {
+    fn add_frame(&mut self, frame: &Image, delay: u16) -> Result<()> {
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| anyhow!("APNG output already finished"))?;
+        writer.set_frame_delay(delay, 100)?;
+        writer.write_image_data(&frame_pixels(frame, self.sixteen_bit))?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.take() {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+/// Lossless animated WebP output. Each frame is encoded as a VP8L bitstream and wrapped in
+/// an ANMF chunk.
+pub struct WebPSink {
+    output: String,
+    width: usize,
+    height: usize,
+    frames: Vec<Vec<u8>>,
+}
+
+impl WebPSink {
+    pub fn new(output: &str, width: usize, height: usize) -> Result<Self> {
+        if width == 0 || height == 0 || width > 16384 || height > 16384 {
+            return Err(anyhow!(
+                "WebP dimensions must be between 1 and 16384 pixels"
+            ));
+        }
+        Ok(WebPSink {
+            output: output.to_string(),
+            width,
+            height,
+            frames: vec![],
+        })
+    }
+}
+
+fn push_u24(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&v.to_le_bytes()[..3]);
+}
+
+fn push_chunk(buf: &mut Vec<u8>, fourcc: &[u8; 4], data: &[u8]) {
+    buf.extend_from_slice(fourcc);
+    buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    buf.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        buf.push(0);
+    }
+}
+
+/// Extracts the VP8L chunk (header and payload) from a complete still WebP file
+fn extract_vp8l_chunk(webp: &[u8]) -> Result<Vec<u8>> {
+    let mut pos = 12;
+    while pos + 8 <= webp.len() {
+        let size = u32::from_le_bytes([webp[pos + 4], webp[pos + 5], webp[pos + 6], webp[pos + 7]])
+            as usize;
+        let end = (pos + 8 + size).min(webp.len());
+        if &webp[pos..pos + 4] == b"VP8L" {
+            let mut chunk = vec![];
+            push_chunk(&mut chunk, b"VP8L", &webp[pos + 8..end]);
+            return Ok(chunk);
+        }
+        pos = end + size % 2;
+    }
+    Err(anyhow!("Encoded WebP frame lacks a VP8L chunk"))
+}
+
+impl FrameSink for WebPSink {
+    fn add_frame(&mut self, frame: &Image, delay: u16) -> Result<()> {
+        let mut still: Vec<u8> = vec![];
+        WebPEncoder::new_lossless(&mut still).encode(
+            &frame_to_rgb8(frame),
+            frame.width as u32,
+            frame.height as u32,
+            ColorType::Rgb8,
+        )?;
+
+        let mut anmf: Vec<u8> = vec![];
+        push_u24(&mut anmf, 0); // X offset / 2
+        push_u24(&mut anmf, 0); // Y offset / 2
+        push_u24(&mut anmf, frame.width as u32 - 1);
+        push_u24(&mut anmf, frame.height as u32 - 1);
+        push_u24(&mut anmf, delay as u32 * 10); // Milliseconds
+        anmf.push(0b10); // No blending, no disposal
+        anmf.extend_from_slice(&extract_vp8l_chunk(&still)?);
+
+        self.frames.push(anmf);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let mut body: Vec<u8> = vec![];
+        body.extend_from_slice(b"WEBP");
+
+        let mut vp8x: Vec<u8> = vec![0b0000_0010, 0, 0, 0]; // Animation flag
+        push_u24(&mut vp8x, self.width as u32 - 1);
+        push_u24(&mut vp8x, self.height as u32 - 1);
+        push_chunk(&mut body, b"VP8X", &vp8x);
+
+        let mut anim: Vec<u8> = vec![0, 0, 0, 0]; // Background color
+        anim.extend_from_slice(&0_u16.to_le_bytes()); // Loop forever
+        push_chunk(&mut body, b"ANIM", &anim);
+
+        for frame in self.frames.drain(..) {
+            push_chunk(&mut body, b"ANMF", &frame);
+        }
+
+        vprintln!("Writing animated WebP to {}", self.output);
+        let mut file = BufWriter::new(File::create(&self.output)?);
+        file.write_all(b"RIFF")?;
+        file.write_all(&(body.len() as u32).to_le_bytes())?;
+        file.write_all(&body)?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+/// Numbered PNG frame sequence. Frames are written next to `output` as
+/// `<stem>_0000.png`, `<stem>_0001.png`, etc.
+pub struct PngFramesSink {
+    output: String,
+    sixteen_bit: bool,
+    index: usize,
+}
+
+impl PngFramesSink {
+    pub fn new(output: &str, sixteen_bit: bool) -> Self {
+        PngFramesSink {
+            output: output.to_string(),
+            sixteen_bit,
+            index: 0,
+        }
+    }
+
+    /// Path of the frame at the given index
+    pub fn frame_path(&self, index: usize) -> String {
+        let path = Path::new(&self.output);
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or("frame");
+        let file_name = format!("{}_{:04}.png", stem, index);
+        match path.parent() {
+            Some(parent) => parent.join(file_name).to_string_lossy().to_string(),
+            None => file_name,
+        }
+    }
+}
+
+impl FrameSink for PngFramesSink {
+    fn add_frame(&mut self, frame: &Image, _delay: u16) -> Result<()> {
+        let frame_path = self.frame_path(self.index);
+        vprintln!("Writing frame to {}", frame_path);
+
+        let file = BufWriter::new(File::create(&frame_path)?);
+        let mut encoder = png::Encoder::new(file, frame.width as u32, frame.height as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(if self.sixteen_bit {
+            png::BitDepth::Sixteen
+        } else {
+            png::BitDepth::Eight
+        });
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&frame_pixels(frame, self.sixteen_bit))?;
+        writer.finish()?;
+
+        self.index += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Creates a sink for the output format
+pub fn create_sink(
+    format: OutputFormat,
+    output: &str,
+    width: usize,
+    height: usize,
+    num_frames: usize,
+) -> Result<Box<dyn FrameSink>> {
+    Ok(match format {
+        OutputFormat::Gif => Box::new(GifSink::new(output, width, height)?),
+        OutputFormat::Apng => Box::new(ApngSink::new(output, width, height, num_frames, false)?),
+        OutputFormat::Apng16 => Box::new(ApngSink::new(output, width, height, num_frames, true)?),
+        OutputFormat::WebP => Box::new(WebPSink::new(output, width, height)?),
+        OutputFormat::PngFrames => Box::new(PngFramesSink::new(output, false)),
+        OutputFormat::PngFrames16 => Box::new(PngFramesSink::new(output, true)),
+    })
+}
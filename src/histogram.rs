@@ -0,0 +1,319 @@
+use crate::vprintln;
+
+use sciimg::{enums::ImageMode, image::Image, imagebuffer::ImageBuffer};
+
+/// Minimum ratio of estimated onboard stretch gain for an image to be considered stretched
+const MIN_STRETCH_GAIN: f32 = 1.05;
+
+/// Minimum number of occupied levels needed to infer a stretch
+const MIN_STRETCH_LEVELS: usize = 8;
+
+/// Fraction of level spacings that must agree with the estimated gain for the stretch to be
+/// considered regular. Sparse histogram tails can leave a few irregular spacings.
+const MIN_STRETCH_REGULARITY: f32 = 0.9;
+
+/// Histogram with one bin per integer value
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub bins: Vec<u64>,
+}
+
+/// A run of empty bins between occupied ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gap {
+    pub start: usize,
+    pub len: usize,
+}
+
+/// Summary statistics of a histogram
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistogramStats {
+    pub count: u64,
+    pub min: usize,
+    pub max: usize,
+    pub mean: f64,
+    pub median: usize,
+    pub std_dev: f64,
+    pub occupied: usize,
+    pub num_gaps: usize,
+    pub gap_bins: usize,
+}
+
+/// Number of bins for the value range of an image mode: 256 for 8 bit, 65536 otherwise
+pub fn bins_for_mode(mode: ImageMode) -> usize {
+    match mode {
+        ImageMode::U8BIT => 256,
+        _ => 65536,
+    }
+}
+
+impl Histogram {
+    pub fn new(num_bins: usize) -> Self {
+        Histogram {
+            bins: vec![0; num_bins],
+        }
+    }
+
+    /// Adds all values of `buffer`, rounded and clamped to the bin range
+    pub fn add_buffer(&mut self, buffer: &ImageBuffer) {
+        let last = self.bins.len() - 1;
+        for v in buffer.buffer.iter() {
+            let i = (v.round().max(0.0) as usize).min(last);
+            self.bins[i] += 1;
+        }
+    }
+
+    pub fn of_buffer(buffer: &ImageBuffer, num_bins: usize) -> Self {
+        let mut hist = Histogram::new(num_bins);
+        hist.add_buffer(buffer);
+        hist
+    }
+
+    /// Histogram of a single band, sized for the image mode
+    pub fn of_band(image: &Image, band: usize) -> Self {
+        Histogram::of_buffer(image.get_band(band), bins_for_mode(image.get_mode()))
+    }
+
+    /// Combined histogram of all bands, sized for the image mode
+    pub fn of_image(image: &Image) -> Self {
+        let mut hist = Histogram::new(bins_for_mode(image.get_mode()));
+        for b in 0..image.num_bands() {
+            hist.add_buffer(image.get_band(b));
+        }
+        hist
+    }
+
+    pub fn count(&self) -> u64 {
+        self.bins.iter().sum()
+    }
+
+    /// Lowest occupied value
+    pub fn min(&self) -> Option<usize> {
+        self.bins.iter().position(|c| *c > 0)
+    }
+
+    /// Highest occupied value
+    pub fn max(&self) -> Option<usize> {
+        self.bins.iter().rposition(|c| *c > 0)
+    }
+
+    /// Occupied values in ascending order
+    pub fn levels(&self) -> Vec<usize> {
+        self.bins
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| **c > 0)
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Smallest value at or below which `fraction` of the counts lie
+    pub fn percentile(&self, fraction: f64) -> Option<usize> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let target = (fraction.clamp(0.0, 1.0) * count as f64).ceil().max(1.0) as u64;
+        let mut acc = 0;
+        for (i, c) in self.bins.iter().enumerate() {
+            acc += c;
+            if acc >= target {
+                return Some(i);
+            }
+        }
+        self.max()
+    }
+
+    /// Runs of empty bins between the lowest and highest occupied values
+    pub fn gaps(&self) -> Vec<Gap> {
+        let levels = self.levels();
+        levels
+            .windows(2)
+            .filter(|w| w[1] - w[0] > 1)
+            .map(|w| Gap {
+                start: w[0] + 1,
+                len: w[1] - w[0] - 1,
+            })
+            .collect()
+    }
+
+    pub fn stats(&self) -> Option<HistogramStats> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let mean = self
+            .bins
+            .iter()
+            .enumerate()
+            .map(|(i, c)| i as f64 * *c as f64)
+            .sum::<f64>()
+            / count as f64;
+        let variance = self
+            .bins
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (i as f64 - mean).powi(2) * *c as f64)
+            .sum::<f64>()
+            / count as f64;
+        let gaps = self.gaps();
+        Some(HistogramStats {
+            count,
+            min: self.min()?,
+            max: self.max()?,
+            mean,
+            median: self.percentile(0.5)?,
+            std_dev: variance.sqrt(),
+            occupied: self.levels().len(),
+            num_gaps: gaps.len(),
+            gap_bins: gaps.iter().map(|g| g.len).sum(),
+        })
+    }
+}
+
+/// Linear stretch inferred from the regular gaps it leaves in a histogram. Stretching `n`
+/// consecutive levels by `gain` spreads them `gain` apart, leaving one or more empty bins
+/// between each.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StretchPattern {
+    /// Lowest occupied value, which the destretch keeps in place
+    pub min: usize,
+    pub max: usize,
+    pub gain: f32,
+
+    /// Fraction of level spacings consistent with `gain`
+    pub regularity: f32,
+}
+
+/// Infers the onboard stretch of a histogram, if its gaps follow a regular pattern. Gaps
+/// may span several bins. Original levels missing from the image are allowed for, as
+/// spacings spanning a multiple of the gain, but no two levels may map back to the same
+/// original level.
+pub fn detect_stretch(hist: &Histogram) -> Option<StretchPattern> {
+    let levels = hist.levels();
+    if levels.len() < MIN_STRETCH_LEVELS {
+        return None;
+    }
+    let (min, max) = (levels[0], levels[levels.len() - 1]);
+    let spacings: Vec<f32> = levels.windows(2).map(|w| (w[1] - w[0]) as f32).collect();
+
+    // Start from the mean spacing, then refine by counting how many original levels each
+    // spacing spans
+    let span = (max - min) as f32;
+    let mut gain = span / spacings.len() as f32;
+    for _ in 0..3 {
+        let steps: f32 = spacings.iter().map(|s| (s / gain).round().max(1.0)).sum();
+        gain = span / steps;
+    }
+    if gain < MIN_STRETCH_GAIN {
+        return None;
+    }
+
+    let regular = spacings
+        .iter()
+        .filter(|s| {
+            let steps = (*s / gain).round().max(1.0);
+            (*s - steps * gain).abs() <= 1.0
+        })
+        .count();
+    let regularity = regular as f32 / spacings.len() as f32;
+    vprintln!(
+        "Histogram stretch: gain {}, regularity {}, levels {}..{}",
+        gain,
+        regularity,
+        min,
+        max
+    );
+    if regularity < MIN_STRETCH_REGULARITY {
+        return None;
+    }
+
+    // Gaps at irregular positions can fit a small gain within the spacing tolerance, but
+    // then merge neighbouring levels when destretched
+    let pattern = StretchPattern {
+        min,
+        max,
+        gain,
+        regularity,
+    };
+    let mut originals: Vec<f32> = levels
+        .iter()
+        .map(|l| pattern.destretch_value(*l as f32))
+        .collect();
+    originals.dedup();
+    if originals.len() < levels.len() {
+        vprintln!("Stretch would merge levels, not a linear stretch");
+        return None;
+    }
+    Some(pattern)
+}
+
+/// Baseline destretch table: each gap of a single empty bin between occupied ones is
+/// collapsed, shifting every value above it down by one
+pub fn gap_collapse_lut(hist: &Histogram) -> Vec<f32> {
+    let last = hist.bins.len() - 1;
+    let mut removed = 0.0;
+    (0..hist.bins.len())
+        .map(|i| {
+            if i > 0
+                && i < last
+                && hist.bins[i] == 0
+                && hist.bins[i - 1] > 0
+                && hist.bins[i + 1] > 0
+            {
+                removed += 1.0;
+            }
+            i as f32 - removed
+        })
+        .collect()
+}
+
+/// Maps every value of every band through `lut`, clamping to its range
+fn apply_lut(image: &mut Image, lut: &[f32]) {
+    let last = lut.len() - 1;
+    for b in 0..image.num_bands() {
+        let mut band = image.get_band(b).clone();
+        for v in band.buffer.iter_mut() {
+            *v = lut[(v.round().max(0.0) as usize).min(last)];
+        }
+        image.set_band(&band, b);
+    }
+}
+
+/// Collapses the single bin gaps of the combined histogram of all bands, the fallback for
+/// stretches without a regular pattern. Returns the number of gaps collapsed.
+pub fn collapse_gaps(image: &mut Image) -> usize {
+    let hist = Histogram::of_image(image);
+    let collapsed = hist.gaps().iter().filter(|g| g.len == 1).count();
+    if collapsed > 0 {
+        apply_lut(image, &gap_collapse_lut(&hist));
+    }
+    collapsed
+}
+
+impl StretchPattern {
+    /// Original value of stretched value `v`. Values below the stretch minimum are kept.
+    pub fn destretch_value(&self, v: f32) -> f32 {
+        if v <= self.min as f32 {
+            v
+        } else {
+            self.min as f32 + ((v - self.min as f32) / self.gain).round()
+        }
+    }
+
+    /// Lookup table of original values for each of `num_bins` stretched values
+    pub fn destretch_lut(&self, num_bins: usize) -> Vec<f32> {
+        (0..num_bins)
+            .map(|v| self.destretch_value(v as f32))
+            .collect()
+    }
+}
+
+/// Undoes the stretch inferred from the combined histogram of all bands. Returns the
+/// stretch, or None, leaving the image untouched, if no regular gap pattern is found.
+pub fn destretch(image: &mut Image) -> Option<StretchPattern> {
+    let hist = Histogram::of_image(image);
+    let pattern = detect_stretch(&hist)?;
+    apply_lut(image, &pattern.destretch_lut(hist.bins.len()));
+    Some(pattern)
+}
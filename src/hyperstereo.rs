@@ -0,0 +1,693 @@
+use crate::{
+    anaglyph,
+    metadata::{load_image_metadata, Metadata},
+    prelude::*,
+    stereopair::{self, product_suffix},
+};
+use sciimg::{path, prelude::*, vector::Vector};
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+use std::collections::HashMap;
+use std::fs;
+use std::str::FromStr;
+
+/// Largest disparity, as a fraction of the image width, considered comfortable to view.
+/// Roughly the "1/30 rule" used by stereo photographers.
+pub const MAX_COMFORTABLE_DISPARITY: f64 = 1.0 / 30.0;
+
+/// Rover attitude as a unit quaternion (scalar first), rotating vectors from the rover
+/// frame into the site frame.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Quaternion {
+    pub s: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Quaternion {
+    pub fn identity() -> Self {
+        Quaternion {
+            s: 1.0,
+            x: 0.0,
+            y: 0.0,
+            z: 0.0,
+        }
+    }
+
+    /// Creates a normalized quaternion from a four element (s, x, y, z) vector
+    pub fn from_vec(v: &[f64]) -> Result<Self> {
+        if v.len() != 4 {
+            return Err(anyhow!("Attitude quaternion requires four elements"));
+        }
+        let norm = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2] + v[3] * v[3]).sqrt();
+        if norm == 0.0 || !norm.is_finite() {
+            return Err(anyhow!("Invalid attitude quaternion"));
+        }
+        Ok(Quaternion {
+            s: v[0] / norm,
+            x: v[1] / norm,
+            y: v[2] / norm,
+            z: v[3] / norm,
+        })
+    }
+
+    pub fn conjugate(&self) -> Self {
+        Quaternion {
+            s: self.s,
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
+
+    /// Rotates the vector by this quaternion
+    pub fn rotate(&self, v: &[f64; 3]) -> [f64; 3] {
+        // v' = v + 2s(q x v) + 2q x (q x v)
+        let q = [self.x, self.y, self.z];
+        let t = cross(&q, v).map(|c| c * 2.0);
+        let u = cross(&q, &t);
+        [
+            v[0] + self.s * t[0] + u[0],
+            v[1] + self.s * t[1] + u[1],
+            v[2] + self.s * t[2] + u[2],
+        ]
+    }
+}
+
+fn cross(a: &[f64; 3], b: &[f64; 3]) -> [f64; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn dot(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn length(a: &[f64; 3]) -> f64 {
+    dot(a, a).sqrt()
+}
+
+fn to_array(v: &Vector) -> [f64; 3] {
+    [v.x, v.y, v.z]
+}
+
+fn vec3(v: &Option<Vec<f64>>, name: &str) -> Result<[f64; 3]> {
+    match v {
+        Some(v) if v.len() == 3 => Ok([v[0], v[1], v[2]]),
+        _ => Err(anyhow!("Image metadata lacks a valid {}", name)),
+    }
+}
+
+/// Position and orientation of a camera at the time an image was acquired
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CameraPose {
+    pub site: u32,
+    pub drive: u32,
+
+    /// Rover position in the site frame (meters)
+    pub rover_position: [f64; 3],
+
+    /// Camera position in the rover frame (meters)
+    pub camera_position: [f64; 3],
+
+    /// Rover attitude relative to the site frame
+    pub attitude: Quaternion,
+}
+
+impl CameraPose {
+    /// Builds a pose from image metadata. Requires site, drive, rover xyz and camera
+    /// position. A missing attitude is treated as identity (the rover frame is aligned
+    /// with the site frame).
+    pub fn from_metadata(md: &Metadata) -> Result<Self> {
+        let site = md
+            .site
+            .ok_or_else(|| anyhow!("Image metadata lacks a site index"))?;
+        let drive = md
+            .drive
+            .ok_or_else(|| anyhow!("Image metadata lacks a drive index"))?;
+
+        let attitude = match &md.attitude {
+            Some(a) => Quaternion::from_vec(a)?,
+            None => {
+                vprintln!("Image metadata lacks rover attitude, assuming identity");
+                Quaternion::identity()
+            }
+        };
+
+        Ok(CameraPose {
+            site,
+            drive,
+            rover_position: vec3(&md.xyz, "rover position (xyz)")?,
+            camera_position: vec3(&md.camera_position, "camera position")?,
+            attitude,
+        })
+    }
+
+    /// Camera position in the site frame
+    pub fn site_position(&self) -> [f64; 3] {
+        let c = self.attitude.rotate(&self.camera_position);
+        [
+            self.rover_position[0] + c[0],
+            self.rover_position[1] + c[1],
+            self.rover_position[2] + c[2],
+        ]
+    }
+}
+
+/// Origins of site frames within a common frame (meters). Site frames share the same
+/// local level, north aligned orientation, so a translation carries positions between them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SiteFrames {
+    origins: HashMap<u32, [f64; 3]>,
+}
+
+impl SiteFrames {
+    pub fn new() -> Self {
+        SiteFrames::default()
+    }
+
+    pub fn insert(&mut self, site: u32, origin: [f64; 3]) {
+        self.origins.insert(site, origin);
+    }
+
+    /// Loads site origins from a JSON object mapping site indices to `[x, y, z]`
+    pub fn load(file_path: &str) -> Result<Self> {
+        let text = fs::read_to_string(file_path)?;
+        let raw: HashMap<String, Vec<f64>> = serde_json::from_str(&text)?;
+        let mut frames = SiteFrames::new();
+        for (site, origin) in raw.iter() {
+            let index = site
+                .trim()
+                .parse::<u32>()
+                .map_err(|_| anyhow!("Invalid site index '{}' in {}", site, file_path))?;
+            if origin.len() != 3 {
+                return Err(anyhow!(
+                    "Origin of site {} in {} requires three elements",
+                    index,
+                    file_path
+                ));
+            }
+            frames.insert(index, [origin[0], origin[1], origin[2]]);
+        }
+        Ok(frames)
+    }
+
+    /// Offset of the origin of site `to` from the origin of site `from`. None if either
+    /// origin is unknown.
+    pub fn offset(&self, from: u32, to: u32) -> Option<[f64; 3]> {
+        if from == to {
+            return Some([0.0, 0.0, 0.0]);
+        }
+        let f = self.origins.get(&from)?;
+        let t = self.origins.get(&to)?;
+        Some([t[0] - f[0], t[1] - f[1], t[2] - f[2]])
+    }
+}
+
+/// Separation between the two camera positions of a hyperstereo pair
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Baseline {
+    pub left: CameraPose,
+    pub right: CameraPose,
+
+    /// Vector from the left camera to the right camera in the left image's site frame (meters)
+    pub vector: [f64; 3],
+
+    /// Baseline length (meters)
+    pub length: f64,
+}
+
+impl Baseline {
+    /// Computes the baseline between two images within the same site
+    pub fn from_metadata(left: &Metadata, right: &Metadata) -> Result<Self> {
+        Baseline::from_metadata_with_sites(left, right, &SiteFrames::default())
+    }
+
+    /// Computes the baseline between two images. Images from different sites are carried
+    /// into the left image's site frame using the site origins in `sites`.
+    pub fn from_metadata_with_sites(
+        left: &Metadata,
+        right: &Metadata,
+        sites: &SiteFrames,
+    ) -> Result<Self> {
+        Baseline::from_poses(
+            CameraPose::from_metadata(left)?,
+            CameraPose::from_metadata(right)?,
+            sites,
+        )
+    }
+
+    /// Computes the baseline between two camera poses
+    pub fn from_poses(left: CameraPose, right: CameraPose, sites: &SiteFrames) -> Result<Self> {
+        let site_offset = match sites.offset(left.site, right.site) {
+            Some(o) => o,
+            None => {
+                return Err(anyhow!(
+                    "Images are from different sites ({} and {}) and the offset between their site frames is unknown",
+                    left.site,
+                    right.site
+                ))
+            }
+        };
+
+        let l = left.site_position();
+        let r = right.site_position();
+        let vector = [
+            r[0] + site_offset[0] - l[0],
+            r[1] + site_offset[1] - l[1],
+            r[2] + site_offset[2] - l[2],
+        ];
+        Ok(Baseline {
+            left,
+            right,
+            vector,
+            length: length(&vector),
+        })
+    }
+
+    /// Subject distance (meters) at which this baseline yields the maximum comfortable
+    /// disparity for a camera with the given angular pixel size (radians)
+    pub fn nearest_comfortable_distance(&self, width: usize, ifov: f64) -> f64 {
+        self.length / (MAX_COMFORTABLE_DISPARITY * width as f64 * ifov)
+    }
+}
+
+/// Expected disparity (pixels) of a subject at `distance` meters for a baseline of
+/// `baseline` meters and a camera angular pixel size of `ifov` radians.
+pub fn expected_disparity(baseline: f64, distance: f64, ifov: f64) -> f64 {
+    if distance <= 0.0 || ifov <= 0.0 {
+        f64::INFINITY
+    } else {
+        (baseline / distance) / ifov
+    }
+}
+
+/// Angular size (radians) of a single pixel at the center of the camera model
+pub fn ifov(model: &CameraModel, width: usize, height: usize) -> Result<f64> {
+    let look = |sample: f64, line: f64| -> Result<[f64; 3]> {
+        let lv = model.ls_to_look_vector(&ImageCoordinate { line, sample })?;
+        Ok(to_array(&lv.look_direction))
+    };
+    let cx = width as f64 / 2.0;
+    let cy = height as f64 / 2.0;
+    let a = look(cx, cy)?;
+    let b = look(cx + 1.0, cy)?;
+    let cos = dot(&a, &b) / (length(&a) * length(&b));
+    Ok(cos.clamp(-1.0, 1.0).acos())
+}
+
+/// Baseline and disparity summary for a hyperstereo pair
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HyperstereoReport {
+    pub baseline: Baseline,
+
+    /// Angular pixel size (radians) of the output camera model
+    pub ifov: f64,
+
+    /// Distance (meters) to the ground plane at the bottom center of the left image, if
+    /// the ground is visible
+    pub nearest_ground_distance: Option<f64>,
+
+    /// Expected disparity (pixels) at the nearest visible ground
+    pub max_disparity: Option<f64>,
+
+    /// Largest comfortable disparity (pixels) for the output width
+    pub comfortable_disparity: f64,
+
+    /// Nearest subject distance (meters) viewable comfortably with this baseline
+    pub nearest_comfortable_distance: f64,
+}
+
+impl HyperstereoReport {
+    /// Returns warnings for excessive disparity or an unusable baseline
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = vec![];
+        if self.baseline.length < 0.5 {
+            warnings.push(format!(
+                "Baseline of {:.3}m is no wider than a standard stereo camera pair",
+                self.baseline.length
+            ));
+        }
+        if let (Some(d), Some(r)) = (self.max_disparity, self.nearest_ground_distance) {
+            if d > self.comfortable_disparity {
+                warnings.push(format!(
+                    "Foreground at {:.1}m will have {:.0} pixels of disparity (comfortable limit {:.0}). Crop to terrain beyond {:.0}m",
+                    r, d, self.comfortable_disparity, self.nearest_comfortable_distance
+                ));
+            }
+        }
+        warnings
+    }
+}
+
+/// Distance from the camera to the ground plane along the bottom center pixel's look vector
+fn nearest_ground_distance(model: &CameraModel, width: usize, height: usize) -> Option<f64> {
+    let lv = model
+        .ls_to_look_vector(&ImageCoordinate {
+            line: (height - 1) as f64,
+            sample: width as f64 / 2.0,
+        })
+        .ok()?;
+    let ground = lv.intersect_to_plane(&anaglyph::ground_plane())?;
+    let offset = ground.subtract(&model.c());
+    Some(length(&to_array(&offset)))
+}
+
+/// Projects `img` into `output_model` at infinity. The output model is expressed in the rover
+/// frame of `output_pose`, the input model in the rover frame of `input_pose`. Only camera
+/// rotation is removed, leaving the parallax from the baseline intact.
+fn project_at_infinity(
+    img: &MarsImage,
+    map: &mut Image,
+    input_model: &CameraModel,
+    input_pose: &CameraPose,
+    output_model: &CameraModel,
+    output_pose: &CameraPose,
+) {
+    let to_input = input_pose.attitude.conjugate();
+    for y in 0..map.height {
+        for x in 0..map.width {
+            if let Ok(lv) = output_model.ls_to_look_vector(&ImageCoordinate {
+                line: y as f64,
+                sample: x as f64,
+            }) {
+                let site_dir = output_pose.attitude.rotate(&to_array(&lv.look_direction));
+                let d = to_input.rotate(&site_dir);
+                let ls_in = input_model.xyz_to_ls(&Vector::new(d[0], d[1], d[2]), true);
+
+                let in_x = ls_in.sample.round() as usize;
+                let in_y = ls_in.line.round() as usize;
+
+                if ls_in.sample >= 0.0
+                    && ls_in.line >= 0.0
+                    && in_x < img.image.width
+                    && in_y < img.image.height
+                {
+                    for b in 0..img.image.num_bands().min(map.num_bands()) {
+                        map.put(x, y, img.image.get_band(b).get(in_x, in_y), b);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Creates a wide-baseline anaglyph from two images taken at different rover positions. Both
+/// images are reprojected at infinity into the linearized left camera model so that only the
+/// parallax from the rover's movement remains. Images from different sites require their site
+/// origins in `sites`.
+pub fn create_hyperstereo_anaglyph(
+    left_img: &MarsImage,
+    right_img: &MarsImage,
+    options: &anaglyph::AnaglyphOptions,
+    sites: &SiteFrames,
+) -> Result<(Image, HyperstereoReport)> {
+    let (left_md, right_md) = match (&left_img.metadata, &right_img.metadata) {
+        (Some(l), Some(r)) => (l, r),
+        _ => return Err(anyhow!("Hyperstereo requires metadata for both images")),
+    };
+
+    let baseline = Baseline::from_metadata_with_sites(left_md, right_md, sites)?;
+    vprintln!(
+        "Baseline: {:.3}m (site {}, drive {} -> site {}, drive {})",
+        baseline.length,
+        baseline.left.site,
+        baseline.left.drive,
+        baseline.right.site,
+        baseline.right.drive
+    );
+
+    let left_cahv = anaglyph::get_camera_model(left_img)?;
+    let right_cahv = anaglyph::get_camera_model(right_img)?;
+
+    let width = left_img.image.width;
+    let height = left_img.image.height;
+    let output_model = left_cahv.linearize(width, height, width, height)?;
+
+    let pixel_size = ifov(&output_model, width, height)?;
+    let nearest = nearest_ground_distance(&output_model, width, height);
+    let report = HyperstereoReport {
+        baseline,
+        ifov: pixel_size,
+        nearest_ground_distance: nearest,
+        max_disparity: nearest.map(|r| expected_disparity(baseline.length, r, pixel_size)),
+        comfortable_disparity: MAX_COMFORTABLE_DISPARITY * width as f64,
+        nearest_comfortable_distance: baseline.nearest_comfortable_distance(width, pixel_size),
+    };
+
+    let mut left_map = Image::create(width, height);
+    project_at_infinity(
+        left_img,
+        &mut left_map,
+        &left_cahv,
+        &baseline.left,
+        &output_model,
+        &baseline.left,
+    );
+
+    let mut right_map = Image::create(width, height);
+    project_at_infinity(
+        right_img,
+        &mut right_map,
+        &right_cahv,
+        &baseline.right,
+        &output_model,
+        &baseline.left,
+    );
+
+    let vertical_offset = if options.auto_vertical {
+        anaglyph::estimate_vertical_disparity(
+            &left_map,
+            &right_map,
+            anaglyph::MAX_VERTICAL_DISPARITY,
+        )
+    } else {
+        options.vertical_offset
+    };
+
+    if options.horizontal_offset != 0 || vertical_offset != 0 {
+        right_map = anaglyph::shift_image(&right_map, -options.horizontal_offset, vertical_offset);
+    }
+
+    let max_value = util::max_value_for_mode(left_img.image.get_mode());
+    Ok((
+        anaglyph::combine_eyes(&left_map, &right_map, options.mode, max_value),
+        report,
+    ))
+}
+
+/// Options controlling hyperstereo pair discovery
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct HyperstereoCriteria {
+    /// Minimum baseline across the viewing direction (meters)
+    pub min_baseline: f64,
+
+    /// Maximum baseline length (meters)
+    pub max_baseline: f64,
+
+    /// Maximum angle between the pointing of the two cameras (degrees)
+    pub max_pointing_difference: f64,
+}
+
+impl Default for HyperstereoCriteria {
+    fn default() -> Self {
+        HyperstereoCriteria {
+            min_baseline: 0.5,
+            max_baseline: 100.0,
+            max_pointing_difference: 10.0,
+        }
+    }
+}
+
+/// An image considered for hyperstereo pairing
+#[derive(Clone)]
+pub struct HyperstereoCandidate {
+    pub file_path: String,
+    pub instrument: Instrument,
+    pub sclk: f64,
+    pub pose: CameraPose,
+
+    /// Camera pointing (the camera model's A vector) in the site frame
+    pub pointing: [f64; 3],
+    pub metadata: Metadata,
+}
+
+/// A discovered hyperstereo pair
+#[derive(Debug, Clone, PartialEq)]
+pub struct HyperstereoPair {
+    pub left: String,
+    pub right: String,
+    pub instrument: Instrument,
+
+    /// Baseline length (meters)
+    pub baseline: f64,
+}
+
+fn angle_between(a: &[f64; 3], b: &[f64; 3]) -> f64 {
+    let cos = dot(a, b) / (length(a) * length(b));
+    cos.clamp(-1.0, 1.0).acos().to_degrees()
+}
+
+impl HyperstereoCandidate {
+    /// Constructs a candidate from an image path and its metadata. Returns an error if the
+    /// image lacks a spacecraft clock, camera pose or camera model.
+    pub fn new_from_metadata(file_path: &str, metadata: &Metadata) -> Result<Self> {
+        let sclk = match metadata.sclk {
+            Some(s) => s,
+            None => return Err(anyhow!("Image lacks a spacecraft clock value")),
+        };
+        let pose = CameraPose::from_metadata(metadata)?;
+        if !metadata.camera_model_component_list.is_valid() {
+            return Err(anyhow!("Image lacks a camera model"));
+        }
+        let a = to_array(&metadata.camera_model_component_list.a());
+
+        Ok(HyperstereoCandidate {
+            file_path: file_path.to_string(),
+            instrument: Instrument::from_str(&metadata.instrument).unwrap(),
+            sclk,
+            pose,
+            pointing: pose.attitude.rotate(&a),
+            metadata: metadata.clone(),
+        })
+    }
+
+    /// Constructs a candidate from an image path, loading the accompanying metadata file
+    pub fn new_from_file(file_path: &str) -> Result<Self> {
+        let metadata_file = util::replace_image_extension(file_path, "-metadata.json");
+        if !path::file_exists(&metadata_file) {
+            return Err(anyhow!("Metadata file not found for {}", file_path));
+        }
+        let md = load_image_metadata(&metadata_file)?;
+        HyperstereoCandidate::new_from_metadata(file_path, &md)
+    }
+
+    /// Returns the pair formed with `other` if both were taken by the same camera with the same
+    /// processing, point the same way and are separated by a usable baseline. The eyes are
+    /// assigned by which camera sits to the left when looking along the mean pointing.
+    pub fn pair_with(
+        &self,
+        other: &HyperstereoCandidate,
+        criteria: &HyperstereoCriteria,
+        sites: &SiteFrames,
+    ) -> Option<HyperstereoPair> {
+        if self.instrument != other.instrument
+            || product_suffix(&self.file_path, &self.metadata.imageid)
+                != product_suffix(&other.file_path, &other.metadata.imageid)
+        {
+            return None;
+        }
+
+        if angle_between(&self.pointing, &other.pointing) > criteria.max_pointing_difference {
+            return None;
+        }
+
+        let baseline = Baseline::from_poses(self.pose, other.pose, sites).ok()?;
+        if baseline.length > criteria.max_baseline {
+            return None;
+        }
+
+        // The baseline runs from this camera to the other, expressed in this camera's site
+        // frame. Site frames point +Z down, so up is (0, 0, -1) and the viewer's right is the
+        // mean pointing crossed with up. The baseline's component along that direction is the
+        // lateral separation, which must be wide enough and tells which camera is the left eye.
+        let a = self.pointing.map(|c| c / length(&self.pointing));
+        let b = other.pointing.map(|c| c / length(&other.pointing));
+        let forward = [a[0] + b[0], a[1] + b[1], a[2] + b[2]];
+        let side = cross(&forward, &[0.0, 0.0, -1.0]);
+        if length(&side) < 1e-6 {
+            return None;
+        }
+        let lateral = dot(&baseline.vector, &side) / length(&side);
+        if lateral.abs() < criteria.min_baseline {
+            return None;
+        }
+
+        let (left, right) = if lateral > 0.0 {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        Some(HyperstereoPair {
+            left: left.file_path.clone(),
+            right: right.file_path.clone(),
+            instrument: self.instrument,
+            baseline: baseline.length,
+        })
+    }
+}
+
+/// Pairs candidates into hyperstereo pairs. Each image is used at most once, with the partner
+/// pointing most nearly the same way winning. Pairs are returned in spacecraft clock order.
+pub fn find_pairs(
+    candidates: &[HyperstereoCandidate],
+    criteria: &HyperstereoCriteria,
+    sites: &SiteFrames,
+) -> Vec<HyperstereoPair> {
+    let mut sorted: Vec<&HyperstereoCandidate> = candidates.iter().collect();
+    sorted.sort_by(|a, b| a.sclk.total_cmp(&b.sclk));
+    let mut used = vec![false; sorted.len()];
+
+    let mut pairs = vec![];
+    for i in 0..sorted.len() {
+        if used[i] {
+            continue;
+        }
+        let best = (i + 1..sorted.len())
+            .filter(|j| !used[*j])
+            .filter_map(|j| {
+                sorted[i]
+                    .pair_with(sorted[j], criteria, sites)
+                    .map(|p| (j, p))
+            })
+            .min_by(|(a, _), (b, _)| {
+                let da = angle_between(&sorted[i].pointing, &sorted[*a].pointing);
+                let db = angle_between(&sorted[i].pointing, &sorted[*b].pointing);
+                da.total_cmp(&db)
+            });
+
+        if let Some((j, pair)) = best {
+            used[i] = true;
+            used[j] = true;
+            vprintln!(
+                "Matched hyperstereo pair: {} / {} ({:.3}m)",
+                pair.left,
+                pair.right,
+                pair.baseline
+            );
+            pairs.push(pair);
+        }
+    }
+    pairs
+}
+
+/// Builds candidates from a list of image files, skipping those that are not usable for pairing
+pub fn candidates_from_files(input_files: &[String]) -> Vec<HyperstereoCandidate> {
+    input_files
+        .iter()
+        .filter_map(|f| match HyperstereoCandidate::new_from_file(f) {
+            Ok(c) => Some(c),
+            Err(why) => {
+                vprintln!("Skipping {}: {}", f, why);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Scans a directory and returns all hyperstereo pairs found
+pub fn find_pairs_in_directory(
+    directory: &str,
+    criteria: &HyperstereoCriteria,
+    sites: &SiteFrames,
+) -> Vec<HyperstereoPair> {
+    let files = stereopair::list_images_in_directory(directory);
+    vprintln!("Found {} images in {}", files.len(), directory);
+    find_pairs(&candidates_from_files(&files), criteria, sites)
+}
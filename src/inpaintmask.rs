@@ -1,5 +1,6 @@
 // https://www.researchgate.net/publication/238183352_An_Image_Inpainting_Technique_Based_on_the_Fast_Marching_Method
 
+use crate::calibfile::ObservationTime;
 use crate::{calibfile, constants, enums, memcache, vprintln};
 
 use sciimg::{imagebuffer::ImageBuffer, path};
@@ -7,16 +8,23 @@ use sciimg::{imagebuffer::ImageBuffer, path};
 use anyhow::anyhow;
 use anyhow::Result;
 
-fn determine_mask_file(instrument: enums::Instrument) -> Result<String> {
-    calibfile::get_calibration_file_for_instrument(instrument, enums::CalFileType::InpaintMask)
+use std::sync::Arc;
+
+fn determine_mask_file(instrument: enums::Instrument, when: &ObservationTime) -> Result<String> {
+    calibfile::get_calibration_file_for_instrument_at(
+        instrument,
+        enums::CalFileType::InpaintMask,
+        when,
+    )
 }
 
 pub fn inpaint_supported_for_instrument(instrument: enums::Instrument) -> bool {
-    let r = determine_mask_file(instrument);
+    let r = determine_mask_file(instrument, &ObservationTime::default());
     r.is_ok()
 }
 
-fn load_mask_file(filename: &str, instrument: enums::Instrument) -> Result<ImageBuffer> {
+/// Loads a mask, shared with the calibration file cache unless it has to be cut down
+fn load_mask_file(filename: &str, instrument: enums::Instrument) -> Result<Arc<ImageBuffer>> {
     vprintln!("Loading inpaint mask file {}", filename);
 
     if !path::file_exists(filename) {
@@ -29,13 +37,21 @@ fn load_mask_file(filename: &str, instrument: enums::Instrument) -> Result<Image
     };
 
     match instrument {
-        enums::Instrument::MslMAHLI => mask.get_subframe(32, 16, 1584, 1184),
+        enums::Instrument::MslMAHLI => Ok(Arc::new(mask.get_subframe(32, 16, 1584, 1184)?)),
         _ => Ok(mask),
     }
 }
 
-pub fn load_mask(instrument: enums::Instrument) -> Result<ImageBuffer> {
-    let mask_file = match determine_mask_file(instrument) {
+pub fn load_mask(instrument: enums::Instrument) -> Result<Arc<ImageBuffer>> {
+    load_mask_at(instrument, &ObservationTime::default())
+}
+
+/// Loads the inpaint mask valid for an image taken at `when`
+pub fn load_mask_at(
+    instrument: enums::Instrument,
+    when: &ObservationTime,
+) -> Result<Arc<ImageBuffer>> {
+    let mask_file = match determine_mask_file(instrument, when) {
         Ok(m) => m,
         Err(e) => return Err(e),
     };
@@ -50,10 +50,11 @@ fn vec_to_str(v: &[f64]) -> String {
 
 fn str_to_vec(s: &str) -> Result<Vec<f64>> {
     let mut tuple_vec: Vec<f64> = Vec::new();
-    let mut s0 = String::from(s);
-    s0.remove(0);
-    s0.remove(s0.len() - 1);
-    let split = s0.split(',');
+    let mut chars = s.trim().chars();
+    if chars.next().is_none() || chars.next_back().is_none() {
+        return Err(anyhow!(constants::status::INVALID_FLOAT_VALUE));
+    }
+    let split = chars.as_str().split(',');
     for n in split {
         let n_t = n.trim();
         if string_is_valid_f64(n_t) {
@@ -66,6 +67,15 @@ fn str_to_vec(s: &str) -> Result<Vec<f64>> {
     Ok(tuple_vec)
 }
 
+/// Reads a number the raw image APIs may encode either as a number or as a string
+pub fn value_to_f64(value: &Option<Value>) -> Option<f64> {
+    match value {
+        Some(Value::Number(n)) => n.as_f64(),
+        Some(Value::String(s)) => s.trim().parse::<f64>().ok(),
+        _ => None,
+    }
+}
+
 pub fn default_vec_f64_none() -> Option<Vec<f64>> {
     None
 }
@@ -267,10 +277,7 @@ pub mod tuple_format {
             Err(_) => Ok(None),
             Ok(s) => match s {
                 "UNK" => Ok(None),
-                _ => {
-                    let tuple_vec = str_to_vec(s).unwrap();
-                    Ok(Some(tuple_vec))
-                }
+                _ => Ok(str_to_vec(s).ok()),
             },
         }
     }
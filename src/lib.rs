@@ -11,12 +11,18 @@ pub mod anaglyph;
 /// Support for calibration file loading
 pub mod calibfile;
 
+/// Flat field and bad pixel mask generation
+pub mod calgen;
+
 /// Calibration entrypoint
 pub mod calibrate;
 
 /// Support for calibration specification profiles
 pub mod calprofile;
 
+/// Calibration target based color correction
+pub mod colorcorrect;
+
 /// Image linearization and mosaic compositing
 pub mod composite;
 
@@ -44,9 +50,21 @@ pub mod flatfield;
 /// Focus stack processing
 pub mod focusmerge;
 
+/// Discovery of MAHLI and WATSON focus stacks
+pub mod focusstack;
+
+/// Animated image and frame sequence writers
+pub mod framesink;
+
+/// Per-band histograms, gap analysis and destretching of stretched 8 bit products
+pub mod histogram;
+
 /// Remote data retrieval via HTTP
 pub mod httpfetch;
 
+/// Wide-baseline stereo from images at different rover positions
+pub mod hyperstereo;
+
 /// Extensions to `RgbImage` to support Mars mission image data
 pub mod marsimage;
 
@@ -68,15 +86,51 @@ pub mod metadata;
 /// Routines for Mars Science Laboratory Curiosity Rover processing
 pub mod msl;
 
+/// Moving feature (dust devil, cloud) detection and tracking
+pub mod motiondetect;
+
 /// Routines for InSight image processing
 pub mod nsyt;
 
+/// Colored point cloud and textured mesh export from per-pixel XYZ
+pub mod pointcloud;
+
 /// Single-point import for most utilized MRU API
 pub mod prelude;
 
 /// Utilities for outputting verbose and error text
 pub mod print;
 
+/// Image registration and alignment of image stacks
+pub mod registration;
+
+/// Multispectral filter cubes and ROI spectra for Mastcam-Z and MSL Mastcam
+pub mod spectral;
+
+/// Band ratio, band depth and slope maps and false color composites from filter cubes
+pub mod spectralparams;
+
+/// Median, sigma-clipped, winsorized, min and max stacking of image sequences
+pub mod stacking;
+
+/// Side-by-side, over-under, interleaved and MPO stereo output
+pub mod stereoformat;
+
+/// Stereo pair discovery from image metadata
+pub mod stereopair;
+
+/// Drizzle multi-frame super-resolution
+pub mod superres;
+
+/// Reassembly of subframed images onto the full sensor
+pub mod tileassembly;
+
+/// Grouping of Mars 2020 engineering camera tiles into frames
+pub mod tilegroup;
+
+/// Global tile level matching and gradient domain seam removal
+pub mod tilelevels;
+
 /// Time and date support
 pub mod time;
 
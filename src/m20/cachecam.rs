@@ -30,7 +30,12 @@ impl Calibration for M20CacheCam {
 
         let data_max = if cal_context.apply_ilt {
             vprintln!("Decompanding...");
-            let lut = decompanding::get_ilt_for_instrument(enums::Instrument::M20CacheCam).unwrap();
+            let lut = decompanding::get_ilt_for_image(
+                cal_context,
+                enums::Instrument::M20CacheCam,
+                &raw.image,
+                &raw.observation_time(),
+            )?;
             raw.decompand(&lut);
             lut.max() as f32
         } else {
@@ -5,6 +5,7 @@ use crate::{
 
 use anyhow::Result;
 use sciimg::{image::Image, path, prelude::ImageBuffer};
+use std::sync::Arc;
 
 #[derive(Copy, Clone)]
 pub struct M20EECam {}
@@ -90,7 +91,12 @@ impl Calibration for M20EECam {
 
         let data_max = if cal_context.apply_ilt {
             vprintln!("Decompanding...");
-            let lut = decompanding::get_ilt_for_instrument(instrument).unwrap();
+            let lut = decompanding::get_ilt_for_image(
+                cal_context,
+                instrument,
+                &raw.image,
+                &raw.observation_time(),
+            )?;
             raw.decompand(&lut);
             lut.max() as f32
         } else {
@@ -111,10 +117,13 @@ impl Calibration for M20EECam {
         };
         let scale_factor_str = format!("sf{}", scale_factor);
 
+        // Flat and mask are shared with the calibration file cache and only copied when
+        // cropped to a subframe
         // let mut flat = flatfield::load_flat(instrument).unwrap();
-        let mut flat = match calibfile::get_calibration_file_for_instrument(
+        let mut flat: Arc<Image> = match calibfile::get_calibration_file_for_instrument_at(
             instrument,
             enums::CalFileType::FlatField,
+            &raw.observation_time(),
         ) {
             Ok(s) => {
                 let flat_file_path = s.replace("-scalefactor-", scale_factor_str.as_str());
@@ -123,7 +132,7 @@ impl Calibration for M20EECam {
                     scale_factor,
                     flat_file_path
                 );
-                load_image(&flat_file_path).unwrap()
+                load_image(&flat_file_path)?
             }
             Err(why) => {
                 vprintln!(
@@ -131,14 +140,15 @@ impl Calibration for M20EECam {
                     instrument,
                     why
                 );
-                Image::new_empty().unwrap()
+                Arc::new(Image::new_empty().unwrap())
             }
         };
 
         vprintln!("Loading image mask");
-        let mut mask = match calibfile::get_calibration_file_for_instrument(
+        let mut mask: Arc<Image> = match calibfile::get_calibration_file_for_instrument_at(
             instrument,
             enums::CalFileType::Mask,
+            &raw.observation_time(),
         ) {
             Ok(s) => {
                 let mask_file_path = s.replace("-scalefactor-", scale_factor_str.as_str());
@@ -147,7 +157,7 @@ impl Calibration for M20EECam {
                     scale_factor,
                     mask_file_path
                 );
-                load_image(&mask_file_path).unwrap()
+                load_image(&mask_file_path)?
             }
             Err(why) => {
                 vprintln!(
@@ -155,13 +165,13 @@ impl Calibration for M20EECam {
                     instrument,
                     why
                 );
-                Image::new_empty().unwrap()
+                Arc::new(Image::new_empty().unwrap())
             }
         };
 
         if let Some(md) = raw.metadata.clone() {
             if let Some(rect) = &md.subframe_rect {
-                flat.crop(
+                Arc::make_mut(&mut flat).crop(
                     (rect[0] as usize - 1) / scale_factor as usize,
                     (rect[1] as usize - 1) / scale_factor as usize,
                     (rect[2] as usize) / scale_factor as usize,
@@ -169,7 +179,7 @@ impl Calibration for M20EECam {
                 );
 
                 if !mask.is_empty() {
-                    mask.crop(
+                    Arc::make_mut(&mut mask).crop(
                         (rect[0] as usize - 1) / scale_factor as usize,
                         (rect[1] as usize - 1) / scale_factor as usize,
                         (rect[2] as usize) / scale_factor as usize,
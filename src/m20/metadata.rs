@@ -29,6 +29,10 @@ pub struct Extended {
 
     #[serde(with = "crate::jsonfetch::tuple_format")]
     pub dimension: Option<Vec<f64>>,
+
+    /// WATSON focus motor position
+    #[serde(default, alias = "focusMotorCount", alias = "motorCount")]
+    pub focus_motor_count: Option<serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -59,7 +63,8 @@ pub struct Camera {
 pub struct ImageRecord {
     pub extended: Extended,
     pub sol: u32,
-    pub attitude: String,
+    #[serde(with = "crate::jsonfetch::tuple_format")]
+    pub attitude: Option<Vec<f64>>,
     pub image_files: ImageFiles,
     pub imageid: String,
     pub camera: Camera,
@@ -167,6 +172,14 @@ impl ImageMetadata for ImageRecord {
         self.camera.camera_position.clone()
     }
 
+    fn get_attitude(&self) -> Option<Vec<f64>> {
+        self.attitude.clone()
+    }
+
+    fn get_focus_motor_count(&self) -> Option<f64> {
+        crate::jsonfetch::value_to_f64(&self.extended.focus_motor_count)
+    }
+
     fn get_camera_model_type(&self) -> Option<String> {
         Some(self.camera.camera_model_type.clone())
     }
@@ -1,4 +1,4 @@
-/// Support for assembling NavCam tiles
+/// Support for assembling NavCam tiles. `tileassembly` assembles subframes of any camera.
 pub mod assemble;
 
 /// Calibration routines for M20 CacheCam
@@ -25,7 +25,7 @@ pub mod metadata;
 /// Support for calculating realtime mission time
 pub mod missiontime;
 
-/// Support for intensity matching navcam tiles
+/// Support for intensity matching navcam tiles. `tilelevels` matches tiles of any camera.
 pub mod ncamlevels;
 
 /// Calibration routines for M20 PIXL images
@@ -31,9 +31,10 @@ impl Calibration for M20SuperCam {
         raw.destretch_image();
 
         vprintln!("Loading image mask");
-        let mask_file_path = calibfile::get_calibration_file_for_instrument(
+        let mask_file_path = calibfile::get_calibration_file_for_instrument_at(
             enums::Instrument::M20SuperCam,
             enums::CalFileType::Mask,
+            &raw.observation_time(),
         )
         .unwrap();
         vprintln!("Loading supercam mask from {}", mask_file_path);
@@ -45,8 +46,12 @@ impl Calibration for M20SuperCam {
 
         let data_max = if cal_context.apply_ilt {
             vprintln!("Decompanding...");
-            let lut = decompanding::get_ilt_for_instrument(enums::Instrument::M20SuperCam)
-                .expect("Failed to determine ILT for M20 SuperCam");
+            let lut = decompanding::get_ilt_for_image(
+                cal_context,
+                enums::Instrument::M20SuperCam,
+                &raw.image,
+                &raw.observation_time(),
+            )?;
             raw.decompand(&lut);
             lut.max() as f32
         } else {
@@ -63,8 +68,9 @@ impl Calibration for M20SuperCam {
         raw.image
             .crop(1, 1, raw.image.width - 2, raw.image.height - 2);
         vprintln!("Flatfielding...");
-        let mut flat = flatfield::load_flat(enums::Instrument::M20SuperCam)
-            .expect("Failed to load flatfield image for M20 SuperCam");
+        let mut flat =
+            flatfield::load_flat_at(enums::Instrument::M20SuperCam, &raw.observation_time())
+                .expect("Failed to load flatfield image for M20 SuperCam");
         flat.image
             .crop(1, 1, flat.image.width - 2, flat.image.height - 2);
         raw.flatfield_with_flat(&flat);
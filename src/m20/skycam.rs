@@ -30,8 +30,10 @@ impl Calibration for M20SkyCam {
         let mut raw = MarsImage::open(String::from(input_file), enums::Instrument::M20SkyCam);
 
         vprintln!("Flatfielding...");
-        let flat = flatfield::load_flat(enums::Instrument::M20SkyCam).unwrap();
-        raw.flatfield_with_flat(&flat);
+        let flat =
+            flatfield::load_flat_image_at(enums::Instrument::M20SkyCam, &raw.observation_time())
+                .unwrap();
+        raw.apply_flat(&flat);
 
         if cal_context.hot_pixel_detection_threshold > 0.0 {
             vprintln!(
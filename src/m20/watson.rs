@@ -7,6 +7,8 @@ use sciimg::path;
 
 use anyhow::Result;
 
+use std::sync::Arc;
+
 #[derive(Copy, Clone)]
 pub struct M20Watson {}
 
@@ -31,7 +33,12 @@ impl Calibration for M20Watson {
 
         let data_max = if cal_context.apply_ilt {
             vprintln!("Decompanding...");
-            let lut = decompanding::get_ilt_for_instrument(enums::Instrument::M20Watson).unwrap();
+            let lut = decompanding::get_ilt_for_image(
+                cal_context,
+                enums::Instrument::M20Watson,
+                &raw.image,
+                &raw.observation_time(),
+            )?;
             raw.decompand(&lut);
             lut.max() as f32
         } else {
@@ -44,16 +51,19 @@ impl Calibration for M20Watson {
         }
 
         vprintln!("Flatfielding...");
-        let mut flat = flatfield::load_flat(enums::Instrument::M20Watson).unwrap();
+        let mut flat =
+            flatfield::load_flat_at(enums::Instrument::M20Watson, &raw.observation_time()).unwrap();
         if raw.image.width == 1584 && raw.image.height == 1184 {
             flat.image.crop(32, 16, 1584, 1184);
         }
         raw.flatfield_with_flat(&flat);
 
         vprintln!("Inpainting...");
-        let mut inpaint_mask = inpaintmask::load_mask(enums::Instrument::M20Watson).unwrap();
+        let mut inpaint_mask =
+            inpaintmask::load_mask_at(enums::Instrument::M20Watson, &raw.observation_time())
+                .unwrap();
         if raw.image.width == 1584 && raw.image.height == 1184 {
-            inpaint_mask = inpaint_mask.get_subframe(32, 16, 1584, 1184).unwrap();
+            inpaint_mask = Arc::new(inpaint_mask.get_subframe(32, 16, 1584, 1184).unwrap());
         }
         raw.apply_inpaint_fix_with_mask(&inpaint_mask);
 
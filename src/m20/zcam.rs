@@ -8,6 +8,8 @@ use sciimg::prelude::*;
 use anyhow::anyhow;
 use anyhow::Result;
 
+use std::sync::Arc;
+
 pub const MASTCAMZ_PIXEL_SIZE_MM: f32 = 0.0074;
 pub const FOCAL_STOPS: [f32; 7] = [26.0, 34.0, 48.0, 63.0, 79.0, 100.0, 110.0];
 pub const MOTOR_COUNT_STOPS: [u16; 7] = [0, 2448, 3834, 5196, 6720, 8652, 9600];
@@ -88,7 +90,12 @@ impl Calibration for M20MastcamZ {
 
         let data_max = if cal_context.apply_ilt {
             vprintln!("Decompanding...");
-            let lut = decompanding::get_ilt_for_instrument(instrument).unwrap();
+            let lut = decompanding::get_ilt_for_image(
+                cal_context,
+                instrument,
+                &raw.image,
+                &raw.observation_time(),
+            )?;
             raw.decompand(&lut);
             lut.max() as f32
         } else {
@@ -131,9 +138,10 @@ impl Calibration for M20MastcamZ {
                 vprintln!("Flatfielding...");
                 vprintln!("Determined camera focal length at {}mm", fl);
 
-                let calfile = calibfile::get_calibration_file_for_instrument(
+                let calfile = calibfile::get_calibration_file_for_instrument_at(
                     instrument,
                     enums::CalFileType::FlatField,
+                    &raw.observation_time(),
                 )
                 .unwrap();
 
@@ -171,17 +179,20 @@ impl Calibration for M20MastcamZ {
         };
 
         vprintln!("Inpainting...");
-        let mut inpaint_mask = inpaintmask::load_mask(instrument).unwrap();
+        let mut inpaint_mask =
+            inpaintmask::load_mask_at(instrument, &raw.observation_time()).unwrap();
         if let Some(md) = &raw.metadata {
             if let Some(rect) = &md.subframe_rect {
-                inpaint_mask = inpaint_mask
-                    .get_subframe(
-                        rect[0] as usize - 1,
-                        rect[1] as usize - 1,
-                        rect[2] as usize,
-                        rect[3] as usize,
-                    )
-                    .unwrap();
+                inpaint_mask = Arc::new(
+                    inpaint_mask
+                        .get_subframe(
+                            rect[0] as usize - 1,
+                            rect[1] as usize - 1,
+                            rect[2] as usize,
+                            rect[3] as usize,
+                        )
+                        .unwrap(),
+                );
             }
         }
         raw.apply_inpaint_fix_with_mask(&inpaint_mask);
@@ -1,12 +1,16 @@
+use crate::calibfile::ObservationTime;
+use crate::histogram::{self, Histogram};
 use crate::{
-    decompanding::LookUpTable, enums, flatfield, inpaintmask, metadata::*, util, vprintln,
+    caldata, decompanding::LookUpTable, enums, flatfield, inpaintmask, metadata::*, util, vprintln,
 };
 
 use sciimg::{
     debayer::DebayerMethod, drawable::Drawable, enums::ImageMode, image::Image,
-    imagebuffer::ImageBuffer, inpaint, path, DnVec, VecMath,
+    imagebuffer::ImageBuffer, inpaint, path, DnVec,
 };
 
+use std::sync::Arc;
+
 #[derive(Clone)]
 pub struct MarsImage {
     pub image: Image,
@@ -134,7 +138,9 @@ impl MarsImage {
 
         if let Some(ref mut md) = self.metadata {
             md.decompand = true;
+            md.decompand_lut = Some(ilt.name.clone());
         }
+        self.record_caldata_version();
     }
 
     pub fn compand(&mut self, ilt: &LookUpTable) {
@@ -142,6 +148,7 @@ impl MarsImage {
 
         if let Some(ref mut md) = self.metadata {
             md.decompand = false;
+            md.decompand_lut = None;
         }
     }
 
@@ -151,6 +158,17 @@ impl MarsImage {
         if let Some(ref mut md) = self.metadata {
             md.flatfield = true;
         }
+        self.record_caldata_version();
+    }
+
+    /// Notes the installed calibration data version in the metadata, if one was recorded by
+    /// `mru update-cal-data`. The version is resolved once per data path, not per image.
+    fn record_caldata_version(&mut self) {
+        if let Some(ref mut md) = self.metadata {
+            if let Some(installed) = caldata::current_installed_version() {
+                md.caldata_version = installed.version;
+            }
+        }
     }
 
     pub fn flatfield_with_flat(&mut self, flat: &MarsImage) {
@@ -161,8 +179,16 @@ impl MarsImage {
         self.image.crop(x, y, width, height);
     }
 
+    /// Sol and UTC time the image was taken, used to select calibration file versions
+    pub fn observation_time(&self) -> ObservationTime {
+        ObservationTime::from_metadata(&self.metadata)
+    }
+
     pub fn flatfield(&mut self) {
-        let mut flat = if let Ok(flat) = flatfield::load_flat(self.instrument) {
+        // Shared with the calibration file cache, copied only if it needs cropping
+        let mut flat = if let Ok(flat) =
+            flatfield::load_flat_image_at(self.instrument, &self.observation_time())
+        {
             flat
         } else {
             vprintln!("No flat field found for instrument {:?}", self.instrument);
@@ -184,7 +210,7 @@ impl MarsImage {
                 sf[3]
             );
 
-            flat.image.crop(
+            Arc::make_mut(&mut flat).crop(
                 sf[0] as usize - 1,
                 sf[1] as usize - 1,
                 sf[2] as usize,
@@ -195,9 +221,9 @@ impl MarsImage {
         // If the flat is still too big we'll
         // crop the flatfield image if it's larger than the input image.
         // Sizes need to match
-        if flat.image.width > self.image.width {
-            let x = (flat.image.width - self.image.width) / 2;
-            let y = (flat.image.height - self.image.height) / 2;
+        if flat.width > self.image.width {
+            let x = (flat.width - self.image.width) / 2;
+            let y = (flat.height - self.image.height) / 2;
             vprintln!(
                 "Cropping flat with x/y/width/height: {},{} {}x{}",
                 x,
@@ -205,7 +231,7 @@ impl MarsImage {
                 self.image.width,
                 self.image.height
             );
-            flat.image.crop(x, y, self.image.width, self.image.height);
+            Arc::make_mut(&mut flat).crop(x, y, self.image.width, self.image.height);
         }
 
         // if inpaint::inpaint_supported_for_instrument(self.instrument) {
@@ -213,7 +239,7 @@ impl MarsImage {
         // } else {
         //     vprintln!("No inpaint available for flatfield image on {:?}", self.instrument);
         // }
-        self.apply_flat(&flat.image);
+        self.apply_flat(&flat);
     }
 
     pub fn apply_alpha(&mut self, mask: &ImageBuffer) {
@@ -229,7 +255,7 @@ impl MarsImage {
     }
 
     pub fn apply_inpaint_fix(&mut self) {
-        let mask = inpaintmask::load_mask(self.instrument).unwrap();
+        let mask = inpaintmask::load_mask_at(self.instrument, &self.observation_time()).unwrap();
         self.apply_inpaint_fix_with_mask(&mask);
     }
 
@@ -255,60 +281,31 @@ impl MarsImage {
         self.image.resize_to(to_width, to_height);
     }
 
+    /// Histogram of `band`, with 256 bins for 8 bit images and 65536 otherwise
     pub fn calc_histogram(&self, band: usize) -> DnVec {
-        let buffer = self.image.get_band(band);
-        let mut hist = DnVec::fill(255, 0.0);
-        (0..buffer.buffer.len()).for_each(|i| {
-            let r = self.image.get_band(0).buffer[i];
-            let g = self.image.get_band(1).buffer[i];
-            let b = self.image.get_band(2).buffer[i];
-            hist[r as usize] += 1.0;
-            hist[g as usize] += 1.0;
-            hist[b as usize] += 1.0;
-        });
-        hist
-    }
-
-    fn is_index_a_histogram_gap(hist: &DnVec, index: usize) -> bool {
-        // We will define a histogram gap as an index with a zero value that is bounded by
-        // non-zero values.
-        if index == 0 || index == 254 {
-            // So by definition, the zeroth and last index cannot be a gap
-            false
-        } else {
-            hist[index] == 0.0 && hist[index - 1] > 0.0 && hist[index + 1] > 0.0
-        }
-    }
-
-    fn compute_destretch_lut(&self) -> DnVec {
-        let hist = self.calc_histogram(0);
-        let mut lut = DnVec::zeros(255);
-
-        let mut value_minus = 0.0;
-        (0..255).for_each(|i| {
-            if MarsImage::is_index_a_histogram_gap(&hist, i) {
-                value_minus += 1.0;
-            }
-            lut[i] = i as f32 - value_minus;
-        });
-        lut
-    }
-
-    fn destretch_buffer_with_lut(buffer: &ImageBuffer, lut: &DnVec) -> ImageBuffer {
-        let mut corrected = buffer.clone();
-        (0..corrected.buffer.len()).for_each(|i| {
-            corrected.buffer[i] = lut[corrected.buffer[i].round() as usize];
-        });
-        corrected
+        Histogram::of_band(&self.image, band)
+            .bins
+            .iter()
+            .map(|c| *c as f32)
+            .collect()
     }
 
+    /// Undoes an onboard linear stretch inferred from regular gaps in the histogram. Without
+    /// a regular pattern, single bin gaps are collapsed one by one instead.
     pub fn destretch_image(&mut self) {
-        let lut = self.compute_destretch_lut();
-        (0..self.image.num_bands()).for_each(|i| {
-            self.image.set_band(
-                &MarsImage::destretch_buffer_with_lut(self.image.get_band(i), &lut),
-                i,
-            );
-        });
+        match histogram::destretch(&mut self.image) {
+            Some(pattern) => vprintln!(
+                "Destretched with gain {} from value {}",
+                pattern.gain,
+                pattern.min
+            ),
+            None => {
+                let collapsed = histogram::collapse_gaps(&mut self.image);
+                vprintln!(
+                    "No linear stretch detected in histogram, collapsed {} gaps",
+                    collapsed
+                );
+            }
+        }
     }
 }
@@ -1,83 +1,243 @@
-use crate::vprintln;
-use sciimg::prelude::*;
-use std::collections::HashMap;
-use std::fs;
-use std::sync::Arc;
-use std::sync::Mutex;
-
-use anyhow::anyhow;
-use anyhow::Result;
-
-lazy_static! {
-    static ref IMAGE_CACHE: Arc<Mutex<ImageCache<Image>>> =
-        Arc::new(Mutex::new(ImageCache::default()));
-    static ref IMAGEBUFFER_CACHE: Arc<Mutex<ImageCache<ImageBuffer>>> =
-        Arc::new(Mutex::new(ImageCache::default()));
-    static ref TEXT_CACHE: Arc<Mutex<ImageCache<String>>> =
-        Arc::new(Mutex::new(ImageCache::default()));
-}
-
-/// A *very simple* in-memory cache. Wrapped in a mutex for some semblance of thread
-/// safety. Returns copies which causes some memcopy overhead.
-struct ImageCache<T: Clone> {
-    cache: HashMap<String, T>,
-}
-
-impl<T: Clone> ImageCache<T> {
-    pub fn default() -> Self {
-        ImageCache {
-            cache: HashMap::new(),
-        }
-    }
-
-    pub fn cache_has_file(&self, file_path: &str) -> bool {
-        self.cache.contains_key(file_path)
-    }
-
-    pub fn load_file<F: Fn(&str) -> Result<T>>(
-        &mut self,
-        file_path: &str,
-        load_file: F,
-    ) -> Result<T> {
-        if self.cache_has_file(file_path) {
-            match self.cache.get(file_path) {
-                Some(img) => {
-                    vprintln!("File found in cache: {}", file_path);
-                    Ok(img.clone())
-                }
-                None => Err(anyhow!("file not in cache")),
-            }
-        } else {
-            let img_res = load_file(file_path);
-            match img_res {
-                Ok(img) => {
-                    vprintln!("Adding file to calibration cache: {}", file_path);
-                    self.cache.insert(file_path.into(), img.clone());
-                    Ok(img)
-                }
-                Err(why) => panic!("Failed to load image from {}: {:?}", file_path, why),
-            }
-        }
-    }
-}
-
-pub fn load_image(file_path: &str) -> Result<Image> {
-    IMAGE_CACHE
-        .lock()
-        .unwrap()
-        .load_file(file_path, Image::open_str)
-}
-
-pub fn load_imagebuffer(file_path: &str) -> Result<ImageBuffer> {
-    IMAGEBUFFER_CACHE
-        .lock()
-        .unwrap()
-        .load_file(file_path, ImageBuffer::from_file)
-}
-
-pub fn load_text_file(file_path: &str) -> Result<String> {
-    TEXT_CACHE
-        .lock()
-        .unwrap()
-        .load_file(file_path, |fp| Ok(fs::read_to_string(fp).unwrap())) // Awkward rewrapping of error
-}
+use crate::vprintln;
+use sciimg::prelude::*;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use anyhow::Result;
+
+/// Default byte budget of each calibration file cache
+pub const DEFAULT_BUDGET: usize = 1024 * 1024 * 1024;
+
+lazy_static! {
+    static ref IMAGE_CACHE: LruCache<Image> = LruCache::new(DEFAULT_BUDGET);
+    static ref IMAGEBUFFER_CACHE: LruCache<ImageBuffer> = LruCache::new(DEFAULT_BUDGET);
+    static ref TEXT_CACHE: LruCache<String> = LruCache::new(DEFAULT_BUDGET);
+}
+
+/// Approximate memory used by a cached value, counted against the cache budget
+pub trait CacheSize {
+    fn cache_size(&self) -> usize;
+}
+
+impl CacheSize for Image {
+    fn cache_size(&self) -> usize {
+        self.width * self.height * self.num_bands() * std::mem::size_of::<f32>()
+    }
+}
+
+impl CacheSize for ImageBuffer {
+    fn cache_size(&self) -> usize {
+        self.width * self.height * std::mem::size_of::<f32>()
+    }
+}
+
+impl CacheSize for String {
+    fn cache_size(&self) -> usize {
+        self.len()
+    }
+}
+
+/// Cache counters and current usage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+    pub load_errors: usize,
+    pub evictions: usize,
+    pub entries: usize,
+    pub bytes: usize,
+    pub budget: usize,
+}
+
+struct Entry<T> {
+    value: Arc<T>,
+    size: usize,
+    last_used: u64,
+}
+
+struct CacheState<T> {
+    entries: HashMap<String, Entry<T>>,
+
+    /// Per-key locks held while a file loads, so concurrent requests for the same file
+    /// wait for one load while other files load in parallel
+    loading: HashMap<String, Arc<Mutex<()>>>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl<T> CacheState<T> {
+    /// Returns the cached value for `key`, marking it most recently used
+    fn hit(&mut self, key: &str) -> Option<Arc<T>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key)?;
+        entry.last_used = tick;
+        self.stats.hits += 1;
+        Some(entry.value.clone())
+    }
+
+    /// Drops least recently used entries until the cache fits its budget
+    fn evict(&mut self) {
+        while self.stats.bytes > self.stats.budget {
+            let oldest = match self.entries.iter().min_by_key(|(_, e)| e.last_used) {
+                Some((k, _)) => k.clone(),
+                None => break,
+            };
+            if let Some(e) = self.entries.remove(&oldest) {
+                vprintln!("Evicting file from calibration cache: {}", oldest);
+                self.stats.bytes -= e.size;
+                self.stats.evictions += 1;
+            }
+        }
+        self.stats.entries = self.entries.len();
+    }
+}
+
+/// Least recently used cache of immutable, shared values with a byte budget. Loads happen
+/// outside of the cache lock. Evicted values stay alive for as long as callers hold them.
+pub struct LruCache<T> {
+    state: Mutex<CacheState<T>>,
+}
+
+/// Locks `m`, ignoring poisoning by a panicked holder. Cache state is only modified in
+/// short, non-panicking sections.
+fn lock<T>(m: &Mutex<T>) -> MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl<T: CacheSize> LruCache<T> {
+    pub fn new(budget: usize) -> Self {
+        LruCache {
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                loading: HashMap::new(),
+                tick: 0,
+                stats: CacheStats {
+                    budget,
+                    ..Default::default()
+                },
+            }),
+        }
+    }
+
+    /// Returns the value for `key`, calling `load` on a miss. Load errors are returned and
+    /// not cached, so a later request retries. Values larger than the whole budget are
+    /// returned without being cached.
+    pub fn get_or_load<F: FnOnce(&str) -> Result<T>>(&self, key: &str, load: F) -> Result<Arc<T>> {
+        let key_lock = {
+            let mut state = lock(&self.state);
+            if let Some(v) = state.hit(key) {
+                vprintln!("File found in cache: {}", key);
+                return Ok(v);
+            }
+            state
+                .loading
+                .entry(key.to_string())
+                .or_insert_with(|| Arc::new(Mutex::new(())))
+                .clone()
+        };
+
+        let _loading = lock(&key_lock);
+
+        // Another thread may have loaded it while we waited
+        if let Some(v) = lock(&self.state).hit(key) {
+            vprintln!("File found in cache: {}", key);
+            return Ok(v);
+        }
+
+        let result = load(key);
+
+        let mut state = lock(&self.state);
+        state.stats.misses += 1;
+        if state
+            .loading
+            .get(key)
+            .is_some_and(|l| Arc::ptr_eq(l, &key_lock))
+        {
+            state.loading.remove(key);
+        }
+
+        let value = match result {
+            Ok(v) => Arc::new(v),
+            Err(why) => {
+                state.stats.load_errors += 1;
+                return Err(why);
+            }
+        };
+
+        let size = value.cache_size();
+        if size > state.stats.budget {
+            vprintln!(
+                "File exceeds calibration cache budget, not caching: {}",
+                key
+            );
+            return Ok(value);
+        }
+
+        vprintln!("Adding file to calibration cache: {}", key);
+        state.tick += 1;
+        let entry = Entry {
+            value: value.clone(),
+            size,
+            last_used: state.tick,
+        };
+        if let Some(old) = state.entries.insert(key.to_string(), entry) {
+            state.stats.bytes -= old.size;
+        }
+        state.stats.bytes += size;
+        state.evict();
+        Ok(value)
+    }
+
+    /// Changes the byte budget, evicting entries if the cache no longer fits
+    pub fn set_budget(&self, budget: usize) {
+        let mut state = lock(&self.state);
+        state.stats.budget = budget;
+        state.evict();
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        lock(&self.state).entries.contains_key(key)
+    }
+
+    /// Drops all entries. Counters are kept.
+    pub fn clear(&self) {
+        let mut state = lock(&self.state);
+        state.entries.clear();
+        state.stats.bytes = 0;
+        state.stats.entries = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        lock(&self.state).stats
+    }
+}
+
+pub fn load_image(file_path: &str) -> Result<Arc<Image>> {
+    IMAGE_CACHE.get_or_load(file_path, Image::open_str)
+}
+
+pub fn load_imagebuffer(file_path: &str) -> Result<Arc<ImageBuffer>> {
+    IMAGEBUFFER_CACHE.get_or_load(file_path, ImageBuffer::from_file)
+}
+
+pub fn load_text_file(file_path: &str) -> Result<Arc<String>> {
+    TEXT_CACHE.get_or_load(file_path, |fp| Ok(fs::read_to_string(fp)?))
+}
+
+/// Sets the byte budget of each of the image, image buffer and text caches
+pub fn set_memory_budget(budget: usize) {
+    IMAGE_CACHE.set_budget(budget);
+    IMAGEBUFFER_CACHE.set_budget(budget);
+    TEXT_CACHE.set_budget(budget);
+}
+
+/// Statistics of the image, image buffer and text caches
+pub fn cache_stats() -> Vec<(&'static str, CacheStats)> {
+    vec![
+        ("image", IMAGE_CACHE.stats()),
+        ("imagebuffer", IMAGEBUFFER_CACHE.stats()),
+        ("text", TEXT_CACHE.stats()),
+    ]
+}
@@ -24,6 +24,8 @@ pub trait ImageMetadata {
     fn get_camera_vector(&self) -> Option<Vec<f64>>;
     fn get_camera_model_component_list(&self) -> CameraModel;
     fn get_camera_position(&self) -> Option<Vec<f64>>;
+    fn get_attitude(&self) -> Option<Vec<f64>>;
+    fn get_focus_motor_count(&self) -> Option<f64>;
     fn get_camera_model_type(&self) -> Option<String>;
     fn get_site(&self) -> Option<u32>;
     fn get_drive(&self) -> Option<u32>;
@@ -69,6 +71,13 @@ pub struct Metadata {
     #[serde(with = "crate::jsonfetch::tuple_format")]
     pub camera_position: Option<Vec<f64>>,
 
+    /// Rover attitude quaternion (scalar first) relative to the site frame
+    #[serde(
+        with = "crate::jsonfetch::tuple_format",
+        default = "crate::jsonfetch::default_vec_f64_none"
+    )]
+    pub attitude: Option<Vec<f64>>,
+
     #[serde(
         with = "crate::jsonfetch::tuple_format",
         default = "crate::jsonfetch::default_vec_f64_none"
@@ -76,6 +85,11 @@ pub struct Metadata {
     pub xyz: Option<Vec<f64>>,
 
     pub camera_model_type: Option<String>,
+
+    /// Focus motor position for variable focus cameras (MAHLI, WATSON), when the raw image
+    /// metadata carries it
+    pub focus_motor_count: Option<f64>,
+
     pub site: Option<u32>,
     pub drive: Option<u32>,
 
@@ -99,6 +113,12 @@ pub struct Metadata {
 
     #[serde(default = "crate::jsonfetch::default_false")]
     pub cropped: bool,
+
+    /// Version of the installed calibration data used to process the image
+    pub caldata_version: Option<String>,
+
+    /// Name or file of the LUT the image was decompanded with
+    pub decompand_lut: Option<String>,
 }
 
 pub fn convert_to_std_metadata<T: ImageMetadata>(im: &T) -> Metadata {
@@ -120,10 +140,14 @@ pub fn convert_to_std_metadata<T: ImageMetadata>(im: &T) -> Metadata {
         radiometric: jsonfetch::default_false(),
         inpaint: jsonfetch::default_false(),
         cropped: jsonfetch::default_false(),
+        caldata_version: None,
+        decompand_lut: None,
         camera_vector: im.get_camera_vector(),
         camera_model_component_list: im.get_camera_model_component_list(),
         camera_position: im.get_camera_position(),
+        attitude: im.get_attitude(),
         camera_model_type: im.get_camera_model_type(),
+        focus_motor_count: im.get_focus_motor_count(),
         site: im.get_site(),
         drive: im.get_drive(),
         mast_el: im.get_mast_el(),
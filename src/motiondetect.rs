@@ -0,0 +1,527 @@
+use crate::{anaglyph, marsimage::MarsImage, util, vprintln};
+
+use sciimg::{image::Image, imagebuffer::ImageBuffer, prelude::*};
+
+use anyhow::anyhow;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+/// Tuning parameters for moving feature detection and tracking
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DetectionParams {
+    /// Detection threshold in standard deviations above the mean of the differential frame
+    pub threshold_sigma: f32,
+
+    /// Smallest connected region (pixels) accepted as a detection
+    pub min_area: usize,
+
+    /// Box blur radius (pixels) applied to the differential frame before thresholding
+    pub blur_radius: usize,
+
+    /// Largest centroid movement (pixels per frame) accepted when associating detections
+    pub max_track_distance: f64,
+
+    /// Number of frames a track may go undetected before it is closed
+    pub max_gap: usize,
+
+    /// Minimum number of detections for a track to be reported
+    pub min_track_length: usize,
+}
+
+impl Default for DetectionParams {
+    fn default() -> Self {
+        DetectionParams {
+            threshold_sigma: 4.0,
+            min_area: 20,
+            blur_radius: 2,
+            max_track_distance: 50.0,
+            max_gap: 1,
+            min_track_length: 2,
+        }
+    }
+}
+
+/// Single channel absolute difference between a frame and the sequence mean
+#[derive(Debug, Clone)]
+pub struct DifferenceFrame {
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<f32>,
+}
+
+impl DifferenceFrame {
+    pub fn new(width: usize, height: usize) -> Self {
+        DifferenceFrame {
+            width,
+            height,
+            data: vec![0.0; width * height],
+        }
+    }
+
+    /// Mean absolute difference across the bands of `frame` and `mean`
+    pub fn from_images(frame: &Image, mean: &Image) -> Result<Self> {
+        if frame.width != mean.width || frame.height != mean.height {
+            return Err(anyhow!("Frame and mean have differing dimensions"));
+        }
+        let bands = frame.num_bands().min(mean.num_bands()).max(1);
+        let mut diff = DifferenceFrame::new(frame.width, frame.height);
+        for b in 0..bands {
+            let f = frame.get_band(b);
+            let m = mean.get_band(b);
+            for y in 0..frame.height {
+                for x in 0..frame.width {
+                    diff.data[y * frame.width + x] += (f.get(x, y) - m.get(x, y)).abs();
+                }
+            }
+        }
+        diff.data.iter_mut().for_each(|v| *v /= bands as f32);
+        Ok(diff)
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> f32 {
+        self.data[y * self.width + x]
+    }
+
+    /// Separable box blur of the given radius
+    pub fn blur(&self, radius: usize) -> Self {
+        if radius == 0 {
+            return self.clone();
+        }
+        let pass = |src: &[f32], horizontal: bool| -> Vec<f32> {
+            let mut out = vec![0.0; src.len()];
+            for y in 0..self.height {
+                for x in 0..self.width {
+                    let (pos, len) = if horizontal {
+                        (x, self.width)
+                    } else {
+                        (y, self.height)
+                    };
+                    let start = pos.saturating_sub(radius);
+                    let end = (pos + radius).min(len - 1);
+                    let mut sum = 0.0;
+                    for i in start..=end {
+                        sum += if horizontal {
+                            src[y * self.width + i]
+                        } else {
+                            src[i * self.width + x]
+                        };
+                    }
+                    out[y * self.width + x] = sum / (end - start + 1) as f32;
+                }
+            }
+            out
+        };
+        DifferenceFrame {
+            width: self.width,
+            height: self.height,
+            data: pass(&pass(&self.data, true), false),
+        }
+    }
+
+    /// Mean and standard deviation of the frame
+    pub fn statistics(&self) -> (f32, f32) {
+        let n = self.data.len().max(1) as f32;
+        let mean = self.data.iter().sum::<f32>() / n;
+        let var = self.data.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / n;
+        (mean, var.sqrt())
+    }
+}
+
+/// Connected region of above-threshold pixels in a single frame
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Blob {
+    pub frame: usize,
+    pub x: f64,
+    pub y: f64,
+    pub area: usize,
+    pub min_x: usize,
+    pub min_y: usize,
+    pub max_x: usize,
+    pub max_y: usize,
+
+    /// Mean differential value within the blob
+    pub intensity: f32,
+}
+
+/// Finds 8-connected regions of `diff` above `mean + threshold_sigma * stddev`
+pub fn find_blobs(diff: &DifferenceFrame, params: &DetectionParams, frame: usize) -> Vec<Blob> {
+    let smoothed = diff.blur(params.blur_radius);
+    let (mean, stddev) = smoothed.statistics();
+    let threshold = mean + params.threshold_sigma * stddev;
+
+    let width = smoothed.width;
+    let height = smoothed.height;
+    let mut visited = vec![false; width * height];
+    let mut blobs = vec![];
+
+    for start in 0..width * height {
+        if visited[start] || smoothed.data[start] <= threshold {
+            continue;
+        }
+
+        let mut queue = VecDeque::from([start]);
+        visited[start] = true;
+
+        let mut area = 0;
+        let (mut sum_x, mut sum_y, mut sum_v) = (0.0, 0.0, 0.0);
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (width, height, 0, 0);
+
+        while let Some(idx) = queue.pop_front() {
+            let x = idx % width;
+            let y = idx / width;
+            let v = smoothed.data[idx];
+
+            area += 1;
+            sum_x += x as f64 * v as f64;
+            sum_y += y as f64 * v as f64;
+            sum_v += v as f64;
+            min_x = min_x.min(x);
+            min_y = min_y.min(y);
+            max_x = max_x.max(x);
+            max_y = max_y.max(y);
+
+            for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+                for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                    let n = ny * width + nx;
+                    if !visited[n] && smoothed.data[n] > threshold {
+                        visited[n] = true;
+                        queue.push_back(n);
+                    }
+                }
+            }
+        }
+
+        if area >= params.min_area && sum_v > 0.0 {
+            blobs.push(Blob {
+                frame,
+                x: sum_x / sum_v,
+                y: sum_y / sum_v,
+                area,
+                min_x,
+                min_y,
+                max_x,
+                max_y,
+                intensity: (sum_v / area as f64) as f32,
+            });
+        }
+    }
+    blobs
+}
+
+/// Likely origin of a track, based on its elevation when a camera model is available
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MotionClass {
+    DustDevil,
+    Cloud,
+    Unknown,
+}
+
+/// Elevation (degrees) above which a track is considered atmospheric rather than surface
+pub const CLOUD_MIN_ELEVATION: f64 = 10.0;
+
+/// A blob in the context of a track, with pointing when the frame has a camera model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Detection {
+    #[serde(flatten)]
+    pub blob: Blob,
+
+    /// Azimuth (degrees, rover frame, clockwise from forward)
+    pub azimuth: Option<f64>,
+
+    /// Elevation (degrees, rover frame, positive above the horizontal)
+    pub elevation: Option<f64>,
+
+    /// Spacecraft clock of the frame, if known
+    pub sclk: Option<f64>,
+}
+
+/// A moving feature followed across frames
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Track {
+    pub id: usize,
+    pub detections: Vec<Detection>,
+
+    /// Mean centroid speed (pixels per frame)
+    pub velocity_px_per_frame: f64,
+
+    /// Mean centroid speed (pixels per second), when frame times are known
+    pub velocity_px_per_sec: Option<f64>,
+
+    /// Mean motion direction in the image (x, y pixels per frame)
+    pub direction: (f64, f64),
+
+    pub mean_area: f64,
+    pub classification: MotionClass,
+}
+
+impl Track {
+    fn from_blobs(id: usize, blobs: Vec<Blob>) -> Self {
+        let detections = blobs
+            .into_iter()
+            .map(|blob| Detection {
+                blob,
+                azimuth: None,
+                elevation: None,
+                sclk: None,
+            })
+            .collect();
+        let mut track = Track {
+            id,
+            detections,
+            velocity_px_per_frame: 0.0,
+            velocity_px_per_sec: None,
+            direction: (0.0, 0.0),
+            mean_area: 0.0,
+            classification: MotionClass::Unknown,
+        };
+        track.update_statistics();
+        track
+    }
+
+    pub fn first(&self) -> &Detection {
+        &self.detections[0]
+    }
+
+    pub fn last(&self) -> &Detection {
+        &self.detections[self.detections.len() - 1]
+    }
+
+    /// Recomputes velocity, area and classification from the detections
+    pub fn update_statistics(&mut self) {
+        let n = self.detections.len();
+        self.mean_area = self
+            .detections
+            .iter()
+            .map(|d| d.blob.area as f64)
+            .sum::<f64>()
+            / n.max(1) as f64;
+
+        let (first, last) = (self.first().blob, self.last().blob);
+        let frames = (last.frame - first.frame) as f64;
+        let (dx, dy) = (last.x - first.x, last.y - first.y);
+        if frames > 0.0 {
+            self.direction = (dx / frames, dy / frames);
+            self.velocity_px_per_frame = (dx * dx + dy * dy).sqrt() / frames;
+        }
+
+        self.velocity_px_per_sec = match (self.first().sclk, self.last().sclk) {
+            (Some(t0), Some(t1)) if t1 > t0 => Some((dx * dx + dy * dy).sqrt() / (t1 - t0)),
+            _ => None,
+        };
+
+        let elevations: Vec<f64> = self.detections.iter().filter_map(|d| d.elevation).collect();
+        self.classification = if elevations.is_empty() {
+            MotionClass::Unknown
+        } else if elevations.iter().sum::<f64>() / elevations.len() as f64 >= CLOUD_MIN_ELEVATION {
+            MotionClass::Cloud
+        } else {
+            MotionClass::DustDevil
+        };
+    }
+}
+
+/// Associates per-frame blobs into tracks by greedy nearest-neighbor matching. `frames`
+/// holds the blobs found in each frame, in order.
+pub fn track_blobs(frames: &[Vec<Blob>], params: &DetectionParams) -> Vec<Track> {
+    let mut active: Vec<Vec<Blob>> = vec![];
+    let mut closed: Vec<Vec<Blob>> = vec![];
+
+    for (frame_index, blobs) in frames.iter().enumerate() {
+        // Candidate (distance, track, blob) associations, best first
+        let mut candidates: Vec<(f64, usize, usize)> = vec![];
+        for (t, track) in active.iter().enumerate() {
+            let last = track[track.len() - 1];
+            let gap = (frame_index - last.frame) as f64;
+            for (b, blob) in blobs.iter().enumerate() {
+                let dist = ((blob.x - last.x).powi(2) + (blob.y - last.y).powi(2)).sqrt();
+                if dist <= params.max_track_distance * gap {
+                    candidates.push((dist, t, b));
+                }
+            }
+        }
+        candidates.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        let mut track_used = vec![false; active.len()];
+        let mut blob_used = vec![false; blobs.len()];
+        for (_, t, b) in candidates {
+            if !track_used[t] && !blob_used[b] {
+                track_used[t] = true;
+                blob_used[b] = true;
+                active[t].push(blobs[b]);
+            }
+        }
+
+        // Close tracks that have gone undetected for too long
+        let (still_active, expired): (Vec<Vec<Blob>>, Vec<Vec<Blob>>) = active
+            .into_iter()
+            .partition(|track| frame_index - track[track.len() - 1].frame <= params.max_gap);
+        active = still_active;
+        closed.extend(expired);
+
+        for (b, blob) in blobs.iter().enumerate() {
+            if !blob_used[b] {
+                active.push(vec![*blob]);
+            }
+        }
+    }
+    closed.extend(active);
+
+    let mut tracks: Vec<Track> = closed
+        .into_iter()
+        .filter(|t| t.len() >= params.min_track_length.max(1))
+        .enumerate()
+        .map(|(id, blobs)| Track::from_blobs(id, blobs))
+        .collect();
+    tracks.sort_by_key(|t| t.first().blob.frame);
+    tracks.iter_mut().enumerate().for_each(|(id, t)| t.id = id);
+    tracks
+}
+
+/// Azimuth and elevation (degrees, rover frame) of the pixel, from the camera model
+pub fn pixel_az_el(model: &CameraModel, x: f64, y: f64) -> Option<(f64, f64)> {
+    let lv = model
+        .ls_to_look_vector(&ImageCoordinate { line: y, sample: x })
+        .ok()?;
+    let d = lv.look_direction;
+
+    // Rover frame: +X forward, +Y right, +Z down
+    let az = d.y.atan2(d.x).to_degrees().rem_euclid(360.0);
+    let el = (-d.z).atan2((d.x * d.x + d.y * d.y).sqrt()).to_degrees();
+    Some((az, el))
+}
+
+/// Results of a motion detection run
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MotionReport {
+    pub files: Vec<String>,
+    pub params: DetectionParams,
+    pub tracks: Vec<Track>,
+}
+
+impl MotionReport {
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn save(&self, output_file: &str) -> Result<()> {
+        vprintln!("Writing motion report to {}", output_file);
+        let mut file = File::create(output_file)?;
+        file.write_all(self.to_json()?.as_bytes())?;
+        Ok(())
+    }
+
+    /// Detections of all tracks within the given frame
+    pub fn detections_in_frame(&self, frame: usize) -> Vec<(usize, &Detection)> {
+        self.tracks
+            .iter()
+            .flat_map(|t| t.detections.iter().map(move |d| (t.id, d)))
+            .filter(|(_, d)| d.blob.frame == frame)
+            .collect()
+    }
+}
+
+fn mean_of(images: &[MarsImage]) -> Result<Image> {
+    let first = images.first().ok_or_else(|| anyhow!("No input frames"))?;
+    let mut mean = first.image.clone();
+    for img in images.iter().skip(1) {
+        if img.image.width != mean.width || img.image.height != mean.height {
+            return Err(anyhow!("Input image has differing dimensions"));
+        }
+        mean.add(&img.image);
+    }
+    let count = ImageBuffer::new_with_fill(mean.width, mean.height, images.len() as f32).unwrap();
+    mean.divide_from_each(&count);
+    Ok(mean)
+}
+
+/// Detects and tracks moving features across a sequence of co-pointed frames
+pub fn detect_in_images(images: &[MarsImage], params: &DetectionParams) -> Result<Vec<Track>> {
+    let mean = mean_of(images)?;
+
+    let mut frames = vec![];
+    for (i, img) in images.iter().enumerate() {
+        let diff = DifferenceFrame::from_images(&img.image, &mean)?;
+        let blobs = find_blobs(&diff, params, i);
+        vprintln!("Frame {}: {} candidate detections", i, blobs.len());
+        frames.push(blobs);
+    }
+
+    let mut tracks = track_blobs(&frames, params);
+    for track in tracks.iter_mut() {
+        for d in track.detections.iter_mut() {
+            let img = &images[d.blob.frame];
+            d.sclk = img.metadata.as_ref().and_then(|md| md.sclk);
+            if let Ok(model) = anaglyph::get_camera_model(img) {
+                if let Some((az, el)) = pixel_az_el(&model, d.blob.x, d.blob.y) {
+                    d.azimuth = Some(az);
+                    d.elevation = Some(el);
+                }
+            }
+        }
+        track.update_statistics();
+    }
+    vprintln!("Found {} tracks", tracks.len());
+    Ok(tracks)
+}
+
+/// Opens the input files and detects moving features across them
+pub fn detect(input_files: &[String], params: &DetectionParams) -> Result<MotionReport> {
+    let images: Vec<MarsImage> = input_files
+        .iter()
+        .map(|f| MarsImage::open(f.to_owned(), crate::enums::Instrument::None))
+        .collect();
+    Ok(MotionReport {
+        files: input_files.to_vec(),
+        params: *params,
+        tracks: detect_in_images(&images, params)?,
+    })
+}
+
+/// Draws a one pixel box outline in the maximum value of the image mode
+pub fn draw_box(img: &mut Image, min_x: usize, min_y: usize, max_x: usize, max_y: usize) {
+    let value = util::max_value_for_mode(img.get_mode());
+    let max_x = max_x.min(img.width - 1);
+    let max_y = max_y.min(img.height - 1);
+    let color = [value, 0.0, 0.0];
+    for b in 0..img.num_bands() {
+        let v = color[b.min(2)];
+        for x in min_x..=max_x {
+            img.put(x, min_y, v, b);
+            img.put(x, max_y, v, b);
+        }
+        for y in min_y..=max_y {
+            img.put(min_x, y, v, b);
+            img.put(max_x, y, v, b);
+        }
+    }
+}
+
+/// Writes a copy of each input frame with its detections outlined into `output_dir`
+pub fn save_annotated_frames(report: &MotionReport, output_dir: &str) -> Result<()> {
+    for (i, file) in report.files.iter().enumerate() {
+        let mut img = MarsImage::open(file.to_owned(), crate::enums::Instrument::None).image;
+        for (_, d) in report.detections_in_frame(i) {
+            // Pad the box so it doesn't cover the feature itself
+            draw_box(
+                &mut img,
+                d.blob.min_x.saturating_sub(3),
+                d.blob.min_y.saturating_sub(3),
+                d.blob.max_x + 3,
+                d.blob.max_y + 3,
+            );
+        }
+        let stem = Path::new(file)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("frame");
+        let out_file = Path::new(output_dir).join(format!("{}-motion.png", stem));
+        vprintln!("Writing annotated frame to {:?}", out_file);
+        img.save(out_file.to_str().unwrap());
+    }
+    Ok(())
+}
@@ -31,9 +31,10 @@ impl Calibration for MslChemCam {
 
         vprintln!("Loading image mask");
         let mask = imagebuffer::ImageBuffer::from_file(
-            calibfile::get_calibration_file_for_instrument(
+            calibfile::get_calibration_file_for_instrument_at(
                 enums::Instrument::MslChemCam,
                 enums::CalFileType::Mask,
+                &raw.observation_time(),
             )
             .unwrap()
             .as_str(),
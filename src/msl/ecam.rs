@@ -130,9 +130,10 @@ impl Calibration for MslEcam {
 
         let data_max = 255.0;
 
-        let flat_file_path = calibfile::get_calibration_file_for_instrument(
+        let flat_file_path = calibfile::get_calibration_file_for_instrument_at(
             instrument,
             enums::CalFileType::FlatField,
+            &raw.observation_time(),
         )
         .unwrap();
         vprintln!("Using flat file: {}", flat_file_path);
@@ -48,7 +48,12 @@ impl Calibration for MslMahli {
 
         let data_max = if cal_context.apply_ilt {
             vprintln!("Decompanding...");
-            let lut = decompanding::get_ilt_for_instrument(enums::Instrument::MslMAHLI).unwrap();
+            let lut = decompanding::get_ilt_for_image(
+                cal_context,
+                enums::Instrument::MslMAHLI,
+                &raw.image,
+                &raw.observation_time(),
+            )?;
             raw.decompand(&lut);
             lut.max() as f32
         } else {
@@ -56,7 +61,8 @@ impl Calibration for MslMahli {
         };
 
         vprintln!("Flatfielding...");
-        let mut flat = flatfield::load_flat(enums::Instrument::MslMAHLI).unwrap();
+        let mut flat =
+            flatfield::load_flat_at(enums::Instrument::MslMAHLI, &raw.observation_time()).unwrap();
         if flat.image.width == 1632 && flat.image.height == 1200 {
             flat.image.crop(32, 16, 1584, 1184);
         }
@@ -31,7 +31,12 @@ impl Calibration for MslMardi {
 
         let data_max = if cal_context.apply_ilt {
             vprintln!("Decompanding...");
-            let lut = decompanding::get_ilt_for_instrument(enums::Instrument::MslMARDI).unwrap();
+            let lut = decompanding::get_ilt_for_image(
+                cal_context,
+                enums::Instrument::MslMARDI,
+                &raw.image,
+                &raw.observation_time(),
+            )?;
             raw.decompand(&lut);
             lut.max() as f32
         } else {
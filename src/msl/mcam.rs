@@ -7,6 +7,8 @@ use sciimg::{enums::ImageMode, path};
 
 use anyhow::Result;
 
+use std::sync::Arc;
+
 #[derive(Copy, Clone)]
 pub struct MslMastcam {}
 
@@ -41,7 +43,12 @@ impl Calibration for MslMastcam {
 
         let mut raw = MarsImage::open(String::from(input_file), instrument);
 
-        let lut = decompanding::get_ilt_for_instrument(instrument).unwrap();
+        let lut = decompanding::get_ilt_for_image(
+            cal_context,
+            instrument,
+            &raw.image,
+            &raw.observation_time(),
+        )?;
         let data_max = if cal_context.apply_ilt {
             vprintln!("Decompanding...");
             raw.decompand(&lut);
@@ -57,8 +64,9 @@ impl Calibration for MslMastcam {
             raw.debayer_with_method(cal_context.debayer_method);
         }
 
-        let mut inpaint_mask = inpaintmask::load_mask(instrument).unwrap();
-        let mut flat = flatfield::load_flat(instrument).unwrap();
+        let mut inpaint_mask =
+            inpaintmask::load_mask_at(instrument, &raw.observation_time()).unwrap();
+        let mut flat = flatfield::load_flat_at(instrument, &raw.observation_time()).unwrap();
 
         if raw.image.width == 1536 {
             raw.image.crop(161, 0, 1328, raw.image.height);
@@ -74,15 +82,15 @@ impl Calibration for MslMastcam {
             if raw.image.width == 1328 && raw.image.height == 1184 {
                 //x160, y16
                 flat.image.crop(160, 16, 1328, 1184);
-                inpaint_mask = inpaint_mask.get_subframe(160, 16, 1328, 1184).unwrap();
+                inpaint_mask = Arc::new(inpaint_mask.get_subframe(160, 16, 1328, 1184).unwrap());
             } else if raw.image.width == 848 && raw.image.height == 848 {
                 //x400, y192
                 flat.image.crop(400, 192, 848, 848);
-                inpaint_mask = inpaint_mask.get_subframe(400, 192, 848, 848).unwrap();
+                inpaint_mask = Arc::new(inpaint_mask.get_subframe(400, 192, 848, 848).unwrap());
             } else if raw.image.width == 1344 && raw.image.height == 1200 {
                 //x400, y192
                 flat.image.crop(160, 0, 1344, 1200);
-                inpaint_mask = inpaint_mask.get_subframe(160, 0, 1344, 1200).unwrap();
+                inpaint_mask = Arc::new(inpaint_mask.get_subframe(160, 0, 1344, 1200).unwrap());
             }
 
             if raw.image.get_mode() == ImageMode::U8BIT {
@@ -96,16 +104,16 @@ impl Calibration for MslMastcam {
             if raw.image.width == 1328 && raw.image.height == 1184 {
                 //9
                 flat.image.crop(160, 16, 1328, 1184);
-                inpaint_mask = inpaint_mask.get_subframe(160, 16, 1328, 1184).unwrap();
+                inpaint_mask = Arc::new(inpaint_mask.get_subframe(160, 16, 1328, 1184).unwrap());
             } else if raw.image.width == 1152 && raw.image.height == 432 {
                 flat.image.crop(305, 385, 1152, 432);
-                inpaint_mask = inpaint_mask.get_subframe(305, 385, 1152, 432).unwrap();
+                inpaint_mask = Arc::new(inpaint_mask.get_subframe(305, 385, 1152, 432).unwrap());
             } else if raw.image.width == 1600 && raw.image.height == 1200 {
                 flat.image.crop(33, 0, 1600, 1200);
-                inpaint_mask = inpaint_mask.get_subframe(33, 0, 1600, 1200).unwrap();
+                inpaint_mask = Arc::new(inpaint_mask.get_subframe(33, 0, 1600, 1200).unwrap());
             } else if raw.image.width == 1456 && raw.image.height == 640 {
                 flat.image.crop(96, 280, 1456, 640);
-                inpaint_mask = inpaint_mask.get_subframe(96, 280, 1456, 640).unwrap();
+                inpaint_mask = Arc::new(inpaint_mask.get_subframe(96, 280, 1456, 640).unwrap());
             }
 
             if raw.image.get_mode() == ImageMode::U8BIT {
@@ -135,9 +143,11 @@ impl Calibration for MslMastcam {
                 raw.image.height
             );
             flat.image.crop(x, y, raw.image.width, raw.image.height);
-            inpaint_mask = inpaint_mask
-                .get_subframe(x, y, raw.image.width, raw.image.height)
-                .unwrap();
+            inpaint_mask = Arc::new(
+                inpaint_mask
+                    .get_subframe(x, y, raw.image.width, raw.image.height)
+                    .unwrap(),
+            );
         }
 
         flat.apply_inpaint_fix_with_mask(&inpaint_mask);
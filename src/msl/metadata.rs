@@ -20,6 +20,10 @@ pub struct Extended {
     pub contributor: String,
     pub filter_name: Option<String>,
     pub sample_type: String,
+
+    /// MAHLI focus motor position
+    #[serde(default, alias = "motor_count")]
+    pub focus_motor_count: Option<serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -155,6 +159,14 @@ impl ImageMetadata for ImageRecord {
         self.camera_position.clone()
     }
 
+    fn get_attitude(&self) -> Option<Vec<f64>> {
+        self.attitude.clone()
+    }
+
+    fn get_focus_motor_count(&self) -> Option<f64> {
+        crate::jsonfetch::value_to_f64(&self.extended.focus_motor_count)
+    }
+
     fn get_camera_model_type(&self) -> Option<String> {
         self.camera_model_type.clone()
     }
@@ -30,7 +30,12 @@ impl Calibration for NsytIcc {
 
         let data_max = if cal_context.apply_ilt {
             vprintln!("Decompanding...");
-            let lut = decompanding::get_ilt_for_instrument(enums::Instrument::NsytICC).unwrap();
+            let lut = decompanding::get_ilt_for_image(
+                cal_context,
+                enums::Instrument::NsytICC,
+                &raw.image,
+                &raw.observation_time(),
+            )?;
             raw.decompand(&lut);
             lut.max() as f32
         } else {
@@ -159,6 +159,14 @@ impl ImageMetadata for ImageRecord {
         self.camera_position.clone()
     }
 
+    fn get_attitude(&self) -> Option<Vec<f64>> {
+        self.attitude.clone()
+    }
+
+    fn get_focus_motor_count(&self) -> Option<f64> {
+        None
+    }
+
     fn get_camera_model_type(&self) -> Option<String> {
         self.camera_model_type.clone()
     }
@@ -0,0 +1,344 @@
+use crate::{marsimage::MarsImage, util::max_value_for_mode, vprintln};
+
+use sciimg::image::Image;
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// A single XYZ point with an 8-bit RGB color
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ColoredPoint {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+/// Per-pixel XYZ coordinates. Bands 0, 1 and 2 of the source image hold
+/// the X, Y and Z values respectively, typically in meters in the rover or site frame.
+pub struct XyzImage {
+    pub xyz: Image,
+}
+
+impl XyzImage {
+    /// Wraps an existing three band image as an XYZ image
+    pub fn new_from_image(xyz: &Image) -> Result<Self> {
+        if xyz.num_bands() < 3 {
+            return Err(anyhow!("XYZ image requires three bands"));
+        }
+        Ok(XyzImage { xyz: xyz.clone() })
+    }
+
+    /// Opens an XYZ image from disk (e.g. a three band floating point TIFF)
+    pub fn open(file_path: &str) -> Result<Self> {
+        vprintln!("Loading XYZ image from {}", file_path);
+        XyzImage::new_from_image(&Image::open_str(file_path)?)
+    }
+
+    pub fn width(&self) -> usize {
+        self.xyz.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.xyz.height
+    }
+
+    /// Returns the XYZ coordinate at the pixel, or `None` if the pixel is masked
+    /// out, non-finite, or all zeros (the conventional no-data value)
+    pub fn get(&self, x: usize, y: usize) -> Option<(f64, f64, f64)> {
+        if !self.xyz.get_band(0).get_mask_at_point(x, y) {
+            return None;
+        }
+
+        let px = self.xyz.get_band(0).get(x, y);
+        let py = self.xyz.get_band(1).get(x, y);
+        let pz = self.xyz.get_band(2).get(x, y);
+
+        if !px.is_finite() || !py.is_finite() || !pz.is_finite() {
+            None
+        } else if px == 0.0 && py == 0.0 && pz == 0.0 {
+            None
+        } else {
+            Some((px as f64, py as f64, pz as f64))
+        }
+    }
+}
+
+fn color_at(img: &MarsImage, x: usize, y: usize) -> (u8, u8, u8) {
+    let max = max_value_for_mode(img.image.get_mode());
+    let to_u8 = |v: f32| (v / max * 255.0).round().clamp(0.0, 255.0) as u8;
+
+    if img.image.num_bands() >= 3 {
+        (
+            to_u8(img.image.get_band(0).get(x, y)),
+            to_u8(img.image.get_band(1).get(x, y)),
+            to_u8(img.image.get_band(2).get(x, y)),
+        )
+    } else {
+        let v = to_u8(img.image.get_band(0).get(x, y));
+        (v, v, v)
+    }
+}
+
+fn check_dimensions(xyz: &XyzImage, img: &MarsImage) -> Result<()> {
+    if xyz.width() != img.image.width || xyz.height() != img.image.height {
+        Err(anyhow!(
+            "XYZ image ({}x{}) and color image ({}x{}) have differing dimensions",
+            xyz.width(),
+            xyz.height(),
+            img.image.width,
+            img.image.height
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// A colored point cloud
+#[derive(Default)]
+pub struct PointCloud {
+    pub points: Vec<ColoredPoint>,
+}
+
+impl PointCloud {
+    /// Builds a point cloud from the valid pixels of `xyz`, colored by `img`. Only
+    /// every `step`th pixel in each direction is sampled.
+    pub fn from_xyz_and_image(xyz: &XyzImage, img: &MarsImage, step: usize) -> Result<Self> {
+        check_dimensions(xyz, img)?;
+        let step = step.max(1);
+
+        let mut points = vec![];
+        for y in (0..xyz.height()).step_by(step) {
+            for x in (0..xyz.width()).step_by(step) {
+                if let Some((px, py, pz)) = xyz.get(x, y) {
+                    let (r, g, b) = color_at(img, x, y);
+                    points.push(ColoredPoint {
+                        x: px,
+                        y: py,
+                        z: pz,
+                        r,
+                        g,
+                        b,
+                    });
+                }
+            }
+        }
+        vprintln!("Point cloud contains {} points", points.len());
+        Ok(PointCloud { points })
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Writes the point cloud as an ASCII PLY to any writer
+    pub fn write_ply<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(writer, "ply")?;
+        writeln!(writer, "format ascii 1.0")?;
+        writeln!(writer, "comment Generated by mars_raw_utils")?;
+        writeln!(writer, "element vertex {}", self.points.len())?;
+        writeln!(writer, "property float x")?;
+        writeln!(writer, "property float y")?;
+        writeln!(writer, "property float z")?;
+        writeln!(writer, "property uchar red")?;
+        writeln!(writer, "property uchar green")?;
+        writeln!(writer, "property uchar blue")?;
+        writeln!(writer, "end_header")?;
+        for p in self.points.iter() {
+            writeln!(writer, "{} {} {} {} {} {}", p.x, p.y, p.z, p.r, p.g, p.b)?;
+        }
+        Ok(())
+    }
+
+    /// Saves the point cloud as an ASCII PLY file
+    pub fn save_ply(&self, output_file: &str) -> Result<()> {
+        vprintln!("Writing PLY point cloud to {}", output_file);
+        let mut writer = BufWriter::new(File::create(output_file)?);
+        self.write_ply(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// A triangulated mesh with per-vertex texture coordinates
+#[derive(Default)]
+pub struct Mesh {
+    pub vertices: Vec<ColoredPoint>,
+
+    /// Texture coordinates (u, v) matching `vertices` index for index
+    pub uvs: Vec<(f64, f64)>,
+
+    /// Zero-based vertex indices for each triangle
+    pub faces: Vec<[usize; 3]>,
+}
+
+fn distance(a: &ColoredPoint, b: &ColoredPoint) -> f64 {
+    ((a.x - b.x).powi(2) + (a.y - b.y).powi(2) + (a.z - b.z).powi(2)).sqrt()
+}
+
+impl Mesh {
+    /// Triangulates the pixel grid of `xyz`, sampling every `step`th pixel. Grid cells
+    /// are split into two triangles. Triangles with an edge longer than `max_edge_length`
+    /// (same units as the XYZ data) are discarded to avoid bridging depth discontinuities.
+    pub fn from_xyz_and_image(
+        xyz: &XyzImage,
+        img: &MarsImage,
+        step: usize,
+        max_edge_length: f64,
+    ) -> Result<Self> {
+        check_dimensions(xyz, img)?;
+        let step = step.max(1);
+
+        let grid_width = (xyz.width() + step - 1) / step;
+        let grid_height = (xyz.height() + step - 1) / step;
+
+        let mut mesh = Mesh::default();
+        let mut grid: Vec<Option<usize>> = vec![None; grid_width * grid_height];
+
+        for gy in 0..grid_height {
+            for gx in 0..grid_width {
+                let x = gx * step;
+                let y = gy * step;
+                if let Some((px, py, pz)) = xyz.get(x, y) {
+                    let (r, g, b) = color_at(img, x, y);
+                    grid[gy * grid_width + gx] = Some(mesh.vertices.len());
+                    mesh.vertices.push(ColoredPoint {
+                        x: px,
+                        y: py,
+                        z: pz,
+                        r,
+                        g,
+                        b,
+                    });
+                    mesh.uvs.push((
+                        (x as f64 + 0.5) / xyz.width() as f64,
+                        1.0 - (y as f64 + 0.5) / xyz.height() as f64,
+                    ));
+                }
+            }
+        }
+
+        let short_enough = |tri: &[usize; 3]| {
+            let a = &mesh.vertices[tri[0]];
+            let b = &mesh.vertices[tri[1]];
+            let c = &mesh.vertices[tri[2]];
+            distance(a, b) <= max_edge_length
+                && distance(b, c) <= max_edge_length
+                && distance(c, a) <= max_edge_length
+        };
+
+        let mut faces = vec![];
+        for gy in 0..grid_height.saturating_sub(1) {
+            for gx in 0..grid_width.saturating_sub(1) {
+                let tl = grid[gy * grid_width + gx];
+                let tr = grid[gy * grid_width + gx + 1];
+                let bl = grid[(gy + 1) * grid_width + gx];
+                let br = grid[(gy + 1) * grid_width + gx + 1];
+
+                if let (Some(tl), Some(bl), Some(tr)) = (tl, bl, tr) {
+                    let tri = [tl, bl, tr];
+                    if short_enough(&tri) {
+                        faces.push(tri);
+                    }
+                }
+                if let (Some(tr), Some(bl), Some(br)) = (tr, bl, br) {
+                    let tri = [tr, bl, br];
+                    if short_enough(&tri) {
+                        faces.push(tri);
+                    }
+                }
+            }
+        }
+        mesh.faces = faces;
+
+        vprintln!(
+            "Mesh contains {} vertices and {} faces",
+            mesh.vertices.len(),
+            mesh.faces.len()
+        );
+        Ok(mesh)
+    }
+
+    /// Writes the mesh as Wavefront OBJ. If `material_lib` is supplied, the mesh
+    /// references the `texture` material within it.
+    pub fn write_obj<W: Write>(&self, writer: &mut W, material_lib: Option<&str>) -> Result<()> {
+        writeln!(writer, "# Generated by mars_raw_utils")?;
+        if let Some(mtl) = material_lib {
+            writeln!(writer, "mtllib {}", mtl)?;
+            writeln!(writer, "usemtl texture")?;
+        }
+        for v in self.vertices.iter() {
+            writeln!(
+                writer,
+                "v {} {} {} {} {} {}",
+                v.x,
+                v.y,
+                v.z,
+                v.r as f32 / 255.0,
+                v.g as f32 / 255.0,
+                v.b as f32 / 255.0
+            )?;
+        }
+        for (u, v) in self.uvs.iter() {
+            writeln!(writer, "vt {} {}", u, v)?;
+        }
+        // OBJ indices are one-based
+        for f in self.faces.iter() {
+            writeln!(
+                writer,
+                "f {}/{} {}/{} {}/{}",
+                f[0] + 1,
+                f[0] + 1,
+                f[1] + 1,
+                f[1] + 1,
+                f[2] + 1,
+                f[2] + 1
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Saves the mesh as an OBJ file alongside a material file (`.mtl`) and a PNG
+    /// texture generated from `texture`.
+    pub fn save_obj(&self, output_file: &str, texture: &MarsImage) -> Result<()> {
+        let obj_path = Path::new(output_file);
+        let mtl_path = obj_path.with_extension("mtl");
+        let tex_path = obj_path.with_extension("png");
+
+        let file_name = |p: &Path| -> Result<String> {
+            match p.file_name().and_then(|f| f.to_str()) {
+                Some(f) => Ok(f.to_string()),
+                None => Err(anyhow!("Invalid output file path: {:?}", p)),
+            }
+        };
+        let mtl_name = file_name(&mtl_path)?;
+        let tex_name = file_name(&tex_path)?;
+
+        vprintln!("Writing OBJ texture to {:?}", tex_path);
+        texture.image.save(tex_path.to_str().unwrap());
+
+        vprintln!("Writing OBJ material to {:?}", mtl_path);
+        let mut mtl = BufWriter::new(File::create(&mtl_path)?);
+        writeln!(mtl, "newmtl texture")?;
+        writeln!(mtl, "Ka 1.0 1.0 1.0")?;
+        writeln!(mtl, "Kd 1.0 1.0 1.0")?;
+        writeln!(mtl, "map_Kd {}", tex_name)?;
+        mtl.flush()?;
+
+        vprintln!("Writing OBJ mesh to {}", output_file);
+        let mut writer = BufWriter::new(File::create(obj_path)?);
+        self.write_obj(&mut writer, Some(&mtl_name))?;
+        writer.flush()?;
+        Ok(())
+    }
+}
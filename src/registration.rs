@@ -0,0 +1,574 @@
+use crate::vprintln;
+
+use sciimg::{image::Image, path};
+
+use anyhow::anyhow;
+use anyhow::Result;
+use rustfft::{num_complex::Complex, FftPlanner};
+
+use std::str::FromStr;
+
+/// Image alignment models
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RegistrationMethod {
+    /// Whole-frame translation by phase correlation
+    Translation,
+
+    /// Affine fit to local translations measured by phase correlation over a grid of patches.
+    /// Corrects small rotations and scale changes in addition to translation.
+    Affine,
+}
+
+impl FromStr for RegistrationMethod {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "translation" | "shift" => Ok(RegistrationMethod::Translation),
+            "affine" => Ok(RegistrationMethod::Affine),
+            _ => Err("Invalid registration method"),
+        }
+    }
+}
+
+/// Affine mapping from reference frame coordinates to source image coordinates:
+///
+/// `xs = a[0] * x + a[1] * y + a[2]`, `ys = a[3] * x + a[4] * y + a[5]`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Transform {
+    pub a: [f64; 6],
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform::identity()
+    }
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Transform::translation(0.0, 0.0)
+    }
+
+    /// A feature at reference (x, y) is found at (x + dx, y + dy) in the source image
+    pub fn translation(dx: f64, dy: f64) -> Self {
+        Transform {
+            a: [1.0, 0.0, dx, 0.0, 1.0, dy],
+        }
+    }
+
+    /// Maps a reference frame coordinate into the source image
+    pub fn apply(&self, x: f64, y: f64) -> (f64, f64) {
+        (
+            self.a[0] * x + self.a[1] * y + self.a[2],
+            self.a[3] * x + self.a[4] * y + self.a[5],
+        )
+    }
+
+    /// Mapping from source image coordinates back into the reference frame
+    pub fn inverse(&self) -> Result<Transform> {
+        let [a, b, c, d, e, f] = self.a;
+        let det = a * e - b * d;
+        if det.abs() < 1e-12 {
+            return Err(anyhow!("Transform is not invertible"));
+        }
+        Ok(Transform {
+            a: [
+                e / det,
+                -b / det,
+                (b * f - c * e) / det,
+                -d / det,
+                a / det,
+                (c * d - a * f) / det,
+            ],
+        })
+    }
+}
+
+/// Result of phase correlating an image against a reference
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Translation {
+    pub dx: f64,
+    pub dy: f64,
+
+    /// Height of the correlation peak, between 0 and 1. Low values indicate an unreliable match.
+    pub confidence: f64,
+}
+
+/// Mean of all bands as a `width` x `height` grid, optionally offset into the image. Pixels
+/// outside the image are zero.
+fn luminance(img: &Image, x0: usize, y0: usize, width: usize, height: usize) -> Vec<f32> {
+    let bands = img.num_bands().max(1);
+    let mut out = vec![0.0; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let (sx, sy) = (x0 + x, y0 + y);
+            if sx < img.width && sy < img.height {
+                let mut v = 0.0;
+                for b in 0..bands {
+                    v += img.get_band(b).get(sx, sy);
+                }
+                out[y * width + x] = v / bands as f32;
+            }
+        }
+    }
+    out
+}
+
+fn hann(i: usize, n: usize) -> f32 {
+    if n <= 1 {
+        1.0
+    } else {
+        0.5 - 0.5 * (2.0 * std::f32::consts::PI * i as f32 / (n - 1) as f32).cos()
+    }
+}
+
+/// In-place 2D FFT of a row-major buffer
+fn fft2d(data: &mut [Complex<f32>], width: usize, height: usize, inverse: bool) {
+    let mut planner = FftPlanner::<f32>::new();
+    let row_fft = if inverse {
+        planner.plan_fft_inverse(width)
+    } else {
+        planner.plan_fft_forward(width)
+    };
+    for row in data.chunks_mut(width) {
+        row_fft.process(row);
+    }
+
+    let col_fft = if inverse {
+        planner.plan_fft_inverse(height)
+    } else {
+        planner.plan_fft_forward(height)
+    };
+    let mut column = vec![Complex::new(0.0, 0.0); height];
+    for x in 0..width {
+        for (y, c) in column.iter_mut().enumerate() {
+            *c = data[y * width + x];
+        }
+        col_fft.process(&mut column);
+        for (y, c) in column.iter().enumerate() {
+            data[y * width + x] = *c;
+        }
+    }
+}
+
+/// Mean-removed, Hann windowed spectrum of a luminance grid
+fn spectrum(lum: &[f32], width: usize, height: usize) -> Vec<Complex<f32>> {
+    let mean = lum.iter().sum::<f32>() / lum.len().max(1) as f32;
+    let mut data: Vec<Complex<f32>> = lum
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            let w = hann(i % width, width) * hann(i / width, height);
+            Complex::new((v - mean) * w, 0.0)
+        })
+        .collect();
+    fft2d(&mut data, width, height, false);
+    data
+}
+
+/// Sub-pixel offset of a peak from its neighbors by parabolic interpolation
+fn parabolic_offset(left: f32, center: f32, right: f32) -> f64 {
+    let denom = left - 2.0 * center + right;
+    if denom.abs() < f32::EPSILON {
+        0.0
+    } else {
+        (0.5 * (left - right) / denom).clamp(-0.5, 0.5) as f64
+    }
+}
+
+/// Phase correlation of two equally sized luminance grids. Returns the displacement of
+/// `image` relative to `reference`.
+fn phase_correlate_grids(
+    reference: &[f32],
+    image: &[f32],
+    width: usize,
+    height: usize,
+) -> Translation {
+    let f_ref = spectrum(reference, width, height);
+    let f_img = spectrum(image, width, height);
+
+    let mut cross: Vec<Complex<f32>> = f_img
+        .iter()
+        .zip(f_ref.iter())
+        .map(|(i, r)| {
+            let c = i * r.conj();
+            let norm = c.norm();
+            if norm > f32::EPSILON {
+                c / norm
+            } else {
+                Complex::new(0.0, 0.0)
+            }
+        })
+        .collect();
+    fft2d(&mut cross, width, height, true);
+
+    let scale = (width * height) as f32;
+    let surface: Vec<f32> = cross.iter().map(|c| c.re / scale).collect();
+
+    let (peak_idx, peak) =
+        surface.iter().enumerate().fold(
+            (0, f32::MIN),
+            |best, (i, v)| {
+                if *v > best.1 {
+                    (i, *v)
+                } else {
+                    best
+                }
+            },
+        );
+    let px = peak_idx % width;
+    let py = peak_idx / width;
+
+    let at = |x: isize, y: isize| {
+        let x = x.rem_euclid(width as isize) as usize;
+        let y = y.rem_euclid(height as isize) as usize;
+        surface[y * width + x]
+    };
+    let sub_x = parabolic_offset(
+        at(px as isize - 1, py as isize),
+        peak,
+        at(px as isize + 1, py as isize),
+    );
+    let sub_y = parabolic_offset(
+        at(px as isize, py as isize - 1),
+        peak,
+        at(px as isize, py as isize + 1),
+    );
+
+    // Peaks past the midpoint wrap around to negative displacements
+    let unwrap = |p: usize, n: usize| {
+        if p > n / 2 {
+            p as f64 - n as f64
+        } else {
+            p as f64
+        }
+    };
+
+    Translation {
+        dx: unwrap(px, width) + sub_x,
+        dy: unwrap(py, height) + sub_y,
+        confidence: peak.clamp(0.0, 1.0) as f64,
+    }
+}
+
+/// Estimates the translation of `image` relative to `reference` by phase correlation. Images
+/// of differing dimensions are compared over the reference's extent.
+pub fn phase_correlate(reference: &Image, image: &Image) -> Result<Translation> {
+    if reference.width < 8 || reference.height < 8 {
+        return Err(anyhow!("Reference image is too small to register"));
+    }
+    let (w, h) = (reference.width, reference.height);
+    Ok(phase_correlate_grids(
+        &luminance(reference, 0, 0, w, h),
+        &luminance(image, 0, 0, w, h),
+        w,
+        h,
+    ))
+}
+
+/// Smallest acceptable phase correlation peak for a patch to contribute to an affine fit
+const MIN_PATCH_CONFIDENCE: f64 = 0.05;
+
+/// Solves the 3x3 normal equations `m * x = v` by Cramer's rule
+fn solve3(m: [[f64; 3]; 3], v: [f64; 3]) -> Option<[f64; 3]> {
+    let det = |m: [[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(m);
+    if d.abs() < 1e-12 {
+        return None;
+    }
+    let mut out = [0.0; 3];
+    for (c, o) in out.iter_mut().enumerate() {
+        let mut mc = m;
+        for (row, value) in mc.iter_mut().zip(v.iter()) {
+            row[c] = *value;
+        }
+        *o = det(mc) / d;
+    }
+    Some(out)
+}
+
+/// Least-squares affine fit mapping reference points to source points
+fn fit_affine(matches: &[((f64, f64), (f64, f64))]) -> Option<Transform> {
+    if matches.len() < 3 {
+        return None;
+    }
+    let mut m = [[0.0; 3]; 3];
+    let mut vx = [0.0; 3];
+    let mut vy = [0.0; 3];
+    for ((x, y), (sx, sy)) in matches.iter() {
+        let p = [*x, *y, 1.0];
+        for (r, row) in m.iter_mut().enumerate() {
+            for (c, v) in row.iter_mut().enumerate() {
+                *v += p[r] * p[c];
+            }
+            vx[r] += p[r] * sx;
+            vy[r] += p[r] * sy;
+        }
+    }
+    let ax = solve3(m, vx)?;
+    let ay = solve3(m, vy)?;
+    Some(Transform {
+        a: [ax[0], ax[1], ax[2], ay[0], ay[1], ay[2]],
+    })
+}
+
+/// Estimates an affine transform by phase correlating a `grid` x `grid` set of patches after
+/// removing the global translation, then fitting the patch displacements with outlier rejection.
+pub fn estimate_affine(reference: &Image, image: &Image, grid: usize) -> Result<Transform> {
+    let global = phase_correlate(reference, image)?;
+    let grid = grid.max(2);
+    let patch_w = reference.width / grid;
+    let patch_h = reference.height / grid;
+    if patch_w < 16 || patch_h < 16 {
+        vprintln!("Image too small for affine registration, using translation");
+        return Ok(Transform::translation(global.dx, global.dy));
+    }
+
+    let mut matches = vec![];
+    for gy in 0..grid {
+        for gx in 0..grid {
+            let x0 = gx * patch_w;
+            let y0 = gy * patch_h;
+
+            // Sample the source patch at the globally shifted location so only residual motion remains
+            let sx0 = x0 as f64 + global.dx.round();
+            let sy0 = y0 as f64 + global.dy.round();
+            if sx0 < 0.0 || sy0 < 0.0 {
+                continue;
+            }
+            let r = luminance(reference, x0, y0, patch_w, patch_h);
+            let s = luminance(image, sx0 as usize, sy0 as usize, patch_w, patch_h);
+            let t = phase_correlate_grids(&r, &s, patch_w, patch_h);
+            if t.confidence < MIN_PATCH_CONFIDENCE {
+                continue;
+            }
+
+            let cx = x0 as f64 + patch_w as f64 / 2.0;
+            let cy = y0 as f64 + patch_h as f64 / 2.0;
+            matches.push((
+                (cx, cy),
+                (cx + global.dx.round() + t.dx, cy + global.dy.round() + t.dy),
+            ));
+        }
+    }
+
+    // Refit after discarding matches with large residuals
+    let mut transform = match fit_affine(&matches) {
+        Some(t) => t,
+        None => return Ok(Transform::translation(global.dx, global.dy)),
+    };
+    for _ in 0..2 {
+        let inliers: Vec<((f64, f64), (f64, f64))> = matches
+            .iter()
+            .filter(|((x, y), (sx, sy))| {
+                let (px, py) = transform.apply(*x, *y);
+                ((px - sx).powi(2) + (py - sy).powi(2)).sqrt() < 2.0
+            })
+            .copied()
+            .collect();
+        match fit_affine(&inliers) {
+            Some(t) => transform = t,
+            None => break,
+        }
+    }
+    Ok(transform)
+}
+
+/// Estimates the transform aligning `image` to `reference`
+pub fn register(reference: &Image, image: &Image, method: RegistrationMethod) -> Result<Transform> {
+    match method {
+        RegistrationMethod::Translation => {
+            let t = phase_correlate(reference, image)?;
+            vprintln!(
+                "Translation: dx={:.2}, dy={:.2}, confidence {:.3}",
+                t.dx,
+                t.dy,
+                t.confidence
+            );
+            Ok(Transform::translation(t.dx, t.dy))
+        }
+        RegistrationMethod::Affine => {
+            let t = estimate_affine(reference, image, 4)?;
+            vprintln!("Affine: {:?}", t.a);
+            Ok(t)
+        }
+    }
+}
+
+/// Resamples `image` into a `width` x `height` reference frame using bilinear interpolation.
+/// Pixels that map outside the source image are zero.
+pub fn warp(image: &Image, transform: &Transform, width: usize, height: usize) -> Image {
+    let mut out =
+        Image::new_with_bands(width, height, image.num_bands(), image.get_mode()).unwrap();
+    for y in 0..height {
+        for x in 0..width {
+            let (sx, sy) = transform.apply(x as f64, y as f64);
+            if sx < 0.0
+                || sy < 0.0
+                || sx > (image.width - 1) as f64
+                || sy > (image.height - 1) as f64
+            {
+                continue;
+            }
+            let x0 = sx.floor() as usize;
+            let y0 = sy.floor() as usize;
+            let x1 = (x0 + 1).min(image.width - 1);
+            let y1 = (y0 + 1).min(image.height - 1);
+            let fx = (sx - x0 as f64) as f32;
+            let fy = (sy - y0 as f64) as f32;
+            for b in 0..image.num_bands() {
+                let band = image.get_band(b);
+                let top = band.get(x0, y0) * (1.0 - fx) + band.get(x1, y0) * fx;
+                let bottom = band.get(x0, y1) * (1.0 - fx) + band.get(x1, y1) * fx;
+                out.put(x, y, top * (1.0 - fy) + bottom * fy, b);
+            }
+        }
+    }
+    out
+}
+
+/// Axis aligned region of the reference frame
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/// Region of the reference frame covered by every source image. `sizes` holds the
+/// (width, height) of each source image matching `transforms`.
+pub fn common_overlap(
+    transforms: &[Transform],
+    sizes: &[(usize, usize)],
+    width: usize,
+    height: usize,
+) -> Result<Rect> {
+    let (mut left, mut top) = (0.0_f64, 0.0_f64);
+    let (mut right, mut bottom) = ((width - 1) as f64, (height - 1) as f64);
+
+    for (t, (w, h)) in transforms.iter().zip(sizes.iter()) {
+        let inv = t.inverse()?;
+        let (w, h) = ((*w - 1) as f64, (*h - 1) as f64);
+        let tl = inv.apply(0.0, 0.0);
+        let tr = inv.apply(w, 0.0);
+        let bl = inv.apply(0.0, h);
+        let br = inv.apply(w, h);
+        left = left.max(tl.0).max(bl.0);
+        right = right.min(tr.0).min(br.0);
+        top = top.max(tl.1).max(tr.1);
+        bottom = bottom.min(bl.1).min(br.1);
+    }
+
+    // Tolerate sub-pixel noise in the estimated transforms
+    const EPSILON: f64 = 0.01;
+    let x = (left - EPSILON).ceil().max(0.0) as usize;
+    let y = (top - EPSILON).ceil().max(0.0) as usize;
+    let x_end = (right + EPSILON).floor();
+    let y_end = (bottom + EPSILON).floor();
+    if x_end < x as f64 || y_end < y as f64 {
+        return Err(anyhow!("Images have no common overlap"));
+    }
+    Ok(Rect {
+        x,
+        y,
+        width: x_end as usize - x + 1,
+        height: y_end as usize - y + 1,
+    })
+}
+
+/// A set of images aligned to a common reference frame and cropped to their overlap
+pub struct RegisteredStack {
+    pub images: Vec<Image>,
+
+    /// Transform of each input image relative to the reference
+    pub transforms: Vec<Transform>,
+
+    /// Overlap region in reference frame coordinates
+    pub crop: Rect,
+}
+
+/// Aligns each image to `images[reference_index]` and crops all of them to the common overlap
+pub fn register_stack(
+    images: &[Image],
+    reference_index: usize,
+    method: RegistrationMethod,
+) -> Result<RegisteredStack> {
+    let reference = images
+        .get(reference_index)
+        .ok_or_else(|| anyhow!("Invalid reference frame index"))?;
+
+    let mut transforms = vec![];
+    for (i, img) in images.iter().enumerate() {
+        if i == reference_index {
+            transforms.push(Transform::identity());
+        } else {
+            vprintln!("Registering frame {} to frame {}", i, reference_index);
+            transforms.push(register(reference, img, method)?);
+        }
+    }
+
+    let sizes: Vec<(usize, usize)> = images.iter().map(|i| (i.width, i.height)).collect();
+    let crop = common_overlap(&transforms, &sizes, reference.width, reference.height)?;
+    vprintln!(
+        "Common overlap: {}x{} at ({}, {})",
+        crop.width,
+        crop.height,
+        crop.x,
+        crop.y
+    );
+
+    let aligned = images
+        .iter()
+        .zip(transforms.iter())
+        .map(|(img, t)| {
+            let mut warped = warp(img, t, reference.width, reference.height);
+            warped.crop(crop.x, crop.y, crop.width, crop.height);
+            warped
+        })
+        .collect();
+
+    Ok(RegisteredStack {
+        images: aligned,
+        transforms,
+        crop,
+    })
+}
+
+/// Opens the input images and, when a method is given, aligns them to the first image and
+/// crops them to their common overlap. Without a method the images must share dimensions.
+pub fn open_and_register(
+    input_files: &[String],
+    method: Option<RegistrationMethod>,
+) -> Result<Vec<Image>> {
+    let mut images = vec![];
+    for in_file in input_files.iter() {
+        if !path::file_exists(in_file) {
+            return Err(anyhow!("File not found: {}", in_file));
+        }
+        vprintln!("Loading image from {}", in_file);
+        images.push(Image::open_str(in_file)?);
+    }
+    if images.is_empty() {
+        return Err(anyhow!("No input images"));
+    }
+
+    match method {
+        Some(method) => Ok(register_stack(&images, 0, method)?.images),
+        None => {
+            let (w, h) = (images[0].width, images[0].height);
+            if images.iter().any(|i| i.width != w || i.height != h) {
+                Err(anyhow!(
+                    "Input images have differing dimensions. Use registration to align them"
+                ))
+            } else {
+                Ok(images)
+            }
+        }
+    }
+}
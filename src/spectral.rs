@@ -0,0 +1,531 @@
+use crate::{
+    enums::Instrument,
+    metadata::{load_image_metadata, Metadata},
+    registration::{self, RegistrationMethod},
+    stereopair::sequence_id_from_name,
+    util, vprintln,
+};
+
+use sciimg::{image::Image, imagebuffer::ImageBuffer, path};
+
+use anyhow::anyhow;
+use anyhow::Result;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::str::FromStr;
+
+lazy_static! {
+    /// Matches filter identifiers such as `L1`, `R6` or `ZCAM_R0` in metadata filter names
+    static ref FILTER_NAME: Regex = Regex::new(r"([LR])([0-7])").unwrap();
+
+    /// Mastcam-Z product ids encode eye and filter in the second and third characters (`ZL6_...`)
+    static ref ZCAM_FILE_FILTER: Regex = Regex::new(r"^Z([LR])([0-7])").unwrap();
+}
+
+/// Effective center wavelengths (nm) of the Mastcam-Z filters, Bayer filter 0 as red, green, blue.
+/// Bell, J. F. et al. (2021), Space Sci Rev 217, 24.
+const ZCAM_LEFT: [&[f32]; 8] = [
+    &[630.0, 544.0, 480.0],
+    &[800.0],
+    &[754.0],
+    &[677.0],
+    &[605.0],
+    &[528.0],
+    &[442.0],
+    &[590.0],
+];
+const ZCAM_RIGHT: [&[f32]; 8] = [
+    &[631.0, 544.0, 480.0],
+    &[800.0],
+    &[866.0],
+    &[910.0],
+    &[939.0],
+    &[978.0],
+    &[1022.0],
+    &[880.0],
+];
+
+/// Effective center wavelengths (nm) of the MSL Mastcam filters, Bayer filter 0 as red, green,
+/// blue. Bell, J. F. et al. (2017), Earth and Space Science, 4, 396–452.
+const MCAM_LEFT: [&[f32]; 8] = [
+    &[640.0, 554.0, 495.0],
+    &[527.0],
+    &[445.0],
+    &[751.0],
+    &[676.0],
+    &[867.0],
+    &[1012.0],
+    &[440.0],
+];
+const MCAM_RIGHT: [&[f32]; 8] = [
+    &[638.0, 551.0, 493.0],
+    &[447.0],
+    &[527.0],
+    &[805.0],
+    &[908.0],
+    &[937.0],
+    &[1013.0],
+    &[880.0],
+];
+
+const BAYER_BAND_NAMES: [&str; 3] = ["R", "G", "B"];
+
+/// Beyond this wavelength (nm) all three Bayer filters transmit about equally
+const BAYER_NIR_NM: f32 = 750.0;
+
+/// Fixed focal lengths (mm) of the MSL Mastcam eyes (M-34 and M-100), which share a detector
+/// and so differ in pixel scale by their ratio. The Mastcam-Z eyes zoom together.
+fn focal_length(instrument: Instrument) -> Option<f32> {
+    match instrument {
+        Instrument::MslMastcamLeft => Some(34.0),
+        Instrument::MslMastcamRight => Some(100.0),
+        _ => None,
+    }
+}
+
+/// Center wavelengths of each band of a filter image (three for the Bayer broadband filter
+/// 0, one otherwise). `filter` is the filter number 0-7.
+pub fn filter_wavelengths(instrument: Instrument, filter: usize) -> Option<Vec<f32>> {
+    let table = match instrument {
+        Instrument::M20MastcamZLeft => ZCAM_LEFT,
+        Instrument::M20MastcamZRight => ZCAM_RIGHT,
+        Instrument::MslMastcamLeft => MCAM_LEFT,
+        Instrument::MslMastcamRight => MCAM_RIGHT,
+        _ => return None,
+    };
+    table.get(filter).map(|w| w.to_vec())
+}
+
+/// Determines the filter number from the metadata filter name, falling back to the
+/// Mastcam-Z product id
+pub fn filter_number(file_path: &str, metadata: &Metadata) -> Option<usize> {
+    let from_caps = |caps: regex::Captures| caps.get(2).unwrap().as_str().parse::<usize>().ok();
+    metadata
+        .filter_name
+        .as_ref()
+        .and_then(|f| FILTER_NAME.captures(f).and_then(from_caps))
+        .or_else(|| {
+            ZCAM_FILE_FILTER
+                .captures(&path::basename(file_path))
+                .and_then(from_caps)
+        })
+}
+
+/// A calibrated filter image of a multispectral sequence
+#[derive(Clone)]
+pub struct SpectralFrame {
+    pub file_path: String,
+    pub instrument: Instrument,
+    pub filter: usize,
+    pub wavelengths: Vec<f32>,
+    pub sequence_id: Option<String>,
+    pub sclk: f64,
+    pub metadata: Metadata,
+}
+
+impl SpectralFrame {
+    fn eye_letter(&self) -> &str {
+        match self.instrument {
+            Instrument::M20MastcamZLeft | Instrument::MslMastcamLeft => "L",
+            _ => "R",
+        }
+    }
+
+    /// Filter label (e.g. `L3`, or `R0G` for a Bayer band)
+    pub fn band_label(&self, band: usize) -> String {
+        if self.wavelengths.len() > 1 {
+            format!(
+                "{}{}{}",
+                self.eye_letter(),
+                self.filter,
+                BAYER_BAND_NAMES[band]
+            )
+        } else {
+            format!("{}{}", self.eye_letter(), self.filter)
+        }
+    }
+
+    pub fn new_from_metadata(file_path: &str, metadata: &Metadata) -> Result<Self> {
+        let instrument = Instrument::from_str(&metadata.instrument).unwrap();
+        let filter = filter_number(file_path, metadata)
+            .ok_or_else(|| anyhow!("Unable to determine filter for {}", file_path))?;
+        let wavelengths = filter_wavelengths(instrument, filter).ok_or_else(|| {
+            anyhow!(
+                "Instrument {} is not a multispectral camera",
+                metadata.instrument
+            )
+        })?;
+        Ok(SpectralFrame {
+            file_path: file_path.to_string(),
+            instrument,
+            filter,
+            wavelengths,
+            sequence_id: sequence_id_from_name(&metadata.imageid)
+                .or_else(|| sequence_id_from_name(file_path)),
+            sclk: metadata.sclk.unwrap_or(0.0),
+            metadata: metadata.clone(),
+        })
+    }
+
+    pub fn new_from_file(file_path: &str) -> Result<Self> {
+        let metadata_file = util::replace_image_extension(file_path, "-metadata.json");
+        if !path::file_exists(&metadata_file) {
+            return Err(anyhow!("Metadata file not found for {}", file_path));
+        }
+        let md = load_image_metadata(&metadata_file)?;
+        SpectralFrame::new_from_metadata(file_path, &md)
+    }
+
+    /// Factor by which this frame is resized to match the pixel scale of `reference`, from
+    /// the eyes' focal lengths and the downsampling scale factors
+    pub fn scale_to(&self, reference: &SpectralFrame) -> f32 {
+        match (
+            focal_length(self.instrument),
+            focal_length(reference.instrument),
+        ) {
+            (Some(f), Some(f_ref)) => {
+                (f_ref * self.metadata.scale_factor.max(1) as f32)
+                    / (f * reference.metadata.scale_factor.max(1) as f32)
+            }
+            _ => 1.0,
+        }
+    }
+
+    /// Image plane for each band. A narrowband filter imaged through the Bayer pattern takes
+    /// the channel whose broadband wavelength is nearest, or the mean of the channels in the
+    /// near infrared where they all transmit.
+    fn planes(&self, img: &Image) -> Result<Vec<ImageBuffer>> {
+        if self.wavelengths.len() > 1 || img.num_bands() < 3 {
+            return Ok((0..self.wavelengths.len())
+                .map(|b| img.get_band(b.min(img.num_bands() - 1)).clone())
+                .collect());
+        }
+
+        let wavelength = self.wavelengths[0];
+        let broadband = filter_wavelengths(self.instrument, 0).unwrap_or_default();
+        if wavelength < BAYER_NIR_NM && broadband.len() == 3 {
+            let channel = (0..3)
+                .min_by(|a, b| {
+                    (broadband[*a] - wavelength)
+                        .abs()
+                        .partial_cmp(&(broadband[*b] - wavelength).abs())
+                        .unwrap()
+                })
+                .unwrap();
+            return Ok(vec![img.get_band(channel).clone()]);
+        }
+
+        let mut mean =
+            ImageBuffer::new_with_fill_as_mode(img.width, img.height, 0.0, img.get_band(0).mode)?;
+        for y in 0..img.height {
+            for x in 0..img.width {
+                let sum: f32 = (0..3).map(|b| img.get_band(b).get(x, y)).sum();
+                mean.put(x, y, sum / 3.0);
+            }
+        }
+        Ok(vec![mean])
+    }
+}
+
+/// Builds frames from a list of image files, skipping those that are not usable
+pub fn frames_from_files(input_files: &[String]) -> Vec<SpectralFrame> {
+    input_files
+        .iter()
+        .filter_map(|f| match SpectralFrame::new_from_file(f) {
+            Ok(c) => Some(c),
+            Err(why) => {
+                vprintln!("Skipping {}: {}", f, why);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Groups frames by sequence id. Frames without one are grouped under an empty id.
+pub fn group_by_sequence(frames: &[SpectralFrame]) -> BTreeMap<String, Vec<SpectralFrame>> {
+    let mut groups: BTreeMap<String, Vec<SpectralFrame>> = BTreeMap::new();
+    for frame in frames.iter() {
+        groups
+            .entry(frame.sequence_id.clone().unwrap_or_default())
+            .or_default()
+            .push(frame.clone());
+    }
+    groups
+}
+
+/// Index entry describing one plane of a cube
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CubeBand {
+    pub label: String,
+    pub wavelength_nm: f32,
+    pub source: String,
+}
+
+/// Co-registered image planes ordered by wavelength
+pub struct SpectralCube {
+    pub width: usize,
+    pub height: usize,
+    pub bands: Vec<CubeBand>,
+    pub planes: Vec<ImageBuffer>,
+}
+
+const CUBE_INDEX_FILE: &str = "cube.json";
+
+impl SpectralCube {
+    /// Builds a cube from the frames of one sequence. The first left eye frame (the Bayer
+    /// filter if present) defines the geometry and every other frame is registered to it,
+    /// after resampling frames of the other MSL Mastcam eye to its pixel scale. When a filter
+    /// was acquired more than once, the earliest frame is used.
+    pub fn build(frames: &[SpectralFrame], method: Option<RegistrationMethod>) -> Result<Self> {
+        let mut frames: Vec<&SpectralFrame> = frames.iter().collect();
+        frames.sort_by(|a, b| a.sclk.total_cmp(&b.sclk));
+        let mut seen = vec![];
+        frames.retain(|f| {
+            let key = (f.instrument, f.filter);
+            if seen.contains(&key) {
+                vprintln!("Ignoring repeated filter image {}", f.file_path);
+                false
+            } else {
+                seen.push(key);
+                true
+            }
+        });
+
+        let reference_frame = frames
+            .iter()
+            .filter(|f| f.eye_letter() == "L")
+            .min_by_key(|f| f.filter)
+            .or_else(|| frames.first())
+            .ok_or_else(|| anyhow!("No frames"))?;
+        vprintln!("Reference frame: {}", reference_frame.file_path);
+        let reference = Image::open_str(&reference_frame.file_path)?;
+        let (width, height) = (reference.width, reference.height);
+
+        let mut entries: Vec<(CubeBand, ImageBuffer)> = vec![];
+        for frame in frames.iter() {
+            let mut img = Image::open_str(&frame.file_path)?;
+            if frame.file_path != reference_frame.file_path {
+                let scale = frame.scale_to(reference_frame);
+                if (scale - 1.0).abs() > 0.01 {
+                    vprintln!("Resampling {} by {:.3}", frame.file_path, scale);
+                    img.resize_to(
+                        ((img.width as f32 * scale).round() as usize).max(1),
+                        ((img.height as f32 * scale).round() as usize).max(1),
+                    );
+                }
+                if let Some(method) = method {
+                    vprintln!("Registering {}", frame.file_path);
+                    let t = registration::register(&reference, &img, method)?;
+                    img = registration::warp(&img, &t, width, height);
+                } else if img.width != width || img.height != height {
+                    return Err(anyhow!(
+                        "{} differs in size from the reference frame. Use registration",
+                        frame.file_path
+                    ));
+                }
+            }
+
+            for (b, plane) in frame.planes(&img)?.into_iter().enumerate() {
+                entries.push((
+                    CubeBand {
+                        label: frame.band_label(b),
+                        wavelength_nm: frame.wavelengths[b],
+                        source: frame.file_path.clone(),
+                    },
+                    plane,
+                ));
+            }
+        }
+
+        entries.sort_by(|a, b| a.0.wavelength_nm.total_cmp(&b.0.wavelength_nm));
+        let (bands, planes) = entries.into_iter().unzip();
+        Ok(SpectralCube {
+            width,
+            height,
+            bands,
+            planes,
+        })
+    }
+
+    pub fn band_index(&self, label: &str) -> Option<usize> {
+        self.bands.iter().position(|b| b.label == label)
+    }
+
+    /// Band with the center wavelength closest to `wavelength_nm`
+    pub fn nearest_band(&self, wavelength_nm: f32) -> Option<usize> {
+        self.bands
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                (a.wavelength_nm - wavelength_nm)
+                    .abs()
+                    .total_cmp(&(b.wavelength_nm - wavelength_nm).abs())
+            })
+            .map(|(i, _)| i)
+    }
+
+    /// Writes each plane as a PNG in its source bit depth with a `cube.json` index of labels
+    /// and wavelengths
+    pub fn save(&self, directory: &str) -> Result<()> {
+        let mut index = vec![];
+        for (band, plane) in self.bands.iter().zip(self.planes.iter()) {
+            let file_name = format!("{}_{:.0}nm.png", band.label, band.wavelength_nm);
+            let mut out = Image::new_with_bands(plane.width, plane.height, 1, plane.mode)?;
+            out.set_band(plane, 0);
+            out.save(&format!("{}/{}", directory, file_name));
+            index.push(CubeBand {
+                label: band.label.clone(),
+                wavelength_nm: band.wavelength_nm,
+                source: file_name,
+            });
+        }
+        fs::write(
+            format!("{}/{}", directory, CUBE_INDEX_FILE),
+            serde_json::to_string_pretty(&index)?,
+        )?;
+        Ok(())
+    }
+
+    /// Loads a cube written by `save`
+    pub fn load(directory: &str) -> Result<Self> {
+        let index: Vec<CubeBand> = serde_json::from_str(&fs::read_to_string(format!(
+            "{}/{}",
+            directory, CUBE_INDEX_FILE
+        ))?)?;
+        let mut planes = vec![];
+        for band in index.iter() {
+            let img = Image::open_str(&format!("{}/{}", directory, band.source))?;
+            planes.push(img.get_band(0).clone());
+        }
+        let first = planes
+            .first()
+            .ok_or_else(|| anyhow!("Cube index is empty"))?;
+        Ok(SpectralCube {
+            width: first.width,
+            height: first.height,
+            bands: index,
+            planes,
+        })
+    }
+}
+
+/// Rectangular region of interest
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Roi {
+    pub name: String,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl FromStr for Roi {
+    type Err = String;
+
+    /// Parses `name:x,y,width,height`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, rect) = s
+            .split_once(':')
+            .ok_or_else(|| String::from("ROI must be formatted as name:x,y,width,height"))?;
+        let values: Vec<usize> = rect
+            .split(',')
+            .map(|v| v.trim().parse::<usize>())
+            .collect::<Result<Vec<usize>, _>>()
+            .map_err(|e| format!("Invalid ROI value: {}", e))?;
+        if values.len() != 4 || values[2] == 0 || values[3] == 0 {
+            return Err(String::from(
+                "ROI must be formatted as name:x,y,width,height",
+            ));
+        }
+        Ok(Roi {
+            name: name.to_string(),
+            x: values[0],
+            y: values[1],
+            width: values[2],
+            height: values[3],
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SpectrumPoint {
+    pub filter: String,
+    pub wavelength_nm: f32,
+    pub mean: f32,
+    pub stddev: f32,
+    pub pixels: usize,
+}
+
+/// Mean value per cube band within an ROI
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Spectrum {
+    pub roi: Roi,
+    pub points: Vec<SpectrumPoint>,
+}
+
+/// Extracts the mean and standard deviation of each band within each ROI. Inputs are expected
+/// to be radiometrically calibrated (e.g. I/F), so the result is a reflectance spectrum.
+pub fn extract_spectra(cube: &SpectralCube, rois: &[Roi]) -> Result<Vec<Spectrum>> {
+    rois.iter()
+        .map(|roi| {
+            if roi.x + roi.width > cube.width || roi.y + roi.height > cube.height {
+                return Err(anyhow!("ROI {} extends outside the image", roi.name));
+            }
+            let points = cube
+                .bands
+                .iter()
+                .zip(cube.planes.iter())
+                .map(|(band, plane)| {
+                    let mut values = vec![];
+                    for y in roi.y..roi.y + roi.height {
+                        for x in roi.x..roi.x + roi.width {
+                            values.push(plane.get(x, y) as f64);
+                        }
+                    }
+                    let n = values.len() as f64;
+                    let mean = values.iter().sum::<f64>() / n;
+                    let var = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+                    SpectrumPoint {
+                        filter: band.label.clone(),
+                        wavelength_nm: band.wavelength_nm,
+                        mean: mean as f32,
+                        stddev: var.sqrt() as f32,
+                        pixels: values.len(),
+                    }
+                })
+                .collect();
+            Ok(Spectrum {
+                roi: roi.clone(),
+                points,
+            })
+        })
+        .collect()
+}
+
+/// One row per ROI and filter
+pub fn spectra_to_csv(spectra: &[Spectrum]) -> String {
+    let mut csv = String::from("roi,filter,wavelength_nm,mean,stddev,pixels\n");
+    for spectrum in spectra.iter() {
+        for p in spectrum.points.iter() {
+            csv.push_str(&format!(
+                "{},{},{},{},{},{}\n",
+                spectrum.roi.name, p.filter, p.wavelength_nm, p.mean, p.stddev, p.pixels
+            ));
+        }
+    }
+    csv
+}
+
+/// Writes spectra as JSON when `output_file` ends in `.json`, CSV otherwise
+pub fn save_spectra(spectra: &[Spectrum], output_file: &str) -> Result<()> {
+    let text = if output_file.to_lowercase().ends_with(".json") {
+        serde_json::to_string_pretty(spectra)?
+    } else {
+        spectra_to_csv(spectra)
+    };
+    fs::write(output_file, text)?;
+    Ok(())
+}
@@ -0,0 +1,376 @@
+use crate::calibfile;
+use crate::spectral::SpectralCube;
+use crate::util;
+use crate::{veprintln, vprintln};
+
+use sciimg::{enums::ImageMode, image::Image, imagebuffer::ImageBuffer};
+
+use anyhow::anyhow;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+
+/// Maximum distance (nm) between a requested wavelength and the nearest cube band
+pub const MAX_WAVELENGTH_MISMATCH_NM: f32 = 25.0;
+
+/// Definitions used when no file is given, written for the Mastcam-Z filter set. Wavelengths
+/// resolve to the nearest filter, so most also apply to MSL Mastcam cubes.
+pub const DEFAULT_DEFINITIONS: &str = r#"
+[[parameter]]
+name = "bd_530"
+description = "Ferric absorption band depth near 530 nm"
+type = "band_depth"
+left = 442
+center = 528
+right = 605
+
+[[parameter]]
+name = "bd_900"
+description = "Band depth near 900 nm (pyroxene, ferric oxides)"
+type = "band_depth"
+left = 754
+center = 910
+right = 1022
+
+[[parameter]]
+name = "bd_1000"
+description = "1.0 micron band depth (olivine, pyroxene, hydrated minerals)"
+type = "band_depth"
+left = 754
+center = 978
+right = 1022
+
+[[parameter]]
+name = "slope_535"
+description = "Spectral slope across the 535 nm ferric edge (per nm)"
+type = "slope"
+from = 442
+to = 605
+
+[[parameter]]
+name = "ratio_1022_754"
+description = "Near infrared ratio"
+type = "ratio"
+numerator = 1022
+denominator = 754
+
+[[composite]]
+name = "enhanced"
+red = 754
+green = 528
+blue = 442
+stretch = true
+"#;
+
+/// A cube band referenced by filter label (e.g. `"R3"`) or wavelength in nm
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum BandRef {
+    Wavelength(f32),
+    Label(String),
+}
+
+impl BandRef {
+    /// Index of the referenced plane within `cube`
+    pub fn resolve(&self, cube: &SpectralCube) -> Result<usize> {
+        match self {
+            BandRef::Label(label) => cube
+                .band_index(label)
+                .ok_or_else(|| anyhow!("Filter {} not found in cube", label)),
+            BandRef::Wavelength(nm) => match cube.nearest_band(*nm) {
+                Some(i)
+                    if (cube.bands[i].wavelength_nm - nm).abs() <= MAX_WAVELENGTH_MISMATCH_NM =>
+                {
+                    Ok(i)
+                }
+                _ => Err(anyhow!("No filter near {} nm in cube", nm)),
+            },
+        }
+    }
+
+    fn wavelength(&self, cube: &SpectralCube) -> Result<f32> {
+        Ok(cube.bands[self.resolve(cube)?].wavelength_nm)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ParameterKind {
+    /// `numerator / denominator`
+    Ratio {
+        numerator: BandRef,
+        denominator: BandRef,
+    },
+
+    /// `1 - R(center) / continuum`, the continuum linearly interpolated between the
+    /// shoulders at the center wavelength
+    BandDepth {
+        left: BandRef,
+        center: BandRef,
+        right: BandRef,
+    },
+
+    /// `(R(to) - R(from)) / (wavelength(to) - wavelength(from))`
+    Slope { from: BandRef, to: BandRef },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ParameterDefinition {
+    pub name: String,
+    pub description: Option<String>,
+
+    #[serde(flatten)]
+    pub kind: ParameterKind,
+}
+
+/// Three filter false color composite
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CompositeDefinition {
+    pub name: String,
+    pub red: BandRef,
+    pub green: BandRef,
+    pub blue: BandRef,
+
+    /// Stretch each channel independently to its 1st-99th percentile range
+    #[serde(default)]
+    pub stretch: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct SpectralDefinitions {
+    #[serde(default)]
+    pub parameter: Vec<ParameterDefinition>,
+
+    #[serde(default)]
+    pub composite: Vec<CompositeDefinition>,
+}
+
+impl SpectralDefinitions {
+    pub fn parse(text: &str) -> Result<Self> {
+        match toml::from_str(text) {
+            Ok(defs) => Ok(defs),
+            Err(why) => Err(anyhow!("Error parsing spectral definitions: {}", why)),
+        }
+    }
+
+    /// Loads definitions from a file, searching the calibration data locations the same way
+    /// calibration profiles are found
+    pub fn load(file_path: &String) -> Result<Self> {
+        let located =
+            calibfile::locate_calibration_file_no_extention(file_path, &".toml".to_string())?;
+        vprintln!("Loaded spectral definitions from {}", located);
+        SpectralDefinitions::parse(&fs::read_to_string(located)?)
+    }
+
+    pub fn default_definitions() -> Self {
+        SpectralDefinitions::parse(DEFAULT_DEFINITIONS).unwrap()
+    }
+}
+
+/// Computed parameter values. Pixels where the parameter is undefined (e.g. division by
+/// zero) are NaN.
+#[derive(Debug, Clone)]
+pub struct ParameterMap {
+    pub name: String,
+    pub width: usize,
+    pub height: usize,
+    pub data: Vec<f32>,
+}
+
+impl ParameterMap {
+    pub fn get(&self, x: usize, y: usize) -> f32 {
+        self.data[y * self.width + x]
+    }
+
+    /// Minimum and maximum of the defined values
+    pub fn range(&self) -> Option<(f32, f32)> {
+        self.data
+            .iter()
+            .filter(|v| v.is_finite())
+            .fold(None, |acc, v| match acc {
+                None => Some((*v, *v)),
+                Some((lo, hi)) => Some((lo.min(*v), hi.max(*v))),
+            })
+    }
+
+    /// Values linearly scaled from their range to 0-65535. Undefined pixels are zero.
+    pub fn to_imagebuffer(&self) -> ImageBuffer {
+        let (lo, hi) = self.range().unwrap_or((0.0, 1.0));
+        let span = if hi > lo { hi - lo } else { 1.0 };
+        let mut buffer =
+            ImageBuffer::new_with_fill_as_mode(self.width, self.height, 0.0, ImageMode::U16BIT)
+                .unwrap();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let v = self.get(x, y);
+                if v.is_finite() {
+                    buffer.put(x, y, (v - lo) / span * 65535.0);
+                }
+            }
+        }
+        buffer
+    }
+}
+
+pub fn compute_parameter(
+    cube: &SpectralCube,
+    definition: &ParameterDefinition,
+) -> Result<ParameterMap> {
+    let plane = |r: &BandRef| -> Result<&ImageBuffer> { Ok(&cube.planes[r.resolve(cube)?]) };
+    let n = cube.width * cube.height;
+    let at = |p: &ImageBuffer, i: usize| p.get(i % cube.width, i / cube.width);
+
+    let data: Vec<f32> = match &definition.kind {
+        ParameterKind::Ratio {
+            numerator,
+            denominator,
+        } => {
+            let (num, den) = (plane(numerator)?, plane(denominator)?);
+            (0..n).map(|i| at(num, i) / at(den, i)).collect()
+        }
+        ParameterKind::BandDepth {
+            left,
+            center,
+            right,
+        } => {
+            let (wl, wc, wr) = (
+                left.wavelength(cube)?,
+                center.wavelength(cube)?,
+                right.wavelength(cube)?,
+            );
+            if !(wl < wc && wc < wr) {
+                return Err(anyhow!(
+                    "Band depth {} needs left < center < right wavelengths",
+                    definition.name
+                ));
+            }
+            let f = (wc - wl) / (wr - wl);
+            let (l, c, r) = (plane(left)?, plane(center)?, plane(right)?);
+            (0..n)
+                .map(|i| {
+                    let continuum = at(l, i) * (1.0 - f) + at(r, i) * f;
+                    1.0 - at(c, i) / continuum
+                })
+                .collect()
+        }
+        ParameterKind::Slope { from, to } => {
+            let dw = to.wavelength(cube)? - from.wavelength(cube)?;
+            if dw == 0.0 {
+                return Err(anyhow!(
+                    "Slope {} needs two different wavelengths",
+                    definition.name
+                ));
+            }
+            let (a, b) = (plane(from)?, plane(to)?);
+            (0..n).map(|i| (at(b, i) - at(a, i)) / dw).collect()
+        }
+    };
+
+    Ok(ParameterMap {
+        name: definition.name.clone(),
+        width: cube.width,
+        height: cube.height,
+        data: data
+            .into_iter()
+            .map(|v| if v.is_finite() { v } else { f32::NAN })
+            .collect(),
+    })
+}
+
+/// Value at the given fraction (0-1) of the sorted values
+fn percentile(sorted: &[f32], fraction: f32) -> f32 {
+    let i = ((sorted.len() - 1) as f32 * fraction).round() as usize;
+    sorted[i]
+}
+
+pub fn compose(cube: &SpectralCube, definition: &CompositeDefinition) -> Result<Image> {
+    let mut out = Image::new_with_bands(cube.width, cube.height, 3, ImageMode::U16BIT).unwrap();
+    for (b, band) in [&definition.red, &definition.green, &definition.blue]
+        .iter()
+        .enumerate()
+    {
+        let plane = &cube.planes[band.resolve(cube)?];
+        let mut values: Vec<f32> = (0..cube.height)
+            .flat_map(|y| (0..cube.width).map(move |x| plane.get(x, y)))
+            .collect();
+
+        let (lo, hi) = if definition.stretch {
+            let mut sorted = values.clone();
+            sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+            (percentile(&sorted, 0.01), percentile(&sorted, 0.99))
+        } else {
+            (0.0, util::max_value_for_mode(plane.mode))
+        };
+        let span = if hi > lo { hi - lo } else { 1.0 };
+        values
+            .iter_mut()
+            .for_each(|v| *v = ((*v - lo) / span * 65535.0).clamp(0.0, 65535.0));
+
+        for y in 0..cube.height {
+            for x in 0..cube.width {
+                out.put(x, y, values[y * cube.width + x], b);
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Value range of a written parameter map, allowing the scaled PNG to be converted back
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ParameterRange {
+    name: String,
+    description: Option<String>,
+    min: f32,
+    max: f32,
+}
+
+/// Computes every parameter and composite in `definitions`, writing `<name>.png` files to
+/// `output_dir`. Parameter maps are scaled to 16 bits with their value range recorded in
+/// `<name>.json`. Definitions referencing filters missing from the cube are skipped with a
+/// warning. Returns the written image files.
+pub fn save_products(
+    cube: &SpectralCube,
+    definitions: &SpectralDefinitions,
+    output_dir: &str,
+) -> Result<Vec<String>> {
+    let mut written = vec![];
+    for def in definitions.parameter.iter() {
+        vprintln!("Computing parameter {}", def.name);
+        let map = match compute_parameter(cube, def) {
+            Ok(map) => map,
+            Err(why) => {
+                veprintln!("Skipping parameter {}: {}", def.name, why);
+                continue;
+            }
+        };
+        let out_file = format!("{}/{}.png", output_dir, def.name);
+        map.to_imagebuffer().save_16bit(&out_file);
+
+        let (min, max) = map.range().unwrap_or((0.0, 0.0));
+        fs::write(
+            format!("{}/{}.json", output_dir, def.name),
+            serde_json::to_string_pretty(&ParameterRange {
+                name: def.name.clone(),
+                description: def.description.clone(),
+                min,
+                max,
+            })?,
+        )?;
+        written.push(out_file);
+    }
+
+    for def in definitions.composite.iter() {
+        vprintln!("Composing {}", def.name);
+        let out_file = format!("{}/{}.png", output_dir, def.name);
+        match compose(cube, def) {
+            Ok(img) => img.save(&out_file),
+            Err(why) => {
+                veprintln!("Skipping composite {}: {}", def.name, why);
+                continue;
+            }
+        }
+        written.push(out_file);
+    }
+    Ok(written)
+}
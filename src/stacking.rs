@@ -0,0 +1,466 @@
+use crate::vprintln;
+
+use sciimg::image::Image;
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+use std::str::FromStr;
+
+/// Per-pixel combination methods
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum StackMethod {
+    #[default]
+    Mean,
+
+    /// Per-pixel (weighted) median
+    Median,
+
+    /// Mean after iteratively rejecting values outside `sigma_low`/`sigma_high` standard
+    /// deviations of the mean
+    SigmaClip,
+
+    /// Mean after clamping values to `sigma_low`/`sigma_high` standard deviations of the mean
+    Winsorized,
+
+    Min,
+    Max,
+}
+
+impl FromStr for StackMethod {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "mean" | "average" => Ok(StackMethod::Mean),
+            "median" => Ok(StackMethod::Median),
+            "sigma" | "sigmaclip" | "sigma-clip" => Ok(StackMethod::SigmaClip),
+            "winsor" | "winsorized" => Ok(StackMethod::Winsorized),
+            "min" => Ok(StackMethod::Min),
+            "max" => Ok(StackMethod::Max),
+            _ => Err("Invalid stacking method"),
+        }
+    }
+}
+
+/// Stacking parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackOptions {
+    pub method: StackMethod,
+
+    /// Rejection/clamping threshold below the mean, in standard deviations
+    pub sigma_low: f32,
+
+    /// Rejection/clamping threshold above the mean, in standard deviations
+    pub sigma_high: f32,
+
+    /// Number of rejection passes for sigma clipping
+    pub iterations: usize,
+
+    /// Optional per-frame weights. Must match the number of frames.
+    pub weights: Option<Vec<f32>>,
+
+    /// Approximate memory (bytes) available for holding pixel values when computing
+    /// the median. Larger stacks are processed in horizontal strips.
+    pub memory_budget: usize,
+}
+
+impl Default for StackOptions {
+    fn default() -> Self {
+        StackOptions {
+            method: StackMethod::Mean,
+            sigma_low: 3.0,
+            sigma_high: 3.0,
+            iterations: 3,
+            weights: None,
+            memory_budget: 512 * 1024 * 1024,
+        }
+    }
+}
+
+/// A sequence of frames that can be read more than once. Implemented for in-memory images
+/// and for files opened on demand, so long sequences need not be held in memory.
+pub trait FrameSource {
+    fn num_frames(&self) -> usize;
+    fn frame(&self, index: usize) -> Result<Image>;
+}
+
+impl FrameSource for [Image] {
+    fn num_frames(&self) -> usize {
+        self.len()
+    }
+
+    fn frame(&self, index: usize) -> Result<Image> {
+        self.get(index)
+            .cloned()
+            .ok_or_else(|| anyhow!("Frame index out of range"))
+    }
+}
+
+impl FrameSource for Vec<Image> {
+    fn num_frames(&self) -> usize {
+        self.len()
+    }
+
+    fn frame(&self, index: usize) -> Result<Image> {
+        self.as_slice().frame(index)
+    }
+}
+
+/// Frames opened from disk each time they are read
+pub struct FileFrames {
+    pub files: Vec<String>,
+}
+
+impl FileFrames {
+    pub fn new(files: &[String]) -> Self {
+        FileFrames {
+            files: files.to_vec(),
+        }
+    }
+}
+
+impl FrameSource for FileFrames {
+    fn num_frames(&self) -> usize {
+        self.files.len()
+    }
+
+    fn frame(&self, index: usize) -> Result<Image> {
+        let file = self
+            .files
+            .get(index)
+            .ok_or_else(|| anyhow!("Frame index out of range"))?;
+        vprintln!("Reading frame {}", file);
+        Ok(Image::open_str(file)?)
+    }
+}
+
+/// Dimensions shared by every frame in the stack
+struct Geometry {
+    width: usize,
+    height: usize,
+    bands: usize,
+    reference: Image,
+}
+
+impl Geometry {
+    fn len(&self) -> usize {
+        self.width * self.height * self.bands
+    }
+
+    fn check(&self, frame: &Image, index: usize) -> Result<()> {
+        if frame.width != self.width || frame.height != self.height {
+            Err(anyhow!(
+                "Frame {} has differing dimensions ({}x{}, expected {}x{})",
+                index,
+                frame.width,
+                frame.height,
+                self.width,
+                self.height
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Builds the output image from per-pixel values ordered band, row, column
+    fn to_image(&self, values: &[f64]) -> Image {
+        let mut out = Image::new_with_bands(
+            self.width,
+            self.height,
+            self.bands,
+            self.reference.get_mode(),
+        )
+        .unwrap();
+        for b in 0..self.bands {
+            for y in 0..self.height {
+                for x in 0..self.width {
+                    out.put(x, y, values[self.index(x, y, b)] as f32, b);
+                }
+            }
+        }
+        out
+    }
+
+    fn index(&self, x: usize, y: usize, band: usize) -> usize {
+        (band * self.height + y) * self.width + x
+    }
+}
+
+/// Visits every sample of a frame with its index in band, row, column order
+fn for_each_sample<F: FnMut(usize, f64)>(frame: &Image, geometry: &Geometry, mut f: F) {
+    for b in 0..geometry.bands {
+        let band = frame.get_band(b.min(frame.num_bands() - 1));
+        for y in 0..geometry.height {
+            for x in 0..geometry.width {
+                f(geometry.index(x, y, b), band.get(x, y) as f64);
+            }
+        }
+    }
+}
+
+fn weights_for<S: FrameSource + ?Sized>(source: &S, options: &StackOptions) -> Result<Vec<f64>> {
+    match &options.weights {
+        Some(w) if w.len() != source.num_frames() => Err(anyhow!(
+            "Number of weights ({}) does not match the number of frames ({})",
+            w.len(),
+            source.num_frames()
+        )),
+        Some(w) if w.iter().any(|v| *v < 0.0) => Err(anyhow!("Frame weights cannot be negative")),
+        Some(w) => Ok(w.iter().map(|v| *v as f64).collect()),
+        None => Ok(vec![1.0; source.num_frames()]),
+    }
+}
+
+/// Weighted running sums used by the mean based methods
+struct Accumulator {
+    sum: Vec<f64>,
+    sum_sq: Vec<f64>,
+    weight: Vec<f64>,
+}
+
+impl Accumulator {
+    fn new(len: usize) -> Self {
+        Accumulator {
+            sum: vec![0.0; len],
+            sum_sq: vec![0.0; len],
+            weight: vec![0.0; len],
+        }
+    }
+
+    fn add(&mut self, i: usize, v: f64, w: f64) {
+        self.sum[i] += v * w;
+        self.sum_sq[i] += v * v * w;
+        self.weight[i] += w;
+    }
+
+    fn mean(&self, i: usize) -> f64 {
+        if self.weight[i] > 0.0 {
+            self.sum[i] / self.weight[i]
+        } else {
+            0.0
+        }
+    }
+
+    fn stddev(&self, i: usize) -> f64 {
+        if self.weight[i] > 0.0 {
+            let m = self.mean(i);
+            (self.sum_sq[i] / self.weight[i] - m * m).max(0.0).sqrt()
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Streams every frame once, accumulating samples through `f`
+fn stream<S: FrameSource + ?Sized, F: FnMut(usize, f64, f64)>(
+    source: &S,
+    geometry: &Geometry,
+    weights: &[f64],
+    mut f: F,
+) -> Result<()> {
+    for (index, weight) in weights.iter().enumerate() {
+        let frame = source.frame(index)?;
+        geometry.check(&frame, index)?;
+        for_each_sample(&frame, geometry, |i, v| f(i, v, *weight));
+    }
+    Ok(())
+}
+
+/// Mean and standard deviation bounds from an accumulator
+fn bounds(acc: &Accumulator, i: usize, options: &StackOptions) -> (f64, f64) {
+    let m = acc.mean(i);
+    let s = acc.stddev(i);
+    (
+        m - options.sigma_low as f64 * s,
+        m + options.sigma_high as f64 * s,
+    )
+}
+
+fn stack_mean_based<S: FrameSource + ?Sized>(
+    source: &S,
+    geometry: &Geometry,
+    weights: &[f64],
+    options: &StackOptions,
+) -> Result<Vec<f64>> {
+    let len = geometry.len();
+    let mut acc = Accumulator::new(len);
+    stream(source, geometry, weights, |i, v, w| acc.add(i, v, w))?;
+
+    match options.method {
+        StackMethod::SigmaClip => {
+            for pass in 0..options.iterations {
+                vprintln!("Sigma clipping pass {}", pass + 1);
+                let limits: Vec<(f64, f64)> = (0..len).map(|i| bounds(&acc, i, options)).collect();
+                let mut next = Accumulator::new(len);
+                stream(source, geometry, weights, |i, v, w| {
+                    if v >= limits[i].0 && v <= limits[i].1 {
+                        next.add(i, v, w);
+                    }
+                })?;
+                // Keep the previous estimate for pixels where every sample was rejected
+                for i in 0..len {
+                    if next.weight[i] == 0.0 {
+                        next.sum[i] = acc.sum[i];
+                        next.sum_sq[i] = acc.sum_sq[i];
+                        next.weight[i] = acc.weight[i];
+                    }
+                }
+                acc = next;
+            }
+        }
+        StackMethod::Winsorized => {
+            let limits: Vec<(f64, f64)> = (0..len).map(|i| bounds(&acc, i, options)).collect();
+            let mut next = Accumulator::new(len);
+            stream(source, geometry, weights, |i, v, w| {
+                next.add(i, v.clamp(limits[i].0, limits[i].1), w);
+            })?;
+            acc = next;
+        }
+        _ => {}
+    }
+
+    Ok((0..len).map(|i| acc.mean(i)).collect())
+}
+
+fn stack_extrema<S: FrameSource + ?Sized>(
+    source: &S,
+    geometry: &Geometry,
+    weights: &[f64],
+    max: bool,
+) -> Result<Vec<f64>> {
+    let mut values = vec![if max { f64::MIN } else { f64::MAX }; geometry.len()];
+    stream(source, geometry, weights, |i, v, w| {
+        // Zero weight frames are excluded
+        if w > 0.0 {
+            values[i] = if max {
+                values[i].max(v)
+            } else {
+                values[i].min(v)
+            };
+        }
+    })?;
+    values
+        .iter_mut()
+        .filter(|v| **v == f64::MIN || **v == f64::MAX)
+        .for_each(|v| *v = 0.0);
+    Ok(values)
+}
+
+/// Weighted median of (value, weight) samples
+pub fn weighted_median(samples: &mut [(f64, f64)]) -> f64 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    samples.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(std::cmp::Ordering::Equal));
+    let total: f64 = samples.iter().map(|s| s.1).sum();
+    if total <= 0.0 {
+        return 0.0;
+    }
+
+    let mut cumulative = 0.0;
+    for (i, (v, w)) in samples.iter().enumerate() {
+        cumulative += w;
+        if cumulative > total / 2.0 {
+            return *v;
+        }
+        if cumulative == total / 2.0 {
+            // Exactly half the weight below: average with the next value
+            return match samples.get(i + 1) {
+                Some((next, _)) => (v + next) / 2.0,
+                None => *v,
+            };
+        }
+    }
+    samples[samples.len() - 1].0
+}
+
+fn stack_median<S: FrameSource + ?Sized>(
+    source: &S,
+    geometry: &Geometry,
+    weights: &[f64],
+    options: &StackOptions,
+) -> Result<Vec<f64>> {
+    let frames = source.num_frames();
+    let row_bytes = geometry.width * geometry.bands * frames * std::mem::size_of::<(f64, f64)>();
+    let rows_per_strip = (options.memory_budget / row_bytes.max(1)).clamp(1, geometry.height);
+    let strips = (geometry.height + rows_per_strip - 1) / rows_per_strip;
+    if strips > 1 {
+        vprintln!(
+            "Computing median in {} strips of {} rows",
+            strips,
+            rows_per_strip
+        );
+    }
+
+    let mut out = vec![0.0; geometry.len()];
+    for strip in 0..strips {
+        let y0 = strip * rows_per_strip;
+        let y1 = (y0 + rows_per_strip).min(geometry.height);
+        let strip_len = (y1 - y0) * geometry.width;
+
+        // Samples per pixel, indexed by band then position within the strip
+        let mut samples: Vec<Vec<(f64, f64)>> =
+            vec![Vec::with_capacity(frames); strip_len * geometry.bands];
+        for (index, weight) in weights.iter().enumerate() {
+            if *weight <= 0.0 {
+                continue;
+            }
+            let frame = source.frame(index)?;
+            geometry.check(&frame, index)?;
+            for b in 0..geometry.bands {
+                let band = frame.get_band(b.min(frame.num_bands() - 1));
+                for y in y0..y1 {
+                    for x in 0..geometry.width {
+                        let s = b * strip_len + (y - y0) * geometry.width + x;
+                        samples[s].push((band.get(x, y) as f64, *weight));
+                    }
+                }
+            }
+        }
+
+        for b in 0..geometry.bands {
+            for y in y0..y1 {
+                for x in 0..geometry.width {
+                    let s = b * strip_len + (y - y0) * geometry.width + x;
+                    out[geometry.index(x, y, b)] = weighted_median(&mut samples[s]);
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Combines the frames of `source` per pixel using the configured method. Mean, min and max
+/// read each frame once, sigma clipping and winsorizing read the frames once per pass, and
+/// the median reads them once per strip. Only the output sized accumulators and, for the
+/// median, one strip of samples are held in memory.
+pub fn stack<S: FrameSource + ?Sized>(source: &S, options: &StackOptions) -> Result<Image> {
+    if source.num_frames() == 0 {
+        return Err(anyhow!("No frames to stack"));
+    }
+    let weights = weights_for(source, options)?;
+
+    let reference = source.frame(0)?;
+    let geometry = Geometry {
+        width: reference.width,
+        height: reference.height,
+        bands: reference.num_bands(),
+        reference,
+    };
+
+    vprintln!(
+        "Stacking {} frames using {:?}",
+        source.num_frames(),
+        options.method
+    );
+    let values = match options.method {
+        StackMethod::Mean | StackMethod::SigmaClip | StackMethod::Winsorized => {
+            stack_mean_based(source, &geometry, &weights, options)?
+        }
+        StackMethod::Min => stack_extrema(source, &geometry, &weights, false)?,
+        StackMethod::Max => stack_extrema(source, &geometry, &weights, true)?,
+        StackMethod::Median => stack_median(source, &geometry, &weights, options)?,
+    };
+    Ok(geometry.to_image(&values))
+}
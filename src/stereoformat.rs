@@ -0,0 +1,251 @@
+use crate::{util::max_value_for_mode, vprintln};
+
+use sciimg::image::Image;
+
+use anyhow::anyhow;
+use anyhow::Result;
+use image::{codecs::jpeg::JpegEncoder, ColorType};
+
+use std::fs::File;
+use std::io::Write;
+use std::str::FromStr;
+
+/// Stereo output layouts
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StereoFormat {
+    /// Left eye on the left, right eye on the right (wall-eye/parallel viewing, VR)
+    Parallel,
+
+    /// Right eye on the left, left eye on the right (cross-eye viewing)
+    CrossEye,
+
+    /// Left eye above the right eye
+    OverUnder,
+
+    /// Even rows from the left eye, odd rows from the right eye (line-interleaved displays)
+    InterleavedRows,
+
+    /// Multi-picture object (two JPEGs in one file) for stereo cameras, displays and viewers
+    Mpo,
+}
+
+impl FromStr for StereoFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "parallel" | "sbs" | "walleye" => Ok(StereoFormat::Parallel),
+            "cross" | "crosseye" | "cross-eye" => Ok(StereoFormat::CrossEye),
+            "over-under" | "overunder" | "ou" => Ok(StereoFormat::OverUnder),
+            "interleaved" | "rows" => Ok(StereoFormat::InterleavedRows),
+            "mpo" => Ok(StereoFormat::Mpo),
+            _ => Err("Invalid stereo format"),
+        }
+    }
+}
+
+fn check_dimensions(left: &Image, right: &Image) -> Result<()> {
+    if left.width != right.width || left.height != right.height {
+        Err(anyhow!("Left and right images have different dimensions"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Composes the two eyes into a single image in the requested layout. When `squeeze` is set,
+/// side-by-side layouts are produced at half width and over-under at half height (the "half"
+/// frame-compatible formats used by 3D televisions and projectors).
+pub fn compose(left: &Image, right: &Image, format: StereoFormat, squeeze: bool) -> Result<Image> {
+    check_dimensions(left, right)?;
+    let width = left.width;
+    let height = left.height;
+
+    let (mut left, mut right) = (left.clone(), right.clone());
+
+    match format {
+        StereoFormat::Parallel | StereoFormat::CrossEye => {
+            if squeeze {
+                left.resize_to(width / 2, height);
+                right.resize_to(width / 2, height);
+            }
+            let (first, second) = if format == StereoFormat::Parallel {
+                (&left, &right)
+            } else {
+                (&right, &left)
+            };
+            let mut map = Image::create(first.width * 2, first.height);
+            map.paste(first, 0, 0);
+            map.paste(second, first.width, 0);
+            Ok(map)
+        }
+        StereoFormat::OverUnder => {
+            if squeeze {
+                left.resize_to(width, height / 2);
+                right.resize_to(width, height / 2);
+            }
+            let mut map = Image::create(left.width, left.height * 2);
+            map.paste(&left, 0, 0);
+            map.paste(&right, 0, left.height);
+            Ok(map)
+        }
+        StereoFormat::InterleavedRows => {
+            let mut map = Image::create(width, height);
+            for y in 0..height {
+                let src = if y % 2 == 0 { &left } else { &right };
+                for b in 0..3 {
+                    let band = src.get_band(b.min(src.num_bands() - 1));
+                    for x in 0..width {
+                        map.put(x, y, band.get(x, y), b);
+                    }
+                }
+            }
+            Ok(map)
+        }
+        StereoFormat::Mpo => Err(anyhow!(
+            "MPO is a multi-image container, use save_mpo() instead"
+        )),
+    }
+}
+
+/// Converts an image into interleaved 8-bit RGB bytes, scaling by the image mode
+fn to_rgb8(img: &Image) -> Vec<u8> {
+    let max = max_value_for_mode(img.get_mode());
+    let mut bytes = vec![0; img.width * img.height * 3];
+    for y in 0..img.height {
+        for x in 0..img.width {
+            let idx = (y * img.width + x) * 3;
+            for b in 0..3 {
+                let v = img.get_band(b.min(img.num_bands() - 1)).get(x, y);
+                bytes[idx + b] = (v / max * 255.0).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+    bytes
+}
+
+fn encode_jpeg(img: &Image, quality: u8) -> Result<Vec<u8>> {
+    let mut bytes: Vec<u8> = vec![];
+    let mut encoder = JpegEncoder::new_with_quality(&mut bytes, quality);
+    encoder.encode(
+        &to_rgb8(img),
+        img.width as u32,
+        img.height as u32,
+        ColorType::Rgb8,
+    )?;
+    Ok(bytes)
+}
+
+/// Offset of the MP header (TIFF byte order marker) from the start of the APP2 marker
+const MP_HEADER_OFFSET: usize = 8;
+
+/// Builds the APP2 "MPF" segment describing a two image (left, right) MPO file.
+fn build_mpf_app2(first_size: u32, second_size: u32, second_offset: u32) -> Vec<u8> {
+    let mut seg: Vec<u8> = vec![];
+
+    // Marker and length (length covers everything after the marker)
+    seg.extend_from_slice(&[0xFF, 0xE2]);
+    seg.extend_from_slice(&88_u16.to_be_bytes());
+    seg.extend_from_slice(b"MPF\0");
+
+    // MP header: big endian TIFF header with the index IFD immediately following
+    seg.extend_from_slice(&[0x4D, 0x4D, 0x00, 0x2A]);
+    seg.extend_from_slice(&8_u32.to_be_bytes());
+
+    // MP index IFD with three entries
+    seg.extend_from_slice(&3_u16.to_be_bytes());
+
+    // MPFVersion
+    seg.extend_from_slice(&0xB000_u16.to_be_bytes());
+    seg.extend_from_slice(&7_u16.to_be_bytes());
+    seg.extend_from_slice(&4_u32.to_be_bytes());
+    seg.extend_from_slice(b"0100");
+
+    // NumberOfImages
+    seg.extend_from_slice(&0xB001_u16.to_be_bytes());
+    seg.extend_from_slice(&4_u16.to_be_bytes());
+    seg.extend_from_slice(&1_u32.to_be_bytes());
+    seg.extend_from_slice(&2_u32.to_be_bytes());
+
+    // MPEntry, 16 bytes per image stored after the IFD
+    seg.extend_from_slice(&0xB002_u16.to_be_bytes());
+    seg.extend_from_slice(&7_u16.to_be_bytes());
+    seg.extend_from_slice(&32_u32.to_be_bytes());
+    seg.extend_from_slice(&50_u32.to_be_bytes());
+
+    // Offset of next IFD
+    seg.extend_from_slice(&0_u32.to_be_bytes());
+
+    // First image: representative, baseline MP primary image. Offset is zero by definition.
+    seg.extend_from_slice(&0x2003_0000_u32.to_be_bytes());
+    seg.extend_from_slice(&first_size.to_be_bytes());
+    seg.extend_from_slice(&0_u32.to_be_bytes());
+    seg.extend_from_slice(&[0, 0, 0, 0]);
+
+    // Second image: disparity (stereo) image
+    seg.extend_from_slice(&0x0002_0002_u32.to_be_bytes());
+    seg.extend_from_slice(&second_size.to_be_bytes());
+    seg.extend_from_slice(&second_offset.to_be_bytes());
+    seg.extend_from_slice(&[0, 0, 0, 0]);
+
+    seg
+}
+
+/// Finds where to insert the APP2 segment: after SOI and any APP0 (JFIF) segment.
+fn app2_insert_position(jpeg: &[u8]) -> usize {
+    let mut pos = 2;
+    if jpeg.len() > 4 && jpeg[2] == 0xFF && jpeg[3] == 0xE0 {
+        let len = u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+        pos += 2 + len;
+    }
+    pos
+}
+
+/// Encodes a stereo pair as an MPO byte stream (left eye first)
+pub fn encode_mpo(left: &Image, right: &Image, quality: u8) -> Result<Vec<u8>> {
+    check_dimensions(left, right)?;
+
+    let first = encode_jpeg(left, quality)?;
+    let second = encode_jpeg(right, quality)?;
+
+    let insert_at = app2_insert_position(&first);
+    let first_size = (first.len() + 90) as u32; // APP2 marker (2) + segment (88)
+    let mp_header_pos = insert_at + MP_HEADER_OFFSET;
+    let second_offset = first_size - mp_header_pos as u32;
+
+    let app2 = build_mpf_app2(first_size, second.len() as u32, second_offset);
+
+    let mut mpo: Vec<u8> = Vec::with_capacity(first_size as usize + second.len());
+    mpo.extend_from_slice(&first[..insert_at]);
+    mpo.extend_from_slice(&app2);
+    mpo.extend_from_slice(&first[insert_at..]);
+    mpo.extend_from_slice(&second);
+    Ok(mpo)
+}
+
+/// Saves a stereo pair as an MPO file
+pub fn save_mpo(left: &Image, right: &Image, output_file: &str) -> Result<()> {
+    vprintln!("Writing MPO to {}", output_file);
+    let mpo = encode_mpo(left, right, 95)?;
+    let mut file = File::create(output_file)?;
+    file.write_all(&mpo)?;
+    Ok(())
+}
+
+/// Writes the pair to disk in the requested format
+pub fn save(
+    left: &Image,
+    right: &Image,
+    format: StereoFormat,
+    squeeze: bool,
+    output_file: &str,
+) -> Result<()> {
+    match format {
+        StereoFormat::Mpo => save_mpo(left, right, output_file),
+        _ => {
+            let map = compose(left, right, format, squeeze)?;
+            vprintln!("Writing {:?} stereo image to {}", format, output_file);
+            map.save(output_file);
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use crate::{
+    anaglyph,
+    enums::{Eye, Instrument},
+    marsimage::MarsImage,
+    metadata::{load_image_metadata, Metadata},
+    util, vprintln,
+};
+
+use sciimg::{drawable::*, path, prelude::*, vector::Vector};
+
+use anyhow::anyhow;
+use anyhow::Result;
+use glob::glob;
+use regex::Regex;
+
+use std::str::FromStr;
+
+lazy_static! {
+    /// Matches the sequence id embedded in both MSL and M20 product ids (e.g. `NCAM00545`, `ZCAM08419`)
+    static ref SEQUENCE_ID: Regex = Regex::new(r"([A-Z]{4}[0-9]{5})").unwrap();
+}
+
+/// Options controlling how left and right eye images are matched
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PairingCriteria {
+    /// Maximum difference in spacecraft clock (seconds) between the two eyes
+    pub sclk_tolerance: f64,
+
+    /// Require identical sequence ids when both images have one
+    pub match_sequence: bool,
+
+    /// Require identical site and drive when both images have them
+    pub match_site_drive: bool,
+
+    /// Require identical subframe rectangles when both images have them
+    pub match_subframe: bool,
+}
+
+impl Default for PairingCriteria {
+    fn default() -> Self {
+        PairingCriteria {
+            sclk_tolerance: 2.0,
+            match_sequence: true,
+            match_site_drive: true,
+            match_subframe: true,
+        }
+    }
+}
+
+/// An image file considered for stereo pairing along with the metadata needed to do so
+#[derive(Clone)]
+pub struct StereoCandidate {
+    pub file_path: String,
+    pub instrument: Instrument,
+    pub eye: Eye,
+    pub sclk: f64,
+    pub sequence_id: Option<String>,
+    pub metadata: Metadata,
+}
+
+/// A matched left/right pair of image files
+#[derive(Debug, Clone, PartialEq)]
+pub struct StereoPair {
+    pub left: String,
+    pub right: String,
+    pub instrument: Instrument,
+    pub sclk_delta: f64,
+}
+
+/// Extracts the sequence id (e.g. `NCAM00545`) from an image id or file name
+pub fn sequence_id_from_name(name: &str) -> Option<String> {
+    let bn = path::basename(name);
+    SEQUENCE_ID
+        .captures(&bn)
+        .map(|caps| caps.get(1).unwrap().as_str().to_string())
+}
+
+/// Returns whatever follows the product id in the file name (e.g. `-rjcal`), allowing raw and
+/// calibrated versions of the same product to be told apart.
+pub(crate) fn product_suffix(file_path: &str, imageid: &str) -> String {
+    let bn = path::basename(file_path);
+    let stem = match bn.rfind('.') {
+        Some(i) => &bn[..i],
+        None => bn.as_str(),
+    };
+    match stem.strip_prefix(imageid) {
+        Some(suffix) => suffix.to_string(),
+        None => String::from(""),
+    }
+}
+
+impl StereoCandidate {
+    /// Constructs a candidate from an image path and its metadata. Returns an error if
+    /// the image is not part of a stereo camera pair or lacks a spacecraft clock.
+    pub fn new_from_metadata(file_path: &str, metadata: &Metadata) -> Result<Self> {
+        let instrument = Instrument::from_str(&metadata.instrument).unwrap();
+        let eye = instrument.eye();
+        if eye == Eye::DontCare {
+            return Err(anyhow!(
+                "Instrument {} is not a stereo camera",
+                metadata.instrument
+            ));
+        }
+
+        let sclk = match metadata.sclk {
+            Some(s) => s,
+            None => return Err(anyhow!("Image lacks a spacecraft clock value")),
+        };
+
+        let sequence_id =
+            sequence_id_from_name(&metadata.imageid).or_else(|| sequence_id_from_name(file_path));
+
+        Ok(StereoCandidate {
+            file_path: file_path.to_string(),
+            instrument,
+            eye,
+            sclk,
+            sequence_id,
+            metadata: metadata.clone(),
+        })
+    }
+
+    /// Constructs a candidate from an image path, loading the accompanying metadata file
+    pub fn new_from_file(file_path: &str) -> Result<Self> {
+        let metadata_file = util::replace_image_extension(file_path, "-metadata.json");
+        if !path::file_exists(&metadata_file) {
+            return Err(anyhow!("Metadata file not found for {}", file_path));
+        }
+        let md = load_image_metadata(&metadata_file)?;
+        StereoCandidate::new_from_metadata(file_path, &md)
+    }
+
+    /// Determines if `right` is an acceptable right eye match for this (left eye) candidate
+    pub fn matches(&self, right: &StereoCandidate, criteria: &PairingCriteria) -> bool {
+        if self.eye != Eye::Left || right.eye != Eye::Right {
+            return false;
+        }
+
+        if self.instrument.stereo_counterpart() != Some(right.instrument) {
+            return false;
+        }
+
+        if (self.sclk - right.sclk).abs() > criteria.sclk_tolerance {
+            return false;
+        }
+
+        if criteria.match_sequence {
+            if let (Some(l), Some(r)) = (&self.sequence_id, &right.sequence_id) {
+                if l != r {
+                    return false;
+                }
+            }
+        }
+
+        if criteria.match_site_drive {
+            if let (Some(l), Some(r)) = (self.metadata.site, right.metadata.site) {
+                if l != r {
+                    return false;
+                }
+            }
+            if let (Some(l), Some(r)) = (self.metadata.drive, right.metadata.drive) {
+                if l != r {
+                    return false;
+                }
+            }
+        }
+
+        if criteria.match_subframe {
+            if let (Some(l), Some(r)) =
+                (&self.metadata.subframe_rect, &right.metadata.subframe_rect)
+            {
+                if l != r {
+                    return false;
+                }
+            }
+        }
+
+        if self.metadata.scale_factor != right.metadata.scale_factor {
+            return false;
+        }
+
+        if product_suffix(&self.file_path, &self.metadata.imageid)
+            != product_suffix(&right.file_path, &right.metadata.imageid)
+        {
+            return false;
+        }
+
+        true
+    }
+}
+
+/// Pairs left eye candidates with right eye candidates. Each right eye image is used at most
+/// once, with the closest spacecraft clock match winning. Pairs are returned in left eye
+/// SCLK order.
+pub fn find_pairs(candidates: &[StereoCandidate], criteria: &PairingCriteria) -> Vec<StereoPair> {
+    let mut lefts: Vec<&StereoCandidate> =
+        candidates.iter().filter(|c| c.eye == Eye::Left).collect();
+    lefts.sort_by(|a, b| a.sclk.total_cmp(&b.sclk));
+
+    let rights: Vec<&StereoCandidate> = candidates.iter().filter(|c| c.eye == Eye::Right).collect();
+    let mut used = vec![false; rights.len()];
+
+    let mut pairs = vec![];
+    for left in lefts.iter() {
+        let best = rights
+            .iter()
+            .enumerate()
+            .filter(|(i, r)| !used[*i] && left.matches(r, criteria))
+            .min_by(|(_, a), (_, b)| {
+                let da = (left.sclk - a.sclk).abs();
+                let db = (left.sclk - b.sclk).abs();
+                da.total_cmp(&db)
+            });
+
+        if let Some((i, right)) = best {
+            used[i] = true;
+            vprintln!(
+                "Matched stereo pair: {} / {}",
+                left.file_path,
+                right.file_path
+            );
+            pairs.push(StereoPair {
+                left: left.file_path.clone(),
+                right: right.file_path.clone(),
+                instrument: left.instrument,
+                sclk_delta: (left.sclk - right.sclk).abs(),
+            });
+        } else {
+            vprintln!("No stereo match found for {}", left.file_path);
+        }
+    }
+    pairs
+}
+
+/// Builds candidates from a list of image files, skipping those that are not usable for pairing
+pub fn candidates_from_files(input_files: &[String]) -> Vec<StereoCandidate> {
+    input_files
+        .iter()
+        .filter_map(|f| match StereoCandidate::new_from_file(f) {
+            Ok(c) => Some(c),
+            Err(why) => {
+                vprintln!("Skipping {}: {}", f, why);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Lists the image files (png/jpg) within a directory, excluding metadata files
+pub fn list_images_in_directory(directory: &str) -> Vec<String> {
+    let mut files = vec![];
+    for ext in ["png", "PNG", "jpg", "JPG"].iter() {
+        let pattern = format!("{}/*.{}", directory, ext);
+        for file_path in glob(&pattern)
+            .expect("Failed to read glob pattern")
+            .flatten()
+        {
+            if let Some(f) = file_path.to_str() {
+                files.push(f.to_string());
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Scans a directory and returns all matched stereo pairs
+pub fn find_pairs_in_directory(directory: &str, criteria: &PairingCriteria) -> Vec<StereoPair> {
+    let files = list_images_in_directory(directory);
+    vprintln!("Found {} images in {}", files.len(), directory);
+    find_pairs(&candidates_from_files(&files), criteria)
+}
+
+/// A loaded left/right stereo pair. This is the common input to the anaglyph and
+/// side-by-side/over-under/MPO stereo products.
+#[derive(Clone)]
+pub struct StereoImagePair {
+    pub left: MarsImage,
+    pub right: MarsImage,
+}
+
+/// Projects an image into its linearized camera model via the ground plane
+fn project_to_linearized(img: &Image, input_model: &CameraModel) -> Result<Image> {
+    let output_model = input_model.linearize(img.width, img.height, img.width, img.height)?;
+    let ground = Vector::new(0.0, 0.0, -1.84566);
+    let mut map = Image::create(img.width, img.height);
+
+    for line in 0..img.height {
+        for sample in 0..img.width {
+            if let Ok(lv) = input_model.ls_to_look_vector(&ImageCoordinate {
+                line: line as f64,
+                sample: sample as f64,
+            }) {
+                let ls_in = match lv.intersect_to_plane(&ground) {
+                    Some(ray) => output_model.xyz_to_ls(&ray, false),
+                    None => output_model.xyz_to_ls(&lv.look_direction, true),
+                };
+
+                let in_x = ls_in.sample.round() as usize;
+                let in_y = ls_in.line.round() as usize;
+
+                if ls_in.sample >= 0.0
+                    && ls_in.line >= 0.0
+                    && in_x < img.width - 1
+                    && in_y < img.height - 1
+                {
+                    let r = img.get_band(0).get(in_x, in_y) as f64;
+                    let g = img.get_band(1).get(in_x, in_y) as f64;
+                    let b = img.get_band(2).get(in_x, in_y) as f64;
+
+                    let tl = Point::create_rgb(sample as f64, line as f64, r, g, b);
+                    let bl = Point::create_rgb(sample as f64, (line + 1) as f64, r, g, b);
+                    let tr = Point::create_rgb((sample + 1) as f64, line as f64, r, g, b);
+                    let br = Point::create_rgb((sample + 1) as f64, (line + 1) as f64, r, g, b);
+
+                    map.paint_square(&tl, &bl, &br, &tr, false);
+                }
+            }
+        }
+    }
+    Ok(map)
+}
+
+impl StereoImagePair {
+    /// Constructs a pair from already loaded images. The eyes may differ in size, which the
+    /// anaglyph accepts; side-by-side layouts check with `check_dimensions`.
+    pub fn new(left: MarsImage, right: MarsImage) -> Self {
+        StereoImagePair { left, right }
+    }
+
+    /// Returns an error if the two eyes have different dimensions
+    pub fn check_dimensions(&self) -> Result<()> {
+        if self.left.image.width != self.right.image.width
+            || self.left.image.height != self.right.image.height
+        {
+            Err(anyhow!("Left and right images have different dimensions"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Opens a pair from disk. The instrument is taken from each image's metadata when available.
+    pub fn open(left_path: &str, right_path: &str) -> Result<Self> {
+        let open_eye = |file_path: &str, default_instrument: Instrument| {
+            let mut img = MarsImage::open(String::from(file_path), default_instrument);
+            if let Some(md) = &img.metadata {
+                let instrument = Instrument::from_str(&md.instrument).unwrap();
+                if instrument != Instrument::None {
+                    img.instrument = instrument;
+                }
+            }
+            img
+        };
+
+        Ok(StereoImagePair::new(
+            open_eye(left_path, Instrument::M20MastcamZLeft),
+            open_eye(right_path, Instrument::M20MastcamZRight),
+        ))
+    }
+
+    pub fn width(&self) -> usize {
+        self.left.image.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.left.image.height
+    }
+
+    /// Indicates whether both eyes carry a camera model that can be linearized
+    pub fn supports_linearization(&self) -> bool {
+        let linearizable = |img: &MarsImage| match anaglyph::get_camera_model(img) {
+            Ok(c) => c
+                .linearize(
+                    img.image.width,
+                    img.image.height,
+                    img.image.width,
+                    img.image.height,
+                )
+                .is_ok(),
+            Err(_) => false,
+        };
+        linearizable(&self.left) && linearizable(&self.right)
+    }
+
+    /// Returns the (left, right) eye images. When `use_cm` is set and both eyes support it,
+    /// each eye is projected into its linearized camera model, otherwise the images are
+    /// returned as-is.
+    pub fn eye_images(&self, use_cm: bool) -> Result<(Image, Image)> {
+        if use_cm && self.supports_linearization() {
+            vprintln!("Both images support CAHV linearization. Taking that path");
+            Ok((
+                project_to_linearized(&self.left.image, &anaglyph::get_camera_model(&self.left)?)?,
+                project_to_linearized(
+                    &self.right.image,
+                    &anaglyph::get_camera_model(&self.right)?,
+                )?,
+            ))
+        } else {
+            if use_cm {
+                vprintln!("One or both images do not support CAHV linearization");
+            }
+            Ok((self.left.image.clone(), self.right.image.clone()))
+        }
+    }
+
+    /// Converts both eyes to monochrome
+    pub fn to_mono(&mut self) {
+        self.left.to_mono();
+        self.right.to_mono();
+    }
+
+    /// Creates an anaglyph from the pair
+    pub fn to_anaglyph(&self, options: &anaglyph::AnaglyphOptions) -> Result<Image> {
+        anaglyph::create_anaglyph_with_options(&self.left, &self.right, options)
+    }
+}
@@ -0,0 +1,243 @@
+use crate::registration::{self, RegistrationMethod, Transform};
+use crate::vprintln;
+
+use sciimg::image::Image;
+use sciimg::path;
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+/// Parameters for drizzle reconstruction
+#[derive(Debug, Clone, PartialEq)]
+pub struct DrizzleParams {
+    /// Output resolution relative to the input frames
+    pub scale: f64,
+
+    /// Linear size of each input pixel's "drop" relative to the pixel. Smaller values
+    /// sharpen the result but need more frames (and better sub-pixel coverage) to fill
+    /// every output pixel.
+    pub pixfrac: f64,
+
+    /// How frames are aligned to the reference
+    pub registration: RegistrationMethod,
+
+    /// Index of the frame defining the output geometry
+    pub reference_index: usize,
+
+    /// Optional per-frame weights. Must match the number of frames.
+    pub weights: Option<Vec<f32>>,
+}
+
+impl Default for DrizzleParams {
+    fn default() -> Self {
+        DrizzleParams {
+            scale: 2.0,
+            pixfrac: 0.7,
+            registration: RegistrationMethod::Translation,
+            reference_index: 0,
+            weights: None,
+        }
+    }
+}
+
+impl DrizzleParams {
+    fn validate(&self, num_frames: usize) -> Result<()> {
+        if self.scale < 1.0 {
+            Err(anyhow!("Drizzle scale must be at least 1.0"))
+        } else if self.pixfrac <= 0.0 || self.pixfrac > 1.0 {
+            Err(anyhow!("Drop size (pixfrac) must be in the range (0, 1]"))
+        } else if self.reference_index >= num_frames {
+            Err(anyhow!("Invalid reference frame index"))
+        } else {
+            match &self.weights {
+                Some(w) if w.len() != num_frames => Err(anyhow!(
+                    "Number of weights ({}) does not match the number of frames ({})",
+                    w.len(),
+                    num_frames
+                )),
+                Some(w) if w.iter().any(|v| *v < 0.0) => {
+                    Err(anyhow!("Frame weights cannot be negative"))
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+}
+
+/// Drizzled image and the summed drop weight of each output pixel
+pub struct SuperResolution {
+    pub image: Image,
+
+    /// Per-pixel accumulated weight, row major. Zero where no drop landed and the output
+    /// was filled from the reference frame.
+    pub coverage: Vec<f64>,
+}
+
+impl SuperResolution {
+    /// Number of output pixels no input drop landed on
+    pub fn num_uncovered(&self) -> usize {
+        self.coverage.iter().filter(|w| **w <= 0.0).count()
+    }
+}
+
+/// Length of the overlap between `[a0, a1]` and `[b0, b1]`
+fn overlap(a0: f64, a1: f64, b0: f64, b1: f64) -> f64 {
+    (a1.min(b1) - a0.max(b0)).max(0.0)
+}
+
+/// Drizzles `images` onto an output grid `scale` times finer than the reference frame.
+/// `transforms` map reference frame coordinates into each image, as produced by
+/// `registration::register`. Each input pixel is shrunk to `pixfrac` of its size, mapped
+/// into the output grid and its value distributed over the output pixels it overlaps in
+/// proportion to the overlapping area.
+pub fn drizzle(
+    images: &[Image],
+    transforms: &[Transform],
+    params: &DrizzleParams,
+) -> Result<SuperResolution> {
+    if images.is_empty() {
+        return Err(anyhow!("No input images"));
+    }
+    if images.len() != transforms.len() {
+        return Err(anyhow!(
+            "Number of transforms does not match the number of frames"
+        ));
+    }
+    params.validate(images.len())?;
+
+    let reference = &images[params.reference_index];
+    let scale = params.scale;
+    let out_width = (reference.width as f64 * scale).ceil() as usize;
+    let out_height = (reference.height as f64 * scale).ceil() as usize;
+    let bands = reference.num_bands();
+    let num_pixels = out_width * out_height;
+
+    let mut sums = vec![0.0_f64; num_pixels * bands];
+    let mut coverage = vec![0.0_f64; num_pixels];
+
+    for (i, (img, transform)) in images.iter().zip(transforms.iter()).enumerate() {
+        let weight = match &params.weights {
+            Some(w) => w[i] as f64,
+            None => 1.0,
+        };
+        if weight <= 0.0 {
+            continue;
+        }
+        vprintln!("Drizzling frame {}", i);
+
+        // Source image to reference frame, with drop size scaled by the local pixel area
+        let to_reference = transform.inverse()?;
+        let [a, b, _, d, e, _] = to_reference.a;
+        let half = params.pixfrac * (a * e - b * d).abs().sqrt() * scale / 2.0;
+
+        for sy in 0..img.height {
+            for sx in 0..img.width {
+                let (rx, ry) = to_reference.apply(sx as f64, sy as f64);
+
+                // Output pixel (ox, oy) spans [ox, ox + 1) where reference pixel centers
+                // sit at (x + 0.5) * scale
+                let cx = (rx + 0.5) * scale;
+                let cy = (ry + 0.5) * scale;
+                let (x0, x1) = (cx - half, cx + half);
+                let (y0, y1) = (cy - half, cy + half);
+                if x1 <= 0.0 || y1 <= 0.0 || x0 >= out_width as f64 || y0 >= out_height as f64 {
+                    continue;
+                }
+
+                let ox_start = x0.max(0.0).floor() as usize;
+                let ox_end = (x1.ceil() as usize).min(out_width);
+                let oy_start = y0.max(0.0).floor() as usize;
+                let oy_end = (y1.ceil() as usize).min(out_height);
+
+                for oy in oy_start..oy_end {
+                    let fy = overlap(y0, y1, oy as f64, oy as f64 + 1.0);
+                    for ox in ox_start..ox_end {
+                        let area = fy * overlap(x0, x1, ox as f64, ox as f64 + 1.0);
+                        if area <= 0.0 {
+                            continue;
+                        }
+                        let w = area * weight;
+                        let p = oy * out_width + ox;
+                        coverage[p] += w;
+                        for band in 0..bands {
+                            let v = img.get_band(band.min(img.num_bands() - 1)).get(sx, sy);
+                            sums[band * num_pixels + p] += v as f64 * w;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    let mut image =
+        Image::new_with_bands(out_width, out_height, bands, reference.get_mode()).unwrap();
+    for oy in 0..out_height {
+        for ox in 0..out_width {
+            let p = oy * out_width + ox;
+            for band in 0..bands {
+                let v = if coverage[p] > 0.0 {
+                    sums[band * num_pixels + p] / coverage[p]
+                } else {
+                    // Nothing landed here, fall back to the nearest reference pixel
+                    let rx = ((ox as f64 / scale) as usize).min(reference.width - 1);
+                    let ry = ((oy as f64 / scale) as usize).min(reference.height - 1);
+                    reference.get_band(band).get(rx, ry) as f64
+                };
+                image.put(ox, oy, v as f32, band);
+            }
+        }
+    }
+
+    let result = SuperResolution { image, coverage };
+    let uncovered = result.num_uncovered();
+    if uncovered > 0 {
+        vprintln!(
+            "{} of {} output pixels received no input, filled from the reference frame. Consider more frames or a larger pixfrac",
+            uncovered,
+            num_pixels
+        );
+    }
+    Ok(result)
+}
+
+/// Registers `images` to the reference frame with sub-pixel precision and drizzles them
+pub fn superres(images: &[Image], params: &DrizzleParams) -> Result<SuperResolution> {
+    params.validate(images.len())?;
+    let reference = &images[params.reference_index];
+
+    let mut transforms = vec![];
+    for (i, img) in images.iter().enumerate() {
+        if i == params.reference_index {
+            transforms.push(Transform::identity());
+        } else {
+            vprintln!(
+                "Registering frame {} to frame {}",
+                i,
+                params.reference_index
+            );
+            let t = registration::register(reference, img, params.registration)?;
+            vprintln!("Frame {} transform: {:?}", i, t.a);
+            transforms.push(t);
+        }
+    }
+
+    drizzle(images, &transforms, params)
+}
+
+/// Opens `input_files` and reconstructs a super-resolution image from them
+pub fn superres_files(input_files: &[String], params: &DrizzleParams) -> Result<SuperResolution> {
+    let mut images = vec![];
+    for in_file in input_files.iter() {
+        if !path::file_exists(in_file) {
+            return Err(anyhow!("File not found: {}", in_file));
+        }
+        vprintln!("Loading image from {}", in_file);
+        images.push(Image::open_str(in_file)?);
+    }
+    if images.len() < 2 {
+        return Err(anyhow!(
+            "At least two frames are needed for super-resolution"
+        ));
+    }
+    superres(&images, params)
+}
@@ -0,0 +1,545 @@
+use crate::{enums::Instrument, marsimage::MarsImage, metadata::Metadata, vprintln};
+
+use sciimg::{image::Image, path};
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+/// Rectangle in canvas or tile pixel coordinates
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    pub fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    pub fn right(&self) -> usize {
+        self.x + self.width
+    }
+
+    pub fn bottom(&self) -> usize {
+        self.y + self.height
+    }
+
+    pub fn area(&self) -> usize {
+        self.width * self.height
+    }
+
+    /// Overlapping area of two rectangles, if any
+    pub fn intersect(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        if right > x && bottom > y {
+            Some(Rect::new(x, y, right - x, bottom - y))
+        } else {
+            None
+        }
+    }
+
+    /// The rectangle with `inset` pixels removed from each edge
+    pub fn shrink(&self, inset: usize) -> Rect {
+        let inset = inset.min(self.width / 2).min(self.height / 2);
+        Rect::new(
+            self.x + inset,
+            self.y + inset,
+            self.width - 2 * inset,
+            self.height - 2 * inset,
+        )
+    }
+
+    /// Placement at `scale` of a metadata `subframe_rect` (one based, full resolution)
+    pub fn from_subframe_rect(subframe_rect: &[f64], scale: u32) -> Option<Rect> {
+        if subframe_rect.len() != 4 {
+            return None;
+        }
+        let s = scale.max(1) as f64;
+        let at = |v: f64| (v.max(0.0) / s).round() as usize;
+        Some(Rect::new(
+            at(subframe_rect[0] - 1.0),
+            at(subframe_rect[1] - 1.0),
+            at(subframe_rect[2]),
+            at(subframe_rect[3]),
+        ))
+    }
+
+    /// This rectangle at `scale` as a metadata `subframe_rect`
+    pub fn subframe_rect(&self, scale: u32) -> Vec<f64> {
+        let s = scale as f64;
+        vec![
+            self.x as f64 * s + 1.0,
+            self.y as f64 * s + 1.0,
+            self.width as f64 * s,
+            self.height as f64 * s,
+        ]
+    }
+
+    /// This canvas rectangle relative to `origin`
+    pub fn relative_to(&self, origin: &Rect) -> Rect {
+        Rect::new(
+            self.x - origin.x,
+            self.y - origin.y,
+            self.width,
+            self.height,
+        )
+    }
+}
+
+/// Full sensor size (width, height) in pixels at scale factor 1, where known. Mastcam,
+/// MAHLI and Mastcam-Z subframe coordinates include the dark columns left of the imaging
+/// area.
+pub fn sensor_size(instrument: Instrument) -> Option<(usize, usize)> {
+    if is_m20_ecam(instrument) {
+        return Some((5120, 3840));
+    }
+    match instrument {
+        Instrument::MslNavCamLeft
+        | Instrument::MslNavCamRight
+        | Instrument::MslFrontHazLeft
+        | Instrument::MslFrontHazRight
+        | Instrument::MslRearHazLeft
+        | Instrument::MslRearHazRight => Some((1024, 1024)),
+        Instrument::MslMAHLI
+        | Instrument::MslMastcamLeft
+        | Instrument::MslMastcamRight
+        | Instrument::M20MastcamZLeft
+        | Instrument::M20MastcamZRight => Some((1648, 1200)),
+        _ => None,
+    }
+}
+
+/// Whether `instrument` is a Mars 2020 engineering camera (Navcam or Hazcam)
+pub fn is_m20_ecam(instrument: Instrument) -> bool {
+    matches!(
+        instrument,
+        Instrument::M20NavcamLeft
+            | Instrument::M20NavcamRight
+            | Instrument::M20FrontHazLeft
+            | Instrument::M20FrontHazRight
+            | Instrument::M20RearHazLeft
+            | Instrument::M20RearHazRight
+    )
+}
+
+/// Pixels along each tile edge excluded when assembling. Mars 2020 engineering camera
+/// tiles carry telemetry and compression artifacts in their outer two pixels.
+pub fn default_border(instrument: Instrument) -> usize {
+    if is_m20_ecam(instrument) {
+        2
+    } else {
+        0
+    }
+}
+
+/// A subframed image and its place on the full sensor
+#[derive(Clone)]
+pub struct Tile {
+    pub image: MarsImage,
+
+    /// Placement on the sensor at the tile's scale factor
+    pub rect: Rect,
+    pub scale: u32,
+}
+
+impl Tile {
+    /// Places `image` by the `subframe_rect` (one based, full resolution pixels) and
+    /// `scale_factor` of its metadata
+    pub fn from_image(image: MarsImage) -> Result<Tile> {
+        let md = match &image.metadata {
+            Some(md) => md,
+            None => return Err(anyhow!("Image has no metadata")),
+        };
+        let scale = md.scale_factor.max(1);
+        let placed = match md
+            .subframe_rect
+            .as_ref()
+            .and_then(|sf| Rect::from_subframe_rect(sf, scale))
+        {
+            Some(r) => r,
+            None => return Err(anyhow!("Image metadata has no subframe rectangle")),
+        };
+        let rect = Rect::new(placed.x, placed.y, image.image.width, image.image.height);
+        Ok(Tile { image, rect, scale })
+    }
+
+    /// Opens an image and places it, taking the instrument from its metadata
+    pub fn open(file_path: &str) -> Result<Tile> {
+        if !path::file_exists(file_path) {
+            return Err(anyhow!("File not found: {}", file_path));
+        }
+        let mut image = MarsImage::open(String::from(file_path), Instrument::None);
+        if let Some(md) = &image.metadata {
+            image.instrument = Instrument::from_str(&md.instrument).unwrap();
+        }
+        Tile::from_image(image)
+    }
+
+    /// Placement excluding `border` pixels along each edge
+    pub fn content_rect(&self, border: usize) -> Rect {
+        self.rect.shrink(border)
+    }
+}
+
+/// Two tiles sharing image content, with the regions (in each tile's own pixel
+/// coordinates) to compare when matching their levels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TilePair {
+    pub a: usize,
+    pub b: usize,
+    pub region_a: Rect,
+    pub region_b: Rect,
+
+    /// Whether the regions cover the same scene pixels. Otherwise the tiles only abut and
+    /// the regions are strips either side of the shared edge.
+    pub overlapping: bool,
+}
+
+/// Tile assembly parameters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssemblyOptions {
+    /// Pixels along each tile edge excluded from pasting and matching. Defaults to the
+    /// instrument's border when None.
+    pub border: Option<usize>,
+
+    /// Largest gap (pixels) between tiles still considered adjacent
+    pub margin: usize,
+
+    /// Width of the strips compared between adjacent, non-overlapping tiles
+    pub strip_width: usize,
+
+    /// Crop the canvas to the area covered by tiles instead of the full sensor
+    pub crop_to_tiles: bool,
+}
+
+impl Default for AssemblyOptions {
+    fn default() -> Self {
+        AssemblyOptions {
+            border: None,
+            margin: 2,
+            strip_width: 12,
+            crop_to_tiles: false,
+        }
+    }
+}
+
+impl AssemblyOptions {
+    pub fn border_for(&self, tiles: &[Tile]) -> usize {
+        match self.border {
+            Some(b) => b,
+            None => tiles
+                .first()
+                .map(|t| default_border(t.image.instrument))
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Overlap of ranges [a0, a1) and [b0, b1)
+fn range_overlap(a0: usize, a1: usize, b0: usize, b1: usize) -> Option<(usize, usize)> {
+    let start = a0.max(b0);
+    let end = a1.min(b1);
+    if end > start {
+        Some((start, end))
+    } else {
+        None
+    }
+}
+
+/// Strip pair either side of a shared edge, for tiles `first` then `second` along an axis
+fn abutting_regions(
+    first: &Rect,
+    second: &Rect,
+    horizontal: bool,
+    options: &AssemblyOptions,
+) -> Option<(Rect, Rect)> {
+    if horizontal {
+        let gap = second.x.checked_sub(first.right())?;
+        let (y0, y1) = range_overlap(first.y, first.bottom(), second.y, second.bottom())?;
+        if gap > options.margin {
+            return None;
+        }
+        let w = options
+            .strip_width
+            .min(first.width)
+            .min(second.width)
+            .max(1);
+        Some((
+            Rect::new(first.right() - w, y0, w, y1 - y0),
+            Rect::new(second.x, y0, w, y1 - y0),
+        ))
+    } else {
+        let gap = second.y.checked_sub(first.bottom())?;
+        let (x0, x1) = range_overlap(first.x, first.right(), second.x, second.right())?;
+        if gap > options.margin {
+            return None;
+        }
+        let h = options
+            .strip_width
+            .min(first.height)
+            .min(second.height)
+            .max(1);
+        Some((
+            Rect::new(x0, first.bottom() - h, x1 - x0, h),
+            Rect::new(x0, second.y, x1 - x0, h),
+        ))
+    }
+}
+
+/// Finds overlapping and abutting tiles from their placements
+pub fn find_pairs(tiles: &[Tile], options: &AssemblyOptions) -> Vec<TilePair> {
+    let border = options.border_for(tiles);
+    let rects: Vec<(Rect, Rect)> = tiles
+        .iter()
+        .map(|t| (t.rect, t.content_rect(border)))
+        .collect();
+
+    let mut pairs = vec![];
+    for a in 0..rects.len() {
+        for b in (a + 1)..rects.len() {
+            let ((tile_a, ra), (tile_b, rb)) = (rects[a], rects[b]);
+            if let Some(overlap) = ra.intersect(&rb) {
+                pairs.push(TilePair {
+                    a,
+                    b,
+                    region_a: overlap.relative_to(&tile_a),
+                    region_b: overlap.relative_to(&tile_b),
+                    overlapping: true,
+                });
+                continue;
+            }
+
+            let abutting = abutting_regions(&ra, &rb, true, options)
+                .or_else(|| abutting_regions(&ra, &rb, false, options))
+                .or_else(|| abutting_regions(&rb, &ra, true, options).map(|(r2, r1)| (r1, r2)))
+                .or_else(|| abutting_regions(&rb, &ra, false, options).map(|(r2, r1)| (r1, r2)));
+            if let Some((region_a, region_b)) = abutting {
+                pairs.push(TilePair {
+                    a,
+                    b,
+                    region_a: region_a.relative_to(&tile_a),
+                    region_b: region_b.relative_to(&tile_b),
+                    overlapping: false,
+                });
+            }
+        }
+    }
+    vprintln!("Found {} tile pairs", pairs.len());
+    pairs
+}
+
+/// Values of `region` in all bands of `image`, sorted
+fn sorted_region_values(image: &Image, region: &Rect) -> Vec<f32> {
+    let mut values = vec![];
+    for b in 0..image.num_bands() {
+        let band = image.get_band(b);
+        for y in region.y..region.bottom().min(image.height) {
+            for x in region.x..region.right().min(image.width) {
+                values.push(band.get(x, y));
+            }
+        }
+    }
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    values
+}
+
+/// Low and high (2nd and 98th percentile) values of a region
+pub fn region_range(image: &Image, region: &Rect) -> Option<(f32, f32)> {
+    let values = sorted_region_values(image, region);
+    if values.is_empty() {
+        return None;
+    }
+    let at = |f: f32| values[((values.len() - 1) as f32 * f).round() as usize];
+    Some((at(0.02), at(0.98)))
+}
+
+/// Linearly maps `from` onto `to` in all bands of `image`. Returns the gain and offset.
+fn remap(image: &mut Image, from: (f32, f32), to: (f32, f32)) -> (f32, f32) {
+    let scale = (to.1 - to.0) / (from.1 - from.0);
+    for b in 0..image.num_bands() {
+        let mut band = image.get_band(b).clone();
+        for v in band.buffer.iter_mut() {
+            *v = ((*v - from.0) * scale + to.0).max(0.0);
+        }
+        image.set_band(&band, b);
+    }
+    (scale, to.0 - from.0 * scale)
+}
+
+/// Matches tile levels pair by pair, walking outward from the first tile. Each tile is
+/// stretched so the range of its shared region matches that of an already matched
+/// neighbour. Tiles not connected to the first are left as they are. Returns the gain and
+/// offset applied to each tile.
+pub fn match_levels(tiles: &mut [Tile], pairs: &[TilePair]) -> Vec<(f32, f32)> {
+    let mut applied = vec![(1.0, 0.0); tiles.len()];
+    if tiles.is_empty() {
+        return applied;
+    }
+    let mut matched = vec![false; tiles.len()];
+    matched[0] = true;
+    let mut queue = VecDeque::from([0]);
+
+    while let Some(target) = queue.pop_front() {
+        for pair in pairs.iter().filter(|p| p.a == target || p.b == target) {
+            let (adjust, target_region, adjust_region) = if pair.a == target {
+                (pair.b, pair.region_a, pair.region_b)
+            } else {
+                (pair.a, pair.region_b, pair.region_a)
+            };
+            if matched[adjust] {
+                continue;
+            }
+            matched[adjust] = true;
+            queue.push_back(adjust);
+
+            let to = region_range(&tiles[target].image.image, &target_region);
+            let from = region_range(&tiles[adjust].image.image, &adjust_region);
+            if let (Some(to), Some(from)) = (to, from) {
+                if from.1 > from.0 {
+                    vprintln!(
+                        "Matching tile {} to tile {}: {:?} -> {:?}",
+                        adjust,
+                        target,
+                        from,
+                        to
+                    );
+                    applied[adjust] = remap(&mut tiles[adjust].image.image, from, to);
+                }
+            }
+        }
+    }
+    applied
+}
+
+/// Tiles pasted onto a canvas
+pub struct Assembly {
+    pub image: Image,
+    pub scale: u32,
+
+    /// Canvas area on the sensor at the tiles' scale factor
+    pub area: Rect,
+}
+
+impl Assembly {
+    /// The assembled area as a metadata `subframe_rect` (one based, full resolution)
+    pub fn subframe_rect(&self) -> Vec<f64> {
+        self.area.subframe_rect(self.scale)
+    }
+
+    /// Metadata for the assembled image, based on that of the earliest tile. The subframe
+    /// rectangle and dimensions describe the canvas, the spacecraft clock and acquisition
+    /// time are the earliest of the tiles and the receipt time the latest.
+    pub fn merged_metadata(&self, tiles: &[Tile]) -> Option<Metadata> {
+        let with_md: Vec<&Metadata> = tiles
+            .iter()
+            .filter_map(|t| t.image.metadata.as_ref())
+            .collect();
+        let mut md = (*with_md.iter().min_by(|a, b| {
+            let (a, b) = (a.sclk.unwrap_or(f64::MAX), b.sclk.unwrap_or(f64::MAX));
+            a.partial_cmp(&b).unwrap_or(std::cmp::Ordering::Equal)
+        })?)
+        .clone();
+
+        md.subframe_rect = Some(self.subframe_rect());
+        md.dimension = Some(vec![self.image.width as f64, self.image.height as f64]);
+        md.sclk = with_md
+            .iter()
+            .filter_map(|m| m.sclk)
+            .min_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        if let Some(t) = with_md.iter().map(|m| &m.date_taken_utc).min() {
+            md.date_taken_utc = t.clone();
+        }
+        if let Some(t) = with_md.iter().map(|m| &m.date_received).max() {
+            md.date_received = t.clone();
+        }
+        Some(md)
+    }
+}
+
+/// Pastes tiles onto a canvas covering the full sensor, or the tiles' bounding box if the
+/// sensor size is unknown, the tiles extend past it or `crop_to_tiles` is set. Later tiles
+/// are pasted over earlier ones. Single band tiles fill all bands of a color canvas.
+pub fn assemble(tiles: &[Tile], options: &AssemblyOptions) -> Result<Assembly> {
+    if tiles.is_empty() {
+        return Err(anyhow!("No tiles to assemble"));
+    }
+    let scale = tiles[0].scale;
+    if let Some(t) = tiles.iter().find(|t| t.scale != scale) {
+        return Err(anyhow!(
+            "Tiles have mixed scale factors: {} and {}",
+            scale,
+            t.scale
+        ));
+    }
+
+    let border = options.border_for(tiles);
+    let content: Vec<Rect> = tiles.iter().map(|t| t.content_rect(border)).collect();
+    let right = content.iter().map(|r| r.right()).max().unwrap();
+    let bottom = content.iter().map(|r| r.bottom()).max().unwrap();
+
+    let sensor = sensor_size(tiles[0].image.instrument)
+        .map(|(w, h)| Rect::new(0, 0, w.div_ceil(scale as usize), h.div_ceil(scale as usize)));
+    let area = match sensor {
+        Some(s) if !options.crop_to_tiles && right <= s.right() && bottom <= s.bottom() => s,
+        _ if options.crop_to_tiles => {
+            let x = content.iter().map(|r| r.x).min().unwrap();
+            let y = content.iter().map(|r| r.y).min().unwrap();
+            Rect::new(x, y, right - x, bottom - y)
+        }
+        _ => Rect::new(0, 0, right, bottom),
+    };
+
+    let num_bands = tiles
+        .iter()
+        .map(|t| t.image.image.num_bands())
+        .max()
+        .unwrap();
+    let mut image = Image::new_with_bands(
+        area.width,
+        area.height,
+        num_bands,
+        tiles[0].image.image.get_mode(),
+    )?;
+    vprintln!(
+        "Assembling {} tiles onto a {}x{} canvas at scale factor {}",
+        tiles.len(),
+        area.width,
+        area.height,
+        scale
+    );
+
+    for (tile, rect) in tiles.iter().zip(content.iter()) {
+        let src = &tile.image.image;
+        let offset = rect.relative_to(&tile.rect);
+        let dest = rect.relative_to(&area);
+        for b in 0..num_bands {
+            let band = src.get_band(b.min(src.num_bands() - 1));
+            for y in 0..rect.height {
+                for x in 0..rect.width {
+                    image.put(
+                        dest.x + x,
+                        dest.y + y,
+                        band.get(offset.x + x, offset.y + y),
+                        b,
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(Assembly { image, scale, area })
+}
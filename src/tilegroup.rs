@@ -0,0 +1,286 @@
+use crate::{
+    enums::Instrument,
+    metadata::{load_image_metadata, Metadata},
+    stereopair::{self, product_suffix},
+    tileassembly::{self, AssemblyOptions, Rect, Tile},
+    tilelevels::{self, LevelOptions, LevelReport},
+    util, vprintln,
+};
+
+use sciimg::path;
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Appended to the file name of the first tile to name an assembled frame
+pub const ASSEMBLED_SUFFIX: &str = "assembled";
+
+/// Options controlling how tiles are grouped into frames
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GroupCriteria {
+    /// Maximum spacecraft clock difference (seconds) between consecutive tiles of a frame
+    pub sclk_window: f64,
+
+    /// Minimum number of tiles for a group to be assembled
+    pub min_tiles: usize,
+}
+
+impl Default for GroupCriteria {
+    fn default() -> Self {
+        GroupCriteria {
+            sclk_window: 5.0,
+            min_tiles: 2,
+        }
+    }
+}
+
+/// A Mars 2020 Navcam or Hazcam tile considered for grouping
+#[derive(Clone)]
+pub struct TileCandidate {
+    pub file_path: String,
+    pub instrument: Instrument,
+    pub sclk: f64,
+    pub sequence_id: Option<String>,
+    pub scale: u32,
+
+    /// Placement on the sensor at the tile's scale factor
+    pub rect: Rect,
+    pub metadata: Metadata,
+}
+
+/// Tiles of one frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileGroup {
+    pub instrument: Instrument,
+    pub sequence_id: Option<String>,
+    pub scale: u32,
+    pub files: Vec<String>,
+    pub rects: Vec<Rect>,
+    pub sclk_start: f64,
+    pub sclk_end: f64,
+
+    /// Tile grid cells of the sensor not covered by any tile, at the group's scale factor
+    pub missing: Vec<Rect>,
+}
+
+impl TileCandidate {
+    /// Constructs a candidate from an image path and its metadata. Returns an error if the
+    /// image is not from a Mars 2020 engineering camera or lacks a spacecraft clock or
+    /// subframe rectangle.
+    pub fn new_from_metadata(file_path: &str, metadata: &Metadata) -> Result<Self> {
+        let instrument = Instrument::from_str(&metadata.instrument).unwrap();
+        if !tileassembly::is_m20_ecam(instrument) {
+            return Err(anyhow!(
+                "Instrument {} is not a Mars 2020 engineering camera",
+                metadata.instrument
+            ));
+        }
+
+        let sclk = match metadata.sclk {
+            Some(s) => s,
+            None => return Err(anyhow!("Image lacks a spacecraft clock value")),
+        };
+
+        let scale = metadata.scale_factor.max(1);
+        let rect = match metadata
+            .subframe_rect
+            .as_ref()
+            .and_then(|sf| Rect::from_subframe_rect(sf, scale))
+        {
+            Some(r) => r,
+            None => return Err(anyhow!("Image lacks a subframe rectangle")),
+        };
+
+        Ok(TileCandidate {
+            file_path: file_path.to_string(),
+            instrument,
+            sclk,
+            sequence_id: stereopair::sequence_id_from_name(&metadata.imageid),
+            scale,
+            rect,
+            metadata: metadata.clone(),
+        })
+    }
+
+    /// Constructs a candidate from an image path, loading the accompanying metadata file
+    pub fn new_from_file(file_path: &str) -> Result<Self> {
+        let metadata_file = util::replace_image_extension(file_path, "-metadata.json");
+        if !path::file_exists(&metadata_file) {
+            return Err(anyhow!("Metadata file not found for {}", file_path));
+        }
+        let md = load_image_metadata(&metadata_file)?;
+        TileCandidate::new_from_metadata(file_path, &md)
+    }
+
+    /// Determines if `other` comes from the same camera, sequence and scale factor and has
+    /// been processed the same way
+    pub fn same_frame(&self, other: &TileCandidate) -> bool {
+        self.instrument == other.instrument
+            && self.sequence_id == other.sequence_id
+            && self.scale == other.scale
+            && product_suffix(&self.file_path, &self.metadata.imageid)
+                == product_suffix(&other.file_path, &other.metadata.imageid)
+    }
+}
+
+/// Cells of the sensor tile grid whose centers no tile covers. The grid is sized by the
+/// most common tile size, allowing for the few pixels neighbouring tiles overlap by. Empty
+/// if the sensor size is unknown.
+pub fn missing_tiles(instrument: Instrument, scale: u32, rects: &[Rect]) -> Vec<Rect> {
+    let (sensor_width, sensor_height) = match tileassembly::sensor_size(instrument) {
+        Some((w, h)) => (
+            w.div_ceil(scale.max(1) as usize),
+            h.div_ceil(scale.max(1) as usize),
+        ),
+        None => return vec![],
+    };
+
+    let mut sizes: HashMap<(usize, usize), usize> = HashMap::new();
+    for r in rects.iter().filter(|r| r.area() > 0) {
+        *sizes.entry((r.width, r.height)).or_insert(0) += 1;
+    }
+    let (tile_width, tile_height) = match sizes.iter().max_by_key(|(s, c)| (**c, **s)) {
+        Some((s, _)) => *s,
+        None => return vec![],
+    };
+
+    let cols = sensor_width.div_ceil(tile_width).max(1);
+    let rows = sensor_height.div_ceil(tile_height).max(1);
+    let mut missing = vec![];
+    for row in 0..rows {
+        for col in 0..cols {
+            let x0 = col * sensor_width / cols;
+            let x1 = (col + 1) * sensor_width / cols;
+            let y0 = row * sensor_height / rows;
+            let y1 = (row + 1) * sensor_height / rows;
+            let (cx, cy) = ((x0 + x1) / 2, (y0 + y1) / 2);
+            let covered = rects
+                .iter()
+                .any(|r| cx >= r.x && cx < r.right() && cy >= r.y && cy < r.bottom());
+            if !covered {
+                missing.push(Rect::new(x0, y0, x1 - x0, y1 - y0));
+            }
+        }
+    }
+    missing
+}
+
+impl TileGroup {
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    /// Output file name for the assembled frame, derived from the first tile
+    pub fn output_file(&self, output_dir: Option<&str>) -> String {
+        let out_file = util::append_file_name(&self.files[0], ASSEMBLED_SUFFIX);
+        match output_dir {
+            Some(dir) => format!("{}/{}", dir, path::basename(&out_file)),
+            None => out_file,
+        }
+    }
+}
+
+/// Groups candidates into frames. Tiles are ordered by spacecraft clock and a frame
+/// continues while each tile follows the previous one within the SCLK window, matches the
+/// first tile's camera, sequence and scale factor, and is not placed where a tile of the
+/// frame already is. Groups with fewer than `min_tiles` tiles are dropped.
+pub fn find_groups(candidates: &[TileCandidate], criteria: &GroupCriteria) -> Vec<TileGroup> {
+    let mut sorted: Vec<&TileCandidate> = candidates.iter().collect();
+    sorted.sort_by(|a, b| a.sclk.total_cmp(&b.sclk));
+
+    let mut groups: Vec<Vec<&TileCandidate>> = vec![];
+    for candidate in sorted {
+        // Cameras and sequences may interleave, so look for an open group of any
+        let open = groups.iter_mut().rev().find(|g| {
+            let last = g.last().unwrap();
+            candidate.sclk - last.sclk <= criteria.sclk_window
+                && g[0].same_frame(candidate)
+                && !g.iter().any(|t| t.rect == candidate.rect)
+        });
+        match open {
+            Some(group) => group.push(candidate),
+            None => groups.push(vec![candidate]),
+        }
+    }
+
+    groups
+        .into_iter()
+        .filter(|g| {
+            let enough = g.len() >= criteria.min_tiles.max(1);
+            if !enough {
+                vprintln!(
+                    "Skipping group starting at {}: {} tiles",
+                    g[0].file_path,
+                    g.len()
+                );
+            }
+            enough
+        })
+        .map(|g| {
+            let rects: Vec<Rect> = g.iter().map(|c| c.rect).collect();
+            TileGroup {
+                instrument: g[0].instrument,
+                sequence_id: g[0].sequence_id.clone(),
+                scale: g[0].scale,
+                files: g.iter().map(|c| c.file_path.clone()).collect(),
+                missing: missing_tiles(g[0].instrument, g[0].scale, &rects),
+                rects,
+                sclk_start: g[0].sclk,
+                sclk_end: g[g.len() - 1].sclk,
+            }
+        })
+        .collect()
+}
+
+/// Builds candidates from a list of image files, skipping those that are not usable or
+/// are themselves assembled frames
+pub fn candidates_from_files(input_files: &[String]) -> Vec<TileCandidate> {
+    let assembled = format!("-{}", ASSEMBLED_SUFFIX);
+    input_files
+        .iter()
+        .filter(|f| !util::replace_image_extension(f, "").ends_with(&assembled))
+        .filter_map(|f| match TileCandidate::new_from_file(f) {
+            Ok(c) => Some(c),
+            Err(why) => {
+                vprintln!("Skipping {}: {}", f, why);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Scans a directory and returns all tile groups found
+pub fn find_groups_in_directory(directory: &str, criteria: &GroupCriteria) -> Vec<TileGroup> {
+    let files = stereopair::list_images_in_directory(directory);
+    vprintln!("Found {} images in {}", files.len(), directory);
+    find_groups(&candidates_from_files(&files), criteria)
+}
+
+/// Assembles a group into a single frame, writing it and its merged metadata. Returns the
+/// output file and the level matching report.
+pub fn assemble_group(
+    group: &TileGroup,
+    options: &AssemblyOptions,
+    levels: &LevelOptions,
+    output_dir: Option<&str>,
+) -> Result<(String, LevelReport)> {
+    let out_file = group.output_file(output_dir);
+    let mut tiles = group
+        .files
+        .iter()
+        .map(|f| Tile::open(f))
+        .collect::<Result<Vec<Tile>>>()?;
+
+    let (mut assembly, report) = tilelevels::assemble_levelled(&mut tiles, options, levels)?;
+
+    vprintln!("Saving {} tiles to {}", tiles.len(), out_file);
+    assembly.image.normalize_to_8bit();
+    assembly.image.save(&out_file);
+    if let Some(md) = assembly.merged_metadata(&tiles) {
+        util::save_image_json(&out_file, &md, false, None)?;
+    }
+    Ok((out_file, report))
+}
@@ -0,0 +1,668 @@
+use crate::tileassembly::{self, Assembly, AssemblyOptions, Rect, Tile, TilePair};
+use crate::vprintln;
+
+use sciimg::image::Image;
+
+use anyhow::anyhow;
+use anyhow::Result;
+
+use std::collections::HashMap;
+
+/// Canvas pixels not covered by any tile
+const NO_OWNER: u16 = u16::MAX;
+
+/// Over-relaxation factor of the seam correction solver
+const OVERRELAXATION: f32 = 1.9;
+
+/// Tile level matching and seam removal parameters
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelOptions {
+    /// Solve for per-tile gains and offsets over all tile pairs
+    pub match_levels: bool,
+
+    /// Solve gains and offsets over all pairs together by least squares. Otherwise tiles are
+    /// matched pair by pair outward from the first tile.
+    pub solve: bool,
+
+    /// Remove remaining seams from the assembled image in the gradient domain
+    pub remove_seams: bool,
+
+    /// Weight, relative to an average tile pair, pulling each tile toward unit gain and zero
+    /// offset. Keeps tiles not connected to others unchanged.
+    pub regularization: f64,
+
+    /// Spacing (pixels) of the grid the seam correction is solved on
+    pub cell_size: usize,
+
+    /// Maximum seam correction solver iterations
+    pub iterations: usize,
+
+    /// Seam correction solver stops once no value changes by more than this
+    pub tolerance: f32,
+}
+
+impl Default for LevelOptions {
+    fn default() -> Self {
+        LevelOptions {
+            match_levels: true,
+            solve: true,
+            remove_seams: false,
+            regularization: 1.0e-3,
+            cell_size: 16,
+            iterations: 2000,
+            tolerance: 0.01,
+        }
+    }
+}
+
+/// Per band gain and offset applied to a tile
+#[derive(Debug, Clone, PartialEq)]
+pub struct TileLevels {
+    pub gain: Vec<f32>,
+    pub offset: Vec<f32>,
+}
+
+impl TileLevels {
+    pub fn identity(num_bands: usize) -> Self {
+        TileLevels {
+            gain: vec![1.0; num_bands],
+            offset: vec![0.0; num_bands],
+        }
+    }
+
+    /// Applies the levels to all bands of `image`, clamping at zero
+    pub fn apply(&self, image: &mut Image) {
+        for b in 0..image.num_bands() {
+            let (gain, offset) = (
+                self.gain[b.min(self.gain.len() - 1)],
+                self.offset[b.min(self.offset.len() - 1)],
+            );
+            let mut band = image.get_band(b).clone();
+            for v in band.buffer.iter_mut() {
+                *v = (*v * gain + offset).max(0.0);
+            }
+            image.set_band(&band, b);
+        }
+    }
+}
+
+/// Level mismatch between the shared regions of a tile pair in one band
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SeamResidual {
+    pub a: usize,
+    pub b: usize,
+    pub band: usize,
+
+    /// Mean of tile `a`'s region less that of tile `b`'s
+    pub mean_difference: f32,
+
+    /// Standard deviation of tile `a`'s region less that of tile `b`'s
+    pub spread_difference: f32,
+
+    /// Root mean square pixel difference, for overlapping pairs
+    pub rms: Option<f32>,
+}
+
+/// Outcome of level matching
+#[derive(Debug, Clone, PartialEq)]
+pub struct LevelReport {
+    pub pairs: Vec<TilePair>,
+    pub levels: Vec<TileLevels>,
+
+    /// Seam residuals before and after matching
+    pub before: Vec<SeamResidual>,
+    pub after: Vec<SeamResidual>,
+}
+
+/// Mean and standard deviation of a region in one band
+#[derive(Debug, Clone, Copy)]
+struct RegionStats {
+    mean: f64,
+    std_dev: f64,
+    count: usize,
+}
+
+fn region_values(image: &Image, band: usize, region: &Rect) -> Vec<f32> {
+    let band = image.get_band(band.min(image.num_bands() - 1));
+    let mut values = vec![];
+    for y in region.y..region.bottom().min(image.height) {
+        for x in region.x..region.right().min(image.width) {
+            values.push(band.get(x, y));
+        }
+    }
+    values
+}
+
+fn region_stats(image: &Image, band: usize, region: &Rect) -> Option<RegionStats> {
+    let values = region_values(image, band, region);
+    if values.is_empty() {
+        return None;
+    }
+    let n = values.len() as f64;
+    let mean = values.iter().map(|v| *v as f64).sum::<f64>() / n;
+    let variance = values
+        .iter()
+        .map(|v| (*v as f64 - mean).powi(2))
+        .sum::<f64>()
+        / n;
+    Some(RegionStats {
+        mean,
+        std_dev: variance.sqrt(),
+        count: values.len(),
+    })
+}
+
+/// Largest band count of any tile
+fn max_bands(tiles: &[Tile]) -> usize {
+    tiles
+        .iter()
+        .map(|t| t.image.image.num_bands())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Residual level mismatch of every pair in every band
+pub fn seam_residuals(tiles: &[Tile], pairs: &[TilePair]) -> Vec<SeamResidual> {
+    let mut residuals = vec![];
+    for pair in pairs.iter() {
+        let (image_a, image_b) = (&tiles[pair.a].image.image, &tiles[pair.b].image.image);
+        for band in 0..max_bands(tiles) {
+            let (sa, sb) = match (
+                region_stats(image_a, band, &pair.region_a),
+                region_stats(image_b, band, &pair.region_b),
+            ) {
+                (Some(sa), Some(sb)) => (sa, sb),
+                _ => continue,
+            };
+            let rms = if pair.overlapping {
+                let va = region_values(image_a, band, &pair.region_a);
+                let vb = region_values(image_b, band, &pair.region_b);
+                let sum: f64 = va
+                    .iter()
+                    .zip(vb.iter())
+                    .map(|(a, b)| (*a as f64 - *b as f64).powi(2))
+                    .sum();
+                Some((sum / va.len().min(vb.len()).max(1) as f64).sqrt() as f32)
+            } else {
+                None
+            };
+            residuals.push(SeamResidual {
+                a: pair.a,
+                b: pair.b,
+                band,
+                mean_difference: (sa.mean - sb.mean) as f32,
+                spread_difference: (sa.std_dev - sb.std_dev) as f32,
+                rms,
+            });
+        }
+    }
+    residuals
+}
+
+/// Solves `m x = v` by Gaussian elimination with partial pivoting
+fn solve_linear(mut m: Vec<Vec<f64>>, mut v: Vec<f64>) -> Option<Vec<f64>> {
+    let n = v.len();
+    for col in 0..n {
+        let pivot = (col..n).max_by(|a, b| {
+            m[*a][col]
+                .abs()
+                .partial_cmp(&m[*b][col].abs())
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })?;
+        if m[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        m.swap(col, pivot);
+        v.swap(col, pivot);
+        let (pivot_rows, rows) = m.split_at_mut(col + 1);
+        let (pivot_values, values) = v.split_at_mut(col + 1);
+        let (pivot_row, pivot_value) = (&pivot_rows[col], pivot_values[col]);
+        for (row, value) in rows.iter_mut().zip(values.iter_mut()) {
+            let f = row[col] / pivot_row[col];
+            if f == 0.0 {
+                continue;
+            }
+            for (r, p) in row[col..].iter_mut().zip(pivot_row[col..].iter()) {
+                *r -= f * p;
+            }
+            *value -= f * pivot_value;
+        }
+    }
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let s: f64 = ((row + 1)..n).map(|c| m[row][c] * x[c]).sum();
+        x[row] = (v[row] - s) / m[row][row];
+    }
+    Some(x)
+}
+
+/// Adds weighted equation `coefficients . x = rhs` to the normal equations
+fn add_equation(
+    m: &mut [Vec<f64>],
+    v: &mut [f64],
+    coefficients: &[(usize, f64)],
+    rhs: f64,
+    weight: f64,
+) {
+    for (i, ci) in coefficients.iter() {
+        for (j, cj) in coefficients.iter() {
+            m[*i][*j] += weight * ci * cj;
+        }
+        v[*i] += weight * ci * rhs;
+    }
+}
+
+/// Solves for the gain and offset of every tile in every band at once, so the shared
+/// regions of all pairs agree in mean and spread in the least squares sense. Unlike
+/// matching tile by tile, errors are spread over the whole frame rather than accumulating
+/// away from a reference tile. The mean gain is held at one and the mean offset at zero.
+pub fn solve_levels(
+    tiles: &[Tile],
+    pairs: &[TilePair],
+    options: &LevelOptions,
+) -> Result<Vec<TileLevels>> {
+    let num_bands = max_bands(tiles);
+    let mut levels: Vec<TileLevels> = tiles
+        .iter()
+        .map(|_| TileLevels::identity(num_bands))
+        .collect();
+    if tiles.len() < 2 || pairs.is_empty() {
+        return Ok(levels);
+    }
+
+    let n = tiles.len();
+    for band in 0..num_bands {
+        let stats: Vec<(usize, usize, RegionStats, RegionStats)> = pairs
+            .iter()
+            .filter_map(|p| {
+                let sa = region_stats(&tiles[p.a].image.image, band, &p.region_a)?;
+                let sb = region_stats(&tiles[p.b].image.image, band, &p.region_b)?;
+                Some((p.a, p.b, sa, sb))
+            })
+            .collect();
+        if stats.is_empty() {
+            continue;
+        }
+
+        // Solved in units of the brightest region so gains and offsets are of similar size
+        let scale = stats
+            .iter()
+            .map(|(_, _, sa, sb)| (sa.mean + sa.std_dev).max(sb.mean + sb.std_dev))
+            .fold(0.0_f64, f64::max)
+            .max(1.0);
+        let mean_weight = stats
+            .iter()
+            .map(|(_, _, sa, sb)| sa.count.min(sb.count) as f64)
+            .sum::<f64>()
+            / stats.len() as f64;
+
+        // Unknowns are the gain (2i) and offset (2i + 1) of each tile
+        let mut m = vec![vec![0.0; 2 * n]; 2 * n];
+        let mut v = vec![0.0; 2 * n];
+        for (a, b, sa, sb) in stats.iter() {
+            let w = sa.count.min(sb.count) as f64;
+            add_equation(
+                &mut m,
+                &mut v,
+                &[
+                    (2 * a, sa.mean / scale),
+                    (2 * a + 1, 1.0),
+                    (2 * b, -sb.mean / scale),
+                    (2 * b + 1, -1.0),
+                ],
+                0.0,
+                w,
+            );
+            add_equation(
+                &mut m,
+                &mut v,
+                &[(2 * a, sa.std_dev / scale), (2 * b, -sb.std_dev / scale)],
+                0.0,
+                w,
+            );
+        }
+
+        let gains: Vec<(usize, f64)> = (0..n).map(|i| (2 * i, 1.0 / n as f64)).collect();
+        let offsets: Vec<(usize, f64)> = (0..n).map(|i| (2 * i + 1, 1.0 / n as f64)).collect();
+        add_equation(&mut m, &mut v, &gains, 1.0, mean_weight * n as f64);
+        add_equation(&mut m, &mut v, &offsets, 0.0, mean_weight * n as f64);
+        let reg = mean_weight * options.regularization;
+        for i in 0..n {
+            add_equation(&mut m, &mut v, &[(2 * i, 1.0)], 1.0, reg);
+            add_equation(&mut m, &mut v, &[(2 * i + 1, 1.0)], 0.0, reg);
+        }
+
+        let x = match solve_linear(m, v) {
+            Some(x) => x,
+            None => return Err(anyhow!("Tile level solve failed for band {}", band)),
+        };
+        for (i, l) in levels.iter_mut().enumerate() {
+            let (gain, offset) = (x[2 * i], x[2 * i + 1] * scale);
+            if gain <= 0.0 {
+                return Err(anyhow!(
+                    "Tile level solve gave tile {} a gain of {} in band {}",
+                    i,
+                    gain,
+                    band
+                ));
+            }
+            vprintln!("Tile {} band {}: gain {}, offset {}", i, band, gain, offset);
+            l.gain[band] = gain as f32;
+            l.offset[band] = offset as f32;
+        }
+    }
+    Ok(levels)
+}
+
+/// Applies solved levels to each tile
+pub fn apply_levels(tiles: &mut [Tile], levels: &[TileLevels]) {
+    for (tile, l) in tiles.iter_mut().zip(levels.iter()) {
+        l.apply(&mut tile.image.image);
+    }
+}
+
+/// Solves for and applies tile levels, reporting seam residuals before and after
+pub fn match_levels(
+    tiles: &mut [Tile],
+    pairs: &[TilePair],
+    options: &LevelOptions,
+) -> Result<LevelReport> {
+    let before = seam_residuals(tiles, pairs);
+    let levels = if options.solve {
+        let levels = solve_levels(tiles, pairs, options)?;
+        apply_levels(tiles, &levels);
+        levels
+    } else {
+        tileassembly::match_levels(tiles, pairs)
+            .into_iter()
+            .zip(tiles.iter())
+            .map(|((gain, offset), tile)| {
+                let n = tile.image.image.num_bands();
+                TileLevels {
+                    gain: vec![gain; n],
+                    offset: vec![offset; n],
+                }
+            })
+            .collect()
+    };
+    Ok(LevelReport {
+        pairs: pairs.to_vec(),
+        levels,
+        before,
+        after: seam_residuals(tiles, pairs),
+    })
+}
+
+/// Tile whose pixel is visible at canvas position (`x`, `y`). Later tiles are pasted over
+/// earlier ones.
+fn owner_at(rects: &[Rect], x: usize, y: usize) -> Option<usize> {
+    rects
+        .iter()
+        .rposition(|r| x >= r.x && x < r.right() && y >= r.y && y < r.bottom())
+}
+
+/// Coarse grid of correction values over one tile's canvas rectangle
+struct CorrectionGrid {
+    rect: Rect,
+    cols: usize,
+    rows: usize,
+    first_node: usize,
+}
+
+impl CorrectionGrid {
+    fn node_at(&self, x: usize, y: usize, cell_size: usize) -> usize {
+        let col = ((x - self.rect.x) / cell_size).min(self.cols - 1);
+        let row = ((y - self.rect.y) / cell_size).min(self.rows - 1);
+        self.first_node + row * self.cols + col
+    }
+
+    /// Bilinear interpolation of the node values between cell centers
+    fn interpolate(&self, values: &[f32], x: usize, y: usize, cell_size: usize) -> f32 {
+        let fx = (((x - self.rect.x) as f32 + 0.5) / cell_size as f32 - 0.5)
+            .clamp(0.0, (self.cols - 1) as f32);
+        let fy = (((y - self.rect.y) as f32 + 0.5) / cell_size as f32 - 0.5)
+            .clamp(0.0, (self.rows - 1) as f32);
+        let (c0, r0) = (fx.floor() as usize, fy.floor() as usize);
+        let (c1, r1) = ((c0 + 1).min(self.cols - 1), (r0 + 1).min(self.rows - 1));
+        let (tx, ty) = (fx - c0 as f32, fy - r0 as f32);
+        let at = |c: usize, r: usize| values[self.first_node + r * self.cols + c];
+        let top = at(c0, r0) * (1.0 - tx) + at(c1, r0) * tx;
+        let bottom = at(c0, r1) * (1.0 - tx) + at(c1, r1) * tx;
+        top * (1.0 - ty) + bottom * ty
+    }
+}
+
+/// Link between two correction nodes. The solve favours `value[from] - value[to]` being
+/// `jump`.
+#[derive(Debug, Clone, Copy)]
+struct Link {
+    to: usize,
+    weight: f32,
+    jump: f32,
+}
+
+/// Removes the seams remaining between tiles of an assembled image in the gradient domain.
+/// The image gradient is kept everywhere except across seams, where it is replaced by the
+/// average of the gradients either side, and the image reintegrated. This amounts to adding
+/// a correction to each tile that is smooth (harmonic) within the tile and jumps at its
+/// seams by the seam's step. Corrections are smooth, so they are solved on a grid of
+/// `cell_size` pixel cells per tile and interpolated. Tiles are given by their pasting
+/// order, as passed to `assemble`.
+pub fn remove_seams(
+    assembly: &mut Assembly,
+    tiles: &[Tile],
+    assembly_options: &AssemblyOptions,
+    options: &LevelOptions,
+) -> Result<()> {
+    let border = assembly_options.border_for(tiles);
+    let area = assembly.area;
+    let rects: Vec<Rect> = tiles
+        .iter()
+        .map(|t| t.content_rect(border).relative_to(&area))
+        .collect();
+    let cell_size = options.cell_size.max(1);
+
+    let mut grids = vec![];
+    let mut num_nodes = 0;
+    for r in rects.iter() {
+        let cols = r.width.div_ceil(cell_size).max(1);
+        let rows = r.height.div_ceil(cell_size).max(1);
+        grids.push(CorrectionGrid {
+            rect: *r,
+            cols,
+            rows,
+            first_node: num_nodes,
+        });
+        num_nodes += cols * rows;
+    }
+
+    let image = &mut assembly.image;
+    let (width, height) = (image.width, image.height);
+    if tiles.len() >= NO_OWNER as usize {
+        return Err(anyhow!("Too many tiles for seam removal: {}", tiles.len()));
+    }
+    let owners: Vec<u16> = (0..width * height)
+        .map(|i| owner_at(&rects, i % width, i / width).map_or(NO_OWNER, |o| o as u16))
+        .collect();
+    let owner = |x: usize, y: usize| match owners[y * width + x] {
+        NO_OWNER => None,
+        o => Some(o as usize),
+    };
+
+    // Links within each tile's grid carry no jump
+    let mut links: Vec<Vec<Link>> = vec![vec![]; num_nodes];
+    for g in grids.iter() {
+        for row in 0..g.rows {
+            for col in 0..g.cols {
+                let node = g.first_node + row * g.cols + col;
+                if col + 1 < g.cols {
+                    links[node].push(Link {
+                        to: node + 1,
+                        weight: 1.0,
+                        jump: 0.0,
+                    });
+                    links[node + 1].push(Link {
+                        to: node,
+                        weight: 1.0,
+                        jump: 0.0,
+                    });
+                }
+                if row + 1 < g.rows {
+                    links[node].push(Link {
+                        to: node + g.cols,
+                        weight: 1.0,
+                        jump: 0.0,
+                    });
+                    links[node + g.cols].push(Link {
+                        to: node,
+                        weight: 1.0,
+                        jump: 0.0,
+                    });
+                }
+            }
+        }
+    }
+
+    let mut num_seam_pixels = 0;
+    for band in 0..image.num_bands() {
+        let values = image.get_band(band).clone();
+        let get = |x: usize, y: usize| values.get(x, y);
+
+        // Seam links, accumulated per node pair. Each pixel pair across a seam asks for the
+        // step between them to be the average of the gradients just inside either tile.
+        let mut seams: HashMap<(usize, usize), (f32, f32)> = HashMap::new();
+        for y in 0..height {
+            for x in 0..width {
+                let p_owner = match owner(x, y) {
+                    Some(o) => o,
+                    None => continue,
+                };
+                for (qx, qy) in [(x + 1, y), (x, y + 1)] {
+                    if qx >= width || qy >= height {
+                        continue;
+                    }
+                    let q_owner = match owner(qx, qy) {
+                        Some(o) if o != p_owner => o,
+                        _ => continue,
+                    };
+                    let (dx, dy) = (qx - x, qy - y);
+                    let mut gradients = vec![];
+                    if x >= dx && y >= dy && owner(x - dx, y - dy) == Some(p_owner) {
+                        gradients.push(get(x, y) - get(x - dx, y - dy));
+                    }
+                    if qx + dx < width
+                        && qy + dy < height
+                        && owner(qx + dx, qy + dy) == Some(q_owner)
+                    {
+                        gradients.push(get(qx + dx, qy + dy) - get(qx, qy));
+                    }
+                    let target = if gradients.is_empty() {
+                        0.0
+                    } else {
+                        gradients.iter().sum::<f32>() / gradients.len() as f32
+                    };
+                    // c[p] - c[q] that makes the corrected step (v[q] + c[q]) - (v[p] + c[p])
+                    // equal the target
+                    let jump = (get(qx, qy) - get(x, y)) - target;
+                    let np = grids[p_owner].node_at(x, y, cell_size);
+                    let nq = grids[q_owner].node_at(qx, qy, cell_size);
+                    let e = seams.entry((np, nq)).or_insert((0.0, 0.0));
+                    e.0 += 1.0;
+                    e.1 += jump;
+                }
+            }
+        }
+        if band == 0 {
+            num_seam_pixels = seams.values().map(|(w, _)| *w as usize).sum();
+        }
+
+        let mut band_links = links.clone();
+        for ((np, nq), (w, sum)) in seams.iter() {
+            let jump = sum / w;
+            band_links[*np].push(Link {
+                to: *nq,
+                weight: *w,
+                jump,
+            });
+            band_links[*nq].push(Link {
+                to: *np,
+                weight: *w,
+                jump: -jump,
+            });
+        }
+
+        // Successive over-relaxation of the weighted least squares solution
+        let mut c = vec![0.0_f32; num_nodes];
+        for iteration in 0..options.iterations {
+            let mut max_change = 0.0_f32;
+            for (node, node_links) in band_links.iter().enumerate() {
+                let total: f32 = node_links.iter().map(|l| l.weight).sum();
+                if total == 0.0 {
+                    continue;
+                }
+                let target = node_links
+                    .iter()
+                    .map(|l| l.weight * (c[l.to] + l.jump))
+                    .sum::<f32>()
+                    / total;
+                let change = OVERRELAXATION * (target - c[node]);
+                c[node] += change;
+                max_change = max_change.max(change.abs());
+            }
+            if max_change < options.tolerance {
+                vprintln!(
+                    "Seam correction band {} converged after {} iterations",
+                    band,
+                    iteration + 1
+                );
+                break;
+            }
+        }
+
+        // Corrections are relative, so keep the overall level
+        let mean = c.iter().sum::<f32>() / num_nodes.max(1) as f32;
+        c.iter_mut().for_each(|v| *v -= mean);
+
+        let mut corrected = values.clone();
+        for y in 0..height {
+            for x in 0..width {
+                if let Some(o) = owner(x, y) {
+                    let v = get(x, y) + grids[o].interpolate(&c, x, y, cell_size);
+                    corrected.put(x, y, v.max(0.0));
+                }
+            }
+        }
+        image.set_band(&corrected, band);
+    }
+    vprintln!("Removed seams along {} pixel pairs", num_seam_pixels);
+    Ok(())
+}
+
+/// Finds tile pairs, matches levels if enabled, assembles and removes seams if enabled
+pub fn assemble_levelled(
+    tiles: &mut [Tile],
+    assembly_options: &AssemblyOptions,
+    options: &LevelOptions,
+) -> Result<(Assembly, LevelReport)> {
+    let pairs = tileassembly::find_pairs(tiles, assembly_options);
+    let report = if options.match_levels {
+        match_levels(tiles, &pairs, options)?
+    } else {
+        let residuals = seam_residuals(tiles, &pairs);
+        LevelReport {
+            levels: tiles
+                .iter()
+                .map(|t| TileLevels::identity(t.image.image.num_bands()))
+                .collect(),
+            pairs,
+            before: residuals.clone(),
+            after: residuals,
+        }
+    };
+
+    let mut assembly = tileassembly::assemble(tiles, assembly_options)?;
+    if options.remove_seams {
+        remove_seams(&mut assembly, tiles, assembly_options, options)?;
+    }
+    Ok((assembly, report))
+}
@@ -1,5 +1,6 @@
 use crate::{constants, httpfetch, vprintln};
 
+use sciimg::enums::ImageMode;
 use sciimg::path;
 use sciimg::util as sciutil;
 
@@ -130,6 +131,15 @@ impl InstrumentMap {
     }
 }
 
+/// Maximum DN value for the image mode
+pub fn max_value_for_mode(mode: ImageMode) -> f32 {
+    match mode {
+        ImageMode::U8BIT => 255.0,
+        ImageMode::U16BIT => 65535.0,
+        _ => 4095.0,
+    }
+}
+
 pub fn stringvec(a: &str, b: &str) -> Vec<String> {
     vec![a.to_owned(), b.to_owned()]
 }
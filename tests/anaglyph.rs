@@ -0,0 +1,48 @@
+use mars_raw_utils::anaglyph::{self, AnaglyphMode};
+use sciimg::image::Image;
+use std::str::FromStr;
+
+#[test]
+fn test_anaglyph_mode_from_str() {
+    assert_eq!(
+        AnaglyphMode::from_str("dubois-red-cyan").unwrap(),
+        AnaglyphMode::DuboisRedCyan
+    );
+    assert_eq!(
+        AnaglyphMode::from_str("half-color").unwrap(),
+        AnaglyphMode::HalfColor
+    );
+    assert!(AnaglyphMode::from_str("plaid").is_err());
+}
+
+#[test]
+fn test_color_mode_channel_split() {
+    let mut left = Image::create(2, 2);
+    let mut right = Image::create(2, 2);
+    for y in 0..2 {
+        for x in 0..2 {
+            for b in 0..3 {
+                left.put(x, y, 100.0, b);
+                right.put(x, y, 50.0, b);
+            }
+        }
+    }
+    let map = anaglyph::combine_eyes(&left, &right, AnaglyphMode::Color, 255.0);
+    assert_eq!(map.get_band(0).get(0, 0), 100.0);
+    assert_eq!(map.get_band(1).get(0, 0), 50.0);
+    assert_eq!(map.get_band(2).get(0, 0), 50.0);
+}
+
+#[test]
+fn test_estimate_vertical_disparity() {
+    let mut left = Image::create(8, 40);
+    for y in 0..40 {
+        for x in 0..8 {
+            for b in 0..3 {
+                left.put(x, y, ((y * 37) % 11) as f32 * 10.0, b);
+            }
+        }
+    }
+    let right = anaglyph::shift_image(&left, 0, -3);
+    assert_eq!(anaglyph::estimate_vertical_disparity(&left, &right, 5), 3);
+}
@@ -0,0 +1,114 @@
+use mars_raw_utils::caldata::{self, CalDataManifest, ManifestEntry, VerifyStatus};
+use std::fs;
+use tempfile::tempdir;
+
+#[test]
+fn test_parse_manifest() {
+    let manifest = caldata::parse_manifest(
+        r#"
+version = "2023.11.02"
+
+[[file]]
+path = "m20/ilut/M20_LUT2_v2a.txt"
+size = 5
+sha256 = "2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824"
+
+[[file]]
+path = "msl/MSL_MAHLI_FLAT_Sol2904_V1.png"
+"#,
+    )
+    .unwrap();
+    assert_eq!(manifest.version, Some(String::from("2023.11.02")));
+    assert_eq!(manifest.files.len(), 2);
+    assert_eq!(manifest.files[0].size, Some(5));
+    assert!(manifest.files[1].sha256.is_none());
+
+    // Legacy bare file list
+    let legacy = caldata::parse_manifest("caldata.toml\nm20/ilut/M20_LUT2_v2a.txt\n\n").unwrap();
+    assert!(legacy.version.is_none());
+    assert_eq!(
+        legacy.files,
+        vec![
+            ManifestEntry::new("caldata.toml"),
+            ManifestEntry::new("m20/ilut/M20_LUT2_v2a.txt")
+        ]
+    );
+    assert_eq!(
+        caldata::parse_manifest_file_list("a.png\nb.png").unwrap(),
+        vec![String::from("a.png"), String::from("b.png")]
+    );
+
+    // Looks like TOML but is malformed: an error, not a list of bogus paths
+    assert!(caldata::is_toml_manifest("# comment\nversion = \"1\""));
+    assert!(!caldata::is_toml_manifest(
+        "# comment\nm20/ilut/M20_LUT2_v2a.txt"
+    ));
+    assert!(caldata::parse_manifest("version = \"2023.11.02\"\n[[file]]\npath = ").is_err());
+    assert!(caldata::parse_manifest("[[file]\npath = \"a.png\"").is_err());
+}
+
+#[test]
+fn test_verify_bytes() {
+    assert_eq!(
+        caldata::sha256_hex(b"hello"),
+        "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+    );
+
+    let entry = ManifestEntry {
+        path: String::from("a.txt"),
+        sha256: Some(caldata::sha256_hex(b"hello")),
+        size: Some(5),
+    };
+    assert_eq!(entry.verify_bytes(b"hello"), VerifyStatus::Ok);
+    assert_eq!(
+        entry.verify_bytes(b"hel"),
+        VerifyStatus::SizeMismatch {
+            expected: 5,
+            actual: 3
+        }
+    );
+    assert!(matches!(
+        entry.verify_bytes(b"jello"),
+        VerifyStatus::ChecksumMismatch { .. }
+    ));
+    assert_eq!(
+        ManifestEntry::new("a.txt").verify_bytes(b"anything"),
+        VerifyStatus::Unverifiable
+    );
+}
+
+#[test]
+fn test_verify_local_store() {
+    let dir = tempdir().unwrap();
+    let store = Some(String::from(dir.path().to_str().unwrap()));
+    fs::create_dir_all(dir.path().join("m20")).unwrap();
+    fs::write(dir.path().join("m20/good.txt"), b"hello").unwrap();
+    fs::write(dir.path().join("m20/truncated.txt"), b"hel").unwrap();
+
+    let entry = |path: &str| ManifestEntry {
+        path: String::from(path),
+        sha256: Some(caldata::sha256_hex(b"hello")),
+        size: Some(5),
+    };
+    let manifest = CalDataManifest {
+        version: Some(String::from("1")),
+        files: vec![
+            entry("m20/good.txt"),
+            entry("m20/truncated.txt"),
+            entry("m20/missing.txt"),
+        ],
+    };
+
+    let results = caldata::verify_against_manifest(&manifest, &store);
+    assert_eq!(results[0].1, VerifyStatus::Ok);
+    assert!(matches!(results[1].1, VerifyStatus::SizeMismatch { .. }));
+    assert_eq!(results[2].1, VerifyStatus::Missing);
+    assert_eq!(results.iter().filter(|(_, s)| s.is_bad()).count(), 2);
+
+    assert!(caldata::installed_version(&store).is_none());
+    caldata::save_installed_version(&manifest, &store).unwrap();
+    assert_eq!(
+        caldata::installed_version(&store).unwrap().version,
+        Some(String::from("1"))
+    );
+}
@@ -0,0 +1,134 @@
+use mars_raw_utils::calgen::{self, FlatParams, MaskParams};
+use mars_raw_utils::calibfile;
+use mars_raw_utils::enums::{CalFileType, Instrument};
+use mars_raw_utils::stacking::StackMethod;
+use sciimg::{enums::ImageMode, image::Image};
+
+const WIDTH: usize = 16;
+const HEIGHT: usize = 12;
+
+/// Radial falloff, 1.0 at the center
+fn vignette(x: usize, y: usize) -> f32 {
+    let dx = x as f32 - WIDTH as f32 / 2.0;
+    let dy = y as f32 - HEIGHT as f32 / 2.0;
+    1.0 - (dx * dx + dy * dy) / 400.0
+}
+
+fn frame<F: Fn(usize, usize) -> f32>(f: F) -> Image {
+    let mut img = Image::new_with_bands(WIDTH, HEIGHT, 1, ImageMode::U16BIT).unwrap();
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            img.put(x, y, f(x, y), 0);
+        }
+    }
+    img
+}
+
+#[test]
+fn test_make_flat_recovers_vignetting() {
+    // Sky frames of differing brightness, one with a star
+    let mut frames: Vec<Image> = [1000.0, 1500.0, 2500.0, 800.0, 1200.0]
+        .iter()
+        .map(|level| frame(|x, y| level * vignette(x, y)))
+        .collect();
+    frames[2].put(3, 4, 60000.0, 0);
+
+    let params = FlatParams {
+        method: StackMethod::Median,
+        ..Default::default()
+    };
+    let flat = calgen::make_flat(&frames, &params).unwrap();
+    assert_eq!(flat.get_mode(), ImageMode::U16BIT);
+
+    let center = flat.get_band(0).get(WIDTH / 2, HEIGHT / 2);
+    assert!((center - 65535.0).abs() < 1.0);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            let expected = 65535.0 * vignette(x, y);
+            let v = flat.get_band(0).get(x, y);
+            assert!(
+                (v - expected).abs() < 65535.0 * 0.01,
+                "({}, {}): {} != {}",
+                x,
+                y,
+                v,
+                expected
+            );
+        }
+    }
+}
+
+#[test]
+fn test_make_flat_too_few_frames() {
+    let frames = vec![frame(|_, _| 1000.0), frame(|_, _| 1000.0)];
+    assert!(calgen::make_flat(&frames, &FlatParams::default()).is_err());
+}
+
+#[test]
+fn test_make_mask() {
+    // Noisy darks with one hot pixel
+    let darks: Vec<Image> = (0..3)
+        .map(|i| {
+            let mut d = frame(|x, y| 100.0 + ((x * 7 + y * 3 + i) % 5) as f32);
+            d.put(5, 6, 3000.0, 0);
+            d
+        })
+        .collect();
+    // Uniform frames with one dead pixel
+    let uniform: Vec<Image> = (0..3)
+        .map(|i| {
+            let mut u = frame(|x, y| 20000.0 * vignette(x, y) + i as f32);
+            u.put(10, 3, 500.0, 0);
+            u
+        })
+        .collect();
+
+    let result = calgen::make_mask(&darks, &uniform, &MaskParams::default()).unwrap();
+    assert_eq!(result.num_hot, 1);
+    assert_eq!(result.num_dead, 1);
+    assert_eq!(result.mask.get(5, 6), 65535.0);
+    assert_eq!(result.mask.get(10, 3), 65535.0);
+    assert_eq!(result.mask.get(0, 0), 0.0);
+
+    // Either set of frames on its own
+    let no_frames: Vec<Image> = vec![];
+    let hot_only = calgen::make_mask(&darks, &no_frames, &MaskParams::default()).unwrap();
+    assert_eq!((hot_only.num_hot, hot_only.num_dead), (1, 0));
+    assert!(calgen::make_mask(&no_frames, &no_frames, &MaskParams::default()).is_err());
+}
+
+#[test]
+fn test_register_calibration_file() {
+    let store = tempfile::tempdir().unwrap();
+    let store_dir = store.path().to_str().unwrap();
+    let flat_file = store.path().join("flat.png");
+    std::fs::write(&flat_file, b"").unwrap();
+    let flat_file = flat_file.to_str().unwrap();
+
+    let overrides_file =
+        calgen::register_calibration_file(store_dir, "MCZ_LEFT", CalFileType::FlatField, flat_file)
+            .unwrap();
+    calgen::register_calibration_file(store_dir, "MCZ_LEFT", CalFileType::InpaintMask, flat_file)
+        .unwrap();
+
+    let overrides = calibfile::load_overrides(&overrides_file).unwrap();
+    assert_eq!(overrides.len(), 2);
+    for o in overrides.iter() {
+        assert_eq!(o.instrument, Instrument::M20MastcamZLeft);
+        assert!(o.file.ends_with("flat.png"));
+    }
+    assert!(overrides
+        .iter()
+        .any(|o| o.file_type == CalFileType::FlatField));
+    assert!(overrides
+        .iter()
+        .any(|o| o.file_type == CalFileType::InpaintMask));
+
+    assert!(calgen::register_calibration_file(
+        store_dir,
+        "NOT_AN_INSTRUMENT",
+        CalFileType::FlatField,
+        flat_file
+    )
+    .is_err());
+}
@@ -1,4 +1,8 @@
-use mars_raw_utils::calibfile;
+use mars_raw_utils::calibfile::{
+    self, CalDataResolver, ObservationTime, SearchLocation, SearchSource,
+};
+use mars_raw_utils::enums::{CalFileType, Instrument};
+use std::str::FromStr;
 
 #[test]
 fn test_load_caldata_mapping_file() {
@@ -16,3 +20,236 @@ fn test_locate_without_extention() {
     calibfile::locate_calibration_file_no_extention(&"caldata".to_string(), &".toml".to_string())
         .expect("Failed to locate caldata.toml");
 }
+
+#[test]
+fn test_resolver_search_order() {
+    let high = tempfile::tempdir().unwrap();
+    let low = tempfile::tempdir().unwrap();
+    std::fs::write(high.path().join("flat.png"), b"high").unwrap();
+    std::fs::write(low.path().join("flat.png"), b"low").unwrap();
+    std::fs::write(low.path().join("lut.txt"), b"low").unwrap();
+
+    let resolver = CalDataResolver::with_locations(vec![
+        SearchLocation {
+            source: SearchSource::CommandLine,
+            path: String::from(high.path().to_str().unwrap()),
+        },
+        SearchLocation {
+            source: SearchSource::User,
+            path: String::from(low.path().to_str().unwrap()),
+        },
+    ]);
+
+    let r = resolver.resolve("flat.png").unwrap();
+    assert_eq!(r.location.unwrap().source, SearchSource::CommandLine);
+    assert_eq!(r.shadowed.len(), 1);
+
+    let r = resolver.resolve("lut.txt").unwrap();
+    assert_eq!(r.location.unwrap().source, SearchSource::User);
+    assert!(r.shadowed.is_empty());
+
+    assert!(resolver.resolve("missing.png").is_err());
+    assert_eq!(
+        resolver.local_store(),
+        Some(String::from(high.path().to_str().unwrap()))
+    );
+}
+
+#[test]
+fn test_resolver_overrides() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("my_flat.png"), b"flat").unwrap();
+    std::fs::write(
+        dir.path().join(calibfile::OVERRIDES_FILE),
+        "[MCZ_LEFT]\nflat = \"my_flat.png\"\n",
+    )
+    .unwrap();
+
+    let mut resolver = CalDataResolver::with_locations(vec![SearchLocation {
+        source: SearchSource::Project,
+        path: String::from(dir.path().to_str().unwrap()),
+    }]);
+    assert_eq!(resolver.overrides.len(), 1);
+
+    let located = resolver
+        .locate_for_instrument(Instrument::M20MastcamZLeft, CalFileType::FlatField)
+        .unwrap();
+    assert!(located.ends_with("my_flat.png"));
+
+    let explanation = resolver.explain(Instrument::M20MastcamZLeft, CalFileType::FlatField);
+    assert!(explanation.override_used.is_some());
+    assert_eq!(explanation.base_file, "my_flat.png");
+    assert!(explanation.resolution.is_ok());
+
+    resolver.add_override(
+        Instrument::M20MastcamZLeft,
+        CalFileType::FlatField,
+        "other.png",
+    );
+    assert!(resolver
+        .locate_for_instrument(Instrument::M20MastcamZLeft, CalFileType::FlatField)
+        .is_err());
+
+    assert!(calibfile::load_overrides(dir.path().join("my_flat.png").to_str().unwrap()).is_err());
+}
+
+#[test]
+fn test_versioned_overrides() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("my_flat.png"), b"flat").unwrap();
+    std::fs::write(dir.path().join("my_flat_sol1000.png"), b"flat").unwrap();
+    std::fs::write(
+        dir.path().join(calibfile::OVERRIDES_FILE),
+        r#"
+[MCZ_LEFT]
+flat = "my_flat.png"
+
+[[MCZ_LEFT.versions]]
+type = "flat"
+file = "my_flat_sol1000.png"
+sol_start = 1000
+"#,
+    )
+    .unwrap();
+
+    let resolver = CalDataResolver::with_locations(vec![SearchLocation {
+        source: SearchSource::Project,
+        path: String::from(dir.path().to_str().unwrap()),
+    }]);
+    assert_eq!(resolver.overrides.len(), 1);
+    assert_eq!(resolver.overrides[0].versions.len(), 1);
+
+    let at_sol = |sol| ObservationTime {
+        sol: Some(sol),
+        utc: None,
+    };
+    let located = |sol| {
+        resolver
+            .locate_for_instrument_at(
+                Instrument::M20MastcamZLeft,
+                CalFileType::FlatField,
+                &at_sol(sol),
+            )
+            .unwrap()
+    };
+    assert!(located(999).ends_with("my_flat.png"));
+    assert!(located(1000).ends_with("my_flat_sol1000.png"));
+
+    let explanation = resolver.explain_at(
+        Instrument::M20MastcamZLeft,
+        CalFileType::FlatField,
+        &at_sol(1200),
+    );
+    assert_eq!(explanation.base_file, "my_flat_sol1000.png");
+}
+
+#[test]
+fn test_cal_file_type_from_str() {
+    assert_eq!(
+        CalFileType::from_str("flat").unwrap(),
+        CalFileType::FlatField
+    );
+    assert_eq!(
+        CalFileType::from_str("INPAINT").unwrap(),
+        CalFileType::InpaintMask
+    );
+    assert!(CalFileType::from_str("bias").is_err());
+}
+
+#[test]
+fn test_time_dependent_versions() {
+    let config = calibfile::parse_caldata_from_string(
+        r#"
+[msl.mastcam_left]
+flat = "MSL_MCAM_FLAT_L.png"
+inpaint_mask = "MSL_MCAM_INPAINT_L.png"
+
+[[msl.mastcam_left.versions]]
+type = "flat"
+file = "MSL_MCAM_FLAT_L_Sol2000.png"
+sol_start = 2000
+
+[[msl.mastcam_left.versions]]
+type = "flat"
+file = "MSL_MCAM_FLAT_L_Sol3000.png"
+sol_start = 3000
+sol_end = 3500
+
+[[msl.mastcam_left.versions]]
+type = "inpaint"
+file = "MSL_MCAM_INPAINT_L_2021.png"
+utc_start = "2021-01-01"
+
+[m20]
+[nsyt]
+"#,
+    )
+    .unwrap();
+    let props = calibfile::get_instrument_properties(&config, Instrument::MslMastcamLeft).unwrap();
+
+    let at_sol = |sol: u32| ObservationTime {
+        sol: Some(sol),
+        utc: None,
+    };
+    assert_eq!(
+        props.file_for(CalFileType::FlatField, &at_sol(100)),
+        "MSL_MCAM_FLAT_L.png"
+    );
+    assert_eq!(
+        props.file_for(CalFileType::FlatField, &at_sol(2500)),
+        "MSL_MCAM_FLAT_L_Sol2000.png"
+    );
+    assert_eq!(
+        props.file_for(CalFileType::FlatField, &at_sol(3000)),
+        "MSL_MCAM_FLAT_L_Sol3000.png"
+    );
+    assert_eq!(
+        props.file_for(CalFileType::FlatField, &at_sol(3600)),
+        "MSL_MCAM_FLAT_L_Sol2000.png"
+    );
+    assert_eq!(
+        props.file_for(CalFileType::FlatField, &ObservationTime::default()),
+        "MSL_MCAM_FLAT_L.png"
+    );
+
+    let at_utc = |utc: &str| ObservationTime {
+        sol: None,
+        utc: Some(String::from(utc)),
+    };
+    assert_eq!(
+        props.file_for(CalFileType::InpaintMask, &at_utc("2020-12-31T23:59:59.000")),
+        "MSL_MCAM_INPAINT_L.png"
+    );
+    assert_eq!(
+        props.file_for(CalFileType::InpaintMask, &at_utc("2023-01-08T03:53:04.187")),
+        "MSL_MCAM_INPAINT_L_2021.png"
+    );
+
+    assert!(calibfile::parse_utc("2023-01-08T03:53:04Z").is_some());
+    assert!(calibfile::parse_utc("not a date").is_none());
+}
+
+#[test]
+fn test_malformed_version_bounds() {
+    let mapping = |bounds: &str| {
+        calibfile::parse_caldata_from_string(&format!(
+            "[msl]\n[[msl.mastcam_left.versions]]\ntype = \"flat\"\nfile = \"flat.png\"\n{}\n[m20]\n[nsyt]\n",
+            bounds
+        ))
+    };
+    assert!(mapping("sol_start = 2000\nutc_start = \"2021-01-01\"").is_ok());
+    assert!(mapping("utc_start = \"2021-13-45\"").is_err());
+    assert!(mapping("utc_end = \"last tuesday\"").is_err());
+    assert!(mapping("sol_start = 3000\nsol_end = 2000").is_err());
+    assert!(mapping("sol_start = -1").is_err());
+    assert!(mapping("utc_start = \"2022-01-01\"\nutc_end = \"2021-01-01\"").is_err());
+
+    let dir = tempfile::tempdir().unwrap();
+    let overrides_file = dir.path().join(calibfile::OVERRIDES_FILE);
+    std::fs::write(
+        &overrides_file,
+        "[[MCZ_LEFT.versions]]\ntype = \"flat\"\nfile = \"flat.png\"\nutc_start = \"soon\"\n",
+    )
+    .unwrap();
+    assert!(calibfile::load_overrides(overrides_file.to_str().unwrap()).is_err());
+}
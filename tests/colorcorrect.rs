@@ -0,0 +1,148 @@
+use mars_raw_utils::calprofile::CalProfile;
+use mars_raw_utils::colorcorrect::{self, CalTarget, CalTargetPatch, ColorCorrection};
+use sciimg::{enums::ImageMode, image::Image};
+
+fn patch(name: &str, x: usize, reflectance: [f32; 3]) -> CalTargetPatch {
+    CalTargetPatch {
+        name: name.to_string(),
+        x,
+        y: 0,
+        width: 4,
+        height: 4,
+        reflectance,
+    }
+}
+
+/// Image of uniform patches, 4 pixels wide each, with the given band values
+fn target_image(values: &[[f32; 3]]) -> Image {
+    let mut img = Image::new_with_bands(values.len() * 4, 4, 3, ImageMode::U16BIT).unwrap();
+    for (i, v) in values.iter().enumerate() {
+        for y in 0..4 {
+            for x in (i * 4)..(i * 4 + 4) {
+                for (b, value) in v.iter().enumerate() {
+                    img.put(x, y, *value, b);
+                }
+            }
+        }
+    }
+    img
+}
+
+#[test]
+fn test_scalars_from_target() {
+    let target = CalTarget {
+        patches: vec![
+            patch("white", 0, [0.8, 0.8, 0.8]),
+            patch("gray", 4, [0.4, 0.4, 0.4]),
+        ],
+        color_matrix: false,
+    };
+    // Dusty, reddened illumination: red reads high, blue low
+    let img = target_image(&[[4000.0, 2000.0, 1000.0], [2000.0, 1000.0, 500.0]]);
+
+    let measured = colorcorrect::measure_patches(&img, &target).unwrap();
+    assert!((measured[0][0] - 4000.0).abs() < 0.01);
+
+    let cc = ColorCorrection::from_target_image(&img, &target).unwrap();
+    assert!(cc.matrix.is_none());
+    assert!((cc.red_scalar - 0.5).abs() < 1e-4);
+    assert_eq!(cc.green_scalar, 1.0);
+    assert!((cc.blue_scalar - 2.0).abs() < 1e-4);
+
+    let corrected = cc.correct([4000.0, 2000.0, 1000.0]);
+    assert!((corrected[0] - 2000.0).abs() < 0.1);
+    assert!((corrected[2] - 2000.0).abs() < 0.1);
+}
+
+#[test]
+fn test_matrix_from_target() {
+    let target = CalTarget {
+        patches: vec![
+            patch("red", 0, [0.6, 0.2, 0.1]),
+            patch("green", 4, [0.2, 0.5, 0.2]),
+            patch("blue", 8, [0.1, 0.2, 0.6]),
+            patch("white", 12, [0.8, 0.8, 0.8]),
+        ],
+        color_matrix: true,
+    };
+
+    // Measurements produced by a known crosstalk matrix applied to the reflectances
+    let crosstalk = [[1.2, 0.2, 0.0], [0.1, 1.0, 0.1], [0.0, 0.3, 0.7]];
+    let values: Vec<[f32; 3]> = target
+        .patches
+        .iter()
+        .map(|p| {
+            let mut m = [0.0; 3];
+            for (r, v) in m.iter_mut().enumerate() {
+                *v = (0..3)
+                    .map(|c| crosstalk[r][c] * p.reflectance[c])
+                    .sum::<f32>()
+                    * 10000.0;
+            }
+            m
+        })
+        .collect();
+    let img = target_image(&values);
+
+    let cc = ColorCorrection::from_target_image(&img, &target).unwrap();
+    assert!(cc.matrix.is_some());
+
+    // Corrected patches are proportional to their reflectances
+    let ratio = cc.correct(values[3])[1] / 0.8;
+    for (p, v) in target.patches.iter().zip(values.iter()) {
+        for (out, r) in cc.correct(*v).iter().zip(p.reflectance.iter()) {
+            assert!((out / ratio - r).abs() < 1e-3);
+        }
+    }
+
+    let mut corrected = img.clone();
+    cc.apply(&mut corrected).unwrap();
+    assert!((corrected.get_band(0).get(0, 0) / ratio - 0.6).abs() < 1e-3);
+}
+
+#[test]
+fn test_invalid_targets() {
+    let img = target_image(&[[100.0, 100.0, 100.0]]);
+
+    let outside = CalTarget {
+        patches: vec![patch("far", 40, [0.5, 0.5, 0.5])],
+        color_matrix: false,
+    };
+    assert!(colorcorrect::measure_patches(&img, &outside).is_err());
+
+    let too_few = CalTarget {
+        patches: vec![patch("white", 0, [0.5, 0.5, 0.5])],
+        color_matrix: true,
+    };
+    assert!(ColorCorrection::from_target_image(&img, &too_few).is_err());
+
+    assert!(ColorCorrection::from_target_image(&img, &CalTarget::default()).is_err());
+}
+
+#[test]
+fn test_profile_cal_target() {
+    let profile: CalProfile = toml::from_str(
+        r#"
+calfiletype = "profile"
+red_scalar = 1.1
+
+[cal_target]
+color_matrix = true
+
+[[cal_target.patches]]
+name = "white"
+x = 10
+y = 20
+width = 8
+height = 8
+reflectance = [0.85, 0.84, 0.82]
+"#,
+    )
+    .unwrap();
+    let target = profile.cal_target.unwrap();
+    assert!(target.color_matrix);
+    assert_eq!(target.patches[0].reflectance, [0.85, 0.84, 0.82]);
+
+    let profile: CalProfile = toml::from_str("calfiletype = \"profile\"").unwrap();
+    assert!(profile.cal_target.is_none());
+}
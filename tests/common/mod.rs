@@ -0,0 +1,80 @@
+// Each test crate compiles this module and uses only some of it
+#![allow(dead_code)]
+
+use mars_raw_utils::marsimage::MarsImage;
+use mars_raw_utils::metadata::Metadata;
+use mars_raw_utils::tileassembly::Tile;
+use sciimg::{enums::ImageMode, image::Image, prelude::*};
+use std::str::FromStr;
+
+/// Metadata of product `imageid` from `instrument` at scale factor 1, with every other field
+/// unset for tests to fill in what they exercise
+pub fn metadata(instrument: &str, imageid: &str) -> Metadata {
+    Metadata {
+        link: String::new(),
+        credit: String::new(),
+        sol: 0,
+        imageid: imageid.to_string(),
+        caption: String::new(),
+        date_taken_utc: String::new(),
+        date_taken_mars: None,
+        subframe_rect: None,
+        scale_factor: 1,
+        instrument: instrument.to_string(),
+        filter_name: None,
+        camera_vector: None,
+        mast_az: None,
+        mast_el: None,
+        sclk: None,
+        date_received: String::new(),
+        sample_type: String::new(),
+        dimension: None,
+        camera_position: None,
+        attitude: None,
+        xyz: None,
+        camera_model_type: None,
+        focus_motor_count: None,
+        site: None,
+        drive: None,
+        camera_model_component_list: CameraModel::default(),
+        decompand: false,
+        debayer: false,
+        flatfield: false,
+        radiometric: false,
+        inpaint: false,
+        cropped: false,
+        caldata_version: None,
+        decompand_lut: None,
+    }
+}
+
+/// A single band tile from `instrument` of `width` x `height` at one based sensor position
+/// (`x`, `y`) and scale factor `scale`, with pixel values from `f`
+pub fn tile<F: Fn(usize, usize) -> f32>(
+    instrument: &str,
+    x: f64,
+    y: f64,
+    width: usize,
+    height: usize,
+    scale: u32,
+    f: F,
+) -> Tile {
+    let mut md = metadata(instrument, "");
+    md.scale_factor = scale;
+    md.subframe_rect = Some(vec![
+        x,
+        y,
+        (width as u32 * scale) as f64,
+        (height as u32 * scale) as f64,
+    ]);
+
+    let mut img = Image::new_with_bands(width, height, 1, ImageMode::U16BIT).unwrap();
+    for py in 0..height {
+        for px in 0..width {
+            img.put(px, py, f(px, py), 0);
+        }
+    }
+    let mut image = MarsImage::from_image(&img, FromStr::from_str(instrument).unwrap());
+    image.metadata = Some(md);
+    Tile::from_image(image).unwrap()
+}
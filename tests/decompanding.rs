@@ -0,0 +1,236 @@
+use mars_raw_utils::calprofile::CalProfile;
+use mars_raw_utils::decompanding::{self, LookUpTable};
+use sciimg::{enums::ImageMode, image::Image};
+
+#[test]
+fn test_builtin_luts_are_valid() {
+    let luts = decompanding::builtin_luts();
+    assert_eq!(luts.len(), 3);
+    assert_eq!(luts[0].name, "ILT");
+    for lut in luts.iter() {
+        lut.validate().unwrap();
+    }
+    assert!(LookUpTable::builtin("lut2").is_some());
+    assert!(LookUpTable::builtin("LUT9").is_none());
+}
+
+#[test]
+fn test_validate() {
+    let mut values: Vec<u32> = (0..256).map(|i| i * 16).collect();
+    assert!(LookUpTable::new_from_vec(&values)
+        .unwrap()
+        .validate()
+        .is_ok());
+
+    values[100] = 5;
+    let err = LookUpTable::new_from_vec(&values).unwrap().validate();
+    assert!(err.unwrap_err().to_string().contains("not monotonic"));
+
+    let out_of_range: Vec<u32> = (0..256).map(|i| i * 20).collect();
+    assert!(LookUpTable::new_from_vec(&out_of_range)
+        .unwrap()
+        .validate()
+        .is_err());
+
+    let short = LookUpTable {
+        lut: vec![0; 255],
+        name: "short".to_string(),
+    };
+    assert!(short.validate().is_err());
+    assert!(LookUpTable::new_from_vec(&vec![0; 255]).is_err());
+}
+
+#[test]
+fn test_inverse() {
+    let lut = LookUpTable::builtin("ILT").unwrap();
+    let inverse = lut.inverse();
+    assert_eq!(inverse.table.len(), lut.max() as usize + 1);
+
+    // ILT is strictly increasing after the first entries, so every code round trips
+    for (code, v) in lut.lut.iter().enumerate().skip(7) {
+        assert_eq!(inverse.compand(*v as f32) as usize, code);
+    }
+
+    // Values between entries go to the nearest, values past the end clamp
+    assert_eq!(inverse.compand(2030.0), 255);
+    assert_eq!(inverse.compand(5000.0), 255);
+    assert_eq!(inverse.compand(-10.0), 0);
+
+    // Repeated entries map to the lowest code
+    let nsyt = LookUpTable::builtin("NSYT_ILT").unwrap().inverse();
+    assert_eq!(nsyt.compand(0.0), 0);
+    assert_eq!(nsyt.compand(1.0), 5);
+}
+
+#[test]
+fn test_detect_lut() {
+    let ilt = LookUpTable::builtin("ILT").unwrap();
+    let nsyt = LookUpTable::builtin("NSYT_ILT").unwrap();
+    let lut2 = LookUpTable::builtin("LUT2").unwrap();
+
+    // Repeated entries leave codes the compander never emits
+    let ilt_codes = ilt.output_codes();
+    assert!(ilt_codes[2] && !ilt_codes[3] && ilt_codes[4]);
+    assert!(!nsyt.output_codes()[1]);
+    assert!(lut2.output_codes().iter().all(|c| *c));
+
+    // A linear gradient companded onboard with `lut`
+    let companded = |lut: &LookUpTable| {
+        let inverse = lut.inverse();
+        let mut image = Image::new_with_bands(64, 64, 1, ImageMode::U8BIT).unwrap();
+        for y in 0..64 {
+            for x in 0..64 {
+                let linear = (y * 64 + x) as f32 * lut.max() as f32 / 4095.0;
+                image.put(x, y, inverse.compand(linear) as f32, 0);
+            }
+        }
+        decompanding::value_histogram(&image)
+    };
+
+    let candidates = vec![ilt.clone(), nsyt.clone(), lut2.clone()];
+    let found = decompanding::detect_lut(&companded(&lut2), &candidates).unwrap();
+    assert_eq!(found.lut.name, "LUT2");
+    assert_eq!(found.score, 1.0);
+
+    // LUT2 emits every code, so it only wins when it fits better
+    let found = decompanding::detect_lut(&companded(&ilt), &candidates).unwrap();
+    assert_eq!(found.lut.name, "ILT");
+    assert_eq!(found.score, 1.0);
+
+    // Codes neither table emits
+    let mut image = Image::new_with_bands(64, 16, 1, ImageMode::U8BIT).unwrap();
+    for y in 0..16 {
+        for x in 0..64 {
+            image.put(x, y, if x % 2 == 0 { 1.0 } else { 3.0 }, 0);
+        }
+    }
+    let histogram = decompanding::value_histogram(&image);
+    assert!(decompanding::detect_lut(&histogram, &[ilt.clone(), nsyt.clone()]).is_none());
+
+    // Already decompanded values are not codes
+    let mut image = Image::new_with_bands(64, 16, 1, ImageMode::U16BIT).unwrap();
+    for y in 0..16 {
+        for x in 0..64 {
+            image.put(x, y, lut2.lut[(y * 64 + x) % 256] as f32, 0);
+        }
+    }
+    let histogram = decompanding::value_histogram(&image);
+    assert!(decompanding::detect_lut(&histogram, &candidates).is_none());
+}
+
+/// Histogram of a gradient from `low` to `high` companded with `lut`, with the one code
+/// of noise JPEG compression leaves
+fn noisy_companded(lut: &LookUpTable, low: f32, high: f32) -> Vec<u32> {
+    let inverse = lut.inverse();
+    let mut image = Image::new_with_bands(64, 64, 1, ImageMode::U8BIT).unwrap();
+    for y in 0..64 {
+        for x in 0..64 {
+            let linear = low + (y * 64 + x) as f32 * (high - low) / 4096.0;
+            let noise = ((x * 7 + y * 13) % 3) as i32 - 1;
+            let code = (inverse.compand(linear) as i32 + noise).clamp(0, 255);
+            image.put(x, y, code as f32, 0);
+        }
+    }
+    decompanding::value_histogram(&image)
+}
+
+#[test]
+fn test_select_lut() {
+    let ilt = LookUpTable::builtin("ILT").unwrap();
+    let nsyt = LookUpTable::builtin("NSYT_ILT").unwrap();
+    let builtin = decompanding::builtin_luts();
+
+    // Noise puts a few pixels on codes ILT never emits, so LUT2 fits best, but ILT still
+    // fits and is kept
+    let histogram = noisy_companded(&ilt, 0.0, 2033.0);
+    let best = decompanding::detect_lut(&histogram, &builtin).unwrap();
+    assert_eq!(best.lut.name, "LUT2");
+    assert_eq!(
+        decompanding::select_lut(&histogram, &ilt, &builtin).name,
+        "ILT"
+    );
+
+    // A dark LUT2 product puts many pixels on codes 3 and 6, ruling out ILT and NSYT_ILT
+    let histogram = noisy_companded(&LookUpTable::builtin("LUT2").unwrap(), 120.0, 300.0);
+    assert_eq!(
+        decompanding::select_lut(&histogram, &ilt, &builtin).name,
+        "LUT2"
+    );
+    assert_eq!(
+        decompanding::select_lut(&histogram, &nsyt, &builtin).name,
+        "LUT2"
+    );
+
+    // Raw Mastcam-Z products companded with the instrument default
+    for file in [
+        "tests/testdata/ZL0_0038_0670307360_057ECM_N0031392ZCAM08007_1100LUJ.png",
+        "tests/testdata/ZL0_0053_0671642352_402ECM_N0032046ZCAM05025_110085J01.png",
+    ] {
+        let image = Image::open_str(file).unwrap();
+        let histogram = decompanding::value_histogram(&image);
+        assert_eq!(
+            decompanding::detect_lut(&histogram, &builtin)
+                .unwrap()
+                .lut
+                .name,
+            "LUT2"
+        );
+        assert_eq!(
+            decompanding::select_lut(&histogram, &ilt, &builtin).name,
+            "ILT"
+        );
+        assert_eq!(
+            decompanding::select_lut(&histogram, &nsyt, &builtin).name,
+            "NSYT_ILT"
+        );
+    }
+
+    // Nothing to go on
+    let empty = vec![0; 256];
+    assert_eq!(
+        decompanding::select_lut(&empty, &nsyt, &builtin).name,
+        "NSYT_ILT"
+    );
+}
+
+#[test]
+fn test_profile_lut() {
+    let profile: CalProfile = toml::from_str(
+        r#"
+calfiletype = "profile"
+apply_ilt = true
+lut = "LUT2"
+"#,
+    )
+    .unwrap();
+    assert_eq!(profile.lut, Some("LUT2".to_string()));
+
+    let lut = decompanding::get_ilt_for_profile(
+        &profile,
+        mars_raw_utils::enums::Instrument::M20MastcamZLeft,
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(lut.name, "LUT2");
+    assert_eq!(lut.max(), 4095);
+
+    assert!(decompanding::load_lut("/nonexistent/lut.txt").is_err());
+}
+
+#[test]
+fn test_load_lut_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("custom_lut.txt");
+    let text: String = (0..256).map(|i| format!("{} {}\n", i, i * 8)).collect();
+    std::fs::write(&file, text).unwrap();
+    let file = file.to_str().unwrap();
+
+    let lut = decompanding::load_lut(file).unwrap();
+    assert_eq!(lut.name, file);
+    assert_eq!(lut.max(), 2040);
+
+    let bad = dir.path().join("bad_lut.txt");
+    let text: String = (0..256).map(|i| format!("{} {}\n", i, 255 - i)).collect();
+    std::fs::write(&bad, text).unwrap();
+    assert!(decompanding::load_lut(bad.to_str().unwrap()).is_err());
+}
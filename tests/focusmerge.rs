@@ -0,0 +1,92 @@
+use mars_raw_utils::focusmerge::{
+    self, DepthMap, FocusCalibration, FocusCalibrationPoint, FocusMergeParams,
+};
+use sciimg::image::Image;
+
+fn texture(x: usize, y: usize) -> f32 {
+    let h = ((x as u32).wrapping_mul(73_856_093) ^ (y as u32).wrapping_mul(19_349_663)) % 1000;
+    h as f32
+}
+
+/// Frame with the sharp texture on one half and a featureless (defocused) level elsewhere
+fn half_focused(width: usize, height: usize, left_in_focus: bool) -> Image {
+    let mut img = Image::create(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let in_focus = (x < width / 2) == left_in_focus;
+            let v = if in_focus { texture(x, y) } else { 500.0 };
+            for b in 0..3 {
+                img.put(x, y, v, b);
+            }
+        }
+    }
+    img
+}
+
+#[test]
+fn test_merge_selects_in_focus_regions() {
+    let (w, h) = (64, 48);
+    let images = vec![half_focused(w, h, true), half_focused(w, h, false)];
+    let result = focusmerge::merge_images(
+        &images,
+        &FocusMergeParams {
+            quality_window_size: 5,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    // Away from the seam the merge follows the sharp frame
+    let mut error = 0.0;
+    let mut count = 0;
+    for y in 4..h - 4 {
+        for x in (4..w / 2 - 6).chain(w / 2 + 6..w - 4) {
+            error += (result.image.get_band(0).get(x, y) - texture(x, y)).abs();
+            count += 1;
+        }
+    }
+    assert!(error / (count as f32) < 60.0);
+
+    assert!(result.depth_map.get(8, h / 2) < 0.2);
+    assert!(result.depth_map.get(w - 8, h / 2) > 0.8);
+}
+
+#[test]
+fn test_merge_rejects_mismatched() {
+    let images = vec![Image::create(16, 16), Image::create(20, 16)];
+    assert!(focusmerge::merge_images(&images, &FocusMergeParams::default()).is_err());
+    assert!(focusmerge::merge_images(&[], &FocusMergeParams::default()).is_err());
+}
+
+#[test]
+fn test_focus_calibration() {
+    let cal = FocusCalibration::new(vec![
+        FocusCalibrationPoint {
+            motor_count: 12000.0,
+            distance_mm: 20.0,
+        },
+        FocusCalibrationPoint {
+            motor_count: 10000.0,
+            distance_mm: 100.0,
+        },
+    ])
+    .unwrap();
+    assert_eq!(cal.distance(11000.0), 60.0);
+    assert_eq!(cal.distance(13000.0), 20.0);
+    assert_eq!(cal.distance(9000.0), 100.0);
+
+    assert!(FocusCalibration::new(vec![FocusCalibrationPoint {
+        motor_count: 1.0,
+        distance_mm: 1.0
+    }])
+    .is_err());
+
+    let depth = DepthMap {
+        width: 2,
+        height: 1,
+        data: vec![0.0, 0.5],
+    };
+    let distance = depth.to_distance(&[12000.0, 10000.0], &cal).unwrap();
+    assert_eq!(distance.get(0, 0), 20.0);
+    assert_eq!(distance.get(1, 0), 60.0);
+}
@@ -0,0 +1,250 @@
+use mars_raw_utils::enums::Instrument;
+use mars_raw_utils::focusstack::{self, FocusCandidate, StackCriteria};
+use mars_raw_utils::metadata::{self, Metadata};
+
+mod common;
+
+fn frame(
+    instrument: &str,
+    imageid: &str,
+    sclk: f64,
+    focus: Option<f64>,
+    camera_vector: [f64; 3],
+) -> Metadata {
+    let mut md = common::metadata(instrument, imageid);
+    md.sclk = Some(sclk);
+    md.focus_motor_count = focus;
+    md.camera_vector = Some(camera_vector.to_vec());
+    md
+}
+
+/// WATSON frame whose product id records focus position 0, which `focus` takes precedence over
+fn watson_frame(sclk: f64, focus: Option<f64>, camera_vector: [f64; 3]) -> Metadata {
+    frame(
+        "SHERLOC_WATSON",
+        "SIF_0614_0721455442_734FDR_N0301172SRLC00702_0000LMJ",
+        sclk,
+        focus,
+        camera_vector,
+    )
+}
+
+fn candidate(name: &str, md: &Metadata) -> FocusCandidate {
+    FocusCandidate::new_from_metadata(&format!("/data/{}.png", name), md).unwrap()
+}
+
+#[test]
+fn test_rejects_non_focus_cameras() {
+    let md = metadata::load_image_metadata(&String::from(
+        "tests/testdata/NLF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01-metadata.json",
+    ))
+    .unwrap();
+    assert!(FocusCandidate::new_from_metadata("nav.png", &md).is_err());
+}
+
+#[test]
+fn test_find_stacks() {
+    let down = [0.0, 0.0, 1.0];
+    let tilted = [0.0, 0.2, 0.98];
+    let candidates = vec![
+        candidate("a0", &watson_frame(1000.0, Some(100.0), down)),
+        candidate("a1", &watson_frame(1010.0, Some(200.0), down)),
+        candidate("a2", &watson_frame(1020.0, Some(300.0), down)),
+        // Different target
+        candidate("b0", &watson_frame(1030.0, Some(100.0), tilted)),
+        candidate("b1", &watson_frame(1040.0, Some(200.0), tilted)),
+        candidate("b2", &watson_frame(1050.0, Some(300.0), tilted)),
+        // Same pointing, much later and without focus variation
+        candidate("c0", &watson_frame(5000.0, Some(100.0), down)),
+        candidate("c1", &watson_frame(5010.0, Some(100.0), down)),
+        candidate("c2", &watson_frame(5020.0, Some(100.0), down)),
+    ];
+
+    let stacks = focusstack::find_stacks(&candidates, &StackCriteria::default());
+    assert_eq!(stacks.len(), 2);
+    assert_eq!(stacks[0].instrument, Instrument::M20Watson);
+    assert_eq!(
+        stacks[0].files,
+        vec!["/data/a0.png", "/data/a1.png", "/data/a2.png"]
+    );
+    assert_eq!(
+        stacks[0].known_focus_positions(),
+        Some(vec![100.0, 200.0, 300.0])
+    );
+    assert_eq!(stacks[1].files[0], "/data/b0.png");
+    assert_eq!(stacks[0].output_file(Some("out")), "out/a0-focusmerge.png");
+}
+
+#[test]
+fn test_unknown_focus_positions() {
+    let down = [0.0, 0.0, 1.0];
+    let candidates: Vec<FocusCandidate> = (0..4)
+        .map(|i| {
+            candidate(
+                &format!("f{}", i),
+                // MAHLI product ids carry no focus position
+                &frame(
+                    "MAHLI",
+                    "3372MH0001900001203405C00_DXXX",
+                    100.0 + i as f64 * 5.0,
+                    None,
+                    down,
+                ),
+            )
+        })
+        .collect();
+    assert!(focusstack::find_stacks(&candidates, &StackCriteria::default()).is_empty());
+}
+
+#[test]
+fn test_focus_positions_from_product_id() {
+    assert_eq!(
+        focusstack::focus_position_from_product_id(
+            Instrument::M20Watson,
+            "SIF_0614_0721455442_734FDR_N0301172SRLC00702_0412LMJ"
+        ),
+        Some(412.0)
+    );
+    assert_eq!(
+        focusstack::focus_position_from_product_id(
+            Instrument::M20NavcamLeft,
+            "NLF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J"
+        ),
+        None
+    );
+
+    let down = [0.0, 0.0, 1.0];
+    let candidates: Vec<FocusCandidate> = (0..4)
+        .map(|i| {
+            let mut md = watson_frame(100.0 + i as f64 * 5.0, None, down);
+            md.imageid = format!(
+                "SIF_0614_0721455442_734FDR_N0301172SRLC00702_0{}00LMJ",
+                i + 1
+            );
+            candidate(&format!("{}01", md.imageid), &md)
+        })
+        .collect();
+    assert_eq!(candidates[1].focus_position, Some(200.0));
+
+    let stacks = focusstack::find_stacks(&candidates, &StackCriteria::default());
+    assert_eq!(stacks.len(), 1);
+    assert_eq!(
+        stacks[0].known_focus_positions(),
+        Some(vec![100.0, 200.0, 300.0, 400.0])
+    );
+
+    let strict = StackCriteria {
+        min_frames: 5,
+        ..Default::default()
+    };
+    assert!(focusstack::find_stacks(&candidates, &strict).is_empty());
+}
+
+fn write_raw_metadata(name: &str, json: &serde_json::Value) -> String {
+    let file_path = std::env::temp_dir().join(name);
+    std::fs::write(&file_path, json.to_string()).unwrap();
+    String::from(file_path.to_str().unwrap())
+}
+
+#[test]
+fn test_focus_motor_count_from_raw_metadata() {
+    let mut msl = serde_json::json!({
+        "extended": {
+            "lmst": "Sol-03372M12:22:34.000",
+            "bucket": "msl-raws",
+            "mast_az": "UNK",
+            "mast_el": "UNK",
+            "url_list": "",
+            "contributor": "Team MSLICE",
+            "filter_name": "UNK",
+            "sample_type": "full",
+            "focus_motor_count": "12955"
+        },
+        "id": 1,
+        "camera_vector": "UNK",
+        "site": 97,
+        "imageid": "3372MH0001900001203405C00_DXXX",
+        "subframe_rect": "(1,1,1584,1184)",
+        "sol": 3372,
+        "scale_factor": 1,
+        "camera_model_component_list": "UNK",
+        "instrument": "MAHLI",
+        "url": "",
+        "spacecraft_clock": 686358543.0,
+        "attitude": "UNK",
+        "camera_position": "UNK",
+        "camera_model_type": null,
+        "drive": 1444,
+        "xyz": "UNK",
+        "created_at": "",
+        "updated_at": "",
+        "mission": "msl",
+        "date_taken": "2022-01-01T00:00:00.000Z",
+        "date_received": "2022-01-01T00:00:00.000Z",
+        "instrument_sort": 1,
+        "sample_type_sort": 1,
+        "is_thumbnail": false,
+        "title": "",
+        "description": "",
+        "link": "",
+        "image_credit": "",
+        "https_url": ""
+    });
+    let file_path = write_raw_metadata("mru_test_mahli_raw.json", &msl);
+    let md = mars_raw_utils::msl::metadata::load_metadata_file(file_path.clone()).unwrap();
+    assert_eq!(md.focus_motor_count, Some(12955.0));
+
+    msl["extended"]
+        .as_object_mut()
+        .unwrap()
+        .remove("focus_motor_count");
+    std::fs::write(&file_path, msl.to_string()).unwrap();
+    let md = mars_raw_utils::msl::metadata::load_metadata_file(file_path.clone()).unwrap();
+    assert_eq!(md.focus_motor_count, None);
+    std::fs::remove_file(&file_path).unwrap();
+
+    let m20 = serde_json::json!({
+        "extended": {
+            "mastAz": "UNK",
+            "mastEl": "UNK",
+            "sclk": "721455442.0",
+            "scaleFactor": "1",
+            "xyz": "UNK",
+            "subframeRect": "(1,1,1648,1200)",
+            "dimension": "(1648,1200)",
+            "focusMotorCount": 10462
+        },
+        "sol": 614,
+        "attitude": "UNK",
+        "image_files": {
+            "medium": "",
+            "small": "",
+            "full_res": "",
+            "large": ""
+        },
+        "imageid": "SIF_0614_0721455442_734FDR_N0301172SRLC00702_0000LMJ",
+        "camera": {
+            "filter_name": "UNK",
+            "camera_vector": "UNK",
+            "camera_model_component_list": "UNK",
+            "camera_position": "UNK",
+            "instrument": "SHERLOC_WATSON",
+            "camera_model_type": "UNK"
+        },
+        "caption": "",
+        "sample_type": "Full",
+        "date_taken_mars": "Sol-00614M10:00:00.000",
+        "credit": "",
+        "date_taken_utc": "2022-11-11T00:00:00.000",
+        "json_link": "",
+        "link": "",
+        "drive": "1172",
+        "title": "",
+        "site": 30,
+        "date_received": "2022-11-11T00:00:00Z"
+    });
+    let file_path = write_raw_metadata("mru_test_watson_raw.json", &m20);
+    let md = mars_raw_utils::m20::metadata::load_metadata_file(file_path.clone()).unwrap();
+    assert_eq!(md.focus_motor_count, Some(10462.0));
+    std::fs::remove_file(&file_path).unwrap();
+}
@@ -0,0 +1,89 @@
+use mars_raw_utils::framesink::{self, FrameSink, OutputFormat, PngFramesSink};
+use sciimg::image::Image;
+use std::str::FromStr;
+
+fn test_frame(value: f32) -> Image {
+    let mut img = Image::create(4, 3);
+    for y in 0..3 {
+        for x in 0..4 {
+            for b in 0..3 {
+                img.put(x, y, value, b);
+            }
+        }
+    }
+    img
+}
+
+#[test]
+fn test_output_format() {
+    assert_eq!(
+        OutputFormat::from_str("apng16").unwrap(),
+        OutputFormat::Apng16
+    );
+    assert_eq!(OutputFormat::from_str("WebP").unwrap(), OutputFormat::WebP);
+    assert!(OutputFormat::from_str("avi").is_err());
+
+    assert_eq!(
+        OutputFormat::from_output_path("out/diff.gif"),
+        Some(OutputFormat::Gif)
+    );
+    assert_eq!(
+        OutputFormat::from_output_path("diff.PNG"),
+        Some(OutputFormat::Apng)
+    );
+    assert_eq!(OutputFormat::from_output_path("diff"), None);
+}
+
+#[test]
+fn test_frame_scaling() {
+    let frame = test_frame(65535.0);
+    assert!(framesink::frame_to_rgb8(&frame).iter().all(|v| *v == 255));
+    assert!(framesink::frame_to_rgb16_be(&frame)
+        .iter()
+        .all(|v| *v == 255));
+
+    let frame = test_frame(257.0);
+    assert!(framesink::frame_to_rgb8(&frame).iter().all(|v| *v == 1));
+    assert_eq!(&framesink::frame_to_rgb16_be(&frame)[0..2], &[1, 1]);
+}
+
+#[test]
+fn test_png_frame_paths() {
+    let sink = PngFramesSink::new("out/dustdevil.png", false);
+    assert_eq!(sink.frame_path(12), "out/dustdevil_0012.png");
+}
+
+#[test]
+fn test_animated_webp() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("anim.webp");
+    let output = output.to_str().unwrap();
+
+    let mut sink = framesink::create_sink(OutputFormat::WebP, output, 4, 3, 2).unwrap();
+    sink.add_frame(&test_frame(0.0), 10).unwrap();
+    sink.add_frame(&test_frame(65535.0), 10).unwrap();
+    sink.finish().unwrap();
+
+    let bytes = std::fs::read(output).unwrap();
+    assert_eq!(&bytes[0..4], b"RIFF");
+    assert_eq!(&bytes[8..12], b"WEBP");
+    assert_eq!(&bytes[12..16], b"VP8X");
+    assert_eq!(bytes.windows(4).filter(|w| w == b"ANMF").count(), 2);
+}
+
+#[test]
+fn test_apng_16bit() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("anim.png");
+    let output = output.to_str().unwrap();
+
+    let mut sink = framesink::create_sink(OutputFormat::Apng16, output, 4, 3, 2).unwrap();
+    sink.add_frame(&test_frame(1000.0), 10).unwrap();
+    sink.add_frame(&test_frame(2000.0), 10).unwrap();
+    sink.finish().unwrap();
+
+    let bytes = std::fs::read(output).unwrap();
+    assert!(bytes.windows(4).any(|w| w == b"acTL"));
+    // IHDR bit depth
+    assert_eq!(bytes[24], 16);
+}
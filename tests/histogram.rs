@@ -0,0 +1,126 @@
+use mars_raw_utils::enums::Instrument;
+use mars_raw_utils::histogram::{self, Gap, Histogram};
+use mars_raw_utils::marsimage::MarsImage;
+use sciimg::{enums::ImageMode, image::Image};
+
+/// Single band 8 bit image holding `values`, one per pixel
+fn image_of(values: &[f32]) -> Image {
+    let mut img = Image::new_with_bands(values.len(), 1, 1, ImageMode::U8BIT).unwrap();
+    for (x, v) in values.iter().enumerate() {
+        img.put(x, 0, *v, 0);
+    }
+    img
+}
+
+#[test]
+fn test_per_band_histograms() {
+    let mut img = Image::new_with_bands(4, 4, 3, ImageMode::U8BIT).unwrap();
+    for y in 0..4 {
+        for x in 0..4 {
+            img.put(x, y, 10.0, 0);
+            img.put(x, y, 20.0, 1);
+            img.put(x, y, 255.0, 2);
+        }
+    }
+
+    let green = Histogram::of_band(&img, 1);
+    assert_eq!(green.bins.len(), 256);
+    assert_eq!(green.count(), 16);
+    assert_eq!(green.bins[20], 16);
+    assert_eq!(green.bins[10], 0);
+    assert_eq!(Histogram::of_image(&img).count(), 48);
+
+    // Only the requested band is counted, and the top value has a bin
+    let hist = MarsImage::from_image(&img, Instrument::None).calc_histogram(2);
+    assert_eq!(hist.len(), 256);
+    assert_eq!(hist[255], 16.0);
+    assert_eq!(hist[10], 0.0);
+
+    let wide = Image::new_with_bands(2, 2, 1, ImageMode::U16BIT).unwrap();
+    assert_eq!(Histogram::of_band(&wide, 0).bins.len(), 65536);
+}
+
+#[test]
+fn test_stats_and_gaps() {
+    let hist = Histogram::of_band(&image_of(&[2.0, 2.0, 3.0, 7.0, 8.0, 12.0]), 0);
+    let stats = hist.stats().unwrap();
+    assert_eq!((stats.min, stats.max, stats.median), (2, 12, 3));
+    assert!((stats.mean - 34.0 / 6.0).abs() < 1e-9);
+    assert_eq!(stats.occupied, 5);
+    assert_eq!(
+        hist.gaps(),
+        vec![Gap { start: 4, len: 3 }, Gap { start: 9, len: 3 }]
+    );
+    assert_eq!((stats.num_gaps, stats.gap_bins), (2, 6));
+    assert!(Histogram::new(256).stats().is_none());
+}
+
+#[test]
+fn test_detect_multi_bin_gap_stretch() {
+    // 61 levels stretched by 3 from 10, leaving two bin gaps, with some levels missing
+    let values: Vec<f32> = (0..=60)
+        .filter(|v| !(20..25).contains(v))
+        .map(|v| 10.0 + v as f32 * 3.0)
+        .collect();
+    let mut img = image_of(&values);
+    let pattern = histogram::detect_stretch(&Histogram::of_image(&img)).unwrap();
+    assert_eq!(pattern.min, 10);
+    assert!((pattern.gain - 3.0).abs() < 1e-4);
+
+    histogram::destretch(&mut img).unwrap();
+    let restored: Vec<f32> = (0..=60)
+        .filter(|v| !(20..25).contains(v))
+        .map(|v| 10.0 + v as f32)
+        .collect();
+    for (x, v) in restored.iter().enumerate() {
+        assert_eq!(img.get_band(0).get(x, 0), *v);
+    }
+}
+
+#[test]
+fn test_detect_fractional_stretch() {
+    // A gain of 1.5 leaves single bin gaps between every other pair of levels
+    let values: Vec<f32> = (0..=100).map(|v| (10.0 + v as f32 * 1.5).round()).collect();
+    let mut img = image_of(&values);
+    let pattern = histogram::destretch(&mut img).unwrap();
+    assert!((pattern.gain - 1.5).abs() < 0.01);
+    for x in 0..=100 {
+        assert_eq!(img.get_band(0).get(x, 0), 10.0 + x as f32);
+    }
+}
+
+#[test]
+fn test_no_stretch() {
+    // Contiguous values with a sparse tail are left alone
+    let mut values: Vec<f32> = (20..120).map(|v| v as f32).collect();
+    values.extend([125.0, 131.0]);
+    let mut img = image_of(&values);
+    assert!(histogram::destretch(&mut img).is_none());
+    assert_eq!(img.get_band(0).get(101, 0), 131.0);
+}
+
+#[test]
+fn test_irregular_gaps_fall_back_to_collapse() {
+    // Single bin gaps at irregular positions fit a gain of about 1.42 within the spacing
+    // tolerance, but destretching by it would merge levels
+    let spacings = [
+        1, 2, 2, 1, 1, 1, 2, 1, 2, 2, 2, 1, 1, 1, 1, 2, 1, 2, 1, 1, 2, 2, 1, 1, 1, 2,
+    ];
+    let mut values = vec![20.0];
+    for s in spacings.iter() {
+        values.push(values[values.len() - 1] + *s as f32);
+    }
+    let img = image_of(&values);
+    assert!(histogram::detect_stretch(&Histogram::of_image(&img)).is_none());
+
+    let mut collapsed = img.clone();
+    assert_eq!(histogram::collapse_gaps(&mut collapsed), 11);
+
+    // Each gap is removed, leaving contiguous levels
+    let mut raw = MarsImage::from_image(&img, Instrument::M20SuperCam);
+    raw.destretch_image();
+    for x in 0..values.len() {
+        assert_eq!(raw.image.get_band(0).get(x, 0), 20.0 + x as f32);
+        assert_eq!(collapsed.get_band(0).get(x, 0), 20.0 + x as f32);
+    }
+}
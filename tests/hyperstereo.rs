@@ -0,0 +1,143 @@
+use mars_raw_utils::hyperstereo::{
+    self, Baseline, HyperstereoCandidate, HyperstereoCriteria, Quaternion, SiteFrames,
+};
+use mars_raw_utils::metadata::{self, Metadata};
+
+const LEFT_METADATA: &str =
+    "tests/testdata/NLF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01-metadata.json";
+
+#[test]
+fn test_quaternion_rotation() {
+    // 90 degrees about +Z
+    let half = std::f64::consts::FRAC_PI_4;
+    let q = Quaternion::from_vec(&[half.cos(), 0.0, 0.0, half.sin()]).unwrap();
+    let v = q.rotate(&[1.0, 0.0, 0.0]);
+    assert!((v[0] - 0.0).abs() < 1e-9);
+    assert!((v[1] - 1.0).abs() < 1e-9);
+    assert!((v[2] - 0.0).abs() < 1e-9);
+
+    let back = q.conjugate().rotate(&v);
+    assert!((back[0] - 1.0).abs() < 1e-9);
+
+    assert!(Quaternion::from_vec(&[1.0, 0.0, 0.0]).is_err());
+}
+
+#[test]
+fn test_baseline_from_metadata() {
+    let left = metadata::load_image_metadata(&String::from(LEFT_METADATA)).unwrap();
+    let mut right = left.clone();
+    right.drive = Some(700);
+    right.xyz = Some(vec![12.8213 + 30.0, 60.1509 + 40.0, 0.32331]);
+
+    let baseline = Baseline::from_metadata(&left, &right).unwrap();
+    assert!((baseline.length - 50.0).abs() < 1e-6);
+    assert_eq!(baseline.right.drive, 700);
+
+    right.site = Some(33);
+    assert!(Baseline::from_metadata(&left, &right).is_err());
+}
+
+#[test]
+fn test_baseline_across_sites() {
+    let left = metadata::load_image_metadata(&String::from(LEFT_METADATA)).unwrap();
+    let mut right = left.clone();
+    right.site = Some(33);
+    right.drive = Some(0);
+
+    let mut sites = SiteFrames::new();
+    sites.insert(32, [100.0, 200.0, 0.0]);
+    assert!(Baseline::from_metadata_with_sites(&left, &right, &sites).is_err());
+
+    sites.insert(33, [103.0, 204.0, 0.0]);
+    let baseline = Baseline::from_metadata_with_sites(&left, &right, &sites).unwrap();
+    assert!((baseline.length - 5.0).abs() < 1e-6);
+    assert!((baseline.vector[0] - 3.0).abs() < 1e-6);
+
+    let sites_file = std::env::temp_dir().join("mru_test_site_frames.json");
+    std::fs::write(
+        &sites_file,
+        r#"{"32": [100.0, 200.0, 0.0], "33": [103.0, 204.0, 0.0]}"#,
+    )
+    .unwrap();
+    let loaded = SiteFrames::load(sites_file.to_str().unwrap()).unwrap();
+    assert_eq!(loaded, sites);
+
+    std::fs::write(&sites_file, r#"{"32": [100.0, 200.0]}"#).unwrap();
+    assert!(SiteFrames::load(sites_file.to_str().unwrap()).is_err());
+    std::fs::remove_file(&sites_file).unwrap();
+}
+
+fn candidate(md: &Metadata) -> HyperstereoCandidate {
+    let file_path = format!("/data/{}01.png", md.imageid);
+    HyperstereoCandidate::new_from_metadata(&file_path, md).unwrap()
+}
+
+fn moved(md: &Metadata, drive: u32, offset: [f64; 3], sclk_offset: f64) -> Metadata {
+    let mut m = md.clone();
+    let xyz = md.xyz.clone().unwrap();
+    m.xyz = Some(vec![
+        xyz[0] + offset[0],
+        xyz[1] + offset[1],
+        xyz[2] + offset[2],
+    ]);
+    m.drive = Some(drive);
+    m.sclk = md.sclk.map(|s| s + sclk_offset);
+    m.imageid = m.imageid.replace("N0320604", &format!("N0320{}", drive));
+    m
+}
+
+#[test]
+fn test_find_hyperstereo_pairs() {
+    // The fixture camera looks north (+X) and down, so east (+Y) is to its right
+    let md = metadata::load_image_metadata(&String::from(LEFT_METADATA)).unwrap();
+    let east = moved(&md, 605, [0.0, 2.0, 0.0], -100.0);
+    let north = moved(&md, 606, [2.0, 0.0, 0.0], 100.0);
+    let mut turned = moved(&md, 607, [0.0, -2.0, 0.0], 200.0);
+    // 90 degrees about +Z
+    let half = std::f64::consts::FRAC_PI_4;
+    turned.attitude = Some(vec![half.cos(), 0.0, 0.0, half.sin()]);
+
+    let candidates = vec![
+        candidate(&md),
+        candidate(&east),
+        candidate(&north),
+        candidate(&turned),
+    ];
+    let pairs = hyperstereo::find_pairs(
+        &candidates,
+        &HyperstereoCriteria::default(),
+        &SiteFrames::default(),
+    );
+    assert_eq!(pairs.len(), 1);
+    assert_eq!(pairs[0].left, candidates[0].file_path);
+    assert_eq!(pairs[0].right, candidates[1].file_path);
+    assert!((pairs[0].baseline - 2.0).abs() < 1e-6);
+
+    // Too narrow a baseline across the view
+    let criteria = HyperstereoCriteria {
+        min_baseline: 2.5,
+        ..Default::default()
+    };
+    assert!(hyperstereo::find_pairs(&candidates, &criteria, &SiteFrames::default()).is_empty());
+}
+
+#[test]
+fn test_malformed_tuple_is_none() {
+    let text = std::fs::read_to_string(LEFT_METADATA).unwrap();
+    let mut json: serde_json::Value = serde_json::from_str(&text).unwrap();
+    json["attitude"] = serde_json::Value::from("");
+    json["xyz"] = serde_json::Value::from("()");
+    json["camera_position"] = serde_json::Value::from("(1.0,abc,2.0)");
+    let md: Metadata = serde_json::from_value(json).unwrap();
+    assert_eq!(md.attitude, None);
+    assert_eq!(md.xyz, None);
+    assert_eq!(md.camera_position, None);
+}
+
+#[test]
+fn test_expected_disparity() {
+    // 10m baseline, 1km subject, 1 mrad pixels -> 10 pixels
+    let d = hyperstereo::expected_disparity(10.0, 1000.0, 0.001);
+    assert!((d - 10.0).abs() < 1e-9);
+    assert!(hyperstereo::expected_disparity(10.0, 0.0, 0.001).is_infinite());
+}
@@ -0,0 +1,109 @@
+use anyhow::anyhow;
+use mars_raw_utils::memcache::{CacheSize, LruCache};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier};
+
+struct Blob(usize);
+
+impl CacheSize for Blob {
+    fn cache_size(&self) -> usize {
+        self.0
+    }
+}
+
+#[test]
+fn test_hits_share_entries() {
+    let cache: LruCache<Blob> = LruCache::new(1000);
+    let a = cache.get_or_load("a", |_| Ok(Blob(10))).unwrap();
+    let b = cache
+        .get_or_load("a", |_| panic!("Should have been cached"))
+        .unwrap();
+    assert!(Arc::ptr_eq(&a, &b));
+
+    let stats = cache.stats();
+    assert_eq!((stats.hits, stats.misses), (1, 1));
+    assert_eq!((stats.entries, stats.bytes), (1, 10));
+}
+
+#[test]
+fn test_lru_eviction() {
+    let cache: LruCache<Blob> = LruCache::new(300);
+    cache.get_or_load("a", |_| Ok(Blob(100))).unwrap();
+    cache.get_or_load("b", |_| Ok(Blob(100))).unwrap();
+    cache.get_or_load("c", |_| Ok(Blob(100))).unwrap();
+
+    // Touch "a" so "b" is least recently used
+    cache.get_or_load("a", |_| Ok(Blob(100))).unwrap();
+    let held = cache.get_or_load("d", |_| Ok(Blob(100))).unwrap();
+    assert!(cache.contains("a"));
+    assert!(!cache.contains("b"));
+    assert!(cache.contains("c"));
+    assert!(cache.contains("d"));
+    assert_eq!(cache.stats().evictions, 1);
+    assert_eq!(cache.stats().bytes, 300);
+
+    // Shrinking the budget evicts, but held values stay valid
+    cache.set_budget(100);
+    assert_eq!(cache.stats().entries, 1);
+    assert!(cache.contains("d"));
+    assert_eq!(held.0, 100);
+
+    // Values larger than the budget are returned uncached
+    let big = cache.get_or_load("e", |_| Ok(Blob(500))).unwrap();
+    assert_eq!(big.0, 500);
+    assert!(!cache.contains("e"));
+}
+
+#[test]
+fn test_load_errors_are_returned_and_not_cached() {
+    let cache: LruCache<Blob> = LruCache::new(1000);
+    assert!(cache
+        .get_or_load("missing", |f| Err(anyhow!("File not found: {}", f)))
+        .is_err());
+    assert!(!cache.contains("missing"));
+    assert_eq!(cache.stats().load_errors, 1);
+
+    // A later request retries the load
+    assert!(cache.get_or_load("missing", |_| Ok(Blob(1))).is_ok());
+}
+
+#[test]
+fn test_concurrent_loading() {
+    let cache: LruCache<Blob> = LruCache::new(1000);
+    let loads = AtomicUsize::new(0);
+
+    // Different keys load concurrently: each loader waits for the other to start, which
+    // would deadlock if loads were serialized
+    let barrier = Barrier::new(2);
+    std::thread::scope(|s| {
+        for key in ["x", "y"] {
+            let (cache, barrier) = (&cache, &barrier);
+            s.spawn(move || {
+                cache
+                    .get_or_load(key, |_| {
+                        barrier.wait();
+                        Ok(Blob(1))
+                    })
+                    .unwrap();
+            });
+        }
+    });
+
+    // Concurrent requests for the same key load it once
+    std::thread::scope(|s| {
+        for _ in 0..8 {
+            let (cache, loads) = (&cache, &loads);
+            s.spawn(move || {
+                cache
+                    .get_or_load("z", |_| {
+                        loads.fetch_add(1, Ordering::SeqCst);
+                        std::thread::sleep(std::time::Duration::from_millis(20));
+                        Ok(Blob(1))
+                    })
+                    .unwrap();
+            });
+        }
+    });
+    assert_eq!(loads.load(Ordering::SeqCst), 1);
+    assert_eq!(cache.stats().entries, 3);
+}
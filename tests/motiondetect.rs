@@ -0,0 +1,66 @@
+use mars_raw_utils::motiondetect::{self, Blob, DetectionParams, DifferenceFrame, MotionClass};
+
+fn frame_with_spot(cx: usize, cy: usize) -> DifferenceFrame {
+    let mut diff = DifferenceFrame::new(100, 80);
+    for y in cy - 3..=cy + 3 {
+        for x in cx - 3..=cx + 3 {
+            diff.data[y * 100 + x] = 1000.0;
+        }
+    }
+    diff
+}
+
+fn params() -> DetectionParams {
+    DetectionParams {
+        blur_radius: 0,
+        min_area: 10,
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_find_blobs() {
+    let blobs = motiondetect::find_blobs(&frame_with_spot(20, 30), &params(), 0);
+    assert_eq!(blobs.len(), 1);
+    assert_eq!(blobs[0].area, 49);
+    assert!((blobs[0].x - 20.0).abs() < 1e-6);
+    assert!((blobs[0].y - 30.0).abs() < 1e-6);
+    assert_eq!((blobs[0].min_x, blobs[0].max_x), (17, 23));
+
+    // Regions smaller than the minimum area are dropped
+    let strict = DetectionParams {
+        min_area: 50,
+        ..params()
+    };
+    assert!(motiondetect::find_blobs(&frame_with_spot(20, 30), &strict, 0).is_empty());
+}
+
+#[test]
+fn test_track_blobs() {
+    let p = params();
+    let frames: Vec<Vec<Blob>> = (0..4)
+        .map(|i| motiondetect::find_blobs(&frame_with_spot(20 + i * 10, 30), &p, i))
+        .collect();
+
+    let tracks = motiondetect::track_blobs(&frames, &p);
+    assert_eq!(tracks.len(), 1);
+    assert_eq!(tracks[0].detections.len(), 4);
+    assert!((tracks[0].velocity_px_per_frame - 10.0).abs() < 1e-6);
+    assert!((tracks[0].direction.0 - 10.0).abs() < 1e-6);
+    assert_eq!(tracks[0].classification, MotionClass::Unknown);
+}
+
+#[test]
+fn test_track_distance_limit() {
+    let p = DetectionParams {
+        max_track_distance: 5.0,
+        min_track_length: 1,
+        ..params()
+    };
+    let frames: Vec<Vec<Blob>> = (0..2)
+        .map(|i| motiondetect::find_blobs(&frame_with_spot(20 + i * 40, 30), &p, i))
+        .collect();
+
+    // Jumps beyond the association distance start a new track
+    assert_eq!(motiondetect::track_blobs(&frames, &p).len(), 2);
+}
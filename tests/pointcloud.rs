@@ -0,0 +1,52 @@
+use mars_raw_utils::enums::Instrument;
+use mars_raw_utils::marsimage::MarsImage;
+use mars_raw_utils::pointcloud::{Mesh, PointCloud, XyzImage};
+use sciimg::{enums::ImageMode, image::Image};
+
+fn make_xyz_grid(width: usize, height: usize) -> XyzImage {
+    let mut xyz = Image::new_with_bands(width, height, 3, ImageMode::U16BIT).unwrap();
+    for y in 0..height {
+        for x in 0..width {
+            xyz.put(x, y, x as f32 * 0.1 + 0.1, 0);
+            xyz.put(x, y, y as f32 * 0.1, 1);
+            xyz.put(x, y, 1.0, 2);
+        }
+    }
+    XyzImage::new_from_image(&xyz).unwrap()
+}
+
+#[test]
+fn test_point_cloud_from_xyz() {
+    let xyz = make_xyz_grid(4, 3);
+    let img = MarsImage::new(4, 3, Instrument::None);
+    let cloud = PointCloud::from_xyz_and_image(&xyz, &img, 1).unwrap();
+    assert_eq!(cloud.len(), 12);
+
+    let mut out: Vec<u8> = vec![];
+    cloud.write_ply(&mut out).unwrap();
+    let ply = String::from_utf8(out).unwrap();
+    assert!(ply.starts_with("ply\n"));
+    assert!(ply.contains("element vertex 12\n"));
+}
+
+#[test]
+fn test_mesh_triangulation() {
+    let xyz = make_xyz_grid(4, 3);
+    let img = MarsImage::new(4, 3, Instrument::None);
+    let mesh = Mesh::from_xyz_and_image(&xyz, &img, 1, 1.0).unwrap();
+    assert_eq!(mesh.vertices.len(), 12);
+    assert_eq!(mesh.uvs.len(), 12);
+    // (4 - 1) * (3 - 1) grid cells, two triangles each
+    assert_eq!(mesh.faces.len(), 12);
+
+    // An edge length limit below the grid spacing removes all faces
+    let mesh = Mesh::from_xyz_and_image(&xyz, &img, 1, 0.05).unwrap();
+    assert!(mesh.faces.is_empty());
+}
+
+#[test]
+fn test_mismatched_dimensions() {
+    let xyz = make_xyz_grid(4, 3);
+    let img = MarsImage::new(5, 3, Instrument::None);
+    assert!(PointCloud::from_xyz_and_image(&xyz, &img, 1).is_err());
+}
@@ -0,0 +1,80 @@
+use mars_raw_utils::registration::{self, RegistrationMethod, Transform};
+use sciimg::image::Image;
+use std::str::FromStr;
+
+/// Deterministic texture sampled with an offset so shifted copies share content
+fn textured(width: usize, height: usize, dx: i32, dy: i32) -> Image {
+    let texture = |x: i32, y: i32| {
+        let h = (x.wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663)) as u32;
+        (h % 1000) as f32
+    };
+    let mut img = Image::create(width, height);
+    for y in 0..height as i32 {
+        for x in 0..width as i32 {
+            let v = texture(x - dx, y - dy);
+            for b in 0..3 {
+                img.put(x as usize, y as usize, v, b);
+            }
+        }
+    }
+    img
+}
+
+#[test]
+fn test_registration_method_from_str() {
+    assert_eq!(
+        RegistrationMethod::from_str("affine").unwrap(),
+        RegistrationMethod::Affine
+    );
+    assert_eq!(
+        RegistrationMethod::from_str("Translation").unwrap(),
+        RegistrationMethod::Translation
+    );
+    assert!(RegistrationMethod::from_str("elastic").is_err());
+}
+
+#[test]
+fn test_phase_correlate() {
+    let reference = textured(64, 64, 0, 0);
+    let shifted = textured(64, 64, 5, -3);
+    let t = registration::phase_correlate(&reference, &shifted).unwrap();
+    assert!((t.dx - 5.0).abs() < 0.5, "dx = {}", t.dx);
+    assert!((t.dy + 3.0).abs() < 0.5, "dy = {}", t.dy);
+    assert!(t.confidence > 0.1);
+}
+
+#[test]
+fn test_transform_inverse() {
+    let t = Transform {
+        a: [1.01, 0.02, 4.0, -0.02, 0.99, -2.0],
+    };
+    let inv = t.inverse().unwrap();
+    let (sx, sy) = t.apply(10.0, 20.0);
+    let (x, y) = inv.apply(sx, sy);
+    assert!((x - 10.0).abs() < 1e-9);
+    assert!((y - 20.0).abs() < 1e-9);
+}
+
+#[test]
+fn test_common_overlap() {
+    let transforms = vec![Transform::identity(), Transform::translation(5.0, -3.0)];
+    let sizes = vec![(64, 64), (64, 64)];
+    let rect = registration::common_overlap(&transforms, &sizes, 64, 64).unwrap();
+    assert_eq!((rect.x, rect.y), (0, 3));
+    assert_eq!((rect.width, rect.height), (59, 61));
+}
+
+#[test]
+fn test_register_stack() {
+    let images = vec![textured(64, 64, 0, 0), textured(64, 64, 4, 2)];
+    let stack = registration::register_stack(&images, 0, RegistrationMethod::Translation).unwrap();
+    assert_eq!(stack.images.len(), 2);
+    assert_eq!(stack.images[0].width, stack.images[1].width);
+    assert_eq!(stack.crop.width, 60);
+    assert_eq!(stack.crop.height, 62);
+
+    // Aligned frames match the reference over the overlap
+    let a = stack.images[0].get_band(0).get(10, 10);
+    let b = stack.images[1].get_band(0).get(10, 10);
+    assert!((a - b).abs() < 50.0);
+}
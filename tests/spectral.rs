@@ -0,0 +1,124 @@
+use mars_raw_utils::enums::Instrument;
+use mars_raw_utils::metadata;
+use mars_raw_utils::spectral::{self, CubeBand, Roi, SpectralCube, SpectralFrame};
+use sciimg::imagebuffer::ImageBuffer;
+use std::str::FromStr;
+
+mod common;
+
+const ZL0: &str = "ZL0_0038_0670307360_057ECM_N0031392ZCAM08007_1100LUJ";
+const ZL1: &str = "ZL1_0038_0670307360_057ECM_N0031392ZCAM08007_1100LUJ";
+const ZR1: &str = "ZR1_0038_0670307360_057ECM_N0031392ZCAM08007_1100LUJ";
+const ZR3: &str = "ZR3_0038_0670307360_057ECM_N0031392ZCAM08007_1100LUJ";
+const ML1: &str = "3372ML0175100011205069C00_DXXX";
+const MR1: &str = "3372MR0175100011205069C00_DXXX";
+const NLF: &str = "NLF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J";
+
+fn frame_metadata(instrument: &str, imageid: &str, filter_name: &str) -> metadata::Metadata {
+    let mut md = common::metadata(instrument, imageid);
+    md.filter_name = Some(String::from(filter_name));
+    md
+}
+
+#[test]
+fn test_filter_wavelengths() {
+    assert_eq!(
+        spectral::filter_wavelengths(Instrument::M20MastcamZLeft, 0),
+        Some(vec![630.0, 544.0, 480.0])
+    );
+    assert_eq!(
+        spectral::filter_wavelengths(Instrument::M20MastcamZRight, 6),
+        Some(vec![1022.0])
+    );
+    assert_eq!(
+        spectral::filter_wavelengths(Instrument::MslMastcamLeft, 2),
+        Some(vec![445.0])
+    );
+    assert_eq!(
+        spectral::filter_wavelengths(Instrument::M20NavcamLeft, 1),
+        None
+    );
+}
+
+#[test]
+fn test_filter_from_name_and_file() {
+    let md = frame_metadata("MCZ_RIGHT", ZR3, "UNK");
+    let frame = SpectralFrame::new_from_metadata(
+        "ZR3_0038_0670307360_057ECM_N0031392ZCAM08007_1100LUJ.png",
+        &md,
+    )
+    .unwrap();
+    assert_eq!(frame.filter, 3);
+    assert_eq!(frame.wavelengths, vec![910.0]);
+    assert_eq!(frame.band_label(0), "R3");
+
+    let md = frame_metadata("MCZ_LEFT", ZL0, "L0");
+    let frame = SpectralFrame::new_from_metadata("image.png", &md).unwrap();
+    assert_eq!(frame.band_label(1), "L0G");
+
+    let md = frame_metadata("NAVCAM_LEFT", NLF, "L0");
+    assert!(SpectralFrame::new_from_metadata("image.png", &md).is_err());
+}
+
+#[test]
+fn test_scale_between_eyes() {
+    let left =
+        SpectralFrame::new_from_metadata("left.png", &frame_metadata("MAST_LEFT", ML1, "L1"))
+            .unwrap();
+    let mut right_md = frame_metadata("MAST_RIGHT", MR1, "R1");
+    let right = SpectralFrame::new_from_metadata("right.png", &right_md).unwrap();
+    assert!((right.scale_to(&left) - 0.34).abs() < 1e-6);
+    assert!((left.scale_to(&left) - 1.0).abs() < 1e-6);
+
+    // A downsampled right eye frame needs less reduction
+    right_md.scale_factor = 2;
+    let right = SpectralFrame::new_from_metadata("right.png", &right_md).unwrap();
+    assert!((right.scale_to(&left) - 0.68).abs() < 1e-6);
+
+    let zl =
+        SpectralFrame::new_from_metadata("zl.png", &frame_metadata("MCZ_LEFT", ZL1, "L1")).unwrap();
+    let zr = SpectralFrame::new_from_metadata("zr.png", &frame_metadata("MCZ_RIGHT", ZR1, "R1"))
+        .unwrap();
+    assert_eq!(zr.scale_to(&zl), 1.0);
+}
+
+#[test]
+fn test_roi_from_str() {
+    let roi = Roi::from_str("rock:10,20,5,6").unwrap();
+    assert_eq!(roi.name, "rock");
+    assert_eq!((roi.x, roi.y, roi.width, roi.height), (10, 20, 5, 6));
+    assert!(Roi::from_str("rock:10,20,5").is_err());
+    assert!(Roi::from_str("10,20,5,6").is_err());
+}
+
+#[test]
+fn test_extract_spectra() {
+    let band = |label: &str, wavelength_nm: f32| CubeBand {
+        label: String::from(label),
+        wavelength_nm,
+        source: String::new(),
+    };
+    let cube = SpectralCube {
+        width: 10,
+        height: 10,
+        bands: vec![band("L6", 442.0), band("L1", 800.0)],
+        planes: vec![
+            ImageBuffer::new_with_fill(10, 10, 0.1).unwrap(),
+            ImageBuffer::new_with_fill(10, 10, 0.3).unwrap(),
+        ],
+    };
+    assert_eq!(cube.nearest_band(780.0), Some(1));
+
+    let rois = vec![Roi::from_str("a:2,2,4,4").unwrap()];
+    let spectra = spectral::extract_spectra(&cube, &rois).unwrap();
+    assert_eq!(spectra[0].points.len(), 2);
+    assert!((spectra[0].points[1].mean - 0.3).abs() < 0.0001);
+    assert_eq!(spectra[0].points[0].pixels, 16);
+
+    let csv = spectral::spectra_to_csv(&spectra);
+    assert!(csv.starts_with("roi,filter,wavelength_nm,mean,stddev,pixels\n"));
+    assert!(csv.contains("a,L1,800,"));
+
+    let outside = vec![Roi::from_str("b:8,8,4,4").unwrap()];
+    assert!(spectral::extract_spectra(&cube, &outside).is_err());
+}
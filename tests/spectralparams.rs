@@ -0,0 +1,163 @@
+use mars_raw_utils::spectral::{CubeBand, SpectralCube};
+use mars_raw_utils::spectralparams::{
+    self, BandRef, CompositeDefinition, ParameterKind, SpectralDefinitions,
+};
+use sciimg::{enums::ImageMode, imagebuffer::ImageBuffer};
+
+/// Cube with a uniform value per band
+fn cube(bands: &[(&str, f32, f32)]) -> SpectralCube {
+    SpectralCube {
+        width: 4,
+        height: 3,
+        bands: bands
+            .iter()
+            .map(|(label, nm, _)| CubeBand {
+                label: label.to_string(),
+                wavelength_nm: *nm,
+                source: String::new(),
+            })
+            .collect(),
+        planes: bands
+            .iter()
+            .map(|(_, _, v)| ImageBuffer::new_with_fill(4, 3, *v).unwrap())
+            .collect(),
+    }
+}
+
+#[test]
+fn test_parse_definitions() {
+    let defs = SpectralDefinitions::default_definitions();
+    assert!(defs.parameter.iter().any(|p| p.name == "bd_1000"));
+    assert_eq!(defs.composite.len(), 1);
+
+    let defs = SpectralDefinitions::parse(
+        r#"
+[[parameter]]
+name = "r"
+type = "ratio"
+numerator = "R6"
+denominator = 754
+"#,
+    )
+    .unwrap();
+    assert_eq!(
+        defs.parameter[0].kind,
+        ParameterKind::Ratio {
+            numerator: BandRef::Label(String::from("R6")),
+            denominator: BandRef::Wavelength(754.0),
+        }
+    );
+
+    assert!(SpectralDefinitions::parse("[[parameter]]\nname = \"x\"\ntype = \"bogus\"").is_err());
+}
+
+#[test]
+fn test_compute_parameters() {
+    let c = cube(&[("L2", 754.0, 0.4), ("R4", 939.0, 0.2), ("R6", 1022.0, 0.3)]);
+    let defs = SpectralDefinitions::parse(
+        r#"
+[[parameter]]
+name = "depth"
+type = "band_depth"
+left = 754
+center = "R4"
+right = 1022
+
+[[parameter]]
+name = "ratio"
+type = "ratio"
+numerator = 1022
+denominator = 754
+
+[[parameter]]
+name = "slope"
+type = "slope"
+from = 754
+to = 1022
+
+[[parameter]]
+name = "missing"
+type = "ratio"
+numerator = 442
+denominator = 754
+"#,
+    )
+    .unwrap();
+
+    // Continuum at 939 nm interpolated between 0.4 at 754 nm and 0.3 at 1022 nm
+    let f = (939.0 - 754.0) / (1022.0 - 754.0);
+    let continuum = 0.4 * (1.0 - f) + 0.3 * f;
+    let depth = spectralparams::compute_parameter(&c, &defs.parameter[0]).unwrap();
+    assert!((depth.get(1, 1) - (1.0 - 0.2 / continuum)).abs() < 1e-5);
+
+    let ratio = spectralparams::compute_parameter(&c, &defs.parameter[1]).unwrap();
+    assert!((ratio.get(0, 0) - 0.75).abs() < 1e-5);
+
+    let slope = spectralparams::compute_parameter(&c, &defs.parameter[2]).unwrap();
+    assert!((slope.get(3, 2) - (-0.1 / 268.0)).abs() < 1e-7);
+
+    assert!(spectralparams::compute_parameter(&c, &defs.parameter[3]).is_err());
+}
+
+#[test]
+fn test_undefined_values() {
+    let c = cube(&[("L2", 754.0, 0.4), ("R6", 1022.0, 0.0)]);
+    let defs = SpectralDefinitions::parse(
+        "[[parameter]]\nname = \"r\"\ntype = \"ratio\"\nnumerator = 754\ndenominator = 1022",
+    )
+    .unwrap();
+    let map = spectralparams::compute_parameter(&c, &defs.parameter[0]).unwrap();
+    assert!(map.get(0, 0).is_nan());
+    assert_eq!(map.range(), None);
+}
+
+#[test]
+fn test_compose() {
+    let c = cube(&[
+        ("L6", 442.0, 1000.0),
+        ("L5", 528.0, 2000.0),
+        ("L2", 754.0, 3000.0),
+    ]);
+    let rgb = spectralparams::compose(
+        &c,
+        &CompositeDefinition {
+            name: String::from("rgb"),
+            red: BandRef::Wavelength(754.0),
+            green: BandRef::Wavelength(528.0),
+            blue: BandRef::Label(String::from("L6")),
+            stretch: false,
+        },
+    )
+    .unwrap();
+    assert_eq!(rgb.num_bands(), 3);
+    assert_eq!(rgb.get_band(0).get(0, 0), 3000.0);
+    assert_eq!(rgb.get_band(2).get(0, 0), 1000.0);
+}
+
+#[test]
+fn test_compose_8bit() {
+    let mut c = cube(&[
+        ("L6", 442.0, 51.0),
+        ("L5", 528.0, 102.0),
+        ("L2", 754.0, 255.0),
+    ]);
+    for plane in c.planes.iter_mut() {
+        plane.mode = ImageMode::U8BIT;
+    }
+    let rgb = spectralparams::compose(
+        &c,
+        &CompositeDefinition {
+            name: String::from("rgb"),
+            red: BandRef::Label(String::from("L2")),
+            green: BandRef::Label(String::from("L5")),
+            blue: BandRef::Label(String::from("L6")),
+            stretch: false,
+        },
+    )
+    .unwrap();
+
+    // Scaled from the 8 bit range rather than left near black
+    assert!((rgb.get_band(0).get(0, 0) - 65535.0).abs() < 0.5);
+    assert!((rgb.get_band(1).get(0, 0) - 26214.0).abs() < 0.5);
+    assert!((rgb.get_band(2).get(0, 0) - 13107.0).abs() < 0.5);
+}
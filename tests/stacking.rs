@@ -0,0 +1,140 @@
+use mars_raw_utils::stacking::{self, StackMethod, StackOptions};
+use sciimg::image::Image;
+use std::str::FromStr;
+
+fn flat(width: usize, height: usize, value: f32) -> Image {
+    let mut img = Image::create(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            for b in 0..3 {
+                img.put(x, y, value, b);
+            }
+        }
+    }
+    img
+}
+
+/// Five frames of a constant level with a cosmic ray hit in the third
+fn frames_with_outlier() -> Vec<Image> {
+    let mut frames: Vec<Image> = [100.0, 102.0, 98.0, 101.0, 99.0]
+        .iter()
+        .map(|v| flat(8, 8, *v))
+        .collect();
+    for b in 0..3 {
+        frames[2].put(4, 4, 4000.0, b);
+    }
+    frames
+}
+
+fn stack_with(frames: &[Image], method: StackMethod) -> Image {
+    stacking::stack(
+        frames,
+        &StackOptions {
+            method,
+            sigma_low: 1.5,
+            sigma_high: 1.5,
+            ..Default::default()
+        },
+    )
+    .unwrap()
+}
+
+#[test]
+fn test_stack_method_from_str() {
+    assert_eq!(
+        StackMethod::from_str("median").unwrap(),
+        StackMethod::Median
+    );
+    assert_eq!(
+        StackMethod::from_str("Sigma").unwrap(),
+        StackMethod::SigmaClip
+    );
+    assert!(StackMethod::from_str("mode").is_err());
+}
+
+#[test]
+fn test_mean_min_max() {
+    let frames = frames_with_outlier();
+    let mean = stack_with(&frames, StackMethod::Mean);
+    assert!((mean.get_band(0).get(0, 0) - 100.0).abs() < 0.01);
+    assert!(mean.get_band(0).get(4, 4) > 800.0);
+
+    let min = stack_with(&frames, StackMethod::Min);
+    assert_eq!(min.get_band(1).get(4, 4), 99.0);
+
+    let max = stack_with(&frames, StackMethod::Max);
+    assert_eq!(max.get_band(2).get(4, 4), 4000.0);
+    assert_eq!(max.get_band(2).get(0, 0), 102.0);
+}
+
+#[test]
+fn test_rejects_outlier() {
+    let frames = frames_with_outlier();
+
+    let median = stack_with(&frames, StackMethod::Median);
+    assert_eq!(median.get_band(0).get(4, 4), 101.0);
+    assert_eq!(median.get_band(0).get(0, 0), 100.0);
+
+    let clipped = stack_with(&frames, StackMethod::SigmaClip);
+    assert!((clipped.get_band(0).get(4, 4) - 100.5).abs() < 0.01);
+
+    let winsorized = stack_with(&frames, StackMethod::Winsorized);
+    assert!(winsorized.get_band(0).get(4, 4) < 1000.0);
+}
+
+#[test]
+fn test_median_in_strips() {
+    let frames = frames_with_outlier();
+    let stripped = stacking::stack(
+        &frames,
+        &StackOptions {
+            method: StackMethod::Median,
+            memory_budget: 1,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(stripped.get_band(0).get(4, 4), 101.0);
+    assert_eq!(stripped.get_band(2).get(7, 7), 100.0);
+}
+
+#[test]
+fn test_weights() {
+    let frames = vec![flat(4, 4, 10.0), flat(4, 4, 20.0)];
+    let weighted = stacking::stack(
+        &frames,
+        &StackOptions {
+            weights: Some(vec![3.0, 1.0]),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert!((weighted.get_band(0).get(1, 1) - 12.5).abs() < 0.001);
+
+    let excluded = stacking::stack(
+        &frames,
+        &StackOptions {
+            method: StackMethod::Max,
+            weights: Some(vec![1.0, 0.0]),
+            ..Default::default()
+        },
+    )
+    .unwrap();
+    assert_eq!(excluded.get_band(0).get(1, 1), 10.0);
+
+    assert!(stacking::stack(
+        &frames,
+        &StackOptions {
+            weights: Some(vec![1.0]),
+            ..Default::default()
+        },
+    )
+    .is_err());
+}
+
+#[test]
+fn test_differing_dimensions() {
+    let frames = vec![flat(4, 4, 1.0), flat(5, 4, 1.0)];
+    assert!(stacking::stack(&frames, &StackOptions::default()).is_err());
+    assert!(stacking::stack(&Vec::<Image>::new(), &StackOptions::default()).is_err());
+}
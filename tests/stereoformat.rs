@@ -0,0 +1,68 @@
+use mars_raw_utils::stereoformat::{self, StereoFormat};
+use sciimg::image::Image;
+use std::str::FromStr;
+
+fn filled(width: usize, height: usize, value: f32) -> Image {
+    let mut img = Image::create(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            for b in 0..3 {
+                img.put(x, y, value, b);
+            }
+        }
+    }
+    img
+}
+
+#[test]
+fn test_stereo_format_from_str() {
+    assert_eq!(
+        StereoFormat::from_str("sbs").unwrap(),
+        StereoFormat::Parallel
+    );
+    assert_eq!(
+        StereoFormat::from_str("over-under").unwrap(),
+        StereoFormat::OverUnder
+    );
+    assert_eq!(StereoFormat::from_str("MPO").unwrap(), StereoFormat::Mpo);
+    assert!(StereoFormat::from_str("hologram").is_err());
+}
+
+#[test]
+fn test_compose_layouts() {
+    let left = filled(8, 4, 100.0);
+    let right = filled(8, 4, 50.0);
+
+    let sbs = stereoformat::compose(&left, &right, StereoFormat::Parallel, false).unwrap();
+    assert_eq!((sbs.width, sbs.height), (16, 4));
+    assert_eq!(sbs.get_band(0).get(0, 0), 100.0);
+    assert_eq!(sbs.get_band(0).get(8, 0), 50.0);
+
+    let xeye = stereoformat::compose(&left, &right, StereoFormat::CrossEye, false).unwrap();
+    assert_eq!(xeye.get_band(0).get(0, 0), 50.0);
+
+    let ou = stereoformat::compose(&left, &right, StereoFormat::OverUnder, true).unwrap();
+    assert_eq!((ou.width, ou.height), (8, 4));
+
+    let rows = stereoformat::compose(&left, &right, StereoFormat::InterleavedRows, false).unwrap();
+    assert_eq!(rows.get_band(1).get(3, 0), 100.0);
+    assert_eq!(rows.get_band(1).get(3, 1), 50.0);
+
+    assert!(
+        stereoformat::compose(&left, &filled(4, 4, 0.0), StereoFormat::Parallel, false).is_err()
+    );
+}
+
+#[test]
+fn test_encode_mpo() {
+    let left = filled(16, 16, 200.0);
+    let right = filled(16, 16, 20.0);
+    let mpo = stereoformat::encode_mpo(&left, &right, 90).unwrap();
+
+    assert_eq!(&mpo[0..2], &[0xFF, 0xD8]);
+    assert!(mpo.windows(4).any(|w| w == b"MPF\0"));
+
+    // Two SOI markers: one per embedded image
+    let soi_count = mpo.windows(3).filter(|w| w == &[0xFF, 0xD8, 0xFF]).count();
+    assert_eq!(soi_count, 2);
+}
@@ -0,0 +1,148 @@
+use mars_raw_utils::enums::{Eye, Instrument};
+use mars_raw_utils::metadata;
+use mars_raw_utils::stereopair::{self, PairingCriteria, StereoCandidate};
+
+mod common;
+
+#[test]
+fn test_sequence_id_from_name() {
+    assert_eq!(
+        stereopair::sequence_id_from_name(
+            "NLF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01.png"
+        ),
+        Some(String::from("NCAM08111"))
+    );
+    assert_eq!(
+        stereopair::sequence_id_from_name("/data/MSL/NRB_670586006EDR_S0871444NCAM00545M_.jpg"),
+        Some(String::from("NCAM00545"))
+    );
+    assert_eq!(stereopair::sequence_id_from_name("foo.png"), None);
+}
+
+#[test]
+fn test_instrument_stereo_counterpart() {
+    assert_eq!(Instrument::M20NavcamLeft.eye(), Eye::Left);
+    assert_eq!(Instrument::MslMastcamRight.eye(), Eye::Right);
+    assert_eq!(Instrument::M20Watson.eye(), Eye::DontCare);
+    assert_eq!(
+        Instrument::M20NavcamLeft.stereo_counterpart(),
+        Some(Instrument::M20NavcamRight)
+    );
+    assert_eq!(
+        Instrument::MslFrontHazRight.stereo_counterpart(),
+        Some(Instrument::MslFrontHazLeft)
+    );
+    assert_eq!(Instrument::MslMAHLI.stereo_counterpart(), None);
+}
+
+#[test]
+fn test_find_pairs_in_testdata() {
+    // The test data contains one left and one right Navcam image from different sequences
+    let pairs = stereopair::find_pairs_in_directory(
+        "tests/testdata",
+        &stereopair::PairingCriteria::default(),
+    );
+    assert!(pairs.is_empty());
+}
+
+const LEFT_METADATA: &str =
+    "tests/testdata/NLF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01-metadata.json";
+const RIGHT_METADATA: &str =
+    "tests/testdata/NRF_0731_0731848568_991ECM_N0361610NCAM12731_04_195J01-metadata.json";
+
+/// Right eye counterpart of the left Navcam fixture, `sclk_offset` seconds later
+fn right_eye_of_fixture(sclk_offset: f64) -> metadata::Metadata {
+    let left = metadata::load_image_metadata(&String::from(LEFT_METADATA)).unwrap();
+    let mut md = common::metadata(
+        "NAVCAM_RIGHT",
+        "NRF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J",
+    );
+    md.sclk = left.sclk.map(|s| s + sclk_offset);
+    md.site = left.site;
+    md.drive = left.drive;
+    md.subframe_rect = left.subframe_rect;
+    md
+}
+
+fn candidate(file_path: &str, md: &metadata::Metadata) -> StereoCandidate {
+    StereoCandidate::new_from_metadata(file_path, md).unwrap()
+}
+
+#[test]
+fn test_find_pairs_from_fixture_metadata() {
+    let left_md = metadata::load_image_metadata(&String::from(LEFT_METADATA)).unwrap();
+    let other_md = metadata::load_image_metadata(&String::from(RIGHT_METADATA)).unwrap();
+    let right_md = right_eye_of_fixture(0.2);
+    let mut other_sequence = right_eye_of_fixture(0.1);
+    other_sequence.imageid = other_sequence.imageid.replace("NCAM08111", "NCAM08112");
+
+    let candidates = vec![
+        candidate(
+            "/data/NLF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01.png",
+            &left_md,
+        ),
+        candidate(
+            "/data/NLF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01-rjcal.png",
+            &left_md,
+        ),
+        candidate(
+            "/data/NRF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01.png",
+            &right_md,
+        ),
+        candidate(
+            "/data/NRF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01-rjcal.png",
+            &right_md,
+        ),
+        // Closer in time, but from another sequence
+        candidate(
+            "/data/NRF_0670_0726421423_362ECM_N0320604NCAM08112_01_095J01.png",
+            &other_sequence,
+        ),
+        // Right eye from another sol
+        candidate(
+            "/data/NRF_0731_0731848568_991ECM_N0361610NCAM12731_04_195J01.png",
+            &other_md,
+        ),
+    ];
+    assert_eq!(candidates[0].sequence_id, Some(String::from("NCAM08111")));
+
+    let pairs = stereopair::find_pairs(&candidates, &PairingCriteria::default());
+    assert_eq!(pairs.len(), 2);
+
+    // Raw pairs with raw and calibrated with calibrated
+    assert_eq!(
+        pairs[0].left,
+        "/data/NLF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01.png"
+    );
+    assert_eq!(
+        pairs[0].right,
+        "/data/NRF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01.png"
+    );
+    assert_eq!(
+        pairs[1].left,
+        "/data/NLF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01-rjcal.png"
+    );
+    assert_eq!(
+        pairs[1].right,
+        "/data/NRF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01-rjcal.png"
+    );
+    assert_eq!(pairs[0].instrument, Instrument::M20NavcamLeft);
+    assert!((pairs[0].sclk_delta - 0.2).abs() < 1e-6);
+
+    // Without sequence matching the closer right eye image wins
+    let criteria = PairingCriteria {
+        match_sequence: false,
+        ..Default::default()
+    };
+    let subset = vec![
+        candidates[0].clone(),
+        candidates[2].clone(),
+        candidates[4].clone(),
+    ];
+    let pairs = stereopair::find_pairs(&subset, &criteria);
+    assert_eq!(pairs.len(), 1);
+    assert_eq!(
+        pairs[0].right,
+        "/data/NRF_0670_0726421423_362ECM_N0320604NCAM08112_01_095J01.png"
+    );
+}
@@ -0,0 +1,103 @@
+use mars_raw_utils::registration::Transform;
+use mars_raw_utils::superres::{self, DrizzleParams};
+use sciimg::image::Image;
+
+/// Deterministic texture sampled with an offset so shifted copies share content
+fn textured(width: usize, height: usize, dx: i32) -> Image {
+    let mut img = Image::create(width, height);
+    for y in 0..height as i32 {
+        for x in 0..width as i32 {
+            let h = ((x - dx).wrapping_mul(73_856_093) ^ y.wrapping_mul(19_349_663)) as u32;
+            for b in 0..3 {
+                img.put(x as usize, y as usize, (h % 1000) as f32, b);
+            }
+        }
+    }
+    img
+}
+
+fn flat(width: usize, height: usize, value: f32) -> Image {
+    let mut img = Image::create(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            for b in 0..3 {
+                img.put(x, y, value, b);
+            }
+        }
+    }
+    img
+}
+
+#[test]
+fn test_drizzle_constant() {
+    let images = vec![flat(16, 12, 50.0), flat(16, 12, 50.0)];
+    let transforms = vec![Transform::identity(), Transform::translation(0.5, 0.5)];
+    let result = superres::drizzle(&images, &transforms, &DrizzleParams::default()).unwrap();
+    assert_eq!(result.image.width, 32);
+    assert_eq!(result.image.height, 24);
+    for y in 0..24 {
+        for x in 0..32 {
+            assert!((result.image.get_band(1).get(x, y) - 50.0).abs() < 0.001);
+        }
+    }
+}
+
+#[test]
+fn test_drizzle_alignment() {
+    // A feature at reference x is found at x + 3 in the second frame
+    let images = vec![textured(24, 16, 0), textured(24, 16, 3)];
+    let transforms = vec![Transform::identity(), Transform::translation(3.0, 0.0)];
+    let params = DrizzleParams {
+        scale: 1.0,
+        pixfrac: 1.0,
+        ..Default::default()
+    };
+    let result = superres::drizzle(&images, &transforms, &params).unwrap();
+    for y in 0..16 {
+        for x in 0..21 {
+            assert!(
+                (result.image.get_band(0).get(x, y) - images[0].get_band(0).get(x, y)).abs() < 0.01
+            );
+        }
+    }
+}
+
+#[test]
+fn test_uncovered_filled_from_reference() {
+    let images = vec![flat(8, 8, 20.0)];
+    let params = DrizzleParams {
+        scale: 4.0,
+        pixfrac: 0.2,
+        ..Default::default()
+    };
+    let result = superres::drizzle(&images, &[Transform::identity()], &params).unwrap();
+    assert!(result.num_uncovered() > 0);
+    assert_eq!(result.image.get_band(0).get(0, 0), 20.0);
+}
+
+#[test]
+fn test_invalid_params() {
+    let images = vec![flat(8, 8, 1.0), flat(8, 8, 1.0)];
+    let transforms = vec![Transform::identity(), Transform::identity()];
+    for params in [
+        DrizzleParams {
+            scale: 0.5,
+            ..Default::default()
+        },
+        DrizzleParams {
+            pixfrac: 0.0,
+            ..Default::default()
+        },
+        DrizzleParams {
+            reference_index: 2,
+            ..Default::default()
+        },
+        DrizzleParams {
+            weights: Some(vec![1.0]),
+            ..Default::default()
+        },
+    ] {
+        assert!(superres::drizzle(&images, &transforms, &params).is_err());
+    }
+    assert!(superres::drizzle(&images, &transforms[..1], &DrizzleParams::default()).is_err());
+}
@@ -0,0 +1,158 @@
+use mars_raw_utils::enums::Instrument;
+use mars_raw_utils::tileassembly::{self, AssemblyOptions, Rect, Tile};
+
+mod common;
+
+use common::tile;
+
+#[test]
+fn test_tile_placement() {
+    let t = tile("NAV_LEFT_B", 513.0, 257.0, 64, 32, 1, |_, _| 0.0);
+    assert_eq!(t.rect, Rect::new(512, 256, 64, 32));
+    assert_eq!(t.image.instrument, Instrument::MslNavCamLeft);
+
+    let t = tile("NAVCAM_RIGHT", 2561.0, 1921.0, 64, 32, 2, |_, _| 0.0);
+    assert_eq!(t.rect, Rect::new(1280, 960, 64, 32));
+    assert_eq!(t.scale, 2);
+}
+
+const NAVCAM_LEFT_SF1: &str =
+    "tests/testdata/NLF_0670_0726421423_362ECM_N0320604NCAM08111_01_095J01.png";
+const NAVCAM_RIGHT_SF2: &str =
+    "tests/testdata/NRF_0731_0731848568_991ECM_N0361610NCAM12731_04_195J01.png";
+
+#[test]
+fn test_open_navcam_tiles() {
+    // Right half of the sensor at scale factor 2
+    let t = Tile::open(NAVCAM_RIGHT_SF2).unwrap();
+    assert_eq!(t.image.instrument, Instrument::M20NavcamRight);
+    assert_eq!(t.scale, 2);
+    assert_eq!(t.rect, Rect::new(1272, 0, 1288, 968));
+    assert_eq!(t.rect.subframe_rect(2), vec![2545.0, 1.0, 2576.0, 1936.0]);
+
+    // Mars 2020 engineering camera tiles lose two pixels along each edge
+    let border = tileassembly::default_border(t.image.instrument);
+    assert_eq!(border, 2);
+    assert_eq!(t.content_rect(border), Rect::new(1274, 2, 1284, 964));
+
+    let t = Tile::open(NAVCAM_LEFT_SF1).unwrap();
+    assert_eq!(t.image.instrument, Instrument::M20NavcamLeft);
+    assert_eq!(t.scale, 1);
+    assert_eq!(t.rect, Rect::new(0, 0, 1288, 968));
+    assert_eq!(t.rect.subframe_rect(1), vec![1.0, 1.0, 1288.0, 968.0]);
+
+    assert!(Tile::open("tests/testdata/not_a_tile.png").is_err());
+}
+
+#[test]
+fn test_navcam_tile_pairs() {
+    // The scale factor 1 tile and a copy placed to its right, overlapping by 8 columns
+    let left = Tile::open(NAVCAM_LEFT_SF1).unwrap();
+    let mut right = left.image.clone();
+    right.metadata.as_mut().unwrap().subframe_rect = Some(vec![1281.0, 1.0, 1288.0, 968.0]);
+    let tiles = vec![left, Tile::from_image(right).unwrap()];
+
+    let pairs = tileassembly::find_pairs(&tiles, &AssemblyOptions::default());
+    assert_eq!(pairs.len(), 1);
+    assert!(pairs[0].overlapping);
+    // Borders excluded on both sides
+    assert_eq!(pairs[0].region_a, Rect::new(1282, 2, 4, 964));
+    assert_eq!(pairs[0].region_b, Rect::new(2, 2, 4, 964));
+}
+
+#[test]
+fn test_find_pairs() {
+    let tiles = vec![
+        tile("MAHLI", 1.0, 1.0, 100, 50, 1, |_, _| 0.0),
+        // Overlaps the first by 16 columns
+        tile("MAHLI", 85.0, 1.0, 100, 50, 1, |_, _| 0.0),
+        // Directly below the first
+        tile("MAHLI", 1.0, 51.0, 100, 50, 1, |_, _| 0.0),
+        // Far away
+        tile("MAHLI", 1001.0, 1001.0, 10, 10, 1, |_, _| 0.0),
+    ];
+    let pairs = tileassembly::find_pairs(&tiles, &AssemblyOptions::default());
+
+    let overlap = pairs.iter().find(|p| (p.a, p.b) == (0, 1)).unwrap();
+    assert!(overlap.overlapping);
+    assert_eq!(overlap.region_a, Rect::new(84, 0, 16, 50));
+    assert_eq!(overlap.region_b, Rect::new(0, 0, 16, 50));
+
+    let below = pairs.iter().find(|p| (p.a, p.b) == (0, 2)).unwrap();
+    assert!(!below.overlapping);
+    assert_eq!(below.region_a, Rect::new(0, 38, 100, 12));
+    assert_eq!(below.region_b, Rect::new(0, 0, 100, 12));
+
+    assert!(pairs.iter().all(|p| p.b != 3));
+}
+
+#[test]
+fn test_assemble_on_sensor() {
+    let tiles = vec![
+        tile("NAV_RIGHT_B", 1.0, 1.0, 512, 256, 1, |_, _| 100.0),
+        tile("NAV_RIGHT_B", 513.0, 1.0, 512, 256, 1, |x, _| x as f32),
+    ];
+    let assembly = tileassembly::assemble(&tiles, &AssemblyOptions::default()).unwrap();
+    assert_eq!((assembly.image.width, assembly.image.height), (1024, 1024));
+    assert_eq!(assembly.image.get_band(0).get(10, 10), 100.0);
+    assert_eq!(assembly.image.get_band(0).get(512 + 7, 10), 7.0);
+    assert_eq!(assembly.image.get_band(0).get(10, 500), 0.0);
+
+    let options = AssemblyOptions {
+        crop_to_tiles: true,
+        ..Default::default()
+    };
+    let cropped = tileassembly::assemble(&tiles[1..], &options).unwrap();
+    assert_eq!((cropped.image.width, cropped.image.height), (512, 256));
+    assert_eq!(cropped.subframe_rect(), vec![513.0, 1.0, 512.0, 256.0]);
+}
+
+#[test]
+fn test_assemble_with_border_and_unknown_sensor() {
+    let tiles = vec![tile("SKYCAM", 11.0, 21.0, 40, 30, 1, |_, _| 50.0)];
+    let options = AssemblyOptions {
+        border: Some(2),
+        ..Default::default()
+    };
+    let assembly = tileassembly::assemble(&tiles, &options).unwrap();
+    // Bounding box from the sensor origin, less the border
+    assert_eq!((assembly.image.width, assembly.image.height), (48, 48));
+    assert_eq!(assembly.image.get_band(0).get(11, 21), 0.0);
+    assert_eq!(assembly.image.get_band(0).get(12, 22), 50.0);
+}
+
+#[test]
+fn test_assemble_errors() {
+    assert!(tileassembly::assemble(&[], &AssemblyOptions::default()).is_err());
+    let mixed = vec![
+        tile("MAHLI", 1.0, 1.0, 10, 10, 1, |_, _| 0.0),
+        tile("MAHLI", 1.0, 1.0, 10, 10, 2, |_, _| 0.0),
+    ];
+    assert!(tileassembly::assemble(&mixed, &AssemblyOptions::default()).is_err());
+}
+
+#[test]
+fn test_match_levels() {
+    let scene = |x: usize, y: usize| 100.0 + x as f32 + y as f32 * 2.0;
+    let mut tiles = vec![
+        tile("MAHLI", 1.0, 1.0, 64, 32, 1, scene),
+        // Same scene, brighter and offset
+        tile("MAHLI", 49.0, 1.0, 64, 32, 1, |x, y| {
+            scene(x + 48, y) * 1.5 + 20.0
+        }),
+    ];
+    let pairs = tileassembly::find_pairs(&tiles, &AssemblyOptions::default());
+    let applied = tileassembly::match_levels(&mut tiles, &pairs);
+    assert_eq!(applied[0], (1.0, 0.0));
+    assert!((applied[1].0 - 1.0 / 1.5).abs() < 0.01);
+
+    for (x, y) in [(0, 0), (8, 16), (15, 31), (40, 5)] {
+        let v = tiles[1].image.image.get_band(0).get(x, y);
+        assert!(
+            (v - scene(x + 48, y)).abs() < 0.5,
+            "{} != {}",
+            v,
+            scene(x + 48, y)
+        );
+    }
+}
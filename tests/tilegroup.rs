@@ -0,0 +1,166 @@
+use mars_raw_utils::enums::Instrument;
+use mars_raw_utils::metadata::Metadata;
+use mars_raw_utils::tileassembly::Rect;
+use mars_raw_utils::tilegroup::{self, GroupCriteria, TileCandidate};
+
+mod common;
+
+/// Metadata of a full resolution 1288x968 Navcam tile at grid position (`col`, `row`)
+fn ecam_tile(instrument: &str, sequence: &str, sclk: f64, col: usize, row: usize) -> Metadata {
+    let camera = if instrument == "NAVCAM_RIGHT" {
+        "NRF"
+    } else {
+        "NLF"
+    };
+    let mut md = common::metadata(
+        instrument,
+        &format!(
+            "{}_0670_0726421423_362ECM_N0320604{}_01_095J",
+            camera, sequence
+        ),
+    );
+    md.sclk = Some(sclk);
+    md.subframe_rect = Some(vec![
+        (col * 1280 + 1) as f64,
+        (row * 960 + 1) as f64,
+        1288.0,
+        968.0,
+    ]);
+    md
+}
+
+fn candidate(name: &str, md: &Metadata) -> TileCandidate {
+    TileCandidate::new_from_metadata(&format!("/data/{}.png", name), md).unwrap()
+}
+
+/// Candidates for a full 4x4 tile frame, skipping tiles in `skip`
+fn frame(
+    prefix: &str,
+    instrument: &str,
+    sequence: &str,
+    sclk: f64,
+    skip: &[(usize, usize)],
+) -> Vec<TileCandidate> {
+    let mut tiles = vec![];
+    for row in 0..4 {
+        for col in 0..4 {
+            if !skip.contains(&(col, row)) {
+                tiles.push(candidate(
+                    &format!("{}_{}{}", prefix, row, col),
+                    &ecam_tile(
+                        instrument,
+                        sequence,
+                        sclk + (row * 4 + col) as f64 * 0.1,
+                        col,
+                        row,
+                    ),
+                ));
+            }
+        }
+    }
+    tiles
+}
+
+#[test]
+fn test_rejects_non_ecam_tiles() {
+    let mut md = common::metadata("MAHLI", "3372MH0001900001203405C00_DXXX");
+    md.sclk = Some(1000.0);
+    md.subframe_rect = Some(vec![1.0, 1.0, 1584.0, 1184.0]);
+    assert!(TileCandidate::new_from_metadata("mahli.png", &md).is_err());
+
+    let mut md = ecam_tile("NAVCAM_LEFT", "NCAM08111", 1000.0, 0, 0);
+    md.subframe_rect = None;
+    assert!(TileCandidate::new_from_metadata("nav.png", &md).is_err());
+}
+
+#[test]
+fn test_candidate_placement() {
+    let c = candidate("t", &ecam_tile("NAVCAM_LEFT", "NCAM08111", 1000.0, 2, 1));
+    assert_eq!(c.instrument, Instrument::M20NavcamLeft);
+    assert_eq!(c.sequence_id, Some(String::from("NCAM08111")));
+    assert_eq!(c.rect, Rect::new(2560, 960, 1288, 968));
+}
+
+#[test]
+fn test_find_groups() {
+    let mut candidates = vec![];
+    candidates.extend(frame("a", "NAVCAM_LEFT", "NCAM08111", 1000.0, &[]));
+    // Right eye at the same time
+    candidates.extend(frame("b", "NAVCAM_RIGHT", "NCAM08111", 1000.0, &[]));
+    // Next frame of the panorama, missing a tile
+    candidates.extend(frame("c", "NAVCAM_LEFT", "NCAM08111", 1030.0, &[(3, 2)]));
+    // Another sequence immediately after
+    candidates.extend(frame("d", "NAVCAM_LEFT", "NCAM08112", 1032.0, &[]));
+
+    let groups = tilegroup::find_groups(&candidates, &GroupCriteria::default());
+    assert_eq!(groups.len(), 4);
+
+    let a = groups
+        .iter()
+        .find(|g| g.files[0] == "/data/a_00.png")
+        .unwrap();
+    assert_eq!(a.instrument, Instrument::M20NavcamLeft);
+    assert_eq!(a.files.len(), 16);
+    assert!(a.is_complete());
+    assert_eq!(a.output_file(Some("out")), "out/a_00-assembled.png");
+
+    let b = groups
+        .iter()
+        .find(|g| g.files[0] == "/data/b_00.png")
+        .unwrap();
+    assert_eq!(b.instrument, Instrument::M20NavcamRight);
+    assert_eq!(b.files.len(), 16);
+
+    let c = groups
+        .iter()
+        .find(|g| g.files[0] == "/data/c_00.png")
+        .unwrap();
+    assert_eq!(c.files.len(), 15);
+    assert_eq!(c.missing, vec![Rect::new(3840, 1920, 1280, 960)]);
+    assert_eq!(
+        c.missing[0].subframe_rect(c.scale),
+        vec![3841.0, 1921.0, 1280.0, 960.0]
+    );
+
+    let d = groups
+        .iter()
+        .find(|g| g.files[0] == "/data/d_00.png")
+        .unwrap();
+    assert_eq!(d.sequence_id, Some(String::from("NCAM08112")));
+    assert_eq!(d.files.len(), 16);
+}
+
+#[test]
+fn test_repeated_position_starts_new_frame() {
+    // Two frames of the same sequence within the SCLK window
+    let mut candidates = frame("a", "NAVCAM_LEFT", "NCAM08111", 1000.0, &[]);
+    candidates.extend(frame("b", "NAVCAM_LEFT", "NCAM08111", 1002.0, &[]));
+
+    let criteria = GroupCriteria {
+        sclk_window: 10.0,
+        ..Default::default()
+    };
+    let groups = tilegroup::find_groups(&candidates, &criteria);
+    assert_eq!(groups.len(), 2);
+    assert!(groups
+        .iter()
+        .all(|g| g.files.len() == 16 && g.is_complete()));
+}
+
+#[test]
+fn test_missing_tiles_at_scale() {
+    // A downsampled frame of four 1288x968 tiles at scale factor 2, one missing
+    let rects = vec![
+        Rect::new(0, 0, 1288, 968),
+        Rect::new(1280, 0, 1288, 968),
+        Rect::new(0, 960, 1288, 968),
+    ];
+    let missing = tilegroup::missing_tiles(Instrument::M20FrontHazLeft, 2, &rects);
+    assert_eq!(missing, vec![Rect::new(1280, 960, 1280, 960)]);
+
+    // A single tile covering the whole downsampled sensor
+    let full = vec![Rect::new(0, 0, 1280, 960)];
+    assert!(tilegroup::missing_tiles(Instrument::M20NavcamRight, 4, &full).is_empty());
+
+    assert!(tilegroup::missing_tiles(Instrument::M20SkyCam, 1, &rects).is_empty());
+}
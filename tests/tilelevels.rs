@@ -0,0 +1,138 @@
+use mars_raw_utils::tileassembly::{self, AssemblyOptions, Tile};
+use mars_raw_utils::tilelevels::{self, LevelOptions};
+
+mod common;
+
+/// A single band MAHLI tile of `width` x `height` at one based sensor position (`x`, `y`),
+/// with pixel values from `f`
+fn tile<F: Fn(usize, usize) -> f32>(x: f64, y: f64, width: usize, height: usize, f: F) -> Tile {
+    common::tile("MAHLI", x, y, width, height, 1, f)
+}
+
+fn scene(x: usize, y: usize) -> f32 {
+    100.0 + x as f32 + y as f32 * 2.0
+}
+
+#[test]
+fn test_match_two_tiles() {
+    let mut tiles = vec![
+        tile(1.0, 1.0, 64, 32, scene),
+        // Same scene, brighter and offset
+        tile(49.0, 1.0, 64, 32, |x, y| scene(x + 48, y) * 1.5 + 20.0),
+    ];
+    let pairs = tileassembly::find_pairs(&tiles, &AssemblyOptions::default());
+    let report = tilelevels::match_levels(&mut tiles, &pairs, &LevelOptions::default()).unwrap();
+
+    // The mean gain is held at one
+    assert!((report.levels[0].gain[0] - 1.2).abs() < 0.01);
+    assert!((report.levels[1].gain[0] - 0.8).abs() < 0.01);
+
+    assert_eq!(report.before.len(), 1);
+    assert!(report.before[0].rms.unwrap() > 50.0);
+    assert!(report.after[0].rms.unwrap() < 0.5);
+    assert!(report.after[0].mean_difference.abs() < 0.5);
+
+    for (x, y) in [(0, 0), (8, 16), (15, 31)] {
+        let a = tiles[0].image.image.get_band(0).get(x + 48, y);
+        let b = tiles[1].image.image.get_band(0).get(x, y);
+        assert!((a - b).abs() < 0.5, "{} != {}", a, b);
+    }
+}
+
+#[test]
+fn test_match_two_tiles_pairwise() {
+    let mut tiles = vec![
+        tile(1.0, 1.0, 64, 32, scene),
+        tile(49.0, 1.0, 64, 32, |x, y| scene(x + 48, y) * 1.5 + 20.0),
+    ];
+    let pairs = tileassembly::find_pairs(&tiles, &AssemblyOptions::default());
+    let options = LevelOptions {
+        solve: false,
+        ..Default::default()
+    };
+    let report = tilelevels::match_levels(&mut tiles, &pairs, &options).unwrap();
+
+    // The first tile is kept as it is and the second matched to it
+    assert_eq!(report.levels[0].gain[0], 1.0);
+    assert_eq!(report.levels[0].offset[0], 0.0);
+    assert!((report.levels[1].gain[0] - 1.0 / 1.5).abs() < 0.01);
+    assert!(report.after[0].rms.unwrap() < 0.5);
+}
+
+#[test]
+fn test_no_drift_along_row() {
+    // Each tile 10% brighter than the last, as a chain of pairwise matches would see it
+    let mut tiles: Vec<Tile> = (0..5)
+        .map(|i| {
+            let gain = 1.1_f32.powi(i as i32);
+            tile((i * 48 + 1) as f64, 1.0, 64, 32, move |x, y| {
+                scene(x + i * 48, y) * gain
+            })
+        })
+        .collect();
+    let pairs = tileassembly::find_pairs(&tiles, &AssemblyOptions::default());
+    assert_eq!(pairs.len(), 4);
+    let report = tilelevels::match_levels(&mut tiles, &pairs, &LevelOptions::default()).unwrap();
+
+    let mean_gain: f32 = report.levels.iter().map(|l| l.gain[0]).sum::<f32>() / 5.0;
+    assert!((mean_gain - 1.0).abs() < 0.01);
+    for r in report.after.iter() {
+        assert!(r.rms.unwrap() < 0.5, "Seam {}-{}: {:?}", r.a, r.b, r.rms);
+    }
+
+    // Every tile now maps the scene identically
+    let first = tiles[0].image.image.get_band(0).get(60, 10) / scene(60, 10);
+    let last = tiles[4].image.image.get_band(0).get(20, 10) / scene(20 + 4 * 48, 10);
+    assert!((first - last).abs() < 0.01, "{} != {}", first, last);
+}
+
+#[test]
+fn test_unconnected_tile_unchanged() {
+    let mut tiles = vec![
+        tile(1.0, 1.0, 64, 32, scene),
+        tile(49.0, 1.0, 64, 32, |x, y| scene(x + 48, y) + 30.0),
+        tile(1001.0, 1001.0, 16, 16, |_, _| 500.0),
+    ];
+    let pairs = tileassembly::find_pairs(&tiles, &AssemblyOptions::default());
+    let report = tilelevels::match_levels(&mut tiles, &pairs, &LevelOptions::default()).unwrap();
+    assert!((report.levels[2].gain[0] - 1.0).abs() < 0.01);
+    assert!(report.levels[2].offset[0].abs() < 5.0);
+}
+
+#[test]
+fn test_residuals_of_abutting_tiles() {
+    let tiles = vec![
+        tile(1.0, 1.0, 32, 32, |_, _| 100.0),
+        tile(33.0, 1.0, 32, 32, |_, _| 120.0),
+    ];
+    let pairs = tileassembly::find_pairs(&tiles, &AssemblyOptions::default());
+    let residuals = tilelevels::seam_residuals(&tiles, &pairs);
+    assert_eq!(residuals.len(), 1);
+    assert_eq!(residuals[0].mean_difference, -20.0);
+    assert_eq!(residuals[0].rms, None);
+}
+
+#[test]
+fn test_remove_seams() {
+    let mut tiles = vec![
+        tile(1.0, 1.0, 64, 32, |_, _| 100.0),
+        tile(65.0, 1.0, 64, 32, |_, _| 120.0),
+    ];
+    let assembly_options = AssemblyOptions {
+        crop_to_tiles: true,
+        ..Default::default()
+    };
+    let options = LevelOptions {
+        match_levels: false,
+        remove_seams: true,
+        ..Default::default()
+    };
+    let (assembly, report) =
+        tilelevels::assemble_levelled(&mut tiles, &assembly_options, &options).unwrap();
+    assert_eq!(report.after[0].mean_difference, -20.0);
+
+    let band = assembly.image.get_band(0);
+    for (x, y) in [(0, 0), (63, 16), (64, 16), (127, 31)] {
+        assert!((band.get(x, y) - 110.0).abs() < 0.5, "{}", band.get(x, y));
+    }
+}